@@ -0,0 +1,381 @@
+use crate::application::config::RuleConfig;
+use crate::application::match_space::{self, Space};
+use crate::domain::rule::{Action, RuleEntry};
+use crate::domain::ruleset::RuleSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleRef {
+    pub id: u64,
+    pub name: String,
+    pub chain: String,
+    pub priority: i32,
+}
+
+impl RuleRef {
+    fn new(entry: &RuleEntry) -> Self {
+        Self {
+            id: entry.id,
+            name: entry.filter.name().to_string(),
+            chain: entry.chain.to_string(),
+            priority: entry.filter.priority(),
+        }
+    }
+}
+
+impl fmt::Display for RuleRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' (#{})", self.name, self.id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    // Earlier rules decide every packet the rule could match, at least one
+    // of them differently than the rule would
+    Shadowed { rule: RuleRef, by: Vec<RuleRef> },
+    // Removing the rule changes no verdict: earlier rules already decide its
+    // packets the same way, or (with `by` empty) nothing after it matches
+    // them and the chain's policy is the same action
+    Redundant { rule: RuleRef, by: Vec<RuleRef> },
+    // The rule partly overlaps earlier rules that give the overlap the
+    // opposite verdict (allow vs. drop); those rules win
+    Conflict { rule: RuleRef, with: Vec<RuleRef> },
+    // Same priority, overlapping matches and different actions: only
+    // insertion order decides which one applies
+    AmbiguousOrder { first: RuleRef, second: RuleRef },
+}
+
+impl Finding {
+    // The rule the finding is about; for pairs, the later one
+    pub fn rule(&self) -> &RuleRef {
+        match self {
+            Finding::Shadowed { rule, .. }
+            | Finding::Redundant { rule, .. }
+            | Finding::Conflict { rule, .. } => rule,
+            Finding::AmbiguousOrder { second, .. } => second,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |rules: &[RuleRef]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ");
+        match self {
+            Finding::Shadowed { rule, by } => write!(
+                f,
+                "rule {} in chain '{}' never applies: shadowed by {}",
+                rule, rule.chain, list(by)
+            ),
+            Finding::Redundant { rule, by } if by.is_empty() => write!(
+                f,
+                "rule {} in chain '{}' is redundant with the chain policy",
+                rule, rule.chain
+            ),
+            Finding::Redundant { rule, by } => write!(
+                f,
+                "rule {} in chain '{}' is redundant after {}",
+                rule, rule.chain, list(by)
+            ),
+            Finding::Conflict { rule, with } => write!(
+                f,
+                "rule {} in chain '{}' partly overlaps {} with the opposite verdict; the earlier rules win",
+                rule, rule.chain, list(with)
+            ),
+            Finding::AmbiguousOrder { first, second } => write!(
+                f,
+                "rules {} and {} in chain '{}' share priority {} and overlap with different actions",
+                first, second, first.chain, first.priority
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnalysisReport {
+    // Rule set generation that was analyzed
+    pub generation: u64,
+    pub findings: Vec<Finding>,
+    // Enabled rules without a config form; the analysis can't see what
+    // they match, so they are left out of it
+    pub unmodeled: Vec<RuleRef>,
+}
+
+impl AnalysisReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+// Static analysis of a rule set, chain by chain. Rules are described by the
+// packets they can match (protocols, prefixes, ports, connection states);
+// conditions it doesn't model, like time windows, geo lookups or TCP
+// flags, make the description an over-approximation, and such rules never
+// count as covering another one. Disabled rules are ignored.
+pub fn analyze(rules: &RuleSet) -> AnalysisReport {
+    let mut report = AnalysisReport {
+        generation: rules.generation(),
+        ..AnalysisReport::default()
+    };
+
+    for index in 0..=rules.chains().len() {
+        let policy = match index {
+            // A non-terminal default lets the packet through
+            0 if rules.default_action().is_terminal() => Some(rules.default_action().clone()),
+            0 => Some(Action::Allow),
+            // Without a policy, evaluation returns to the caller
+            i => rules.chain_policy(i).cloned(),
+        };
+
+        let mut modeled = Vec::new();
+        for entry in rules.chain_rules(index).iter().filter(|e| e.enabled) {
+            match model(entry) {
+                Some(clauses) => modeled.push(Modeled { entry, clauses }),
+                None => {
+                    report.unmodeled.push(RuleRef::new(entry));
+                    modeled.push(Modeled { entry, clauses: vec![(Space::opaque(), None)] });
+                }
+            }
+        }
+        analyze_chain(&modeled, policy.as_ref(), &mut report.findings);
+    }
+    report
+}
+
+// A rule as a list of (packets, action) clauses; it matches their union.
+// `None` action: unknown, the rule has no config form.
+struct Modeled<'a> {
+    entry: &'a RuleEntry,
+    clauses: Vec<(Space, Option<Action>)>,
+}
+
+impl Modeled<'_> {
+    fn known(&self) -> bool {
+        self.clauses.iter().all(|(_, action)| action.is_some())
+    }
+}
+
+fn analyze_chain(rules: &[Modeled], policy: Option<&Action>, findings: &mut Vec<Finding>) {
+    for (j, rule) in rules.iter().enumerate() {
+        if !rule.known() {
+            continue;
+        }
+        let earlier = &rules[..j];
+
+        // Priority ties are worth knowing about even for dead rules: the
+        // other order could revive them
+        let mut conflicts = Vec::new();
+        for first in earlier.iter().filter(|r| r.known()) {
+            match compare(first, rule) {
+                Some(Overlap::Tie) => findings.push(Finding::AmbiguousOrder {
+                    first: RuleRef::new(first.entry),
+                    second: RuleRef::new(rule.entry),
+                }),
+                Some(Overlap::Opposite) => conflicts.push(RuleRef::new(first.entry)),
+                None => {}
+            }
+        }
+
+        if let Some(finding) = shadowing(rule, earlier) {
+            findings.push(finding);
+            continue;
+        }
+
+        if let Some(policy) = policy
+            && !rule.clauses.is_empty()
+            && rule.clauses.iter().all(|(_, action)| action.as_ref() == Some(policy))
+            && !rules[j + 1..].iter().any(|later| overlaps(rule, later))
+        {
+            findings.push(Finding::Redundant { rule: RuleRef::new(rule.entry), by: Vec::new() });
+        }
+
+        if !conflicts.is_empty() {
+            findings.push(Finding::Conflict { rule: RuleRef::new(rule.entry), with: conflicts });
+        }
+    }
+}
+
+enum Overlap {
+    // Same priority, different actions on shared packets
+    Tie,
+    // Partial overlap, one allows and the other drops
+    Opposite,
+}
+
+// Every clause of `rule` covered by one exact, terminal clause of an earlier
+// rule
+fn shadowing(rule: &Modeled, earlier: &[Modeled]) -> Option<Finding> {
+    if rule.clauses.is_empty() {
+        return None;
+    }
+    let mut by: Vec<RuleRef> = Vec::new();
+    let mut same = true;
+    for (space, action) in &rule.clauses {
+        let (coverer, covering) = earlier.iter().find_map(|r| {
+            r.clauses.iter()
+                .find(|(s, a)| s.exact && a.as_ref().is_some_and(Action::is_terminal) && s.covers(space))
+                .map(|(_, a)| (r, a))
+        })?;
+        same &= covering == action;
+        if !by.iter().any(|b| b.id == coverer.entry.id) {
+            by.push(RuleRef::new(coverer.entry));
+        }
+    }
+    let rule = RuleRef::new(rule.entry);
+    Some(if same { Finding::Redundant { rule, by } } else { Finding::Shadowed { rule, by } })
+}
+
+fn overlaps(rule: &Modeled, other: &Modeled) -> bool {
+    rule.clauses.iter()
+        .any(|(a, _)| other.clauses.iter().any(|(b, _)| a.meet(b).is_some()))
+}
+
+fn compare(first: &Modeled, second: &Modeled) -> Option<Overlap> {
+    let terminal = |(space, action): &(Space, Option<Action>)| {
+        action.as_ref().filter(|a| a.is_terminal()).map(|a| (space.clone(), a.clone()))
+    };
+    let ambiguous = first.entry.filter.priority() == second.entry.filter.priority();
+    for (a, a_action) in first.clauses.iter().filter_map(terminal) {
+        for (b, b_action) in second.clauses.iter().filter_map(terminal) {
+            if a_action == b_action || a.meet(&b).is_none() {
+                continue;
+            }
+            // Order is all that separates them, whatever the overlap
+            if ambiguous {
+                return Some(Overlap::Tie);
+            }
+            // An exception carved out of a broader rule is the normal way to
+            // write a policy; only a partial overlap is suspicious
+            if a.exact && b.exact && a_action.permits() != b_action.permits() && !b.covers(&a) {
+                return Some(Overlap::Opposite);
+            }
+        }
+    }
+    None
+}
+
+// Returns `None` for filters without a config form
+fn model(entry: &RuleEntry) -> Option<Vec<(Space, Option<Action>)>> {
+    let config = RuleConfig::from_filter(&*entry.filter)?;
+    let mut clauses: Vec<(Space, Option<Action>)> = match_space::clauses(&config.kind)
+        .into_iter()
+        .map(|(space, action)| (space, Some(action)))
+        .collect();
+
+    // Only in effect part of the time
+    if entry.schedule.is_scheduled() {
+        for (space, _) in &mut clauses {
+            space.exact = false;
+        }
+    }
+    Some(clauses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::rule_manager::RuleManager;
+    use crate::domain::chain::DEFAULT_CHAIN;
+    use crate::domain::rule::{Filter, RuleSchedule};
+    use crate::rules::{IpPrefixRule, PortBlocklistRule};
+    use std::time::{Duration, SystemTime};
+
+    fn ports(name: &str, start: u16, end: u16, action: Action, priority: i32) -> Box<dyn Filter> {
+        Box::new(PortBlocklistRule::new(name).add_range(start, end).with_action(action).with_priority(priority))
+    }
+
+    fn prefixes(name: &str, entries: &[(&str, Action)], priority: i32) -> Box<dyn Filter> {
+        let entries = entries.iter().map(|(prefix, action)| (prefix.parse().unwrap(), action.clone()));
+        Box::new(IpPrefixRule::new(name).add_prefixes(entries).with_priority(priority))
+    }
+
+    fn rules(default_action: Action, filters: Vec<Box<dyn Filter>>) -> (RuleManager, Vec<RuleRef>) {
+        let manager = RuleManager::with_default_action(default_action);
+        let ids = manager.add_rules(filters).unwrap();
+        let set = manager.snapshot();
+        let refs = ids.iter().map(|&id| RuleRef::new(set.get(id).unwrap())).collect();
+        (manager, refs)
+    }
+
+    #[test]
+    fn shadowed_by_an_earlier_rule() {
+        let (manager, r) = rules(Action::Allow, vec![
+            ports("no-range", 20, 30, Action::Block, 20),
+            ports("allow-smtp", 25, 25, Action::Allow, 10),
+        ]);
+        let expected = Finding::Shadowed { rule: r[1].clone(), by: vec![r[0].clone()] };
+        assert_eq!(manager.analyze().findings, [expected]);
+    }
+
+    #[test]
+    fn redundant_after_an_earlier_rule_or_the_policy() {
+        let (manager, r) = rules(Action::Allow, vec![
+            ports("no-range", 20, 30, Action::Block, 20),
+            ports("no-smtp", 25, 25, Action::Block, 10),
+            ports("allow-http", 80, 80, Action::Allow, 0),
+        ]);
+        assert_eq!(manager.analyze().findings, [
+            Finding::Redundant { rule: r[1].clone(), by: vec![r[0].clone()] },
+            Finding::Redundant { rule: r[2].clone(), by: Vec::new() },
+        ]);
+    }
+
+    #[test]
+    fn conflict_on_a_partial_overlap() {
+        let (manager, r) = rules(Action::Block, vec![
+            ports("no-range", 20, 30, Action::Block, 20),
+            ports("allow-range", 25, 40, Action::Allow, 10),
+        ]);
+        let expected = Finding::Conflict { rule: r[1].clone(), with: vec![r[0].clone()] };
+        assert_eq!(manager.analyze().findings, [expected]);
+    }
+
+    #[test]
+    fn ambiguous_order_on_a_priority_tie() {
+        let (manager, r) = rules(Action::Block, vec![
+            ports("no-smtp", 25, 25, Action::Block, 10),
+            ports("allow-range", 20, 30, Action::Allow, 10),
+        ]);
+        let expected = Finding::AmbiguousOrder { first: r[0].clone(), second: r[1].clone() };
+        assert_eq!(manager.analyze().findings, [expected]);
+    }
+
+    #[test]
+    fn exceptions_carved_out_of_broader_rules_are_fine() {
+        let (manager, _) = rules(Action::Allow, vec![
+            ports("allow-ssh", 22, 22, Action::Allow, 20),
+            ports("no-low-ports", 1, 1024, Action::Block, 10),
+        ]);
+        assert!(manager.analyze().is_clean());
+    }
+
+    #[test]
+    fn scheduled_and_disabled_rules_cover_nothing() {
+        let manager = RuleManager::with_default_action(Action::Allow);
+        let schedule = RuleSchedule::default().until(SystemTime::now() + Duration::from_secs(3600));
+        manager.add_scheduled_rule(DEFAULT_CHAIN, ports("no-range", 20, 30, Action::Block, 30), schedule).unwrap();
+        let disabled = manager.add_rule(ports("allow-range", 20, 30, Action::Allow, 20)).unwrap();
+        manager.set_enabled(disabled, false);
+        manager.add_rule(ports("no-smtp", 25, 25, Action::Block, 10)).unwrap();
+        // Either would make the last rule redundant or shadow it
+        assert!(manager.analyze().is_clean());
+    }
+
+    #[test]
+    fn broader_prefixes_only_cover_when_the_set_agrees() {
+        // 10.2.0.0/16 falls to 10.0.0.0/8 either way, but with a more
+        // specific allow in the set the /8 doesn't decide all of itself
+        let (manager, _) = rules(Action::Allow, vec![
+            prefixes("mixed", &[("10.0.0.0/8", Action::Block), ("10.1.0.0/16", Action::Allow)], 20),
+            prefixes("lab", &[("10.2.0.0/16", Action::Block)], 10),
+        ]);
+        assert!(manager.analyze().is_clean());
+
+        let (manager, r) = rules(Action::Allow, vec![
+            prefixes("uniform", &[("10.0.0.0/8", Action::Block), ("192.168.0.0/16", Action::Block)], 20),
+            prefixes("lab", &[("10.2.0.0/16", Action::Block)], 10),
+        ]);
+        let expected = Finding::Redundant { rule: r[1].clone(), by: vec![r[0].clone()] };
+        assert_eq!(manager.analyze().findings, [expected]);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::application::match_space::{self, Space};
+use crate::domain::packet::{Packet, Protocol};
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rule::RuleEntry;
+use crate::domain::ruleset::{Candidates, RuleSetIndex};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::{Range, RangeInclusive};
+
+// Port segments a clause may span before it's indexed some other way, so a
+// handful of "everything but 22" rules can't blow up the table
+const MAX_PORT_SEGMENTS: usize = 256;
+
+// Lookup tables built alongside a rule set generation. For a packet they
+// give the rules of a chain that could match it; every other rule would
+// return `None` from `check_packet`, so skipping them changes no verdict.
+//
+// Rules are described by their config form (see `match_space`). Filters
+// without one, prefix rules (their table can change without a new
+// generation) and rules whose match isn't keyed on a port, prefix or
+// protocol are checked for every packet, same as linear evaluation.
+pub struct RuleIndex {
+    chains: Vec<ChainIndex>,
+}
+
+impl RuleIndex {
+    // `ranges` slices `entries` into chains, as in `RuleSet`
+    pub fn build(entries: &[RuleEntry], ranges: &[Range<usize>]) -> Self {
+        let chains = ranges.iter()
+            .map(|range| ChainIndex::build(&entries[range.clone()]))
+            .collect();
+        Self { chains }
+    }
+
+    // `RuleSetCell::set_indexer` takes this to compile each generation
+    pub fn boxed(entries: &[RuleEntry], ranges: &[Range<usize>]) -> Box<dyn RuleSetIndex> {
+        Box::new(Self::build(entries, ranges))
+    }
+}
+
+impl RuleSetIndex for RuleIndex {
+    fn candidates(&self, chain: usize, packet: &Packet) -> Candidates {
+        let index = &self.chains[chain];
+        let mut candidates = Candidates::new(chain, index.len);
+        candidates.extend(&index.always);
+        if let Some(port) = packet.destination_port {
+            candidates.extend(index.destination_ports.get(port));
+        }
+        index.destinations.collect(&packet.destination_ip, &mut candidates);
+        index.sources.collect(&packet.source_ip, &mut candidates);
+        if let Some(positions) = index.protocols.get(&packet.protocol) {
+            candidates.extend(positions);
+        }
+        candidates
+    }
+}
+
+#[derive(Default)]
+struct ChainIndex {
+    len: usize,
+    // Checked for every packet
+    always: Vec<u32>,
+    destination_ports: PortIndex,
+    destinations: PrefixIndex,
+    sources: PrefixIndex,
+    protocols: HashMap<Protocol, Vec<u32>>,
+}
+
+impl ChainIndex {
+    fn build(entries: &[RuleEntry]) -> Self {
+        // Disabled rules never match; the index is rebuilt when they change
+        let modeled: Vec<(u32, Option<Vec<Space>>)> = entries.iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.enabled)
+            .map(|(position, entry)| (position as u32, indexable(entry)))
+            .collect();
+
+        let mut index = ChainIndex {
+            len: entries.len(),
+            destination_ports: PortIndex::new(
+                modeled.iter()
+                    .flat_map(|(_, spaces)| spaces.iter().flatten())
+                    .filter_map(|space| space.destination_ports.as_deref())
+                    .flatten(),
+            ),
+            ..ChainIndex::default()
+        };
+
+        for (position, spaces) in modeled {
+            let Some(spaces) = spaces else {
+                index.always.push(position);
+                continue;
+            };
+            for space in &spaces {
+                index.insert(position, space);
+            }
+        }
+        index
+    }
+
+    // Files the clause under its most selective key
+    fn insert(&mut self, position: u32, space: &Space) {
+        if let Some(ports) = &space.destination_ports
+            && self.destination_ports.insert(position, ports)
+        {
+            return;
+        }
+        if let Some(prefixes) = &space.destinations {
+            self.destinations.insert(position, prefixes);
+        } else if let Some(prefixes) = &space.sources {
+            self.sources.insert(position, prefixes);
+        } else if let Some(protocols) = &space.protocols {
+            for protocol in protocols {
+                push_once(self.protocols.entry(*protocol).or_default(), position);
+            }
+        } else if self.always.last() != Some(&position) {
+            self.always.push(position);
+        }
+    }
+}
+
+// `None` for rules that have to be checked for every packet
+fn indexable(entry: &RuleEntry) -> Option<Vec<Space>> {
+    let config = RuleConfig::from_filter(&*entry.filter)?;
+    if matches!(config.kind, RuleKind::IpPrefix { .. }) {
+        return None;
+    }
+    Some(match_space::clauses(&config.kind).into_iter().map(|(space, _)| space).collect())
+}
+
+// A rule can land in several buckets of one table through different
+// clauses; within a bucket it only needs to appear once
+fn push_once(positions: &mut Vec<u32>, position: u32) {
+    if positions.last() != Some(&position) {
+        positions.push(position);
+    }
+}
+
+// Destination ports cut into segments at every range boundary; each
+// segment lists the rules whose ranges cover it
+#[derive(Default)]
+struct PortIndex {
+    // Segment `i` runs from `starts[i]` up to the next start
+    starts: Vec<u16>,
+    rules: Vec<Vec<u32>>,
+}
+
+impl PortIndex {
+    fn new<'a>(ranges: impl Iterator<Item = &'a RangeInclusive<u16>>) -> Self {
+        let mut starts = vec![0];
+        for range in ranges {
+            starts.push(*range.start());
+            if *range.end() < u16::MAX {
+                starts.push(*range.end() + 1);
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+        let rules = vec![Vec::new(); starts.len()];
+        Self { starts, rules }
+    }
+
+    fn segment(&self, port: u16) -> usize {
+        self.starts.partition_point(|&start| start <= port) - 1
+    }
+
+    // False if the ranges cover too many segments to be worth it
+    fn insert(&mut self, position: u32, ranges: &[RangeInclusive<u16>]) -> bool {
+        let spans: Vec<Range<usize>> = ranges.iter()
+            .map(|range| self.segment(*range.start())..self.segment(*range.end()) + 1)
+            .collect();
+        if spans.iter().map(|span| span.len()).sum::<usize>() > MAX_PORT_SEGMENTS {
+            return false;
+        }
+        for span in spans {
+            for segment in span {
+                push_once(&mut self.rules[segment], position);
+            }
+        }
+        true
+    }
+
+    fn get(&self, port: u16) -> &[u32] {
+        if self.starts.is_empty() {
+            return &[];
+        }
+        &self.rules[self.segment(port)]
+    }
+}
+
+// Prefixes by exact value, probed once per prefix length in use
+#[derive(Default)]
+struct PrefixIndex {
+    rules: HashMap<IpPrefix, Vec<u32>>,
+    v4_lengths: Vec<u8>,
+    v6_lengths: Vec<u8>,
+}
+
+impl PrefixIndex {
+    fn insert(&mut self, position: u32, prefixes: &[IpPrefix]) {
+        for prefix in prefixes {
+            push_once(self.rules.entry(*prefix).or_default(), position);
+            let lengths = if prefix.is_ipv4() { &mut self.v4_lengths } else { &mut self.v6_lengths };
+            if let Err(at) = lengths.binary_search(&prefix.prefix_len()) {
+                lengths.insert(at, prefix.prefix_len());
+            }
+        }
+    }
+
+    fn collect(&self, ip: &IpAddr, candidates: &mut Candidates) {
+        let lengths = if ip.is_ipv4() { &self.v4_lengths } else { &self.v6_lengths };
+        for &len in lengths {
+            let Ok(prefix) = IpPrefix::new(*ip, len) else {
+                continue;
+            };
+            if let Some(positions) = self.rules.get(&prefix) {
+                candidates.extend(positions);
+            }
+        }
+    }
+}
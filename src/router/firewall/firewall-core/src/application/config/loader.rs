@@ -0,0 +1,1070 @@
+use crate::application::config::rule_config::{ChainConfig, FirewallConfig, RuleConfig, RuleKind};
+use crate::domain::chain::{self, ChainError, DEFAULT_CHAIN};
+use crate::domain::flow::ConnState;
+use crate::domain::packet::Protocol;
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rate_limiter::{RateLimitAlgorithm, RateLimitConfig, RateLimitKeyType};
+use crate::domain::rule::{Action, RuleSchedule};
+use crate::rules::expr_rules::Expression;
+use crate::rules::ip_rules::AddressMatch;
+use crate::rules::port_rules::Service;
+use crate::rules::rate_limit_rules::time_based_limit_rule::WindowLimit;
+use crate::rules::time_rules::TimeWindow;
+use chrono::{DateTime, NaiveTime, SecondsFormat, Timelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::Spanned;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, source: std::io::Error },
+    // Syntax or validation error; line and column are 1-based
+    Invalid { line: usize, column: usize, message: String },
+    // The rule set holds a filter the config format can't describe
+    NotExportable { rule: String },
+    Export(String),
+    // A config built in code breaks the chain graph; files are checked
+    // while loading and report `Invalid` instead
+    Chain(ChainError),
+    // A config built in code names a limiter that is neither in it nor
+    // registered; files report `Invalid` instead
+    UndefinedRateLimiter(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "failed to access {}: {}", path.display(), source)
+            }
+            ConfigError::Invalid { line, column, message } => {
+                write!(f, "line {}, column {}: {}", line, column, message)
+            }
+            ConfigError::NotExportable { rule } => {
+                write!(f, "rule '{}' has no config representation", rule)
+            }
+            ConfigError::Export(message) => write!(f, "failed to export config: {}", message),
+            ConfigError::Chain(e) => write!(f, "{}", e),
+            ConfigError::UndefinedRateLimiter(name) => {
+                write!(f, "'rate-limit:{}' refers to an undefined rate limiter", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+const DEFAULT_ACTION: Action = Action::Block;
+
+const SERVICES: [(&str, Service); 11] = [
+    ("http", Service::Http),
+    ("https", Service::Https),
+    ("ssh", Service::Ssh),
+    ("telnet", Service::Telnet),
+    ("ftp", Service::Ftp),
+    ("smtp", Service::Smtp),
+    ("dns", Service::Dns),
+    ("dhcp", Service::Dhcp),
+    ("mqtt", Service::Mqtt),
+    ("mqtt-tls", Service::MqttTls),
+    ("rdp", Service::Rdp),
+];
+
+const CONN_STATES: [(&str, ConnState); 4] = [
+    ("new", ConnState::New),
+    ("established", ConnState::Established),
+    ("related", ConnState::Related),
+    ("invalid", ConnState::Invalid),
+];
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Mon),
+    ("tue", Weekday::Tue),
+    ("wed", Weekday::Wed),
+    ("thu", Weekday::Thu),
+    ("fri", Weekday::Fri),
+    ("sat", Weekday::Sat),
+    ("sun", Weekday::Sun),
+];
+
+// Fields every rule type accepts; the rest are checked per type
+const COMMON_FIELDS: [&str; 7] = [
+    "name", "type", "priority", "enabled", "chain", "active_from", "expires_at",
+];
+
+// File layout as written by users. Values that need validation are kept
+// `Spanned` so errors can point at them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    default_action: Option<Spanned<String>>,
+    #[serde(default)]
+    rate_limiters: BTreeMap<String, Spanned<RawRateLimiter>>,
+    #[serde(default)]
+    chains: BTreeMap<Spanned<String>, RawChain>,
+    #[serde(default)]
+    rule: Vec<Spanned<RawRule>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawChain {
+    policy: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimiter {
+    rate: Spanned<f64>,
+    burst: Option<Spanned<f64>>,
+    key: Option<Spanned<String>>,
+    algorithm: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    name: Spanned<String>,
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    priority: Option<Spanned<i32>>,
+    enabled: Option<Spanned<bool>>,
+    chain: Option<Spanned<String>>,
+    active_from: Option<Spanned<toml::Value>>,
+    expires_at: Option<Spanned<toml::Value>>,
+    action: Option<Spanned<String>>,
+    #[serde(rename = "match")]
+    match_on: Option<Spanned<String>>,
+    ports: Option<Spanned<Vec<Spanned<toml::Value>>>>,
+    protocols: Option<Spanned<Vec<Spanned<toml::Value>>>>,
+    services: Option<Spanned<Vec<Spanned<String>>>>,
+    block: Option<Spanned<Vec<Spanned<String>>>>,
+    allow: Option<Spanned<Vec<Spanned<String>>>>,
+    prefixes: Option<Spanned<BTreeMap<Spanned<String>, Spanned<String>>>>,
+    windows: Option<Spanned<Vec<Spanned<RawWindow>>>>,
+    states: Option<Spanned<Vec<Spanned<String>>>>,
+    rate: Option<Spanned<f64>>,
+    burst: Option<Spanned<f64>>,
+    key: Option<Spanned<String>>,
+    algorithm: Option<Spanned<String>>,
+    expr: Option<Spanned<String>>,
+}
+
+impl RawRule {
+    fn present_fields(&self) -> Vec<(&'static str, Range<usize>)> {
+        let mut fields = Vec::new();
+        let mut push = |name, span: Option<Range<usize>>| {
+            if let Some(span) = span {
+                fields.push((name, span));
+            }
+        };
+        push("action", self.action.as_ref().map(Spanned::span));
+        push("match", self.match_on.as_ref().map(Spanned::span));
+        push("ports", self.ports.as_ref().map(Spanned::span));
+        push("protocols", self.protocols.as_ref().map(Spanned::span));
+        push("services", self.services.as_ref().map(Spanned::span));
+        push("block", self.block.as_ref().map(Spanned::span));
+        push("allow", self.allow.as_ref().map(Spanned::span));
+        push("prefixes", self.prefixes.as_ref().map(Spanned::span));
+        push("windows", self.windows.as_ref().map(Spanned::span));
+        push("states", self.states.as_ref().map(Spanned::span));
+        push("rate", self.rate.as_ref().map(Spanned::span));
+        push("burst", self.burst.as_ref().map(Spanned::span));
+        push("key", self.key.as_ref().map(Spanned::span));
+        push("algorithm", self.algorithm.as_ref().map(Spanned::span));
+        push("expr", self.expr.as_ref().map(Spanned::span));
+        fields
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWindow {
+    start: Spanned<String>,
+    end: Spanned<String>,
+    days: Option<Vec<Spanned<String>>>,
+    // Only in time_rate_limit rules
+    rate: Option<Spanned<f64>>,
+    burst: Option<Spanned<f64>>,
+}
+
+impl FirewallConfig {
+    pub fn from_toml(source: &str) -> Result<Self, ConfigError> {
+        let raw: RawConfig = toml::from_str(source).map_err(|e| {
+            let (line, column) = e.span()
+                .map(|span| position(source, span.start))
+                .unwrap_or((1, 1));
+            ConfigError::Invalid { line, column, message: e.message().to_string() }
+        })?;
+        Validator { source }.config(raw)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&source)
+    }
+
+    // Writes the canonical form; loading it back yields an equal config
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        let document = ExportConfig {
+            default_action: self.default_action.to_string(),
+            rate_limiters: self.rate_limiters.iter()
+                .map(|(name, limit)| (name.as_str(), export_limiter(limit)))
+                .collect(),
+            chains: self.chains.iter()
+                .map(|(name, chain)| (name.as_str(), ExportChain {
+                    policy: chain.policy.as_ref().map(Action::to_string),
+                }))
+                .collect(),
+            rule: self.rules.iter().map(export_rule).collect(),
+        };
+        toml::to_string_pretty(&document).map_err(|e| ConfigError::Export(e.to_string()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_toml()?).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+// 1-based line and column (in characters) of a byte offset
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = source[line_start..offset].chars().count() + 1;
+    (line, column)
+}
+
+struct Validator<'a> {
+    source: &'a str,
+}
+
+impl Validator<'_> {
+    fn error(&self, span: Range<usize>, message: impl Into<String>) -> ConfigError {
+        let (line, column) = position(self.source, span.start);
+        ConfigError::Invalid { line, column, message: message.into() }
+    }
+
+    fn config(&self, raw: RawConfig) -> Result<FirewallConfig, ConfigError> {
+        let default_action = match &raw.default_action {
+            Some(action) => self.action(action)?,
+            None => DEFAULT_ACTION,
+        };
+        if let Some(action) = raw.default_action.as_ref().filter(|_| chain::is_flow_control(&default_action)) {
+            return Err(self.error(action.span(), format!("'{}' can't be the default action", default_action)));
+        }
+
+        let mut rate_limiters = BTreeMap::new();
+        for (name, limiter) in &raw.rate_limiters {
+            let limit = self.rate_limit(
+                &limiter.as_ref().rate,
+                limiter.as_ref().burst.as_ref(),
+                limiter.as_ref().key.as_ref(),
+                limiter.as_ref().algorithm.as_ref(),
+            )?;
+            rate_limiters.insert(name.clone(), limit);
+        }
+
+        let mut chains = BTreeMap::new();
+        for (name, raw_chain) in &raw.chains {
+            chains.insert(name.as_ref().clone(), self.chain(name, raw_chain)?);
+        }
+
+        let mut names = HashSet::new();
+        let mut rules = Vec::with_capacity(raw.rule.len());
+        for rule in &raw.rule {
+            let rule_config = self.rule(rule)?;
+            let name = rule.as_ref().name.as_ref();
+            if !names.insert(rule_config.name.clone()) {
+                return Err(self.error(
+                    rule.as_ref().name.span(),
+                    format!("duplicate rule name '{}'", name),
+                ));
+            }
+            rules.push(rule_config);
+        }
+
+        let config = FirewallConfig { default_action, rate_limiters, chains, rules };
+        self.check_rate_limit_references(&config, &raw)?;
+        self.check_chain_references(&config, &raw)?;
+        Ok(config)
+    }
+
+    fn chain(&self, name: &Spanned<String>, raw: &RawChain) -> Result<ChainConfig, ConfigError> {
+        match name.as_ref().as_str() {
+            "" => return Err(self.error(name.span(), "chain name must not be empty")),
+            DEFAULT_CHAIN => {
+                return Err(self.error(name.span(), format!(
+                    "'{}' is the entry chain; its policy is default_action", DEFAULT_CHAIN
+                )));
+            }
+            _ => {}
+        }
+        let policy = match &raw.policy {
+            Some(spanned) => {
+                let policy = self.action(spanned)?;
+                if !chain::valid_policy(&policy) {
+                    return Err(self.error(spanned.span(), format!(
+                        "'{}' can't be a chain policy, it has to end evaluation", policy
+                    )));
+                }
+                Some(policy)
+            }
+            None => None,
+        };
+        Ok(ChainConfig { policy })
+    }
+
+    // Rules have to sit in a defined chain, and jumps have to lead to a
+    // user-defined chain without looping back
+    fn check_chain_references(&self, config: &FirewallConfig, raw: &RawConfig) -> Result<(), ConfigError> {
+        let defined = |name: &str| name == DEFAULT_CHAIN || config.chains.contains_key(name);
+        // Point at the action key when there is one, else at the rule
+        let action_span = |raw_rule: &Spanned<RawRule>| {
+            raw_rule.as_ref().action.as_ref()
+                .map(Spanned::span)
+                .unwrap_or_else(|| raw_rule.span())
+        };
+
+        for (rule, raw_rule) in config.rules.iter().zip(&raw.rule) {
+            if let Some(chain) = raw_rule.as_ref().chain.as_ref().filter(|c| !defined(c.as_ref())) {
+                return Err(self.error(chain.span(), format!("undefined chain '{}'", chain.as_ref())));
+            }
+            for target in rule.kind.actions().into_iter().filter_map(chain::jump_target) {
+                if target == DEFAULT_CHAIN {
+                    return Err(self.error(action_span(raw_rule), "can't jump to the entry chain"));
+                }
+                if !defined(target) {
+                    return Err(self.error(action_span(raw_rule), format!(
+                        "'{}' is not a defined chain", target
+                    )));
+                }
+            }
+        }
+
+        let edges = config.rules.iter().flat_map(|rule| {
+            rule.kind.actions().into_iter()
+                .filter_map(chain::jump_target)
+                .map(|target| (rule.chain.as_str(), target))
+        });
+        let Some(path) = chain::find_loop(edges) else {
+            return Ok(());
+        };
+        // Blame the first rule that makes one of the loop's jumps
+        let in_loop = |rule: &RuleConfig| {
+            rule.kind.actions().into_iter()
+                .filter_map(chain::jump_target)
+                .any(|target| path.windows(2).any(|pair| pair[0] == rule.chain && pair[1] == target))
+        };
+        let span = config.rules.iter().zip(&raw.rule)
+            .find(|(rule, _)| in_loop(rule))
+            .map_or(0..0, |(_, raw_rule)| action_span(raw_rule));
+        Err(self.error(span, format!("chain loop: {}", path.join(" -> "))))
+    }
+
+    // `rate-limit:<name>` has to name a limiter from `[rate_limiters]`
+    fn check_rate_limit_references(&self, config: &FirewallConfig, raw: &RawConfig) -> Result<(), ConfigError> {
+        let known = |action: &Action| match action {
+            Action::RateLimit(name) => config.rate_limiters.contains_key(name),
+            _ => true,
+        };
+        let unknown = |span: Range<usize>, action: &Action| {
+            self.error(span, format!("'{}' refers to an undefined rate limiter", action))
+        };
+
+        if !known(&config.default_action) {
+            let span = raw.default_action.as_ref().map(Spanned::span).unwrap_or(0..0);
+            return Err(unknown(span, &config.default_action));
+        }
+        for (rule, raw_rule) in config.rules.iter().zip(&raw.rule) {
+            if let Some(action) = rule.kind.actions().into_iter().find(|a| !known(a)) {
+                // Point at the action key when there is one, else at the rule
+                let span = raw_rule.as_ref().action.as_ref()
+                    .map(Spanned::span)
+                    .unwrap_or_else(|| raw_rule.span());
+                return Err(unknown(span, action));
+            }
+        }
+        Ok(())
+    }
+
+    fn rule(&self, spanned: &Spanned<RawRule>) -> Result<RuleConfig, ConfigError> {
+        let raw = spanned.as_ref();
+        let name = raw.name.as_ref().trim();
+        if name.is_empty() {
+            return Err(self.error(raw.name.span(), "rule name must not be empty"));
+        }
+
+        let kind_name = raw.kind.as_ref().as_str();
+        let allowed: &[&str] = match kind_name {
+            "port_blocklist" => &["action", "ports", "protocols", "match"],
+            "port_allowlist" => &["ports", "protocols"],
+            "services" => &["action", "services"],
+            "ip_prefix" => &["match", "block", "allow", "prefixes"],
+            "time_window" => &["action", "windows"],
+            "rate_limit" => &["rate", "burst", "key", "algorithm"],
+            "time_rate_limit" => &["rate", "burst", "key", "windows", "action"],
+            "conn_state" => &["action", "states", "protocols"],
+            "expression" => &["action", "expr"],
+            other => {
+                return Err(self.error(raw.kind.span(), format!(
+                    "unknown rule type '{}', expected one of port_blocklist, port_allowlist, \
+                     services, ip_prefix, time_window, rate_limit, time_rate_limit, conn_state, expression",
+                    other
+                )));
+            }
+        };
+        for (field, span) in raw.present_fields() {
+            if !allowed.contains(&field) && !COMMON_FIELDS.contains(&field) {
+                return Err(self.error(span, format!(
+                    "'{}' is not valid for a {} rule", field, kind_name
+                )));
+            }
+        }
+
+        let rule_span = spanned.span();
+        let kind = match kind_name {
+            "port_blocklist" => RuleKind::PortBlocklist {
+                ports: self.ports(raw.ports.as_ref(), &rule_span, kind_name)?,
+                protocols: self.protocols(raw.protocols.as_ref())?,
+                match_on: self.address_match(raw.match_on.as_ref(), AddressMatch::Destination)?,
+                action: self.action_or(raw.action.as_ref(), Action::Block)?,
+            },
+            "port_allowlist" => RuleKind::PortAllowlist {
+                ports: self.ports(raw.ports.as_ref(), &rule_span, kind_name)?,
+                protocols: self.protocols(raw.protocols.as_ref())?,
+            },
+            "services" => RuleKind::Services {
+                services: self.services(raw.services.as_ref(), &rule_span)?,
+                action: self.action_or(raw.action.as_ref(), Action::Block)?,
+            },
+            "ip_prefix" => RuleKind::IpPrefix {
+                prefixes: self.prefixes(raw, &rule_span)?,
+                match_on: self.address_match(raw.match_on.as_ref(), AddressMatch::Source)?,
+            },
+            "time_window" => RuleKind::TimeWindow {
+                windows: self.windows(raw.windows.as_ref(), &rule_span)?,
+                action: self.action_or(raw.action.as_ref(), Action::Block)?,
+            },
+            // `rate` and `burst` give the limit outside all windows
+            "time_rate_limit" => {
+                let rate = raw.rate.as_ref().ok_or_else(|| {
+                    self.error(rule_span.clone(), "time_rate_limit rule needs a 'rate'")
+                })?;
+                RuleKind::TimeRateLimit {
+                    windows: self.limit_windows(raw.windows.as_ref(), &rule_span)?,
+                    default: self.window_limit(rate, raw.burst.as_ref())?,
+                    key: self.key_type(raw.key.as_ref())?,
+                    action: self.action_or(raw.action.as_ref(), Action::Block)?,
+                }
+            }
+            "rate_limit" => {
+                let rate = raw.rate.as_ref().ok_or_else(|| {
+                    self.error(rule_span.clone(), "rate_limit rule needs a 'rate'")
+                })?;
+                RuleKind::RateLimit {
+                    limit: self.rate_limit(rate, raw.burst.as_ref(), raw.key.as_ref(), raw.algorithm.as_ref())?,
+                }
+            }
+            "conn_state" => RuleKind::ConnState {
+                states: self.states(raw.states.as_ref(), &rule_span)?,
+                protocols: self.protocols(raw.protocols.as_ref())?,
+                action: self.action_or(raw.action.as_ref(), Action::Allow)?,
+            },
+            "expression" => RuleKind::Expression {
+                expression: self.expression(raw.expr.as_ref(), &rule_span)?,
+                action: self.action_or(raw.action.as_ref(), Action::Block)?,
+            },
+            _ => unreachable!("rule type checked above"),
+        };
+
+        // Unset priorities keep the built-in rule's default
+        let priority = match &raw.priority {
+            Some(priority) => *priority.as_ref(),
+            None => default_priority(&kind),
+        };
+
+        Ok(RuleConfig {
+            name: name.to_string(),
+            priority,
+            enabled: raw.enabled.as_ref().is_none_or(|e| *e.as_ref()),
+            chain: raw.chain.as_ref().map_or(DEFAULT_CHAIN, |c| c.as_ref().as_str()).to_string(),
+            schedule: self.schedule(raw)?,
+            kind,
+        })
+    }
+
+    fn schedule(&self, raw: &RawRule) -> Result<RuleSchedule, ConfigError> {
+        let active_from = raw.active_from.as_ref().map(|t| self.timestamp(t)).transpose()?;
+        let expires_at = raw.expires_at.as_ref().map(|t| self.timestamp(t)).transpose()?;
+        if let (Some(from), Some(until), Some(span)) = (active_from, expires_at, raw.expires_at.as_ref())
+            && until <= from
+        {
+            return Err(self.error(span.span(), "expires_at has to be after active_from"));
+        }
+        Ok(RuleSchedule { active_from, expires_at })
+    }
+
+    // RFC 3339 with an offset, as a TOML datetime or a string
+    fn timestamp(&self, value: &Spanned<toml::Value>) -> Result<SystemTime, ConfigError> {
+        let text = match value.as_ref() {
+            toml::Value::Datetime(datetime) => datetime.to_string(),
+            toml::Value::String(text) => text.clone(),
+            _ => return Err(self.error(value.span(), "expected a timestamp like 2024-05-01T12:00:00Z")),
+        };
+        DateTime::parse_from_rfc3339(text.trim())
+            .map(SystemTime::from)
+            .map_err(|_| self.error(value.span(), format!(
+                "invalid timestamp '{}', expected RFC 3339 with an offset like 2024-05-01T12:00:00Z", text
+            )))
+    }
+
+    // Errors point into the expression string itself
+    fn expression(&self, expr: Option<&Spanned<String>>, rule_span: &Range<usize>) -> Result<Expression, ConfigError> {
+        let expr = expr.ok_or_else(|| self.error(rule_span.clone(), "expression rule needs an 'expr'"))?;
+        Expression::parse(expr.as_ref()).map_err(|e| {
+            // Skip the opening quote; exact unless the string has escapes
+            let offset = expr.span().start + 1 + e.offset;
+            self.error(offset..offset, e.message)
+        })
+    }
+
+    fn action(&self, action: &Spanned<String>) -> Result<Action, ConfigError> {
+        action.as_ref().parse()
+            .map_err(|e| self.error(action.span(), format!("{}", e)))
+    }
+
+    fn action_or(&self, action: Option<&Spanned<String>>, default: Action) -> Result<Action, ConfigError> {
+        match action {
+            Some(action) => self.action(action),
+            None => Ok(default),
+        }
+    }
+
+    fn address_match(&self, value: Option<&Spanned<String>>, default: AddressMatch) -> Result<AddressMatch, ConfigError> {
+        let Some(value) = value else {
+            return Ok(default);
+        };
+        match value.as_ref().as_str() {
+            "source" => Ok(AddressMatch::Source),
+            "destination" => Ok(AddressMatch::Destination),
+            "either" => Ok(AddressMatch::Either),
+            other => Err(self.error(value.span(), format!(
+                "invalid match '{}', expected source, destination or either", other
+            ))),
+        }
+    }
+
+    // Entries are either a port number or an inclusive "low-high" range
+    fn ports(
+        &self,
+        ports: Option<&Spanned<Vec<Spanned<toml::Value>>>>,
+        rule_span: &Range<usize>,
+        kind: &str,
+    ) -> Result<Vec<RangeInclusive<u16>>, ConfigError> {
+        let ports = match ports {
+            Some(ports) if !ports.as_ref().is_empty() => ports,
+            Some(ports) => return Err(self.error(ports.span(), "'ports' must not be empty")),
+            None => return Err(self.error(rule_span.clone(), format!("{} rule needs 'ports'", kind))),
+        };
+
+        let mut ranges = Vec::with_capacity(ports.as_ref().len());
+        for entry in ports.as_ref() {
+            let invalid = |detail: &str| self.error(entry.span(), detail.to_string());
+            let range = match entry.as_ref() {
+                toml::Value::Integer(port) => {
+                    let port = u16::try_from(*port)
+                        .map_err(|_| invalid(&format!("port {} is out of range 0-65535", port)))?;
+                    port..=port
+                }
+                toml::Value::String(range) => {
+                    let (low, high) = range.split_once('-').unwrap_or((range, range));
+                    let parse = |s: &str| s.trim().parse::<u16>()
+                        .map_err(|_| invalid(&format!("invalid port range '{}'", range)));
+                    let (low, high) = (parse(low)?, parse(high)?);
+                    if low > high {
+                        return Err(invalid(&format!("port range '{}' is reversed", range)));
+                    }
+                    low..=high
+                }
+                _ => return Err(invalid("ports must be numbers or \"low-high\" strings")),
+            };
+            ranges.push(range);
+        }
+        Ok(ranges)
+    }
+
+    // Protocol names ("tcp", "udp", "icmp", "icmpv6") or IP protocol numbers
+    fn protocols(&self, protocols: Option<&Spanned<Vec<Spanned<toml::Value>>>>) -> Result<Vec<Protocol>, ConfigError> {
+        let Some(protocols) = protocols else {
+            return Ok(Vec::new());
+        };
+        protocols.as_ref().iter()
+            .map(|entry| {
+                let protocol = match entry.as_ref() {
+                    toml::Value::String(name) => match name.to_ascii_lowercase().as_str() {
+                        "tcp" => Some(Protocol::Tcp),
+                        "udp" => Some(Protocol::Udp),
+                        "icmp" => Some(Protocol::Icmp),
+                        "icmpv6" => Some(Protocol::Icmpv6),
+                        _ => None,
+                    },
+                    toml::Value::Integer(number) => u8::try_from(*number).ok().map(Protocol::from_number),
+                    _ => None,
+                };
+                protocol.ok_or_else(|| self.error(entry.span(), format!(
+                    "invalid protocol {}, expected tcp, udp, icmp, icmpv6 or a protocol number",
+                    entry.as_ref()
+                )))
+            })
+            .collect()
+    }
+
+    fn services(&self, services: Option<&Spanned<Vec<Spanned<String>>>>, rule_span: &Range<usize>) -> Result<Vec<Service>, ConfigError> {
+        let services = non_empty(services)
+            .ok_or_else(|| self.error(rule_span.clone(), "services rule needs a non-empty 'services' list"))?;
+        services.iter()
+            .map(|name| {
+                SERVICES.iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name.as_ref()))
+                    .map(|(_, service)| *service)
+                    .ok_or_else(|| self.error(name.span(), format!("unknown service '{}'", name.as_ref())))
+            })
+            .collect()
+    }
+
+    // `block` and `allow` are shorthand for prefixes with those actions;
+    // `prefixes` maps a prefix to any other action
+    fn prefixes(&self, raw: &RawRule, rule_span: &Range<usize>) -> Result<Vec<(IpPrefix, Action)>, ConfigError> {
+        let mut prefixes = Vec::new();
+        let mut seen = HashSet::new();
+        let mut add = |prefix: &Spanned<String>, action: Action| -> Result<(), ConfigError> {
+            let parsed: IpPrefix = prefix.as_ref().parse()
+                .map_err(|e| self.error(prefix.span(), format!("{}", e)))?;
+            if !seen.insert(parsed) {
+                return Err(self.error(prefix.span(), format!("prefix {} is listed twice", parsed)));
+            }
+            prefixes.push((parsed, action));
+            Ok(())
+        };
+
+        for prefix in raw.block.iter().flat_map(|list| list.as_ref()) {
+            add(prefix, Action::Block)?;
+        }
+        for prefix in raw.allow.iter().flat_map(|list| list.as_ref()) {
+            add(prefix, Action::Allow)?;
+        }
+        for (prefix, action) in raw.prefixes.iter().flat_map(|table| table.as_ref()) {
+            add(prefix, self.action(action)?)?;
+        }
+
+        if prefixes.is_empty() {
+            return Err(self.error(rule_span.clone(), "ip_prefix rule needs at least one prefix"));
+        }
+        Ok(prefixes)
+    }
+
+    fn windows(&self, windows: Option<&Spanned<Vec<Spanned<RawWindow>>>>, rule_span: &Range<usize>) -> Result<Vec<TimeWindow>, ConfigError> {
+        let windows = non_empty(windows)
+            .ok_or_else(|| self.error(rule_span.clone(), "time_window rule needs a non-empty 'windows' list"))?;
+        windows.iter()
+            .map(|window| {
+                let limit = window.as_ref().rate.as_ref().or(window.as_ref().burst.as_ref());
+                if let Some(field) = limit {
+                    return Err(self.error(field.span(), "a time_window rule's windows take no 'rate' or 'burst'"));
+                }
+                self.window(window.as_ref())
+            })
+            .collect()
+    }
+
+    // Every window needs its own `rate`; `burst` defaults as for rate_limit
+    fn limit_windows(
+        &self,
+        windows: Option<&Spanned<Vec<Spanned<RawWindow>>>>,
+        rule_span: &Range<usize>,
+    ) -> Result<Vec<(TimeWindow, WindowLimit)>, ConfigError> {
+        let windows = non_empty(windows)
+            .ok_or_else(|| self.error(rule_span.clone(), "time_rate_limit rule needs a non-empty 'windows' list"))?;
+        windows.iter()
+            .map(|window| {
+                let rate = window.as_ref().rate.as_ref()
+                    .ok_or_else(|| self.error(window.span(), "window needs a 'rate'"))?;
+                let limit = self.window_limit(rate, window.as_ref().burst.as_ref())?;
+                Ok((self.window(window.as_ref())?, limit))
+            })
+            .collect()
+    }
+
+    fn window(&self, window: &RawWindow) -> Result<TimeWindow, ConfigError> {
+        let start = self.time(&window.start)?;
+        let end = self.time(&window.end)?;
+        let mut days = Vec::new();
+        for day in window.days.iter().flatten() {
+            let parsed = match day.as_ref().to_ascii_lowercase().as_str() {
+                "weekdays" => vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+                "weekends" => vec![Weekday::Sat, Weekday::Sun],
+                // chrono accepts "mon" as well as "monday"
+                name => vec![name.parse::<Weekday>()
+                    .map_err(|_| self.error(day.span(), format!("unknown day '{}'", day.as_ref())))?],
+            };
+            for weekday in parsed {
+                if !days.contains(&weekday) {
+                    days.push(weekday);
+                }
+            }
+        }
+        Ok(TimeWindow::new(start.hour(), start.minute(), end.hour(), end.minute()).on_days(days))
+    }
+
+    fn time(&self, value: &Spanned<String>) -> Result<NaiveTime, ConfigError> {
+        NaiveTime::parse_from_str(value.as_ref().trim(), "%H:%M")
+            .map_err(|_| self.error(value.span(), format!("invalid time '{}', expected HH:MM", value.as_ref())))
+    }
+
+    fn states(&self, states: Option<&Spanned<Vec<Spanned<String>>>>, rule_span: &Range<usize>) -> Result<Vec<ConnState>, ConfigError> {
+        let states = non_empty(states)
+            .ok_or_else(|| self.error(rule_span.clone(), "conn_state rule needs a non-empty 'states' list"))?;
+        states.iter()
+            .map(|name| {
+                CONN_STATES.iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name.as_ref()))
+                    .map(|(_, state)| *state)
+                    .ok_or_else(|| self.error(name.span(), format!(
+                        "unknown connection state '{}', expected new, established, related or invalid",
+                        name.as_ref()
+                    )))
+            })
+            .collect()
+    }
+
+    fn rate_limit(
+        &self,
+        rate: &Spanned<f64>,
+        burst: Option<&Spanned<f64>>,
+        key: Option<&Spanned<String>>,
+        algorithm: Option<&Spanned<String>>,
+    ) -> Result<RateLimitConfig, ConfigError> {
+        let WindowLimit { rate: rate_value, burst: capacity } = self.window_limit(rate, burst)?;
+        let key_type = self.key_type(key)?;
+        let algorithm = match algorithm {
+            None => RateLimitAlgorithm::TokenBucket,
+            Some(algorithm) => match algorithm.as_ref().as_str() {
+                "token_bucket" => RateLimitAlgorithm::TokenBucket,
+                "leaky_bucket" => RateLimitAlgorithm::LeakyBucket,
+                "sliding_window_log" => RateLimitAlgorithm::SlidingWindowLog,
+                "sliding_window_counter" => RateLimitAlgorithm::SlidingWindowCounter,
+                "gcra" => RateLimitAlgorithm::Gcra,
+                other => return Err(self.error(algorithm.span(), format!(
+                    "invalid algorithm '{}', expected token_bucket, leaky_bucket, \
+                     sliding_window_log, sliding_window_counter or gcra", other
+                ))),
+            },
+        };
+        Ok(RateLimitConfig { rate: rate_value, capacity, key_type, algorithm })
+    }
+
+    // `burst` defaults to one second's worth of `rate`
+    fn window_limit(&self, rate: &Spanned<f64>, burst: Option<&Spanned<f64>>) -> Result<WindowLimit, ConfigError> {
+        let rate_value = *rate.as_ref();
+        if !rate_value.is_finite() || rate_value <= 0.0 {
+            return Err(self.error(rate.span(), "rate must be a positive number"));
+        }
+        match burst {
+            Some(burst) if !burst.as_ref().is_finite() || *burst.as_ref() < 1.0 => {
+                Err(self.error(burst.span(), "burst must be at least 1"))
+            }
+            Some(burst) => Ok(WindowLimit::with_burst(rate_value, *burst.as_ref())),
+            None => Ok(WindowLimit::new(rate_value)),
+        }
+    }
+
+    fn key_type(&self, key: Option<&Spanned<String>>) -> Result<RateLimitKeyType, ConfigError> {
+        let Some(key) = key else {
+            return Ok(RateLimitKeyType::SourceIp);
+        };
+        match key.as_ref().as_str() {
+            "source_ip" => Ok(RateLimitKeyType::SourceIp),
+            "destination_ip" => Ok(RateLimitKeyType::DestinationIp),
+            "flow" => Ok(RateLimitKeyType::Flow),
+            "global" => Ok(RateLimitKeyType::Global),
+            other => Err(self.error(key.span(), format!(
+                "invalid key '{}', expected source_ip, destination_ip, flow or global", other
+            ))),
+        }
+    }
+}
+
+fn non_empty<T>(list: Option<&Spanned<Vec<T>>>) -> Option<&Vec<T>> {
+    list.map(|l| l.as_ref()).filter(|l| !l.is_empty())
+}
+
+// Defaults of the built-in rules' constructors
+fn default_priority(kind: &RuleKind) -> i32 {
+    match kind {
+        RuleKind::PortBlocklist { .. } => 80,
+        RuleKind::PortAllowlist { .. } => 90,
+        RuleKind::Services { .. } => 75,
+        RuleKind::IpPrefix { .. } => 85,
+        RuleKind::TimeWindow { .. } => 60,
+        RuleKind::RateLimit { .. } => 70,
+        RuleKind::TimeRateLimit { .. } => 65,
+        RuleKind::ConnState { .. } => 100,
+        RuleKind::Expression { .. } => 50,
+    }
+}
+
+// Export layout; mirrors `RawConfig` without the spans
+#[derive(Serialize)]
+struct ExportConfig<'a> {
+    default_action: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    rate_limiters: BTreeMap<&'a str, ExportLimiter>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    chains: BTreeMap<&'a str, ExportChain>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rule: Vec<ExportRule>,
+}
+
+#[derive(Serialize)]
+struct ExportChain {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportLimiter {
+    rate: f64,
+    burst: f64,
+    key: &'static str,
+    // Left out for the default token bucket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<&'static str>,
+}
+
+#[derive(Serialize, Default)]
+struct ExportRule {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    priority: i32,
+    enabled: bool,
+    // Left out for the entry chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<String>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    match_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<toml::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocols: Option<Vec<toml::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<&'static str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefixes: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    windows: Option<Vec<ExportWindow>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    states: Option<Vec<&'static str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expr: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportWindow {
+    start: String,
+    end: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    days: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst: Option<f64>,
+}
+
+fn export_rule(rule: &RuleConfig) -> ExportRule {
+    let mut out = ExportRule {
+        name: rule.name.clone(),
+        kind: rule.kind.type_name(),
+        priority: rule.priority,
+        enabled: rule.enabled,
+        chain: Some(rule.chain.clone()).filter(|chain| chain != DEFAULT_CHAIN),
+        active_from: rule.schedule.active_from.map(export_timestamp),
+        expires_at: rule.schedule.expires_at.map(export_timestamp),
+        ..ExportRule::default()
+    };
+
+    match &rule.kind {
+        RuleKind::PortBlocklist { ports, protocols, match_on, action } => {
+            out.action = Some(action.to_string());
+            out.match_on = Some(address_match_name(*match_on));
+            out.ports = Some(export_ports(ports));
+            out.protocols = export_protocols(protocols);
+        }
+        RuleKind::PortAllowlist { ports, protocols } => {
+            out.ports = Some(export_ports(ports));
+            out.protocols = export_protocols(protocols);
+        }
+        RuleKind::Services { services, action } => {
+            out.action = Some(action.to_string());
+            out.services = Some(services.iter()
+                .filter_map(|s| SERVICES.iter().find(|(_, service)| service == s).map(|(key, _)| *key))
+                .collect());
+        }
+        RuleKind::IpPrefix { prefixes, match_on } => {
+            out.match_on = Some(address_match_name(*match_on));
+            let mut block = Vec::new();
+            let mut allow = Vec::new();
+            let mut other = BTreeMap::new();
+            for (prefix, action) in prefixes {
+                match action {
+                    Action::Block => block.push(prefix.to_string()),
+                    Action::Allow => allow.push(prefix.to_string()),
+                    action => {
+                        other.insert(prefix.to_string(), action.to_string());
+                    }
+                }
+            }
+            out.block = Some(block).filter(|l| !l.is_empty());
+            out.allow = Some(allow).filter(|l| !l.is_empty());
+            out.prefixes = Some(other).filter(|m| !m.is_empty());
+        }
+        RuleKind::TimeWindow { windows, action } => {
+            out.action = Some(action.to_string());
+            out.windows = Some(windows.iter().map(|window| export_window(window, None)).collect());
+        }
+        RuleKind::RateLimit { limit } => {
+            let limiter = export_limiter(limit);
+            out.rate = Some(limiter.rate);
+            out.burst = Some(limiter.burst);
+            out.key = Some(limiter.key);
+            out.algorithm = limiter.algorithm;
+        }
+        RuleKind::TimeRateLimit { windows, default, key, action } => {
+            out.action = Some(action.to_string());
+            out.windows = Some(windows.iter().map(|(window, limit)| export_window(window, Some(limit))).collect());
+            out.rate = Some(default.rate);
+            out.burst = Some(default.burst);
+            out.key = Some(key_name(key));
+        }
+        RuleKind::ConnState { states, protocols, action } => {
+            out.action = Some(action.to_string());
+            out.states = Some(states.iter()
+                .filter_map(|s| CONN_STATES.iter().find(|(_, state)| state == s).map(|(key, _)| *key))
+                .collect());
+            out.protocols = export_protocols(protocols);
+        }
+        RuleKind::Expression { expression, action } => {
+            out.action = Some(action.to_string());
+            out.expr = Some(expression.to_string());
+        }
+    }
+    out
+}
+
+// Full precision, so a reload gives back the same instant
+fn export_timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn export_window(window: &TimeWindow, limit: Option<&WindowLimit>) -> ExportWindow {
+    ExportWindow {
+        start: window.start_time().format("%H:%M").to_string(),
+        end: window.end_time().format("%H:%M").to_string(),
+        days: window.days().iter()
+            .filter_map(|d| WEEKDAYS.iter().find(|(_, day)| day == d).map(|(key, _)| *key))
+            .collect(),
+        rate: limit.map(|l| l.rate),
+        burst: limit.map(|l| l.burst),
+    }
+}
+
+fn export_limiter(limit: &RateLimitConfig) -> ExportLimiter {
+    ExportLimiter {
+        rate: limit.rate,
+        burst: limit.capacity,
+        key: key_name(&limit.key_type),
+        algorithm: match limit.algorithm {
+            RateLimitAlgorithm::TokenBucket => None,
+            RateLimitAlgorithm::LeakyBucket => Some("leaky_bucket"),
+            RateLimitAlgorithm::SlidingWindowLog => Some("sliding_window_log"),
+            RateLimitAlgorithm::SlidingWindowCounter => Some("sliding_window_counter"),
+            RateLimitAlgorithm::Gcra => Some("gcra"),
+        },
+    }
+}
+
+fn key_name(key: &RateLimitKeyType) -> &'static str {
+    match key {
+        RateLimitKeyType::SourceIp => "source_ip",
+        RateLimitKeyType::DestinationIp => "destination_ip",
+        RateLimitKeyType::Flow => "flow",
+        RateLimitKeyType::Global => "global",
+    }
+}
+
+fn export_ports(ports: &[RangeInclusive<u16>]) -> Vec<toml::Value> {
+    ports.iter()
+        .map(|range| {
+            if range.start() == range.end() {
+                toml::Value::Integer(i64::from(*range.start()))
+            } else {
+                toml::Value::String(format!("{}-{}", range.start(), range.end()))
+            }
+        })
+        .collect()
+}
+
+fn export_protocols(protocols: &[Protocol]) -> Option<Vec<toml::Value>> {
+    if protocols.is_empty() {
+        return None;
+    }
+    Some(protocols.iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp => toml::Value::String("tcp".to_string()),
+            Protocol::Udp => toml::Value::String("udp".to_string()),
+            Protocol::Icmp => toml::Value::String("icmp".to_string()),
+            Protocol::Icmpv6 => toml::Value::String("icmpv6".to_string()),
+            Protocol::Other(number) => toml::Value::Integer(i64::from(*number)),
+        })
+        .collect())
+}
+
+fn address_match_name(match_on: AddressMatch) -> &'static str {
+    match match_on {
+        AddressMatch::Source => "source",
+        AddressMatch::Destination => "destination",
+        AddressMatch::Either => "either",
+    }
+}
@@ -0,0 +1,5 @@
+pub mod loader;
+pub mod rule_config;
+
+pub use loader::ConfigError;
+pub use rule_config::{ChainConfig, FirewallConfig, RuleConfig, RuleKind};
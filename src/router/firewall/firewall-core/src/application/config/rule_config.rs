@@ -0,0 +1,299 @@
+use crate::domain::chain::DEFAULT_CHAIN;
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::flow::ConnState;
+use crate::domain::packet::Protocol;
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rate_limiter::{RateLimitConfig, RateLimitKeyType};
+use crate::domain::rule::{Action, Filter, RuleSchedule};
+use crate::rules::conn_state_rules::ConnStateRule;
+use crate::rules::expr_rules::{Expression, ExpressionRule};
+use crate::rules::ip_rules::{AddressMatch, IpPrefixRule};
+use crate::rules::port_rules::{PortAllowlistRule, PortBlocklistRule, Service, WellKnownServicesRule};
+use crate::rules::rate_limit_rules::rate_limit_rule::RateLimitRule;
+use crate::rules::rate_limit_rules::time_based_limit_rule::{TimeBasedRateLimitRule, WindowLimit};
+use crate::rules::time_rules::{TimeWindow, TimeWindowRule};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+// Everything a config file describes: the default action, the named rate
+// limiters `rate-limit:<name>` actions refer to, the chains `jump:<name>`
+// and `goto:<name>` lead to, and the rules themselves
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirewallConfig {
+    pub default_action: Action,
+    pub rate_limiters: BTreeMap<String, RateLimitConfig>,
+    pub chains: BTreeMap<String, ChainConfig>,
+    pub rules: Vec<RuleConfig>,
+}
+
+impl FirewallConfig {
+    pub fn new(default_action: Action) -> Self {
+        Self {
+            default_action,
+            rate_limiters: BTreeMap::new(),
+            chains: BTreeMap::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    // Limiter names the default action and the rules' actions refer to
+    pub fn rate_limit_references(&self) -> impl Iterator<Item = &str> {
+        let rules = self.rules.iter().flat_map(|rule| rule.kind.actions());
+        std::iter::once(&self.default_action).chain(rules).filter_map(|action| match action {
+            Action::RateLimit(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+}
+
+// A user-defined chain; the entry chain is implicit
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainConfig {
+    pub policy: Option<Action>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleConfig {
+    pub name: String,
+    pub priority: i32,
+    pub enabled: bool,
+    // Chain the rule belongs to, `DEFAULT_CHAIN` unless set
+    pub chain: String,
+    pub schedule: RuleSchedule,
+    pub kind: RuleKind,
+}
+
+// One variant per built-in rule type. Single ports are stored as one-port
+// ranges (`22..=22`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleKind {
+    PortBlocklist {
+        ports: Vec<RangeInclusive<u16>>,
+        protocols: Vec<Protocol>,
+        match_on: AddressMatch,
+        action: Action,
+    },
+    PortAllowlist {
+        ports: Vec<RangeInclusive<u16>>,
+        protocols: Vec<Protocol>,
+    },
+    Services {
+        services: Vec<Service>,
+        action: Action,
+    },
+    IpPrefix {
+        prefixes: Vec<(IpPrefix, Action)>,
+        match_on: AddressMatch,
+    },
+    TimeWindow {
+        windows: Vec<TimeWindow>,
+        action: Action,
+    },
+    RateLimit {
+        limit: RateLimitConfig,
+    },
+    // Token bucket whose limit is the first window's containing the
+    // current time, `default` outside all of them
+    TimeRateLimit {
+        windows: Vec<(TimeWindow, WindowLimit)>,
+        default: WindowLimit,
+        key: RateLimitKeyType,
+        // Given to packets over the limit
+        action: Action,
+    },
+    ConnState {
+        states: Vec<ConnState>,
+        protocols: Vec<Protocol>,
+        action: Action,
+    },
+    Expression {
+        expression: Expression,
+        action: Action,
+    },
+}
+
+impl RuleKind {
+    // Value of the `type` key in the config file
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RuleKind::PortBlocklist { .. } => "port_blocklist",
+            RuleKind::PortAllowlist { .. } => "port_allowlist",
+            RuleKind::Services { .. } => "services",
+            RuleKind::IpPrefix { .. } => "ip_prefix",
+            RuleKind::TimeWindow { .. } => "time_window",
+            RuleKind::RateLimit { .. } => "rate_limit",
+            RuleKind::TimeRateLimit { .. } => "time_rate_limit",
+            RuleKind::ConnState { .. } => "conn_state",
+            RuleKind::Expression { .. } => "expression",
+        }
+    }
+
+    // Every action the rule can produce
+    pub fn actions(&self) -> Vec<&Action> {
+        match self {
+            RuleKind::PortBlocklist { action, .. }
+            | RuleKind::Services { action, .. }
+            | RuleKind::TimeWindow { action, .. }
+            | RuleKind::TimeRateLimit { action, .. }
+            | RuleKind::ConnState { action, .. }
+            | RuleKind::Expression { action, .. } => vec![action],
+            RuleKind::IpPrefix { prefixes, .. } => prefixes.iter().map(|(_, a)| a).collect(),
+            RuleKind::PortAllowlist { .. } | RuleKind::RateLimit { .. } => Vec::new(),
+        }
+    }
+}
+
+impl RuleConfig {
+    // Enabled, always active, in the entry chain
+    pub fn new(name: impl Into<String>, priority: i32, kind: RuleKind) -> Self {
+        Self {
+            name: name.into(),
+            priority,
+            enabled: true,
+            chain: DEFAULT_CHAIN.to_string(),
+            schedule: RuleSchedule::default(),
+            kind,
+        }
+    }
+
+    // The reverse of `build`, used to export, index and analyze the rule
+    // set. Filters the config format can't express, and ones defined
+    // outside this crate, have no config form.
+    pub fn from_filter(filter: &dyn Filter) -> Option<Self> {
+        let filter: &dyn Any = filter;
+        if let Some(rule) = filter.downcast_ref::<PortBlocklistRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<PortAllowlistRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<WellKnownServicesRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<IpPrefixRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<TimeWindowRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<RateLimitRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<TimeBasedRateLimitRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<ConnStateRule>() {
+            rule.to_config()
+        } else if let Some(rule) = filter.downcast_ref::<ExpressionRule>() {
+            rule.to_config()
+        } else {
+            None
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn Filter> {
+        self.build_with_clock(Arc::new(SystemClock))
+    }
+
+    // Time windows, rate limits and expressions read `clock`
+    pub fn build_with_clock(&self, clock: Arc<dyn Clock>) -> Box<dyn Filter> {
+        let name = self.name.clone();
+        match &self.kind {
+            RuleKind::PortBlocklist { ports, protocols, match_on, action } => {
+                let mut rule = PortBlocklistRule::new(name)
+                    .match_source(matches!(match_on, AddressMatch::Source | AddressMatch::Either))
+                    .match_destination(matches!(match_on, AddressMatch::Destination | AddressMatch::Either))
+                    .with_action(action.clone())
+                    .with_priority(self.priority);
+                for range in ports {
+                    rule = if range.start() == range.end() {
+                        rule.add_port(*range.start())
+                    } else {
+                        rule.add_range(*range.start(), *range.end())
+                    };
+                }
+                for protocol in protocols {
+                    rule = rule.for_protocol(*protocol);
+                }
+                Box::new(rule)
+            }
+            RuleKind::PortAllowlist { ports, protocols } => {
+                let mut rule = PortAllowlistRule::new(name).with_priority(self.priority);
+                for range in ports {
+                    rule = if range.start() == range.end() {
+                        rule.add_port(*range.start())
+                    } else {
+                        rule.add_range(*range.start(), *range.end())
+                    };
+                }
+                for protocol in protocols {
+                    rule = rule.for_protocol(*protocol);
+                }
+                Box::new(rule)
+            }
+            RuleKind::Services { services, action } => Box::new(
+                WellKnownServicesRule::new(name)
+                    .add_services(services.iter().copied())
+                    .with_action(action.clone())
+                    .with_priority(self.priority),
+            ),
+            RuleKind::IpPrefix { prefixes, match_on } => Box::new(
+                IpPrefixRule::new(name)
+                    .add_prefixes(prefixes.iter().cloned())
+                    .match_on(*match_on)
+                    .with_priority(self.priority),
+            ),
+            RuleKind::TimeWindow { windows, action } => Box::new(
+                TimeWindowRule::new(name)
+                    .add_windows(windows.clone())
+                    .with_action(action.clone())
+                    .with_priority(self.priority)
+                    .with_clock(clock),
+            ),
+            RuleKind::RateLimit { limit } => Box::new(
+                RateLimitRule::with_limiter(name, limit.clone(), limit.build_with_clock(clock))
+                    .with_priority(self.priority),
+            ),
+            RuleKind::TimeRateLimit { windows, default, key, action } => {
+                let mut rule = TimeBasedRateLimitRule::with_default_limit(name, *default)
+                    .with_key(key.clone())
+                    .with_action(action.clone())
+                    .with_priority(self.priority)
+                    .with_clock(clock);
+                for (window, limit) in windows {
+                    rule = rule.add_window_limit(window.clone(), *limit);
+                }
+                Box::new(rule)
+            }
+            RuleKind::ConnState { states, protocols, action } => {
+                let mut rule = ConnStateRule::new(name)
+                    .match_states(states.iter().copied())
+                    .with_action(action.clone())
+                    .with_priority(self.priority);
+                for protocol in protocols {
+                    rule = rule.for_protocol(*protocol);
+                }
+                Box::new(rule)
+            }
+            RuleKind::Expression { expression, action } => Box::new(
+                ExpressionRule::new(name, expression.clone())
+                    .with_action(action.clone())
+                    .with_priority(self.priority)
+                    .with_clock(clock),
+            ),
+        }
+    }
+}
+
+// Turns a port set plus ranges back into the sorted list the config uses
+pub(crate) fn port_list<'a>(
+    ports: impl IntoIterator<Item = &'a u16>,
+    ranges: impl IntoIterator<Item = &'a RangeInclusive<u16>>,
+) -> Vec<RangeInclusive<u16>> {
+    let mut list: Vec<RangeInclusive<u16>> = ports.into_iter().map(|p| *p..=*p).collect();
+    list.extend(ranges.into_iter().cloned());
+    list.sort_by_key(|r| (*r.start(), *r.end()));
+    list.dedup();
+    list
+}
+
+pub(crate) fn protocol_list<'a>(protocols: impl IntoIterator<Item = &'a Protocol>) -> Vec<Protocol> {
+    let mut list: Vec<Protocol> = protocols.into_iter().copied().collect();
+    list.sort_by_key(|p| p.to_number());
+    list
+}
@@ -1,71 +1,407 @@
 use crate::domain::{
-    packet::{Packet, PacketHeader},
-    rule::{Action, Filter, RuleEntry},
-    flow::{FlowKey, FlowTracker},
+    alert::{Alert, AlertSink},
+    packet::Packet,
+    rule::{Action, RuleEntry, Verdict, VerdictReason},
+    ruleset::{Candidates, RuleSet, RuleSetCell},
+    trace::{PacketTrace, TraceStep},
+    chain::MAX_CHAIN_HOPS,
+    clock::{Clock, SystemClock},
+    flow::{ConnState, FlowTracker},
+    rate_limiter::{RateLimitConfig, RateLimitKeyType, RateLimiter},
     stats::StatsCollector,
 };
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Rate limiter that rules reach through `Action::RateLimit(name)`
+struct NamedRateLimiter {
+    limiter: Mutex<Box<dyn RateLimiter>>,
+    key_type: RateLimitKeyType,
+    // Set when registered from a config, so the limiter can be exported
+    config: Option<RateLimitConfig>,
+}
+
+// Whether verdicts are meant to be carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessingMode {
+    #[default]
+    Enforce,
+    // Passive: every packet is evaluated, counted and alerted on as if
+    // enforcing, but verdicts are marked `monitored` and drop nothing.
+    // Flows, rate limiters and quarantines evolve as they would inline,
+    // so the verdicts are the ones enforcing would give.
+    Monitor,
+}
 
 pub struct PacketProcessor {
-    rules: Arc<RwLock<Vec<RuleEntry>>>,
-    default_action: Action,
+    rules: Arc<RuleSetCell>,
     flow_tracker: Arc<FlowTracker>,
     stats_collector: Arc<dyn StatsCollector>,
+    rate_limiters: RwLock<HashMap<String, Arc<NamedRateLimiter>>>,
+    quarantine: Mutex<HashMap<IpAddr, Instant>>,
+    clock: Arc<dyn Clock>,
+    monitor: AtomicBool,
+    alerts: Option<Arc<dyn AlertSink>>,
 }
 
 impl PacketProcessor {
     pub fn new(
-        default_action: Action,
         flow_tracker: Arc<FlowTracker>,
         stats_collector: Arc<dyn StatsCollector>,
-        rules: Arc<RwLock<Vec<RuleEntry>>>,
+        rules: Arc<RuleSetCell>,
     ) -> Self {
         Self {
             rules,
-            default_action,
             flow_tracker,
             stats_collector,
+            rate_limiters: RwLock::new(HashMap::new()),
+            quarantine: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            monitor: AtomicBool::new(false),
+            alerts: None,
         }
     }
 
-    pub fn process(&self, packet: &Packet) -> Action {
-        let flow_key = FlowKey::new(
-          packet.source_ip,
-          packet.destination_ip,
-          packet.source_port,
-          packet.destination_port,
-          packet.protocol.to_number(),
-        );
+    // Times quarantines, rule schedules and the limiters registered after
+    // this; the flow tracker keeps its own
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_mode(self, mode: ProcessingMode) -> Self {
+        self.set_mode(mode);
+        self
+    }
 
-        self.flow_tracker.record_packet(flow_key, packet.payload.len());
-        // Checks rules
-        let action = self.evaluate_rules(packet);
+    // Raised for drops, would-be drops in monitor mode and logged packets
+    pub fn with_alert_sink(mut self, sink: Arc<dyn AlertSink>) -> Self {
+        self.alerts = Some(sink);
+        self
+    }
+
+    // Takes effect from the next packet
+    pub fn set_mode(&self, mode: ProcessingMode) {
+        self.monitor.store(mode == ProcessingMode::Monitor, Ordering::Relaxed);
+    }
+
+    pub fn mode(&self) -> ProcessingMode {
+        if self.monitor.load(Ordering::Relaxed) {
+            ProcessingMode::Monitor
+        } else {
+            ProcessingMode::Enforce
+        }
+    }
+
+    pub fn process(&self, packet: &Packet) -> Verdict {
+        let conn_state = self.flow_tracker.track(packet);
+        // Quarantined hosts are dropped before any rule runs
+        let mut verdict = match self.quarantine_remaining(&packet.source_ip) {
+            Some(remaining) => {
+                let verdict = Verdict::new(Action::Quarantine(remaining));
+                explain(verdict, false, self.alerts.is_some(), || VerdictReason::Quarantined)
+            }
+            None => self.evaluate_rules(&self.rules.load(), packet, conn_state, None),
+        };
+        if let Action::Quarantine(duration) = verdict.action {
+            self.quarantine_host(packet.source_ip, duration);
+        }
+        verdict.monitored = self.monitor.load(Ordering::Relaxed);
         // Records Statistics
-        self.stats_collector.record_packet(&action);
+        self.stats_collector.record_packet(&verdict);
+        if let Some(alerts) = &self.alerts
+            && (verdict.logged || !verdict.permits())
+        {
+            alerts.raise(&Alert::new(packet, &verdict, self.clock.wall_time()));
+        }
+
+        verdict
+    }
 
-        action
+    // Runs the packet through the rules without side effects: no flow
+    // entry, statistics, rule counters, rate-limit tokens or quarantine
+    pub fn trace(&self, packet: &Packet) -> PacketTrace {
+        let rules = self.rules.load();
+        let conn_state = self.flow_tracker.peek(packet);
+        let mut steps = Vec::new();
+        let verdict = match self.quarantine_peek(&packet.source_ip) {
+            Some(remaining) => {
+                let mut verdict = Verdict::new(Action::Quarantine(remaining));
+                verdict.reason = Some(VerdictReason::Quarantined);
+                verdict
+            }
+            None => self.evaluate_rules(&rules, packet, conn_state, Some(&mut steps)),
+        };
+        PacketTrace {
+            generation: rules.generation(),
+            conn_state,
+            steps,
+            verdict,
+        }
     }
 
-    fn evaluate_rules(&self, packet: &Packet) -> Action {
-        let rules = self.rules.read().unwrap();
-        let header = packet.header();
+    // With `trace`, this is a dry run that records each rule it visits
+    fn evaluate_rules(
+        &self,
+        rules: &RuleSet,
+        packet: &Packet,
+        conn_state: ConnState,
+        mut trace: Option<&mut Vec<TraceStep>>,
+    ) -> Verdict {
+        let dry_run = trace.is_some();
+        let alerting = self.alerts.is_some();
+        let mut header = packet.header();
+        header.conn_state = Some(conn_state);
+        let mut verdict = Verdict::new(rules.default_action().clone());
+        // Where to carry on once a jumped-to chain returns, innermost last
+        let mut returns: Vec<(usize, usize)> = Vec::new();
+        let (mut chain, mut position) = (0, 0);
+        let mut hops = 0;
+        let now = rules.has_schedules().then(|| self.clock.wall_time());
+        // Traces show every rule, so they stay on the linear walk
+        let index = rules.index().filter(|_| !dry_run);
+        let mut candidates: Option<Candidates> = None;
 
-        for entry in rules.iter() {
-            if !entry.enabled {
+        loop {
+            // Skip straight to the next rule that could match
+            if let Some(index) = index {
+                let current = match candidates.take() {
+                    Some(current) if current.chain() == chain => current,
+                    _ => index.candidates(chain, packet),
+                };
+                position = current.next(position).unwrap_or(usize::MAX);
+                candidates = Some(current);
+            }
+            let Some(entry) = rules.chain_rules(chain).get(position) else {
+                // Off the end: the chain's own policy decides, else back to
+                // the caller, else the default action below
+                if chain != 0 && let Some(policy) = rules.chain_policy(chain) {
+                    verdict.logged |= matches!(policy, Action::DropLog);
+                    verdict.action = policy.clone();
+                    return explain(verdict, dry_run, alerting, || VerdictReason::ChainPolicy {
+                        chain: rules.chains()[chain - 1].name.clone(),
+                    });
+                }
+                match returns.pop() {
+                    Some(resume) => (chain, position) = resume,
+                    None => break,
+                }
                 continue;
+            };
+            position += 1;
+
+            // Not active yet, or expired and waiting for the sweeper
+            let active = now.is_none_or(|now| entry.schedule.is_active_at(now));
+            let quick_match = (entry.enabled && active).then(|| entry.filter.quick_match(&header));
+            let action = match quick_match {
+                Some(true) if dry_run => entry.filter.peek_packet(packet),
+                Some(true) => entry.filter.check_packet(packet),
+                _ => None,
+            };
+            let limited = match &action {
+                Some(Action::RateLimit(name)) => !self.within_rate_limit(name, packet, dry_run),
+                _ => false,
+            };
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.push(TraceStep {
+                    rule_id: entry.id,
+                    rule_name: entry.filter.name().to_string(),
+                    chain: entry.chain.to_string(),
+                    enabled: entry.enabled,
+                    active,
+                    quick_match,
+                    action: action.clone(),
+                    rate_limited: limited,
+                });
             }
 
-            if !entry.filter.quick_match(&header) {
+            let Some(action) = action else {
                 continue;
+            };
+            if !dry_run {
+                entry.counters.record(packet.payload.len(), &action, limited, self.clock.wall_time());
             }
 
-            if let Some(action) = entry.filter.check_packet(packet) {
-                return action;
+            match &action {
+                Action::Log => verdict.logged = true,
+                Action::Mark(mark) => verdict.mark = Some(*mark),
+                Action::QosClass(class) => verdict.qos_class = Some(*class),
+                Action::RateLimit(_) => {
+                    if limited {
+                        verdict.action = action;
+                        return explain(verdict, dry_run, alerting, || rule_reason(entry));
+                    }
+                }
+                // Missing targets and runaway loops skip the rule
+                Action::Jump(target) | Action::Goto(target) => {
+                    let next = rules.chain_index(target).filter(|&next| next != 0);
+                    let Some(next) = next.filter(|_| hops < MAX_CHAIN_HOPS) else {
+                        continue;
+                    };
+                    hops += 1;
+                    if matches!(action, Action::Jump(_)) {
+                        returns.push((chain, position));
+                    }
+                    (chain, position) = (next, 0);
+                }
+                Action::Return => match returns.pop() {
+                    Some(resume) => (chain, position) = resume,
+                    None => break,
+                },
+                Action::DropLog => {
+                    verdict.logged = true;
+                    verdict.action = action;
+                    return explain(verdict, dry_run, alerting, || rule_reason(entry));
+                }
+                _ => {
+                    verdict.action = action;
+                    return explain(verdict, dry_run, alerting, || rule_reason(entry));
+                }
             }
         }
-        self.default_action
+
+        // A non-terminal default still has to let the packet through
+        match verdict.action {
+            Action::Log => verdict.logged = true,
+            Action::Mark(mark) => verdict.mark = verdict.mark.or(Some(mark)),
+            Action::QosClass(class) => verdict.qos_class = verdict.qos_class.or(Some(class)),
+            _ => {}
+        }
+        if !verdict.action.is_terminal() {
+            verdict.action = Action::Allow;
+        }
+        explain(verdict, dry_run, alerting, || VerdictReason::DefaultAction)
+    }
+
+    // Configs naming an unknown limiter are rejected when applied; a name
+    // still unknown here (a hand-built rule, a removed limiter) passes
+    // traffic rather than silently dropping it.
+    // A dry run only looks at the limiter's remaining allowance.
+    fn within_rate_limit(&self, name: &str, packet: &Packet, dry_run: bool) -> bool {
+        let limiter = match self.rate_limiters.read().unwrap().get(name) {
+            Some(limiter) => Arc::clone(limiter),
+            None => return true,
+        };
+        let key = limiter.key_type.key_for(packet);
+        let mut inner = limiter.limiter.lock().unwrap();
+        if dry_run {
+            inner.would_allow(&key)
+        } else {
+            inner.is_allowed(&key)
+        }
+    }
+
+    pub fn register_rate_limiter(&self, name: impl Into<String>, config: RateLimitConfig) {
+        let named = Arc::new(NamedRateLimiter {
+            limiter: Mutex::new(config.build_with_clock(Arc::clone(&self.clock))),
+            key_type: config.key_type.clone(),
+            config: Some(config),
+        });
+        self.rate_limiters.write().unwrap().insert(name.into(), named);
+    }
+
+    pub fn register_rate_limiter_with(
+        &self,
+        name: impl Into<String>,
+        key_type: RateLimitKeyType,
+        limiter: Box<dyn RateLimiter>,
+    ) {
+        let named = Arc::new(NamedRateLimiter {
+            limiter: Mutex::new(limiter),
+            key_type,
+            config: None,
+        });
+        self.rate_limiters.write().unwrap().insert(name.into(), named);
+    }
+
+    // Limiters registered from a `RateLimitConfig`; custom limiters are left out
+    pub fn rate_limiter_configs(&self) -> BTreeMap<String, RateLimitConfig> {
+        self.rate_limiters.read().unwrap()
+            .iter()
+            .filter_map(|(name, named)| Some((name.clone(), named.config.clone()?)))
+            .collect()
+    }
+
+    pub fn has_rate_limiter(&self, name: &str) -> bool {
+        self.rate_limiters.read().unwrap().contains_key(name)
+    }
+
+    pub fn remove_rate_limiter(&self, name: &str) -> bool {
+        self.rate_limiters.write().unwrap().remove(name).is_some()
+    }
+
+    // Forgets keys idle for `threshold_secs` in every named limiter
+    pub fn cleanup_rate_limiters(&self, threshold_secs: u64) {
+        let limiters: Vec<_> = self.rate_limiters.read().unwrap().values().cloned().collect();
+        for named in limiters {
+            named.limiter.lock().unwrap().cleanup(threshold_secs);
+        }
+    }
+
+    // Extends an existing quarantine rather than shortening it
+    pub fn quarantine_host(&self, ip: IpAddr, duration: Duration) {
+        let until = self.clock.now() + duration;
+        let mut quarantine = self.quarantine.lock().unwrap();
+        let entry = quarantine.entry(ip).or_insert(until);
+        if *entry < until {
+            *entry = until;
+        }
     }
-    pub(crate) fn rules(&self) -> Arc<RwLock<Vec<RuleEntry>>> {
-        Arc::clone(&self.rules)
+
+    pub fn release_host(&self, ip: &IpAddr) -> bool {
+        self.quarantine.lock().unwrap().remove(ip).is_some()
+    }
+
+    pub fn quarantined_hosts(&self) -> Vec<(IpAddr, Duration)> {
+        let now = self.clock.now();
+        let mut quarantine = self.quarantine.lock().unwrap();
+        quarantine.retain(|_, until| *until > now);
+        quarantine.iter()
+            .map(|(ip, until)| (*ip, until.duration_since(now)))
+            .collect()
+    }
+
+    fn quarantine_remaining(&self, ip: &IpAddr) -> Option<Duration> {
+        let mut quarantine = self.quarantine.lock().unwrap();
+        let until = *quarantine.get(ip)?;
+        let now = self.clock.now();
+        if until > now {
+            Some(until - now)
+        } else {
+            quarantine.remove(ip);
+            None
+        }
+    }
+
+    // `quarantine_remaining` without pruning an expired entry
+    fn quarantine_peek(&self, ip: &IpAddr) -> Option<Duration> {
+        let until = *self.quarantine.lock().unwrap().get(ip)?;
+        let now = self.clock.now();
+        (until > now).then(|| until - now)
     }
-}
\ No newline at end of file
+}
+
+// Logged verdicts carry their reason, and so do drops when they raise
+// alerts; traces always do
+fn explain(
+    mut verdict: Verdict,
+    always: bool,
+    drops: bool,
+    reason: impl FnOnce() -> VerdictReason,
+) -> Verdict {
+    if always || verdict.logged || (drops && !verdict.permits()) {
+        verdict.reason = Some(reason());
+    }
+    verdict
+}
+
+fn rule_reason(entry: &RuleEntry) -> VerdictReason {
+    VerdictReason::Rule {
+        id: entry.id,
+        name: entry.filter.name().to_string(),
+        chain: entry.chain.to_string(),
+    }
+}
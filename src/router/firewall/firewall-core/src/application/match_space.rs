@@ -0,0 +1,326 @@
+use crate::application::config::RuleKind;
+use crate::domain::flow::ConnState;
+use crate::domain::packet::Protocol;
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rule::Action;
+use crate::rules::expr_rules::expression::{Node, NumericField};
+use crate::rules::ip_rules::AddressMatch;
+use std::ops::RangeInclusive;
+
+// Expressions whose OR/AND expansion grows past this are treated as
+// matching anything
+const MAX_CLAUSES: usize = 64;
+
+// The packets a rule of this kind can match, as a union of clauses, each
+// with the action it gives them
+pub(crate) fn clauses(kind: &RuleKind) -> Vec<(Space, Action)> {
+    match kind {
+        RuleKind::PortBlocklist { ports, protocols, match_on, action } => {
+            let base = Space::any().with_protocols(protocols);
+            sides(*match_on)
+                .map(|source| {
+                    let mut space = base.clone();
+                    *space.ports_mut(source) = Some(ports.clone());
+                    (space, action.clone())
+                })
+                .collect()
+        }
+        RuleKind::PortAllowlist { ports, protocols } => {
+            let blocked = complement(ports);
+            if blocked.is_empty() {
+                Vec::new()
+            } else {
+                let mut space = Space::any().with_protocols(protocols);
+                space.destination_ports = Some(blocked);
+                vec![(space, Action::Block)]
+            }
+        }
+        RuleKind::Services { services, action } => services.iter()
+            .map(|service| {
+                let space = Space {
+                    protocols: Some(vec![service.protocol()]),
+                    destination_ports: Some(vec![service.port()..=service.port()]),
+                    ..Space::any()
+                };
+                (space, action.clone())
+            })
+            .collect(),
+        RuleKind::IpPrefix { prefixes, match_on } => {
+            // Longest-prefix match means a broader prefix only decides what
+            // its more specific ones don't, unless they all agree
+            let uniform = prefixes.windows(2).all(|w| w[0].1 == w[1].1);
+            prefixes.iter()
+                .flat_map(|(prefix, action)| {
+                    sides(*match_on).map(move |source| {
+                        let mut space = Space::any();
+                        *space.addresses_mut(source) = Some(vec![*prefix]);
+                        space.exact = uniform;
+                        (space, action.clone())
+                    })
+                })
+                .collect()
+        }
+        RuleKind::TimeWindow { action, .. } => vec![(Space::opaque(), action.clone())],
+        // Acts only once over the limit
+        RuleKind::RateLimit { .. } => vec![(Space::opaque(), Action::Block)],
+        RuleKind::TimeRateLimit { action, .. } => vec![(Space::opaque(), action.clone())],
+        RuleKind::ConnState { states, protocols, action } => {
+            let space = Space {
+                states: Some(states.clone()),
+                ..Space::any().with_protocols(protocols)
+            };
+            vec![(space, action.clone())]
+        }
+        RuleKind::Expression { expression, action } => expand(expression.root())
+            .into_iter()
+            .map(|space| (space, action.clone()))
+            .collect(),
+    }
+}
+
+// `true` for the source side, `false` for the destination
+fn sides(match_on: AddressMatch) -> impl Iterator<Item = bool> + Clone {
+    let (source, destination) = match match_on {
+        AddressMatch::Source => (true, false),
+        AddressMatch::Destination => (false, true),
+        AddressMatch::Either => (true, true),
+    };
+    [true, false].into_iter().filter(move |&side| if side { source } else { destination })
+}
+
+// Expression tree as a union of spaces
+fn expand(node: &Node) -> Vec<Space> {
+    let port = |source: bool, ranges: &[RangeInclusive<u32>]| {
+        let mut space = Space::any();
+        *space.ports_mut(source) = Some(
+            ranges.iter()
+                .map(|r| (*r.start()).min(65535) as u16..=(*r.end()).min(65535) as u16)
+                .collect(),
+        );
+        space
+    };
+
+    let spaces = match node {
+        Node::Const(true) => vec![Space::any()],
+        Node::Const(false) => Vec::new(),
+        Node::And(nodes) => {
+            let mut acc = vec![Space::any()];
+            for node in nodes {
+                let next = expand(node);
+                if acc.len() * next.len() > MAX_CLAUSES {
+                    return vec![Space::opaque()];
+                }
+                acc = acc.iter()
+                    .flat_map(|a| next.iter().filter_map(move |b| a.meet(b)))
+                    .collect();
+            }
+            acc
+        }
+        Node::Or(nodes) => nodes.iter().flat_map(expand).collect(),
+        Node::Protocol(protocols) => vec![Space { protocols: Some(protocols.clone()), ..Space::any() }],
+        Node::Address(match_on, set) => {
+            let prefixes: Vec<IpPrefix> = set.iter().into_iter().map(|(prefix, _)| prefix).collect();
+            sides(*match_on)
+                .map(|source| {
+                    let mut space = Space::any();
+                    *space.addresses_mut(source) = Some(prefixes.clone());
+                    space
+                })
+                .collect()
+        }
+        Node::Number(NumericField::SourcePort, ranges) => vec![port(true, ranges)],
+        Node::Number(NumericField::DestinationPort, ranges) => vec![port(false, ranges)],
+        Node::Number(NumericField::Port, ranges) => vec![port(true, ranges), port(false, ranges)],
+        Node::ConnState(states) => vec![Space { states: Some(states.clone()), ..Space::any() }],
+        // Negation, flags, time, TTL, ...: somewhere in here
+        _ => vec![Space::opaque()],
+    };
+    if spaces.len() > MAX_CLAUSES {
+        return vec![Space::opaque()];
+    }
+    spaces
+}
+
+// Sorted, merged ports not in `ranges`
+fn complement(ranges: &[RangeInclusive<u16>]) -> Vec<RangeInclusive<u16>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|r| *r.start());
+    let mut gaps = Vec::new();
+    let mut next: u32 = 0;
+    for range in sorted {
+        if u32::from(*range.start()) > next {
+            gaps.push(next as u16..=*range.start() - 1);
+        }
+        next = next.max(u32::from(*range.end()) + 1);
+    }
+    if next <= 65535 {
+        gaps.push(next as u16..=65535);
+    }
+    gaps
+}
+
+// Packets a rule clause can match: the conjunction of its dimensions, each
+// `None` for "any"
+#[derive(Debug, Clone)]
+pub(crate) struct Space {
+    pub(crate) protocols: Option<Vec<Protocol>>,
+    pub(crate) sources: Option<Vec<IpPrefix>>,
+    pub(crate) destinations: Option<Vec<IpPrefix>>,
+    pub(crate) source_ports: Option<Vec<RangeInclusive<u16>>>,
+    pub(crate) destination_ports: Option<Vec<RangeInclusive<u16>>>,
+    pub(crate) states: Option<Vec<ConnState>>,
+    // The clause matches all of this space, not just part of it
+    pub(crate) exact: bool,
+}
+
+impl Space {
+    pub(crate) fn any() -> Self {
+        Self {
+            protocols: None,
+            sources: None,
+            destinations: None,
+            source_ports: None,
+            destination_ports: None,
+            states: None,
+            exact: true,
+        }
+    }
+
+    // Matches depending on conditions the analysis can't see
+    pub(crate) fn opaque() -> Self {
+        Self { exact: false, ..Self::any() }
+    }
+
+    // An empty list in the config means every protocol
+    fn with_protocols(mut self, protocols: &[Protocol]) -> Self {
+        if !protocols.is_empty() {
+            self.protocols = Some(protocols.to_vec());
+        }
+        self
+    }
+
+    fn ports_mut(&mut self, source: bool) -> &mut Option<Vec<RangeInclusive<u16>>> {
+        if source { &mut self.source_ports } else { &mut self.destination_ports }
+    }
+
+    fn addresses_mut(&mut self, source: bool) -> &mut Option<Vec<IpPrefix>> {
+        if source { &mut self.sources } else { &mut self.destinations }
+    }
+
+    pub(crate) fn covers(&self, other: &Space) -> bool {
+        covers(&self.protocols, &other.protocols)
+            && covers(&self.sources, &other.sources)
+            && covers(&self.destinations, &other.destinations)
+            && covers(&self.source_ports, &other.source_ports)
+            && covers(&self.destination_ports, &other.destination_ports)
+            && covers(&self.states, &other.states)
+    }
+
+    // Packets in both, `None` if there are none
+    pub(crate) fn meet(&self, other: &Space) -> Option<Space> {
+        let space = Space {
+            protocols: meet(&self.protocols, &other.protocols)?,
+            sources: meet(&self.sources, &other.sources)?,
+            destinations: meet(&self.destinations, &other.destinations)?,
+            source_ports: meet(&self.source_ports, &other.source_ports)?,
+            destination_ports: meet(&self.destination_ports, &other.destination_ports)?,
+            states: meet(&self.states, &other.states)?,
+            exact: self.exact && other.exact,
+        };
+        // Only TCP and UDP packets have ports
+        let ported = space.source_ports.is_some() || space.destination_ports.is_some();
+        if ported && let Some(protocols) = &space.protocols
+            && !protocols.iter().any(|p| matches!(p, Protocol::Tcp | Protocol::Udp))
+        {
+            return None;
+        }
+        Some(space)
+    }
+}
+
+// Values of one dimension
+trait Element: Clone {
+    fn meet(&self, other: &Self) -> Option<Self>;
+
+    fn covered_by(&self, set: &[Self]) -> bool;
+}
+
+impl Element for Protocol {
+    fn meet(&self, other: &Self) -> Option<Self> {
+        (self == other).then_some(*self)
+    }
+
+    fn covered_by(&self, set: &[Self]) -> bool {
+        set.contains(self)
+    }
+}
+
+impl Element for ConnState {
+    fn meet(&self, other: &Self) -> Option<Self> {
+        (self == other).then_some(*self)
+    }
+
+    fn covered_by(&self, set: &[Self]) -> bool {
+        set.contains(self)
+    }
+}
+
+impl Element for IpPrefix {
+    fn meet(&self, other: &Self) -> Option<Self> {
+        if self.covers(other) {
+            Some(*other)
+        } else if other.covers(self) {
+            Some(*self)
+        } else {
+            None
+        }
+    }
+
+    fn covered_by(&self, set: &[Self]) -> bool {
+        set.iter().any(|prefix| prefix.covers(self))
+    }
+}
+
+impl Element for RangeInclusive<u16> {
+    fn meet(&self, other: &Self) -> Option<Self> {
+        let start = *self.start().max(other.start());
+        let end = *self.end().min(other.end());
+        (start <= end).then_some(start..=end)
+    }
+
+    // The union of `set` can cover a range no single member does
+    fn covered_by(&self, set: &[Self]) -> bool {
+        let mut next = u32::from(*self.start());
+        let mut sorted = set.to_vec();
+        sorted.sort_by_key(|r| *r.start());
+        for range in sorted {
+            if u32::from(*range.start()) > next {
+                break;
+            }
+            next = next.max(u32::from(*range.end()) + 1);
+        }
+        next > u32::from(*self.end())
+    }
+}
+
+// Every packet `inner` allows is allowed by `outer`. A constrained
+// dimension never covers an unconstrained one: ports also require the
+// packet to have ports at all.
+fn covers<T: Element>(outer: &Option<Vec<T>>, inner: &Option<Vec<T>>) -> bool {
+    match (outer, inner) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(outer), Some(inner)) => inner.iter().all(|value| value.covered_by(outer)),
+    }
+}
+
+// `None` when the dimensions don't intersect, otherwise the intersection
+fn meet<T: Element>(a: &Option<Vec<T>>, b: &Option<Vec<T>>) -> Option<Option<Vec<T>>> {
+    match (a, b) {
+        (None, other) | (other, None) => Some(other.clone()),
+        (Some(a), Some(b)) => {
+            let both: Vec<T> = a.iter().flat_map(|x| b.iter().filter_map(move |y| x.meet(y))).collect();
+            (!both.is_empty()).then_some(Some(both))
+        }
+    }
+}
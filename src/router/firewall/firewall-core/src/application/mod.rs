@@ -1,2 +1,8 @@
+pub mod analyzer;
+pub mod compiler;
+pub mod config;
 pub mod engine;
-pub mod rule_manager;
\ No newline at end of file
+pub mod match_space;
+pub mod rule_manager;
+pub mod sweeper;
+pub mod transaction;
\ No newline at end of file
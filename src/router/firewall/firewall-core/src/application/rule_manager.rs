@@ -1,87 +1,485 @@
-use crate::domain::rule::{Filter, RuleEntry, Action};
-use std::sync::{Arc, RwLock};
+use crate::application::analyzer::{self, AnalysisReport};
+use crate::application::compiler::RuleIndex;
+use crate::application::config::{ChainConfig, ConfigError, FirewallConfig, RuleConfig};
+use crate::application::transaction::{RuleTransaction, TransactionError};
+use crate::domain::chain::{self, Chain, ChainError, DEFAULT_CHAIN};
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::rule::{Action, Filter, RuleEntry, RuleSchedule};
+use crate::domain::ruleset::{Indexer, RuleSet, RuleSetCell};
+use crate::domain::stats::{RuleCounters, RuleHits, RuleStats};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 
+// Every change publishes a new `RuleSet` generation; the packet path keeps
+// reading the previous one until the swap, so writers never stall it
 pub struct RuleManager {
-    rules: Arc<RwLock<Vec<RuleEntry>>>,
-    next_id: Arc<RwLock<u64>>,
+    rules: Arc<RuleSetCell>,
+    next_id: AtomicU64,
+    clock: Arc<dyn Clock>,
 }
 
 impl RuleManager {
     pub fn new() -> Self {
+        Self::with_default_action(Action::Allow)
+    }
+
+    pub fn with_default_action(default_action: Action) -> Self {
+        Self::with_cell(RuleSetCell::new(default_action))
+    }
+
+    // `history_limit` is how many earlier generations stay available to
+    // `rollback`
+    pub fn with_history_limit(default_action: Action, history_limit: usize) -> Self {
+        Self::with_cell(RuleSetCell::with_history_limit(default_action, history_limit))
+    }
+
+    fn with_cell(rules: RuleSetCell) -> Self {
         Self {
-            rules: Arc::new(RwLock::new(Vec::new())),
-            next_id: Arc::new(RwLock::new(0)),
+            rules: Arc::new(rules),
+            next_id: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
         }
     }
 
-    pub fn add_rule(&self, filter: Box<dyn Filter>) -> u64 {
-        let id = {
-            let mut next_id = self.next_id.write().unwrap;
-            let id = *next_id;
-            *next_id += 1;
-            id
-        };
-
-        let entry = RuleEntry {
-            id,
-            filter,
-            enabled: true,
-            hit_count: 0,
-        };
-        let mut rules = self.rules.write().unwrap();
-        rules.push(entry);
-
-        rules.sort_by(|a, b| {
-            b.filter.priority().cmp(&a.filter.priority())
-        });
-        id
+    // Times TTLs and expiry, and is handed to rules built from a config
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.rules.set_clock(Arc::clone(&clock));
+        self.clock = clock;
+        self
     }
 
-    pub fn remove_rule(&self, id: u64) -> bool {
-        let mut rules = self.rules.write().unwrap();
-        let len_before = rules.len();
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    // Adds to the entry chain. Fails if the rule jumps to a missing chain
+    // or closes a loop.
+    pub fn add_rule(&self, filter: Box<dyn Filter>) -> Result<u64, ChainError> {
+        self.add_rule_to(DEFAULT_CHAIN, filter)
+    }
+
+    pub fn add_rule_to(&self, chain: &str, filter: Box<dyn Filter>) -> Result<u64, ChainError> {
+        let entry = self.new_entry(filter, true, chain);
+        let id = entry.id;
+        self.rules.try_update(|draft| {
+            draft.entries.push(entry);
+            draft.validate()
+        })?;
+        Ok(id)
+    }
+
+    // Temporary rule in the entry chain, e.g. a 15 minute block of a host
+    // the anomaly detector flagged
+    pub fn add_temporary_rule(&self, filter: Box<dyn Filter>, ttl: Duration) -> Result<u64, ChainError> {
+        let schedule = RuleSchedule::expiring_in(ttl, self.clock.wall_time());
+        self.add_scheduled_rule(DEFAULT_CHAIN, filter, schedule)
+    }
+
+    pub fn add_scheduled_rule(
+        &self,
+        chain: &str,
+        filter: Box<dyn Filter>,
+        schedule: RuleSchedule,
+    ) -> Result<u64, ChainError> {
+        let mut entry = self.new_entry(filter, true, chain);
+        entry.schedule = schedule;
+        let id = entry.id;
+        self.rules.try_update(|draft| {
+            draft.entries.push(entry);
+            draft.validate()
+        })?;
+        Ok(id)
+    }
+
+    // Adds a batch to the entry chain as a single generation, e.g. a bulk
+    // blocklist push. Nothing is added if any of them fails validation.
+    pub fn add_rules(&self, filters: impl IntoIterator<Item = Box<dyn Filter>>) -> Result<Vec<u64>, ChainError> {
+        let entries: Vec<RuleEntry> = filters.into_iter()
+            .map(|filter| self.new_entry(filter, true, DEFAULT_CHAIN))
+            .collect();
+        let ids = entries.iter().map(|entry| entry.id).collect();
+        self.rules.try_update(|draft| {
+            draft.entries.extend(entries);
+            draft.validate()
+        })?;
+        Ok(ids)
+    }
+
+    // Replaces every rule, chain and the default action in one generation,
+    // so packets never see a half-loaded config. Returns the new ids in
+    // config order.
+    pub fn load_config(&self, config: &FirewallConfig) -> Result<Vec<u64>, ChainError> {
+        let entries: Vec<RuleEntry> = config.rules.iter()
+            .map(|rule| {
+                let mut entry = self.new_entry(rule.build_with_clock(self.clock()), rule.enabled, &rule.chain);
+                entry.schedule = rule.schedule;
+                entry
+            })
+            .collect();
+        let ids = entries.iter().map(|entry| entry.id).collect();
+        self.rules.try_update(|draft| {
+            draft.entries = entries;
+            draft.default_action = config.default_action.clone();
+            draft.chains = config.chains.iter()
+                .map(|(name, chain)| Chain { name: name.clone(), policy: chain.policy.clone() })
+                .collect();
+            draft.validate()
+        })?;
+        Ok(ids)
+    }
+
+    pub fn export_config(&self) -> Result<Vec<RuleConfig>, ConfigError> {
+        let rules = self.rules.load();
+
+        rules.entries().iter()
+            .map(|entry| entry_config(entry).ok_or_else(|| ConfigError::NotExportable {
+                rule: entry.filter.name().to_string(),
+            }))
+            .collect()
+    }
+
+    // One generation as a config, for backends. Rules without a config form
+    // are left out and named instead of failing the export; rate limiters
+    // live in the processor, so they're left empty.
+    pub fn export_generation(&self) -> (u64, FirewallConfig, Vec<String>) {
+        let rules = self.rules.snapshot();
+        let mut config = FirewallConfig::new(rules.default_action().clone());
+        config.chains = chain_configs(&rules);
+        let mut unexportable = Vec::new();
+        for entry in rules.entries() {
+            match entry_config(entry) {
+                Some(rule) => config.rules.push(rule),
+                None => unexportable.push(entry.filter.name().to_string()),
+            }
+        }
+        (rules.generation(), config, unexportable)
+    }
 
-        rules.retain(|entry| entry.id != id);
-        rules.len() < len_before
+    pub fn chain_configs(&self) -> BTreeMap<String, ChainConfig> {
+        chain_configs(&self.rules.load())
     }
 
+    // Calls `listener` with each generation published from now on, by any
+    // change, transaction or rollback
+    pub fn on_publish(&self, listener: impl Fn(u64) + Send + Sync + 'static) {
+        self.rules.subscribe(listener)
+    }
+
+    pub(crate) fn new_entry(&self, filter: Box<dyn Filter>, enabled: bool, chain: &str) -> RuleEntry {
+        let targets = filter.jump_targets();
+        RuleEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            filter: Arc::from(filter),
+            enabled,
+            counters: Arc::new(RuleCounters::new()),
+            chain: Arc::from(chain),
+            targets: Arc::from(targets),
+            schedule: RuleSchedule::default(),
+        }
+    }
+
+    pub fn remove_rule(&self, id: u64) -> bool {
+        self.rules.update(|draft| {
+            let index = draft.entries.iter().position(|entry| entry.id == id)?;
+            draft.entries.remove(index);
+            Some(())
+        })
+        .is_some()
+    }
+
+    // Grouped by chain in evaluation order: the entry chain first, then the
+    // others in creation order
     pub fn list_rules(&self) -> Vec<RuleInfo> {
-        let rules = self.rules.read().unwrap();
+        let now = self.clock.wall_time();
+        self.rules.load().entries().iter().map(|entry| rule_info(entry, now)).collect()
+    }
+
+    // Every chain with its policy and rules; the entry chain comes first and
+    // its policy is the default action
+    pub fn list_chains(&self) -> Vec<ChainInfo> {
+        let now = self.clock.wall_time();
+        let rules = self.rules.load();
+        let names = std::iter::once(DEFAULT_CHAIN).chain(rules.chains().iter().map(|c| c.name.as_str()));
+
+        names.enumerate()
+            .map(|(index, name)| ChainInfo {
+                name: name.to_string(),
+                policy: rules.chain_policy(index).cloned(),
+                rules: rules.chain_rules(index).iter().map(|entry| rule_info(entry, now)).collect(),
+            })
+            .collect()
+    }
 
-        rules.iter()
-            .map(|entry| RuleInfo {
+    pub fn create_chain(&self, chain: Chain) -> Result<(), ChainError> {
+        self.rules.try_update(|draft| {
+            draft.add_chain(chain)?;
+            draft.validate()
+        })?;
+        Ok(())
+    }
+
+    pub fn delete_chain(&self, name: &str) -> Result<(), ChainError> {
+        self.rules.try_update(|draft| draft.remove_chain(name))?;
+        Ok(())
+    }
+
+    pub fn set_chain_policy(&self, name: &str, policy: Option<Action>) -> Result<(), ChainError> {
+        self.rules.try_update(|draft| {
+            draft.set_chain_policy(name, policy)?;
+            draft.validate()
+        })?;
+        Ok(())
+    }
+
+    // Shadowed, redundant, conflicting and ambiguously ordered rules in the
+    // current generation
+    pub fn analyze(&self) -> AnalysisReport {
+        analyzer::analyze(&self.rules.load())
+    }
+
+    pub fn rule_stats(&self) -> Vec<RuleStats> {
+        let rules = self.rules.load();
+
+        rules.entries().iter()
+            .map(|entry| RuleStats {
                 id: entry.id,
                 name: entry.filter.name().to_string(),
-                priority: entry.filter.priority(),
-                enabled: entry.enabled,
-                hit_count: entry.hit_count,
+                hits: entry.counters.snapshot(),
             })
             .collect()
     }
 
+    pub fn set_schedule(&self, id: u64, schedule: RuleSchedule) -> bool {
+        self.edit_schedule(id, |_| Some(schedule)).is_some()
+    }
+
+    // Expires the rule `ttl` from now, or never with `None`
+    pub fn set_ttl(&self, id: u64, ttl: Option<Duration>) -> bool {
+        let expires_at = ttl.map(|ttl| self.clock.wall_time() + ttl);
+        self.edit_schedule(id, |schedule| Some(RuleSchedule { expires_at, ..*schedule }))
+            .is_some()
+    }
+
+    // Pushes the expiry back by `by`, counting from now if it already
+    // passed, so repeat offenders can get escalating bans. Returns the new
+    // expiry; `None` if there is no such rule or it never expires.
+    pub fn extend_ttl(&self, id: u64, by: Duration) -> Option<SystemTime> {
+        let now = self.clock.wall_time();
+        self.edit_schedule(id, |schedule| {
+            let until = schedule.expires_at?.max(now) + by;
+            Some(RuleSchedule { expires_at: Some(until), ..*schedule })
+        })?
+        .expires_at
+    }
+
+    // Brings the expiry forward by `by`; same return as `extend_ttl`
+    pub fn shorten_ttl(&self, id: u64, by: Duration) -> Option<SystemTime> {
+        self.edit_schedule(id, |schedule| {
+            let until = schedule.expires_at?;
+            let until = until.checked_sub(by).unwrap_or(SystemTime::UNIX_EPOCH);
+            Some(RuleSchedule { expires_at: Some(until), ..*schedule })
+        })?
+        .expires_at
+    }
+
+    // Publishes the schedule `edit` returns; nothing if it returns `None`
+    fn edit_schedule(
+        &self,
+        id: u64,
+        edit: impl FnOnce(&RuleSchedule) -> Option<RuleSchedule>,
+    ) -> Option<RuleSchedule> {
+        self.rules.update(|draft| {
+            let entry = draft.entries.iter_mut().find(|e| e.id == id)?;
+            entry.schedule = edit(&entry.schedule)?;
+            Some(entry.schedule)
+        })
+    }
+
+    // Removes every expired rule in one generation and returns their ids
+    pub fn reap_expired(&self) -> Vec<u64> {
+        let now = self.clock.wall_time();
+        if !self.rules.load().entries().iter().any(|e| e.schedule.is_expired_at(now)) {
+            return Vec::new();
+        }
+        self.rules.update(|draft| {
+            let (expired, kept) = std::mem::take(&mut draft.entries).into_iter()
+                .partition::<Vec<_>, _>(|e| e.schedule.is_expired_at(now));
+            draft.entries = kept;
+            Some(expired.iter().map(|e| e.id).collect())
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn reset_counters(&self, id: u64) -> bool {
+        match self.rules.load().get(id) {
+            Some(entry) => {
+                entry.counters.reset();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn reset_all_counters(&self) {
+        for entry in self.rules.load().entries() {
+            entry.counters.reset();
+        }
+    }
+
+    // Forgets per-key state idle for `threshold_secs` in the live rules
+    pub fn cleanup_rules(&self, threshold_secs: u64) {
+        for entry in self.rules.load().entries() {
+            entry.filter.cleanup(threshold_secs);
+        }
+    }
+
     pub fn clear_all(&self) {
-        let mut rules = self.rules.write().unwrap();
-        rules.clear();
+        self.rules.update(|draft| {
+            draft.entries.clear();
+            Some(())
+        });
+    }
+
+    pub fn default_action(&self) -> Action {
+        self.rules.load().default_action().clone()
+    }
+
+    // Jump, goto and return can't be the default
+    pub fn set_default_action(&self, action: Action) -> Result<(), ChainError> {
+        if chain::is_flow_control(&action) {
+            return Err(ChainError::InvalidPolicy {
+                chain: DEFAULT_CHAIN.to_string(),
+                policy: action,
+            });
+        }
+        self.rules.update(|draft| {
+            draft.default_action = action;
+            Some(())
+        });
+        Ok(())
+    }
+
+    // Generation of the rule set the packet path currently sees. Bumped by
+    // every change, so a caller can tell its update has been published.
+    pub fn generation(&self) -> u64 {
+        self.rules.generation()
     }
-    pub(crate) fn rules_ref(&self) -> Arc<RwLock<Vec<RuleEntry>>> {
+
+    // Index rules by protocol, port and prefix instead of scanning them all
+    // for every packet. Applies from the next generation on.
+    pub fn set_compiled(&self, enabled: bool) {
+        self.rules.set_indexer(enabled.then_some(RuleIndex::boxed as Indexer))
+    }
+
+    pub fn snapshot(&self) -> Arc<RuleSet> {
+        self.rules.snapshot()
+    }
+
+    pub fn begin_transaction(&self) -> RuleTransaction<'_> {
+        RuleTransaction::new(self)
+    }
+
+    // Restores the rules and default action of an earlier generation. Rules
+    // keep their ids and hit counters. Returns the new generation.
+    pub fn rollback(&self, generation: u64) -> Result<u64, TransactionError> {
+        self.rules.rollback(generation)
+            .ok_or(TransactionError::UnknownGeneration(generation))
+    }
+
+    // Generations available to `rollback`, oldest first
+    pub fn history(&self) -> Vec<GenerationInfo> {
+        self.rules.history().iter()
+            .map(|set| GenerationInfo {
+                generation: set.generation(),
+                rule_count: set.len(),
+                default_action: set.default_action().clone(),
+                published_at: set.published_at(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn rules_ref(&self) -> Arc<RuleSetCell> {
         Arc::clone(&self.rules)
     }
+
     pub fn set_enabled(&self, id: u64, enabled: bool) -> bool {
-    let mut rules = self.rules.write().unwrap();
-    if let Some(entry) = rules.iter_mut().find(|e| e.id == id) {
-        entry.enabled = enabled;
-        true
-    } else {
-        false
+        self.rules.update(|draft| {
+            let entry = draft.entries.iter_mut().find(|e| e.id == id)?;
+            entry.enabled = enabled;
+            Some(())
+        })
+        .is_some()
+    }
+}
+
+impl Default for RuleManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
+fn rule_info(entry: &RuleEntry, now: SystemTime) -> RuleInfo {
+    let hits = entry.counters.snapshot();
+    RuleInfo {
+        id: entry.id,
+        name: entry.filter.name().to_string(),
+        chain: entry.chain.to_string(),
+        priority: entry.filter.priority(),
+        enabled: entry.enabled,
+        active: entry.schedule.is_active_at(now),
+        active_from: entry.schedule.active_from,
+        expires_at: entry.schedule.expires_at,
+        ttl: entry.schedule.remaining_at(now),
+        hit_count: hits.packets,
+        hits,
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct RuleInfo {
     pub id: u64,
     pub name: String,
+    pub chain: String,
     pub priority: i32,
     pub enabled: bool,
+    // Within its schedule right now; says nothing about `enabled`
+    pub active: bool,
+    pub active_from: Option<SystemTime>,
+    pub expires_at: Option<SystemTime>,
+    // Time left before the rule expires; `None` if it never does
+    pub ttl: Option<Duration>,
     pub hit_count: u64,
-}
\ No newline at end of file
+    pub hits: RuleHits,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainInfo {
+    pub name: String,
+    // Verdict when evaluation falls off the end; `None` returns to the caller
+    pub policy: Option<Action>,
+    pub rules: Vec<RuleInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GenerationInfo {
+    pub generation: u64,
+    pub rule_count: usize,
+    pub default_action: Action,
+    pub published_at: SystemTime,
+}
+
+fn entry_config(entry: &RuleEntry) -> Option<RuleConfig> {
+    let mut config = RuleConfig::from_filter(&*entry.filter)?;
+    config.enabled = entry.enabled;
+    config.chain = entry.chain.to_string();
+    config.schedule = entry.schedule;
+    Some(config)
+}
+
+fn chain_configs(rules: &RuleSet) -> BTreeMap<String, ChainConfig> {
+    rules.chains().iter()
+        .map(|chain| (chain.name.clone(), ChainConfig { policy: chain.policy.clone() }))
+        .collect()
+}
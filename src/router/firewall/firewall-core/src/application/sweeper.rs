@@ -0,0 +1,45 @@
+use crate::application::rule_manager::RuleManager;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Background thread that reaps expired rules every `interval`. Stops when
+// dropped.
+pub struct RuleSweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RuleSweeper {
+    pub fn spawn(rules: Arc<RuleManager>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("rule-sweeper".to_string())
+            .spawn(move || {
+                // Wakes early on stop, so shutdown doesn't wait out the interval
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    rules.reap_expired();
+                }
+            })
+            .expect("failed to spawn rule sweeper thread");
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    // Same as dropping the handle
+    pub fn stop(self) {}
+}
+
+impl Drop for RuleSweeper {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread with `Disconnected`
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
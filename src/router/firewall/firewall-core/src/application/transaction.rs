@@ -0,0 +1,190 @@
+use crate::application::rule_manager::RuleManager;
+use crate::domain::chain::{Chain, ChainError, DEFAULT_CHAIN};
+use crate::domain::rule::{Action, Filter, RuleEntry, RuleSchedule};
+use crate::domain::ruleset::RuleSetDraft;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    // A staged remove or toggle names a rule that is neither live nor added
+    // earlier in the transaction
+    UnknownRule(u64),
+    // The same rule is removed twice
+    AlreadyRemoved(u64),
+    // Rollback target has aged out of the history, or never existed
+    UnknownGeneration(u64),
+    // The result would break the chain graph
+    Chain(ChainError),
+}
+
+impl From<ChainError> for TransactionError {
+    fn from(e: ChainError) -> Self {
+        TransactionError::Chain(e)
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::UnknownRule(id) => write!(f, "no rule with id {}", id),
+            TransactionError::AlreadyRemoved(id) => write!(f, "rule {} is removed twice", id),
+            TransactionError::UnknownGeneration(generation) => {
+                write!(f, "generation {} is not in the history", generation)
+            }
+            TransactionError::Chain(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+enum Change {
+    Add(RuleEntry),
+    Remove(u64),
+    SetEnabled(u64, bool),
+    SetSchedule(u64, RuleSchedule),
+    SetDefaultAction(Action),
+    Clear,
+    CreateChain(Chain),
+    DeleteChain(String),
+    SetChainPolicy(String, Option<Action>),
+}
+
+// Staged rule changes. Nothing is visible to traffic until `commit`, which
+// validates every change against the live rule set and publishes them all
+// as one generation, or none of them. Dropping the transaction discards it.
+pub struct RuleTransaction<'a> {
+    manager: &'a RuleManager,
+    changes: Vec<Change>,
+}
+
+impl<'a> RuleTransaction<'a> {
+    pub(crate) fn new(manager: &'a RuleManager) -> Self {
+        Self {
+            manager,
+            changes: Vec::new(),
+        }
+    }
+
+    // The id is reserved now so later changes in the same transaction can
+    // refer to the rule
+    pub fn add_rule(&mut self, filter: Box<dyn Filter>) -> u64 {
+        self.add_rule_to(DEFAULT_CHAIN, filter)
+    }
+
+    // The chain may be one this transaction creates
+    pub fn add_rule_to(&mut self, chain: &str, filter: Box<dyn Filter>) -> u64 {
+        let entry = self.manager.new_entry(filter, true, chain);
+        let id = entry.id;
+        self.changes.push(Change::Add(entry));
+        id
+    }
+
+    pub fn add_scheduled_rule(&mut self, chain: &str, filter: Box<dyn Filter>, schedule: RuleSchedule) -> u64 {
+        let mut entry = self.manager.new_entry(filter, true, chain);
+        entry.schedule = schedule;
+        let id = entry.id;
+        self.changes.push(Change::Add(entry));
+        id
+    }
+
+    pub fn create_chain(&mut self, chain: Chain) -> &mut Self {
+        self.changes.push(Change::CreateChain(chain));
+        self
+    }
+
+    pub fn delete_chain(&mut self, name: impl Into<String>) -> &mut Self {
+        self.changes.push(Change::DeleteChain(name.into()));
+        self
+    }
+
+    pub fn set_chain_policy(&mut self, name: impl Into<String>, policy: Option<Action>) -> &mut Self {
+        self.changes.push(Change::SetChainPolicy(name.into(), policy));
+        self
+    }
+
+    pub fn remove_rule(&mut self, id: u64) -> &mut Self {
+        self.changes.push(Change::Remove(id));
+        self
+    }
+
+    pub fn set_enabled(&mut self, id: u64, enabled: bool) -> &mut Self {
+        self.changes.push(Change::SetEnabled(id, enabled));
+        self
+    }
+
+    pub fn set_schedule(&mut self, id: u64, schedule: RuleSchedule) -> &mut Self {
+        self.changes.push(Change::SetSchedule(id, schedule));
+        self
+    }
+
+    pub fn set_default_action(&mut self, action: Action) -> &mut Self {
+        self.changes.push(Change::SetDefaultAction(action));
+        self
+    }
+
+    // Removes every rule live when the transaction commits, including ones
+    // added by other writers since it began
+    pub fn clear(&mut self) -> &mut Self {
+        self.changes.push(Change::Clear);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Publishes the staged changes and returns the new generation
+    pub fn commit(self) -> Result<u64, TransactionError> {
+        let changes = self.changes;
+        self.manager.rules_ref().try_update(|draft| {
+            apply(draft, changes)?;
+            draft.validate()?;
+            Ok(())
+        })
+    }
+
+    pub fn discard(self) {}
+}
+
+fn apply(draft: &mut RuleSetDraft, changes: Vec<Change>) -> Result<(), TransactionError> {
+    let mut removed = HashSet::new();
+
+    for change in changes {
+        match change {
+            Change::Add(entry) => draft.entries.push(entry),
+            Change::Remove(id) => {
+                if removed.contains(&id) {
+                    return Err(TransactionError::AlreadyRemoved(id));
+                }
+                let index = draft.entries.iter().position(|e| e.id == id)
+                    .ok_or(TransactionError::UnknownRule(id))?;
+                draft.entries.remove(index);
+                removed.insert(id);
+            }
+            Change::SetEnabled(id, enabled) => {
+                let entry = draft.entries.iter_mut().find(|e| e.id == id)
+                    .ok_or(TransactionError::UnknownRule(id))?;
+                entry.enabled = enabled;
+            }
+            Change::SetSchedule(id, schedule) => {
+                let entry = draft.entries.iter_mut().find(|e| e.id == id)
+                    .ok_or(TransactionError::UnknownRule(id))?;
+                entry.schedule = schedule;
+            }
+            Change::SetDefaultAction(action) => draft.default_action = action,
+            Change::Clear => {
+                removed.extend(draft.entries.drain(..).map(|e| e.id));
+            }
+            Change::CreateChain(chain) => draft.add_chain(chain)?,
+            Change::DeleteChain(name) => draft.remove_chain(&name)?,
+            Change::SetChainPolicy(name, policy) => draft.set_chain_policy(&name, policy)?,
+        }
+    }
+    Ok(())
+}
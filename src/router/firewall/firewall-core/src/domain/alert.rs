@@ -0,0 +1,107 @@
+use crate::domain::packet::{Packet, Protocol};
+use crate::domain::rule::Verdict;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// A packet the firewall dropped, or would have dropped in monitor mode, or
+// that a rule asked to log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub timestamp: SystemTime,
+    pub source_ip: IpAddr,
+    pub destination_ip: IpAddr,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    pub protocol: Protocol,
+    // Always carries its reason
+    pub verdict: Verdict,
+}
+
+impl Alert {
+    pub fn new(packet: &Packet, verdict: &Verdict, timestamp: SystemTime) -> Self {
+        Self {
+            timestamp,
+            source_ip: packet.source_ip,
+            destination_ip: packet.destination_ip,
+            source_port: packet.source_port,
+            destination_port: packet.destination_port,
+            protocol: packet.protocol,
+            verdict: verdict.clone(),
+        }
+    }
+}
+
+// `would block tcp 10.0.0.5:4312 -> 10.0.0.1:23: rule 'no-telnet' (#3) in chain 'main'`
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let endpoint = |ip: IpAddr, port: Option<u16>| match port {
+            Some(port) => SocketAddr::new(ip, port).to_string(),
+            None => ip.to_string(),
+        };
+        let protocol = match self.protocol {
+            Protocol::Tcp => "tcp".to_string(),
+            Protocol::Udp => "udp".to_string(),
+            Protocol::Icmp => "icmp".to_string(),
+            Protocol::Icmpv6 => "icmpv6".to_string(),
+            Protocol::Other(number) => format!("proto {}", number),
+        };
+        if self.verdict.monitored && !self.verdict.permits() {
+            f.write_str("would ")?;
+        }
+        write!(
+            f,
+            "{} {} {} -> {}",
+            self.verdict.action,
+            protocol,
+            endpoint(self.source_ip, self.source_port),
+            endpoint(self.destination_ip, self.destination_port),
+        )?;
+        if let Some(reason) = &self.verdict.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+// Where the engine sends alerts. Called on the packet path, so it should
+// hand the alert off rather than block.
+pub trait AlertSink: Send + Sync {
+    fn raise(&self, alert: &Alert);
+}
+
+// Keeps the most recent alerts, for polling and tests
+pub struct InMemoryAlertSink {
+    alerts: Mutex<VecDeque<Alert>>,
+    capacity: usize,
+}
+
+impl InMemoryAlertSink {
+    pub fn new(capacity: usize) -> Self {
+        Self { alerts: Mutex::new(VecDeque::new()), capacity }
+    }
+
+    // Oldest first
+    pub fn alerts(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Removes and returns what's buffered, oldest first
+    pub fn take(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl AlertSink for InMemoryAlertSink {
+    fn raise(&self, alert: &Alert) {
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() == self.capacity {
+            alerts.pop_front();
+        }
+        if self.capacity > 0 {
+            alerts.push_back(alert.clone());
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::domain::rule::Action;
+use std::collections::HashMap;
+use std::fmt;
+
+// Chain every packet starts in. Its policy is the rule set's default action,
+// and it can't be created, deleted or jumped to.
+pub const DEFAULT_CHAIN: &str = "input";
+
+// Jumps and gotos one packet may take. Checked rule sets can't loop; this
+// only stops loops through filters that don't report their actions.
+pub const MAX_CHAIN_HOPS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    pub name: String,
+    // Verdict when evaluation falls off the end of the chain. Without one,
+    // evaluation goes back to the chain that jumped here.
+    pub policy: Option<Action>,
+}
+
+impl Chain {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            policy: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Action) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    UnknownChain(String),
+    DuplicateChain(String),
+    // Operation not allowed on the entry chain
+    BuiltIn(String),
+    NotEmpty(String),
+    // A rule still jumps to the chain
+    InUse { chain: String, rule: String },
+    // Policies have to end evaluation, so no log/mark/jump/...
+    InvalidPolicy { chain: String, policy: Action },
+    // Chains along the loop, first one repeated at the end
+    Loop(Vec<String>),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::UnknownChain(name) => write!(f, "no chain named '{}'", name),
+            ChainError::DuplicateChain(name) => write!(f, "chain '{}' already exists", name),
+            ChainError::BuiltIn(name) => write!(f, "'{}' is the entry chain", name),
+            ChainError::NotEmpty(name) => write!(f, "chain '{}' still has rules", name),
+            ChainError::InUse { chain, rule } => {
+                write!(f, "chain '{}' is still a target of rule '{}'", chain, rule)
+            }
+            ChainError::InvalidPolicy { chain, policy } => {
+                write!(f, "'{}' can't be the policy of chain '{}'", policy, chain)
+            }
+            ChainError::Loop(path) => write!(f, "chain loop: {}", path.join(" -> ")),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+// Chains a jump or goto action leads to
+pub fn jump_target(action: &Action) -> Option<&str> {
+    match action {
+        Action::Jump(target) | Action::Goto(target) => Some(target),
+        _ => None,
+    }
+}
+
+// Distinct chains a set of actions can jump or goto, in first-seen order
+pub fn jump_targets<'a>(actions: impl IntoIterator<Item = &'a Action>) -> Vec<String> {
+    let mut targets: Vec<String> = Vec::new();
+    for target in actions.into_iter().filter_map(jump_target) {
+        if !targets.iter().any(|t| t == target) {
+            targets.push(target.to_string());
+        }
+    }
+    targets
+}
+
+pub fn is_flow_control(action: &Action) -> bool {
+    matches!(action, Action::Jump(_) | Action::Goto(_) | Action::Return)
+}
+
+// Whether `action` can be the policy of a user-defined chain
+pub fn valid_policy(action: &Action) -> bool {
+    action.is_terminal()
+}
+
+// Finds a cycle in the graph of jump/goto edges (from, to). Returns the
+// chains along it with the first one repeated at the end.
+pub fn find_loop<'a>(edges: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Vec<String>> {
+    let mut graph: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (from, to) in edges {
+        graph.entry(from).or_default().push(to);
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        Visiting,
+        Done,
+    }
+    let mut marks: HashMap<&str, Mark> = HashMap::new();
+    let mut starts: Vec<&str> = graph.keys().copied().collect();
+    // Deterministic, so the same rule set always reports the same loop
+    starts.sort_unstable();
+
+    for start in starts {
+        if marks.contains_key(start) {
+            continue;
+        }
+        // Iterative DFS; `path` holds the chains currently being visited
+        let mut path: Vec<(&str, usize)> = vec![(start, 0)];
+        marks.insert(start, Mark::Visiting);
+        while let Some((node, next)) = path.last_mut() {
+            let targets = graph.get(*node).map_or(&[][..], Vec::as_slice);
+            let Some(&target) = targets.get(*next) else {
+                marks.insert(*node, Mark::Done);
+                path.pop();
+                continue;
+            };
+            *next += 1;
+            match marks.get(target) {
+                Some(Mark::Visiting) => {
+                    let from = path.iter().position(|(n, _)| *n == target).unwrap_or(0);
+                    let mut cycle: Vec<String> = path[from..].iter().map(|(n, _)| n.to_string()).collect();
+                    cycle.push(target.to_string());
+                    return Some(cycle);
+                }
+                Some(Mark::Done) => {}
+                None => {
+                    marks.insert(target, Mark::Visiting);
+                    path.push((target, 0));
+                }
+            }
+        }
+    }
+    None
+}
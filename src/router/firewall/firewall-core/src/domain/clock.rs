@@ -0,0 +1,177 @@
+use chrono::{DateTime, Local};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// Source of time for everything that ages, expires or depends on the time
+// of day: flows, rate limiters, quarantines, rule schedules and time rules.
+// One clock is shared by a whole firewall (see `FirewallBuilder::with_clock`).
+pub trait Clock: Send + Sync {
+    // Monotonic time, for measuring intervals
+    fn now(&self) -> Instant;
+    // Calendar time, for schedules and time-of-day matches
+    fn wall_time(&self) -> SystemTime;
+    // Capture timestamp of the packet about to be processed. Clocks that
+    // keep their own time ignore it.
+    fn observe(&self, _timestamp: SystemTime) {}
+
+    fn local_time(&self) -> DateTime<Local> {
+        DateTime::from(self.wall_time())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Stands still until it's moved, for tests
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_wall: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+
+    // Wall time starts at `wall`, e.g. a Monday 08:59 to test a window
+    pub fn starting_at(wall: SystemTime) -> Self {
+        Self {
+            start: Instant::now(),
+            start_wall: wall,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    // Time since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.start_wall + self.elapsed()
+    }
+}
+
+// Follows the capture timestamps of replayed packets (see
+// `Firewall::replay_packet`), so a recorded trace sees the same ages,
+// expiries and time windows it did live. Time never runs backwards: a
+// packet stamped earlier than one already seen leaves the clock where it
+// is. Until the first packet, wall time is when the clock was created.
+#[derive(Debug)]
+pub struct PacketClock {
+    start: Instant,
+    state: Mutex<PacketTime>,
+}
+
+#[derive(Debug)]
+struct PacketTime {
+    // Timestamp of the first packet, or creation time before one arrives
+    first: SystemTime,
+    seen: bool,
+    elapsed: Duration,
+}
+
+impl PacketClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Mutex::new(PacketTime {
+                first: SystemTime::now(),
+                seen: false,
+                elapsed: Duration::ZERO,
+            }),
+        }
+    }
+}
+
+impl Default for PacketClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for PacketClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().unwrap().elapsed
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        let state = self.state.lock().unwrap();
+        state.first + state.elapsed
+    }
+
+    fn observe(&self, timestamp: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        if !state.seen {
+            state.first = timestamp;
+            state.seen = true;
+            return;
+        }
+        if let Ok(elapsed) = timestamp.duration_since(state.first) {
+            state.elapsed = state.elapsed.max(elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    #[test]
+    fn manual_clock_moves_both_times_together() {
+        let clock = ManualClock::starting_at(at(0));
+        let start = clock.now();
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(clock.wall_time(), at(90));
+        assert_eq!(clock.elapsed(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn packet_clock_follows_timestamps_forward_only() {
+        let clock = PacketClock::new();
+        let start = clock.now();
+        clock.observe(at(0));
+        assert_eq!((clock.wall_time(), clock.now()), (at(0), start));
+
+        clock.observe(at(30));
+        assert_eq!((clock.wall_time(), clock.now() - start), (at(30), Duration::from_secs(30)));
+
+        // Out of order, and from before the first packet
+        clock.observe(at(10));
+        clock.observe(at(0) - Duration::from_secs(5));
+        assert_eq!((clock.wall_time(), clock.now() - start), (at(30), Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,700 @@
+use crate::domain::packet::{IcmpInfo, Packet, Protocol, TcpFlags, TcpInfo};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_MIN_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const ICMP_HEADER_LEN: usize = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+
+// Stacked 802.1Q/802.1ad tags and IPv6 extension headers are bounded so a
+// crafted packet can't make us walk an arbitrarily long chain
+const MAX_VLAN_TAGS: usize = 2;
+const MAX_EXTENSION_HEADERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Ethernet,
+    Vlan,
+    Ipv4,
+    Ipv6,
+    Ipv6Extension,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated { layer: Layer, needed: usize, available: usize },
+    InvalidHeaderLength { layer: Layer, length: usize },
+    UnsupportedEtherType(u16),
+    UnsupportedIpVersion(u8),
+    TooManyVlanTags,
+    TooManyExtensionHeaders,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { layer, needed, available } => write!(
+                f,
+                "truncated {:?} header: need {} bytes, have {}",
+                layer, needed, available
+            ),
+            DecodeError::InvalidHeaderLength { layer, length } => {
+                write!(f, "invalid {:?} header length {}", layer, length)
+            }
+            DecodeError::UnsupportedEtherType(ether_type) => {
+                write!(f, "unsupported ethertype 0x{:04x}", ether_type)
+            }
+            DecodeError::UnsupportedIpVersion(version) => {
+                write!(f, "unsupported IP version {}", version)
+            }
+            DecodeError::TooManyVlanTags => {
+                write!(f, "more than {} VLAN tags", MAX_VLAN_TAGS)
+            }
+            DecodeError::TooManyExtensionHeaders => {
+                write!(f, "more than {} IPv6 extension headers", MAX_EXTENSION_HEADERS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn decode_ethernet(frame: &[u8]) -> Result<Packet, DecodeError> {
+    require(frame, ETHERNET_HEADER_LEN, Layer::Ethernet)?;
+
+    let mut ether_type = read_u16(frame, 12);
+    let mut offset = ETHERNET_HEADER_LEN;
+    let mut vlan_ids = Vec::new();
+
+    while matches!(ether_type, ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY) {
+        if vlan_ids.len() == MAX_VLAN_TAGS {
+            return Err(DecodeError::TooManyVlanTags);
+        }
+        require(&frame[offset..], VLAN_TAG_LEN, Layer::Vlan)?;
+        vlan_ids.push(read_u16(frame, offset) & 0x0FFF);
+        ether_type = read_u16(frame, offset + 2);
+        offset += VLAN_TAG_LEN;
+    }
+
+    let mut packet = match ether_type {
+        ETHERTYPE_IPV4 => decode_ipv4(frame, offset)?,
+        ETHERTYPE_IPV6 => decode_ipv6(frame, offset)?,
+        other => return Err(DecodeError::UnsupportedEtherType(other)),
+    };
+    packet.vlan_ids = vlan_ids;
+    Ok(packet)
+}
+
+pub fn decode_ip(datagram: &[u8]) -> Result<Packet, DecodeError> {
+    require(datagram, 1, Layer::Ipv4)?;
+
+    match datagram[0] >> 4 {
+        4 => decode_ipv4(datagram, 0),
+        6 => decode_ipv6(datagram, 0),
+        version => Err(DecodeError::UnsupportedIpVersion(version)),
+    }
+}
+
+// `start` is the offset of the IP header within `buf`; all offsets stored on
+// the resulting packet are relative to the start of `buf`
+fn decode_ipv4(buf: &[u8], start: usize) -> Result<Packet, DecodeError> {
+    let ip = &buf[start..];
+    require(ip, IPV4_MIN_HEADER_LEN, Layer::Ipv4)?;
+
+    let version = ip[0] >> 4;
+    if version != 4 {
+        return Err(DecodeError::UnsupportedIpVersion(version));
+    }
+
+    let header_len = ((ip[0] & 0x0F) as usize) * 4;
+    if header_len < IPV4_MIN_HEADER_LEN {
+        return Err(DecodeError::InvalidHeaderLength { layer: Layer::Ipv4, length: header_len });
+    }
+    require(ip, header_len, Layer::Ipv4)?;
+
+    let total_len = read_u16(ip, 2) as usize;
+    if total_len < header_len {
+        return Err(DecodeError::InvalidHeaderLength { layer: Layer::Ipv4, length: total_len });
+    }
+    require(ip, total_len, Layer::Ipv4)?;
+
+    let flags_and_offset = read_u16(ip, 6);
+    let fragment_offset = (flags_and_offset & 0x1FFF) * 8;
+    let protocol = ip[9];
+    let source = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let destination = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    // Trailing Ethernet padding is not part of the datagram
+    let end = start + total_len;
+    let mut packet = Packet::new(IpAddr::V4(source));
+    packet.destination_ip = IpAddr::V4(destination);
+    packet.ttl = ip[8];
+    packet.dscp = ip[1] >> 2;
+    packet.ecn = ip[1] & 0x03;
+    packet.ip_id = Some(read_u16(ip, 4) as u32);
+    packet.fragment_offset = fragment_offset;
+    packet.more_fragments = flags_and_offset & 0x2000 != 0;
+
+    // Only the first fragment carries the transport header
+    if fragment_offset != 0 {
+        packet.protocol = transport_protocol(protocol, false);
+        set_payload(&mut packet, buf, start + header_len, end);
+        return Ok(packet);
+    }
+
+    decode_transport(&mut packet, buf, start + header_len, end, transport_protocol(protocol, false))?;
+    Ok(packet)
+}
+
+fn decode_ipv6(buf: &[u8], start: usize) -> Result<Packet, DecodeError> {
+    let ip = &buf[start..];
+    require(ip, IPV6_HEADER_LEN, Layer::Ipv6)?;
+
+    let version = ip[0] >> 4;
+    if version != 6 {
+        return Err(DecodeError::UnsupportedIpVersion(version));
+    }
+
+    // A zero payload length means a jumbogram; take the rest of the buffer
+    let payload_len = read_u16(ip, 4) as usize;
+    let end = if payload_len == 0 {
+        buf.len()
+    } else {
+        require(ip, IPV6_HEADER_LEN + payload_len, Layer::Ipv6)?;
+        start + IPV6_HEADER_LEN + payload_len
+    };
+
+    let mut source = [0u8; 16];
+    let mut destination = [0u8; 16];
+    source.copy_from_slice(&ip[8..24]);
+    destination.copy_from_slice(&ip[24..40]);
+
+    let mut packet = Packet::new(IpAddr::V6(Ipv6Addr::from(source)));
+    packet.destination_ip = IpAddr::V6(Ipv6Addr::from(destination));
+    packet.ttl = ip[7];
+
+    let traffic_class = (ip[0] << 4) | (ip[1] >> 4);
+    packet.dscp = traffic_class >> 2;
+    packet.ecn = traffic_class & 0x03;
+
+    let mut next_header = ip[6];
+    let mut offset = start + IPV6_HEADER_LEN;
+
+    loop {
+        let ext_len = match next_header {
+            // Hop-by-hop, routing, destination options, mobility
+            0 | 43 | 60 | 135 => {
+                require(&buf[offset..end], 2, Layer::Ipv6Extension)?;
+                (buf[offset + 1] as usize + 1) * 8
+            }
+            // Fragment header has a fixed size
+            44 => 8,
+            // Authentication header counts 4-byte units, minus 2
+            51 => {
+                require(&buf[offset..end], 2, Layer::Ipv6Extension)?;
+                (buf[offset + 1] as usize + 2) * 4
+            }
+            _ => break,
+        };
+
+        if packet.extension_headers.len() == MAX_EXTENSION_HEADERS {
+            return Err(DecodeError::TooManyExtensionHeaders);
+        }
+        require(&buf[offset..end], ext_len, Layer::Ipv6Extension)?;
+
+        let mut is_later_fragment = false;
+        if next_header == 44 {
+            let offset_and_flags = read_u16(buf, offset + 2);
+            packet.fragment_offset = offset_and_flags & 0xFFF8;
+            packet.more_fragments = offset_and_flags & 0x0001 != 0;
+            packet.ip_id = Some(read_u32(buf, offset + 4));
+            is_later_fragment = packet.fragment_offset != 0;
+        }
+
+        packet.extension_headers.push(next_header);
+        next_header = buf[offset];
+        offset += ext_len;
+
+        if is_later_fragment {
+            packet.protocol = transport_protocol(next_header, true);
+            set_payload(&mut packet, buf, offset, end);
+            return Ok(packet);
+        }
+    }
+
+    decode_transport(&mut packet, buf, offset, end, transport_protocol(next_header, true))?;
+    Ok(packet)
+}
+
+// ICMP belongs to IPv4 and ICMPv6 to IPv6. Carried by the other family
+// they're just an unknown protocol, not something to parse as ICMP.
+fn transport_protocol(number: u8, ipv6: bool) -> Protocol {
+    match Protocol::from_number(number) {
+        Protocol::Icmp if ipv6 => Protocol::Other(number),
+        Protocol::Icmpv6 if !ipv6 => Protocol::Other(number),
+        protocol => protocol,
+    }
+}
+
+fn decode_transport(
+    packet: &mut Packet,
+    buf: &[u8],
+    start: usize,
+    end: usize,
+    protocol: Protocol,
+) -> Result<(), DecodeError> {
+    let segment = &buf[start..end];
+    packet.protocol = protocol;
+
+    let header_len = match packet.protocol {
+        Protocol::Tcp => {
+            require(segment, TCP_MIN_HEADER_LEN, Layer::Tcp)?;
+            let data_offset = ((segment[12] >> 4) as usize) * 4;
+            if data_offset < TCP_MIN_HEADER_LEN {
+                return Err(DecodeError::InvalidHeaderLength { layer: Layer::Tcp, length: data_offset });
+            }
+            require(segment, data_offset, Layer::Tcp)?;
+            packet.source_port = Some(read_u16(segment, 0));
+            packet.destination_port = Some(read_u16(segment, 2));
+            packet.tcp = Some(TcpInfo {
+                flags: TcpFlags::from_bits((((segment[12] & 0x01) as u16) << 8) | segment[13] as u16),
+                sequence: read_u32(segment, 4),
+                acknowledgment: read_u32(segment, 8),
+                window: read_u16(segment, 14),
+            });
+            data_offset
+        }
+        Protocol::Udp => {
+            require(segment, UDP_HEADER_LEN, Layer::Udp)?;
+            packet.source_port = Some(read_u16(segment, 0));
+            packet.destination_port = Some(read_u16(segment, 2));
+            UDP_HEADER_LEN
+        }
+        Protocol::Icmp | Protocol::Icmpv6 => {
+            require(segment, ICMP_HEADER_LEN, Layer::Icmp)?;
+            packet.icmp = Some(IcmpInfo { icmp_type: segment[0], code: segment[1] });
+            ICMP_HEADER_LEN
+        }
+        Protocol::Other(_) => 0,
+    };
+
+    set_payload(packet, buf, start + header_len, end);
+    Ok(())
+}
+
+fn set_payload(packet: &mut Packet, buf: &[u8], start: usize, end: usize) {
+    packet.payload_offset = start;
+    packet.payload = buf[start..end].to_vec();
+}
+
+fn require(buf: &[u8], needed: usize, layer: Layer) -> Result<(), DecodeError> {
+    if buf.len() < needed {
+        Err(DecodeError::Truncated { layer, needed, available: buf.len() })
+    } else {
+        Ok(())
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+    const DST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+
+    fn ethernet(tags: &[(u16, u16)], ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&DST_MAC);
+        frame.extend_from_slice(&SRC_MAC);
+        for &(tpid, tci) in tags {
+            frame.extend_from_slice(&tpid.to_be_bytes());
+            frame.extend_from_slice(&tci.to_be_bytes());
+        }
+        frame.extend_from_slice(&ether_type.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // `options` must be a multiple of 4 bytes
+    fn ipv4(protocol: u8, options: &[u8], flags_and_offset: u16, payload: &[u8]) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let total_len = (header_len + payload.len()) as u16;
+        let mut ip = vec![0x40 | (header_len / 4) as u8, 0xb9];
+        ip.extend_from_slice(&total_len.to_be_bytes());
+        ip.extend_from_slice(&0x1234u16.to_be_bytes());
+        ip.extend_from_slice(&flags_and_offset.to_be_bytes());
+        ip.extend_from_slice(&[64, protocol, 0, 0]);
+        ip.extend_from_slice(&[192, 0, 2, 1]);
+        ip.extend_from_slice(&[198, 51, 100, 7]);
+        ip.extend_from_slice(options);
+        ip.extend_from_slice(payload);
+        ip
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0x6b, 0x80, 0, 0];
+        ip.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&[next_header, 255]);
+        ip.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ip.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ip.extend_from_slice(payload);
+        ip
+    }
+
+    // Hop-by-hop/destination options style header padded to `len` bytes
+    fn extension(next_header: u8, len: usize, rest: &[u8]) -> Vec<u8> {
+        let mut header = vec![next_header, (len / 8 - 1) as u8];
+        header.resize(len, 0);
+        header.extend_from_slice(rest);
+        header
+    }
+
+    fn fragment_header(next_header: u8, offset: u16, more: bool, id: u32, rest: &[u8]) -> Vec<u8> {
+        let mut header = vec![next_header, 0];
+        header.extend_from_slice(&(offset | more as u16).to_be_bytes());
+        header.extend_from_slice(&id.to_be_bytes());
+        header.extend_from_slice(rest);
+        header
+    }
+
+    fn tcp(source: u16, destination: u16, flags: u16, options: &[u8], data: &[u8]) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&source.to_be_bytes());
+        segment.extend_from_slice(&destination.to_be_bytes());
+        segment.extend_from_slice(&1000u32.to_be_bytes());
+        segment.extend_from_slice(&2000u32.to_be_bytes());
+        segment.push(((header_len / 4) as u8) << 4 | (flags >> 8) as u8);
+        segment.push(flags as u8);
+        segment.extend_from_slice(&512u16.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment.extend_from_slice(options);
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn udp(source: u16, destination: u16, data: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&source.to_be_bytes());
+        datagram.extend_from_slice(&destination.to_be_bytes());
+        datagram.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    fn icmp(icmp_type: u8, code: u8) -> Vec<u8> {
+        vec![icmp_type, code, 0, 0, 0, 1, 0, 1]
+    }
+
+    fn truncated(layer: Layer, needed: usize, available: usize) -> DecodeError {
+        DecodeError::Truncated { layer, needed, available }
+    }
+
+    fn error(result: Result<Packet, DecodeError>) -> DecodeError {
+        match result {
+            Ok(_) => panic!("decoded"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn decodes_ipv4_tcp() {
+        let segment = tcp(40000, 443, 0x002, &[], b"hello");
+        let frame = ethernet(&[], ETHERTYPE_IPV4, &ipv4(6, &[], 0x4000, &segment));
+        let packet = decode_ethernet(&frame).unwrap();
+
+        assert_eq!(packet.source_ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.destination_ip, "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.protocol, Protocol::Tcp);
+        assert_eq!((packet.source_port, packet.destination_port), (Some(40000), Some(443)));
+        assert_eq!((packet.ttl, packet.dscp, packet.ecn), (64, 0xb9 >> 2, 0xb9 & 3));
+        assert_eq!(packet.ip_id, Some(0x1234));
+        assert!(!packet.is_fragment());
+        let info = packet.tcp.unwrap();
+        assert!(info.flags.is_syn_only());
+        assert_eq!((info.sequence, info.acknowledgment, info.window), (1000, 2000, 512));
+        assert_eq!(packet.payload, b"hello");
+        assert_eq!(packet.payload_offset, 14 + 20 + 20);
+    }
+
+    #[test]
+    fn tcp_ns_flag_and_options() {
+        let segment = tcp(1, 2, 0x100 | 0x010, &[1, 1, 1, 0], b"x");
+        let packet = decode_ip(&ipv4(6, &[], 0, &segment)).unwrap();
+        let flags = packet.tcp_flags().unwrap();
+        assert_eq!(flags.bits(), 0x110);
+        assert_eq!(packet.payload, b"x");
+        assert_eq!(packet.payload_offset, 20 + 24);
+    }
+
+    #[test]
+    fn decodes_vlan_and_qinq() {
+        let datagram = ipv4(17, &[], 0, &udp(5353, 53, b"q"));
+        let single = decode_ethernet(&ethernet(&[(ETHERTYPE_VLAN, 0x2064)], ETHERTYPE_IPV4, &datagram)).unwrap();
+        // Priority bits are masked off
+        assert_eq!(single.vlan_ids, vec![100]);
+        assert_eq!(single.header().vlan_id, Some(100));
+
+        for outer in [ETHERTYPE_QINQ, ETHERTYPE_QINQ_LEGACY] {
+            let frame = ethernet(&[(outer, 10), (ETHERTYPE_VLAN, 20)], ETHERTYPE_IPV4, &datagram);
+            let packet = decode_ethernet(&frame).unwrap();
+            assert_eq!(packet.vlan_ids, vec![10, 20]);
+            // The innermost tag is the one that matters for matching
+            assert_eq!(packet.header().vlan_id, Some(20));
+            assert_eq!(packet.destination_port, Some(53));
+            assert_eq!(packet.payload_offset, 14 + 8 + 20 + 8);
+        }
+    }
+
+    #[test]
+    fn rejects_a_third_vlan_tag() {
+        let datagram = ipv4(17, &[], 0, &udp(1, 2, b""));
+        let tags = [(ETHERTYPE_QINQ, 1), (ETHERTYPE_VLAN, 2), (ETHERTYPE_VLAN, 3)];
+        assert_eq!(error(decode_ethernet(&ethernet(&tags, ETHERTYPE_IPV4, &datagram))), DecodeError::TooManyVlanTags);
+    }
+
+    #[test]
+    fn skips_ipv4_options() {
+        let options = [0x94, 0x04, 0, 0, 0x01, 0x01, 0x01, 0x00];
+        let packet = decode_ip(&ipv4(17, &options, 0, &udp(68, 67, b"dhcp"))).unwrap();
+        assert_eq!(packet.destination_port, Some(67));
+        assert_eq!(packet.payload, b"dhcp");
+        assert_eq!(packet.payload_offset, 28 + 8);
+    }
+
+    #[test]
+    fn rejects_bad_ipv4_lengths() {
+        // IHL below the minimum
+        let mut datagram = ipv4(17, &[], 0, &udp(1, 2, b""));
+        datagram[0] = 0x44;
+        assert_eq!(error(decode_ip(&datagram)), DecodeError::InvalidHeaderLength { layer: Layer::Ipv4, length: 16 });
+
+        // IHL past the end of the buffer
+        let mut datagram = ipv4(17, &[], 0, &[]);
+        datagram[0] = 0x4f;
+        assert_eq!(error(decode_ip(&datagram)), truncated(Layer::Ipv4, 60, 20));
+
+        // Total length shorter than the header
+        let mut datagram = ipv4(17, &[], 0, &udp(1, 2, b""));
+        datagram[2..4].copy_from_slice(&19u16.to_be_bytes());
+        assert_eq!(error(decode_ip(&datagram)), DecodeError::InvalidHeaderLength { layer: Layer::Ipv4, length: 19 });
+
+        // Total length longer than what arrived
+        let mut datagram = ipv4(17, &[], 0, &udp(1, 2, b""));
+        datagram[2..4].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(error(decode_ip(&datagram)), truncated(Layer::Ipv4, 100, 28));
+    }
+
+    #[test]
+    fn ignores_ethernet_padding() {
+        let mut frame = ethernet(&[], ETHERTYPE_IPV4, &ipv4(17, &[], 0, &udp(1, 2, b"ab")));
+        frame.resize(60, 0xee);
+        let packet = decode_ethernet(&frame).unwrap();
+        assert_eq!(packet.payload, b"ab");
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        // First fragment: transport header present, more to come
+        let first = decode_ip(&ipv4(6, &[], 0x2000, &tcp(1, 80, 0x010, &[], b"abc"))).unwrap();
+        assert!(first.is_fragment() && first.more_fragments);
+        assert_eq!(first.fragment_offset, 0);
+        assert_eq!(first.destination_port, Some(80));
+
+        // Later fragment: no ports, the payload is raw data
+        let later = decode_ip(&ipv4(6, &[], 0x0003, b"continued")).unwrap();
+        assert!(later.is_fragment() && !later.more_fragments);
+        assert_eq!(later.fragment_offset, 24);
+        assert_eq!(later.protocol, Protocol::Tcp);
+        assert_eq!((later.source_port, later.tcp), (None, None));
+        assert_eq!(later.payload, b"continued");
+    }
+
+    #[test]
+    fn decodes_ipv6_udp() {
+        let frame = ethernet(&[], ETHERTYPE_IPV6, &ipv6(17, &udp(546, 547, b"v6")));
+        let packet = decode_ethernet(&frame).unwrap();
+        assert_eq!(packet.destination_ip, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!((packet.ttl, packet.dscp, packet.ecn), (255, 0xb8 >> 2, 0));
+        assert_eq!(packet.destination_port, Some(547));
+        assert!(packet.extension_headers.is_empty());
+        assert_eq!(packet.payload, b"v6");
+    }
+
+    #[test]
+    fn walks_ipv6_extension_chain() {
+        // Hop-by-hop -> routing -> AH -> destination options -> TCP
+        let segment = tcp(1, 22, 0x002, &[], b"");
+        let dest_opts = extension(6, 8, &segment);
+        let mut ah = vec![60, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        ah.extend_from_slice(&dest_opts);
+        let routing = extension(51, 24, &ah);
+        let hop_by_hop = extension(43, 8, &routing);
+        let packet = decode_ip(&ipv6(0, &hop_by_hop)).unwrap();
+
+        assert_eq!(packet.extension_headers, vec![0, 43, 51, 60]);
+        assert_eq!(packet.protocol, Protocol::Tcp);
+        assert_eq!(packet.destination_port, Some(22));
+        assert_eq!(packet.payload_offset, 40 + 8 + 24 + 12 + 8 + 20);
+    }
+
+    #[test]
+    fn caps_ipv6_extension_headers() {
+        let mut chain = udp(1, 2, b"");
+        let mut next = 17;
+        for _ in 0..MAX_EXTENSION_HEADERS {
+            chain = extension(next, 8, &chain);
+            next = 60;
+        }
+        let packet = decode_ip(&ipv6(60, &chain)).unwrap();
+        assert_eq!(packet.extension_headers.len(), MAX_EXTENSION_HEADERS);
+
+        let chain = extension(60, 8, &chain);
+        assert_eq!(error(decode_ip(&ipv6(60, &chain))), DecodeError::TooManyExtensionHeaders);
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        let first = decode_ip(&ipv6(44, &fragment_header(17, 0, true, 0xdeadbeef, &udp(1, 53, b"q")))).unwrap();
+        assert!(first.more_fragments);
+        assert_eq!(first.ip_id, Some(0xdeadbeef));
+        assert_eq!(first.destination_port, Some(53));
+        assert_eq!(first.extension_headers, vec![44]);
+
+        let later = decode_ip(&ipv6(44, &fragment_header(17, 1448, false, 7, b"rest"))).unwrap();
+        assert_eq!(later.fragment_offset, 1448);
+        assert!(later.is_fragment() && !later.more_fragments);
+        assert_eq!(later.protocol, Protocol::Udp);
+        assert_eq!(later.source_port, None);
+        assert_eq!(later.payload, b"rest");
+    }
+
+    #[test]
+    fn ipv6_jumbogram_takes_the_rest_of_the_buffer() {
+        let mut datagram = ipv6(17, &udp(1, 2, b"big"));
+        datagram[4..6].copy_from_slice(&[0, 0]);
+        let packet = decode_ip(&datagram).unwrap();
+        assert_eq!(packet.payload, b"big");
+    }
+
+    #[test]
+    fn icmp_only_under_its_own_family() {
+        let v4 = decode_ip(&ipv4(1, &[], 0, &icmp(8, 0))).unwrap();
+        assert_eq!(v4.protocol, Protocol::Icmp);
+        assert!(v4.icmp.unwrap().is_echo_request(v4.protocol));
+
+        let v6 = decode_ip(&ipv6(58, &icmp(128, 0))).unwrap();
+        assert_eq!(v6.protocol, Protocol::Icmpv6);
+        assert!(v6.icmp.unwrap().is_echo_request(v6.protocol));
+
+        // Not parsed as ICMP, and not rejected as a short ICMP header either
+        let v4_icmpv6 = decode_ip(&ipv4(58, &[], 0, &[128])).unwrap();
+        assert_eq!((v4_icmpv6.protocol, v4_icmpv6.icmp), (Protocol::Other(58), None));
+        assert_eq!(v4_icmpv6.payload, [128]);
+        let v6_icmp = decode_ip(&ipv6(1, &icmp(8, 0))).unwrap();
+        assert_eq!((v6_icmp.protocol, v6_icmp.icmp), (Protocol::Other(1), None));
+
+        let fragment = decode_ip(&ipv4(58, &[], 0x0001, b"x")).unwrap();
+        assert_eq!(fragment.protocol, Protocol::Other(58));
+    }
+
+    #[test]
+    fn unknown_transport_keeps_whole_payload() {
+        let packet = decode_ip(&ipv4(47, &[], 0, b"gre")).unwrap();
+        assert_eq!(packet.protocol, Protocol::Other(47));
+        assert_eq!(packet.payload, b"gre");
+    }
+
+    #[test]
+    fn rejects_unsupported_framing() {
+        assert_eq!(error(decode_ethernet(&ethernet(&[], 0x0806, &[0; 28]))), DecodeError::UnsupportedEtherType(0x0806));
+        assert_eq!(error(decode_ip(&[0x50; 20])), DecodeError::UnsupportedIpVersion(5));
+        // Ethertype and version disagree
+        let frame = ethernet(&[], ETHERTYPE_IPV4, &ipv6(17, &udp(1, 2, b"")));
+        assert_eq!(error(decode_ethernet(&frame)), DecodeError::UnsupportedIpVersion(6));
+        let frame = ethernet(&[], ETHERTYPE_IPV6, &ipv4(17, &[], 0, &[0; 28]));
+        assert_eq!(error(decode_ethernet(&frame)), DecodeError::UnsupportedIpVersion(4));
+    }
+
+    #[test]
+    fn truncated_link_and_network_layers() {
+        assert_eq!(error(decode_ethernet(&[0; 13])), truncated(Layer::Ethernet, 14, 13));
+        let frame = ethernet(&[], ETHERTYPE_VLAN, &[0, 1]);
+        assert_eq!(error(decode_ethernet(&frame)), truncated(Layer::Vlan, 4, 2));
+        assert_eq!(error(decode_ethernet(&ethernet(&[], ETHERTYPE_IPV4, &[0x45; 19]))), truncated(Layer::Ipv4, 20, 19));
+        assert_eq!(error(decode_ethernet(&ethernet(&[], ETHERTYPE_IPV6, &[0x60; 39]))), truncated(Layer::Ipv6, 40, 39));
+        assert_eq!(error(decode_ip(&[])), truncated(Layer::Ipv4, 1, 0));
+
+        let mut datagram = ipv6(17, &udp(1, 2, b""));
+        datagram[4..6].copy_from_slice(&9u16.to_be_bytes());
+        datagram.truncate(45);
+        assert_eq!(error(decode_ip(&datagram)), truncated(Layer::Ipv6, 49, 45));
+    }
+
+    #[test]
+    fn truncated_extension_headers() {
+        // Not even the next-header and length bytes
+        assert_eq!(error(decode_ip(&ipv6(0, &[17]))), truncated(Layer::Ipv6Extension, 2, 1));
+        assert_eq!(error(decode_ip(&ipv6(51, &[17]))), truncated(Layer::Ipv6Extension, 2, 1));
+        // Length says 16 bytes, 8 arrived
+        assert_eq!(error(decode_ip(&ipv6(60, &[17, 1, 0, 0, 0, 0, 0, 0]))), truncated(Layer::Ipv6Extension, 16, 8));
+        assert_eq!(error(decode_ip(&ipv6(44, &[17, 0, 0, 0]))), truncated(Layer::Ipv6Extension, 8, 4));
+        // AH length counts 4-byte units minus 2
+        assert_eq!(error(decode_ip(&ipv6(51, &[17, 4, 0, 0, 0, 0, 0, 0]))), truncated(Layer::Ipv6Extension, 24, 8));
+    }
+
+    #[test]
+    fn truncated_transport_headers() {
+        assert_eq!(error(decode_ip(&ipv4(6, &[], 0, &[0; 19]))), truncated(Layer::Tcp, 20, 19));
+        assert_eq!(error(decode_ip(&ipv4(17, &[], 0, &[0; 7]))), truncated(Layer::Udp, 8, 7));
+        assert_eq!(error(decode_ip(&ipv4(1, &[], 0, &[8, 0, 0]))), truncated(Layer::Icmp, 8, 3));
+        assert_eq!(error(decode_ip(&ipv6(58, &[128, 0]))), truncated(Layer::Icmp, 8, 2));
+
+        // Data offset below the minimum, and past the end of the segment
+        let mut segment = tcp(1, 2, 0x002, &[], b"");
+        segment[12] = 0x40;
+        assert_eq!(
+            error(decode_ip(&ipv4(6, &[], 0, &segment))),
+            DecodeError::InvalidHeaderLength { layer: Layer::Tcp, length: 16 }
+        );
+        segment[12] = 0x60;
+        assert_eq!(error(decode_ip(&ipv4(6, &[], 0, &segment))), truncated(Layer::Tcp, 24, 20));
+    }
+
+    #[test]
+    fn every_prefix_of_a_frame_fails_cleanly() {
+        let segment = tcp(1, 2, 0x002, &[2, 4, 5, 0xb4], b"data");
+        let frames = [
+            ethernet(&[(ETHERTYPE_QINQ, 1), (ETHERTYPE_VLAN, 2)], ETHERTYPE_IPV4, &ipv4(6, &[1, 1, 1, 0], 0, &segment)),
+            ethernet(&[], ETHERTYPE_IPV6, &ipv6(0, &extension(44, 8, &fragment_header(6, 0, true, 1, &segment)))),
+        ];
+        for frame in frames {
+            assert!(decode_ethernet(&frame).is_ok());
+            for len in 0..frame.len() {
+                assert!(decode_ethernet(&frame[..len]).is_err(), "{} bytes decoded", len);
+            }
+        }
+    }
+}
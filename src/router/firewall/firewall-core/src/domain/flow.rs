@@ -1,6 +1,12 @@
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::packet::{Packet, Protocol, TcpFlags};
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct FlowKey {
@@ -28,78 +34,991 @@ impl FlowKey {
         }
     }
 
+    pub fn from_packet(packet: &Packet) -> Self {
+        Self::new(
+            packet.source_ip,
+            packet.destination_ip,
+            packet.source_port,
+            packet.destination_port,
+            packet.protocol.to_number(),
+        )
+    }
+
+    // ICMP errors quote the IP header and first 8 bytes of the offending
+    // packet; that is enough to recover the flow it belongs to
+    pub fn from_icmp_quote(quote: &[u8]) -> Option<Self> {
+        let version = quote.first()? >> 4;
+        let (src_ip, dest_ip, protocol, l4) = match version {
+            4 => {
+                let header_len = ((quote[0] & 0x0F) as usize) * 4;
+                if header_len < 20 || quote.len() < header_len {
+                    return None;
+                }
+                let src = Ipv4Addr::new(quote[12], quote[13], quote[14], quote[15]);
+                let dst = Ipv4Addr::new(quote[16], quote[17], quote[18], quote[19]);
+                (IpAddr::V4(src), IpAddr::V4(dst), quote[9], &quote[header_len..])
+            }
+            6 => {
+                if quote.len() < 40 {
+                    return None;
+                }
+                let mut src = [0u8; 16];
+                let mut dst = [0u8; 16];
+                src.copy_from_slice(&quote[8..24]);
+                dst.copy_from_slice(&quote[24..40]);
+                (IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), quote[6], &quote[40..])
+            }
+            _ => return None,
+        };
+
+        let (src_port, dest_port) = match Protocol::from_number(protocol) {
+            Protocol::Tcp | Protocol::Udp if l4.len() >= 4 => (
+                Some(u16::from_be_bytes([l4[0], l4[1]])),
+                Some(u16::from_be_bytes([l4[2], l4[3]])),
+            ),
+            Protocol::Tcp | Protocol::Udp => return None,
+            _ => (None, None),
+        };
+
+        Some(Self::new(src_ip, dest_ip, src_port, dest_port, protocol))
+    }
+
     pub fn reverse (&self) -> Self {
         Self {
             src_ip: self.dest_ip,
             dest_ip: self.src_ip,
-            src_port: self.dest_port,  
+            src_port: self.dest_port,
             dest_port: self.src_port,
             protocol: self.protocol,
         }
     }
 }
 
+// Connection state as seen by filters, mirroring conntrack's ctstate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnState {
+    New,
+    Established,
+    Related,
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait,
+    LastAck,
+    TimeWait,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlowDirection {
+    Original,
+    Reply,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowTimeouts {
+    pub tcp_syn: Duration,
+    pub tcp_established: Duration,
+    pub tcp_closing: Duration,
+    pub tcp_time_wait: Duration,
+    pub tcp_close: Duration,
+    pub udp: Duration,
+    pub udp_stream: Duration,
+    pub icmp: Duration,
+    pub generic: Duration,
+}
+
+// Defaults follow the Linux nf_conntrack sysctls
+impl Default for FlowTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp_syn: Duration::from_secs(120),
+            tcp_established: Duration::from_secs(5 * 24 * 3600),
+            tcp_closing: Duration::from_secs(120),
+            tcp_time_wait: Duration::from_secs(120),
+            tcp_close: Duration::from_secs(10),
+            udp: Duration::from_secs(30),
+            udp_stream: Duration::from_secs(180),
+            icmp: Duration::from_secs(30),
+            generic: Duration::from_secs(600),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FlowStats {
     pub packets: u64,
     pub bytes: u64,
-    pub first_seen: std::time::Instant,
-    pub last_seen: std::time::Instant
+    pub reply_packets: u64,
+    pub reply_bytes: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub tcp_state: Option<TcpState>,
+    fin_original: bool,
+    fin_reply: bool,
 }
 
 //Statistics for a network flow
 impl FlowStats {
-    pub fn new() -> Self {
-        let now = std::time::Instant::now();
+    pub fn new_at(now: Instant) -> Self {
         FlowStats {
             packets: 0,
             bytes: 0,
+            reply_packets: 0,
+            reply_bytes: 0,
             first_seen: now,
             last_seen: now,
+            tcp_state: None,
+            fin_original: false,
+            fin_reply: false,
         }
     }
-    pub fn update(&mut self, bytes: usize) {
+    pub fn update_at(&mut self, bytes: usize, now: Instant) {
         self.packets += 1;
         self.bytes += bytes as u64;
-        self.last_seen = std::time::Instant::now();
+        self.last_seen = self.last_seen.max(now);
+    }
+    pub fn update_reply_at(&mut self, bytes: usize, now: Instant) {
+        self.reply_packets += 1;
+        self.reply_bytes += bytes as u64;
+        self.last_seen = self.last_seen.max(now);
+    }
+    pub fn seen_reply(&self) -> bool {
+        self.reply_packets > 0
+    }
+    // How the flow's next packet would be classified, short of one that
+    // reopens or closes it (see `observe`). A TCP connection that was reset
+    // or has finished closing takes no more traffic.
+    pub fn state(&self) -> ConnState {
+        match self.tcp_state {
+            Some(TcpState::TimeWait) | Some(TcpState::Close) => ConnState::Invalid,
+            _ if self.seen_reply() => ConnState::Established,
+            _ => ConnState::New,
+        }
+    }
+    fn is_closed(&self) -> bool {
+        matches!(self.tcp_state, Some(TcpState::TimeWait) | Some(TcpState::Close))
+    }
+
+    // Moves the TCP state on for a packet already counted against the flow
+    // and classifies it. The packet that resets or finishes closing a
+    // connection still belongs to it.
+    fn observe(&mut self, direction: FlowDirection, flags: Option<TcpFlags>) -> ConnState {
+        let Some(flags) = flags else {
+            return self.state();
+        };
+        let was_closed = self.is_closed();
+        self.advance_tcp(direction, flags);
+        if !was_closed && self.is_closed() {
+            ConnState::Established
+        } else {
+            self.state()
+        }
+    }
+
+    fn advance_tcp(&mut self, direction: FlowDirection, flags: TcpFlags) {
+        let state = match self.tcp_state {
+            Some(state) => state,
+            None => return,
+        };
+
+        if flags.contains(TcpFlags::RST) {
+            self.tcp_state = Some(TcpState::Close);
+            return;
+        }
+        if flags.contains(TcpFlags::FIN) {
+            match direction {
+                FlowDirection::Original => self.fin_original = true,
+                FlowDirection::Reply => self.fin_reply = true,
+            }
+        }
+
+        let next = match (state, direction) {
+            (TcpState::SynSent, FlowDirection::Reply)
+                if flags.contains(TcpFlags::SYN | TcpFlags::ACK) => TcpState::SynReceived,
+            (TcpState::SynReceived, FlowDirection::Original)
+                if flags.contains(TcpFlags::ACK) => TcpState::Established,
+            (TcpState::Established, _) | (TcpState::SynReceived, _)
+                if self.fin_original || self.fin_reply => TcpState::FinWait,
+            (TcpState::FinWait, _) if self.fin_original && self.fin_reply => TcpState::LastAck,
+            (TcpState::LastAck, _)
+                if flags.contains(TcpFlags::ACK) && !flags.contains(TcpFlags::FIN) => TcpState::TimeWait,
+            (state, _) => state,
+        };
+        self.tcp_state = Some(next);
+    }
+
+    fn timeout(&self, protocol: u8, timeouts: &FlowTimeouts) -> Duration {
+        match Protocol::from_number(protocol) {
+            Protocol::Tcp => match self.tcp_state {
+                Some(TcpState::SynSent) | Some(TcpState::SynReceived) => timeouts.tcp_syn,
+                // A picked-up connection nobody answers is held no longer
+                // than a handshake
+                Some(TcpState::Established) if !self.seen_reply() => timeouts.tcp_syn,
+                Some(TcpState::Established) => timeouts.tcp_established,
+                Some(TcpState::FinWait) | Some(TcpState::LastAck) => timeouts.tcp_closing,
+                Some(TcpState::TimeWait) => timeouts.tcp_time_wait,
+                Some(TcpState::Close) | None => timeouts.tcp_close,
+            },
+            Protocol::Udp if self.seen_reply() => timeouts.udp_stream,
+            Protocol::Udp => timeouts.udp,
+            Protocol::Icmp | Protocol::Icmpv6 => timeouts.icmp,
+            Protocol::Other(_) => timeouts.generic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // Evict the stalest flow that never saw a reply; fail if every flow is established
+    EarlyDrop,
+    // Evict the stalest sampled flow regardless of state
+    Lru,
+    // Never evict; new flows are refused while the table is full
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowTableConfig {
+    pub max_flows: usize,
+    pub shards: usize,
+    pub eviction: EvictionPolicy,
+    pub timeouts: FlowTimeouts,
+    // Pick up TCP connections already under way (say, after a restart) from
+    // their first ACK, as nf_conntrack_tcp_loose does. Without it only a
+    // SYN starts a flow.
+    pub tcp_loose: bool,
+}
+
+impl Default for FlowTableConfig {
+    fn default() -> Self {
+        Self {
+            max_flows: 65_536,
+            shards: 16,
+            eviction: EvictionPolicy::EarlyDrop,
+            timeouts: FlowTimeouts::default(),
+            tcp_loose: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FlowTableStats {
+    pub active_flows: usize,
+    pub max_flows: usize,
+    pub evictions: u64,
+    pub insert_failures: u64,
+    pub expired: u64,
+    pub memory_bytes: usize,
+}
+
+// How many entries an eviction looks at before picking a victim. Sampling
+// keeps eviction O(1) under a flood instead of scanning the whole shard, and
+// the stalest of 32 random flows is close to the stalest overall.
+const EVICTION_SAMPLE: usize = 32;
+
+// Rough per-entry cost: the slot in `entries` plus the index's key, position
+// and control byte
+const ENTRY_OVERHEAD: usize =
+    2 * std::mem::size_of::<FlowKey>() + std::mem::size_of::<FlowStats>() + std::mem::size_of::<usize>() + 1;
+
+type FlowShard = Mutex<FlowSlots>;
+
+// A shard's flows, kept densely in a Vec so eviction can sample uniformly at
+// random. A HashMap only iterates in bucket order, which would keep offering
+// the same few flows.
+struct FlowSlots {
+    index: HashMap<FlowKey, usize>,
+    entries: Vec<(FlowKey, FlowStats)>,
+    // Drives the eviction sample; advanced after every eviction attempt
+    seed: u64,
+}
+
+impl FlowSlots {
+    fn new(seed: u64) -> Self {
+        Self { index: HashMap::new(), entries: Vec::new(), seed }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.entries.capacity().max(self.index.capacity())
+    }
+
+    fn get(&self, key: &FlowKey) -> Option<&FlowStats> {
+        self.index.get(key).map(|&at| &self.entries[at].1)
+    }
+
+    fn get_mut(&mut self, key: &FlowKey) -> Option<&mut FlowStats> {
+        let at = *self.index.get(key)?;
+        Some(&mut self.entries[at].1)
+    }
+
+    fn position(&self, key: &FlowKey) -> Option<usize> {
+        self.index.get(key).copied()
     }
+
+    // The key must not be present yet
+    fn insert(&mut self, key: FlowKey, stats: FlowStats) {
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, stats));
+    }
+
+    fn remove_at(&mut self, at: usize) {
+        let (key, _) = self.entries.swap_remove(at);
+        self.index.remove(&key);
+        if let Some((moved, _)) = self.entries.get(at) {
+            self.index.insert(moved.clone(), at);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&FlowKey, &FlowStats) -> bool) {
+        self.entries.retain(|(key, stats)| keep(key, stats));
+        self.index.clear();
+        for (at, (key, _)) in self.entries.iter().enumerate() {
+            self.index.insert(key.clone(), at);
+        }
+    }
+
+    // Positions of the next eviction sample: every entry when the shard is
+    // small, otherwise EVICTION_SAMPLE random ones (with repeats). Depends
+    // only on `seed`, so `has_room` sees the sample `make_room` will use.
+    fn sample(&self) -> impl Iterator<Item = usize> + '_ {
+        let len = self.entries.len();
+        let random = len > EVICTION_SAMPLE;
+        (0..len.min(EVICTION_SAMPLE)).map(move |i| {
+            if random { (splitmix(self.seed.wrapping_add(i as u64)) % len as u64) as usize } else { i }
+        })
+    }
+
+    fn advance(&mut self) {
+        self.seed = splitmix(self.seed);
+    }
+}
+
+fn splitmix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 // manages network flow tracking
 pub struct FlowTracker {
-    flows: Arc<Mutex<HashMap<FlowKey, FlowStats>>>,
+    shards: Box<[FlowShard]>,
+    shard_capacity: usize,
+    hasher: RandomState,
+    config: FlowTableConfig,
+    evictions: AtomicU64,
+    insert_failures: AtomicU64,
+    expired: AtomicU64,
+    clock: Arc<dyn Clock>,
 }
 
 impl FlowTracker {
     pub fn new() -> Self {
+        Self::with_config(FlowTableConfig::default())
+    }
+
+    pub fn with_timeouts(timeouts: FlowTimeouts) -> Self {
+        Self::with_config(FlowTableConfig {
+            timeouts,
+            ..FlowTableConfig::default()
+        })
+    }
+
+    pub fn with_config(config: FlowTableConfig) -> Self {
+        let shard_count = config.shards.max(1).next_power_of_two();
+        let max_flows = config.max_flows.max(1);
+        let shard_capacity = max_flows.div_ceil(shard_count);
+
+        let hasher = RandomState::new();
+        let shards = (0..shard_count)
+            .map(|shard| Mutex::new(FlowSlots::new(hasher.hash_one(shard))))
+            .collect();
+
         Self {
-            flows: Arc::new(Mutex::new(HashMap::new())),
+            shards,
+            shard_capacity,
+            hasher,
+            config: FlowTableConfig { max_flows, shards: shard_count, ..config },
+            evictions: AtomicU64::new(0),
+            insert_failures: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Both directions of a flow have to land on the same shard, so hash the
+    // endpoints in a canonical order
+    fn shard_for(&self, key: &FlowKey) -> &FlowShard {
+        let a = (key.src_ip, key.src_port);
+        let b = (key.dest_ip, key.dest_port);
+        let (low, high) = if a <= b { (a, b) } else { (b, a) };
+
+        let mut hasher = self.hasher.build_hasher();
+        low.hash(&mut hasher);
+        high.hash(&mut hasher);
+        key.protocol.hash(&mut hasher);
+
+        let index = (hasher.finish() as usize) & (self.shards.len() - 1);
+        &self.shards[index]
+    }
+
+    // Records the packet against its flow (creating one if needed) and
+    // classifies it. Both directions of a conversation share one entry,
+    // keyed by whoever sent the first packet.
+    pub fn track(&self, packet: &Packet) -> ConnState {
+        let key = FlowKey::from_packet(packet);
+        let bytes = packet.payload.len();
+        let flags = packet.tcp_flags();
+
+        if flags.is_some_and(|flags| !valid_tcp_flags(flags)) {
+            return ConnState::Invalid;
+        }
+
+        // ICMP errors belong to the flow they quote and never start their own
+        if let Some(icmp) = packet.icmp && icmp.is_error(packet.protocol) {
+            return match FlowKey::from_icmp_quote(&packet.payload) {
+                Some(quoted) if self.get_flow(&quoted).is_some() => ConnState::Related,
+                _ => ConnState::Invalid,
+            };
+        }
+
+        let now = self.clock.now();
+        let mut flows = self.shard_for(&key).lock().unwrap();
+        self.reap(&mut flows, &key, flags, now);
+
+        if let Some(stats) = flows.get_mut(&key) {
+            stats.update_at(bytes, now);
+            return stats.observe(FlowDirection::Original, flags);
+        }
+        if let Some(stats) = flows.get_mut(&key.reverse()) {
+            stats.update_reply_at(bytes, now);
+            return stats.observe(FlowDirection::Reply, flags);
+        }
+
+        let Some(mut stats) = self.start_flow(flags, now) else {
+            return ConnState::Invalid;
+        };
+
+        // A flow we can't track can't be matched by state rules either
+        if !self.make_room(&mut flows, now) {
+            self.insert_failures.fetch_add(1, Ordering::Relaxed);
+            return ConnState::Invalid;
+        }
+
+        stats.update_at(bytes, now);
+        let state = stats.observe(FlowDirection::Original, flags);
+        flows.insert(key, stats);
+        state
+    }
+
+    // A fresh entry for a packet that has none, or None if the packet
+    // can't start a flow: a TCP segment other than a SYN, unless it's a
+    // plain ACK under `tcp_loose`
+    fn start_flow(&self, flags: Option<TcpFlags>, now: Instant) -> Option<FlowStats> {
+        let mut stats = FlowStats::new_at(now);
+        if let Some(flags) = flags {
+            let control = TcpFlags::SYN | TcpFlags::FIN | TcpFlags::RST;
+            stats.tcp_state = if flags.is_syn_only() {
+                Some(TcpState::SynSent)
+            } else if self.config.tcp_loose && flags.contains(TcpFlags::ACK) && !flags.intersects(control) {
+                Some(TcpState::Established)
+            } else {
+                return None;
+            };
+        }
+        Some(stats)
+    }
+
+    fn is_expired(&self, key: &FlowKey, stats: &FlowStats, now: Instant) -> bool {
+        now.saturating_duration_since(stats.last_seen) >= stats.timeout(key.protocol, &self.config.timeouts)
+    }
+
+    // The flow's entry unless it's past its timeout: an expired entry
+    // counts as gone whether or not `expire_flows` has run since
+    fn live<'a>(&self, flows: &'a FlowSlots, key: &FlowKey, now: Instant) -> Option<&'a FlowStats> {
+        flows.get(key).filter(|stats| !self.is_expired(key, stats, now))
+    }
+
+    // Whether the packet's flow, found as `stats`, is over and the packet
+    // starts the next one: a SYN on the ports of a closed connection
+    fn reopens(stats: &FlowStats, flags: Option<TcpFlags>) -> bool {
+        stats.is_closed() && flags.is_some_and(|flags| flags.is_syn_only())
+    }
+
+    // Drops the entry for either direction of `key` if it has expired or
+    // the packet reopens it, so the packet starts afresh
+    fn reap(&self, flows: &mut FlowSlots, key: &FlowKey, flags: Option<TcpFlags>, now: Instant) {
+        for key in [key.clone(), key.reverse()] {
+            let Some(at) = flows.position(&key) else {
+                continue;
+            };
+            let stats = &flows.entries[at].1;
+            if self.is_expired(&key, stats, now) {
+                flows.remove_at(at);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            } else if Self::reopens(stats, flags) {
+                flows.remove_at(at);
+            }
+        }
+    }
+
+    // What `track` would report for the packet, without recording it or
+    // making room for a new flow
+    pub fn peek(&self, packet: &Packet) -> ConnState {
+        let key = FlowKey::from_packet(packet);
+        let flags = packet.tcp_flags();
+
+        if flags.is_some_and(|flags| !valid_tcp_flags(flags)) {
+            return ConnState::Invalid;
+        }
+        if let Some(icmp) = packet.icmp && icmp.is_error(packet.protocol) {
+            return match FlowKey::from_icmp_quote(&packet.payload) {
+                Some(quoted) if self.get_flow(&quoted).is_some() => ConnState::Related,
+                _ => ConnState::Invalid,
+            };
+        }
+
+        // Works on copies, so the table is left as it was
+        let now = self.clock.now();
+        let bytes = packet.payload.len();
+        let flows = self.shard_for(&key).lock().unwrap();
+        let live = |key: &FlowKey| self.live(&flows, key, now).filter(|stats| !Self::reopens(stats, flags));
+        if let Some(stats) = live(&key) {
+            let mut stats = stats.clone();
+            stats.update_at(bytes, now);
+            return stats.observe(FlowDirection::Original, flags);
+        }
+        if let Some(stats) = live(&key.reverse()) {
+            let mut stats = stats.clone();
+            stats.update_reply_at(bytes, now);
+            return stats.observe(FlowDirection::Reply, flags);
+        }
+        let Some(mut stats) = self.start_flow(flags, now) else {
+            return ConnState::Invalid;
+        };
+        if !self.has_room(&flows, now) {
+            return ConnState::Invalid;
+        }
+        stats.observe(FlowDirection::Original, flags)
+    }
+
+    // Whether `make_room` would find a slot
+    fn has_room(&self, flows: &FlowSlots, now: Instant) -> bool {
+        flows.len() < self.shard_capacity || self.victim(flows, now).is_some()
+    }
+
+    fn make_room(&self, flows: &mut FlowSlots, now: Instant) -> bool {
+        if flows.len() < self.shard_capacity {
+            return true;
+        }
+        let victim = self.victim(flows, now);
+        flows.advance();
+        let Some(at) = victim else {
+            return false;
+        };
+        let (key, stats) = &flows.entries[at];
+        let counter = if self.is_expired(key, stats, now) { &self.expired } else { &self.evictions };
+        counter.fetch_add(1, Ordering::Relaxed);
+        flows.remove_at(at);
+        true
+    }
+
+    // A flow in the shard's current sample to make room with: one past its
+    // timeout under any policy, otherwise the stalest the policy lets us
+    // evict
+    fn victim(&self, flows: &FlowSlots, now: Instant) -> Option<usize> {
+        let expired = flows.sample().find(|&at| {
+            let (key, stats) = &flows.entries[at];
+            self.is_expired(key, stats, now)
+        });
+        if expired.is_some() {
+            return expired;
+        }
+        let early_drop = match self.config.eviction {
+            EvictionPolicy::Reject => return None,
+            EvictionPolicy::EarlyDrop => true,
+            EvictionPolicy::Lru => false,
+        };
+        flows.sample()
+            .filter(|&at| !early_drop || !flows.entries[at].1.seen_reply())
+            .min_by_key(|&at| flows.entries[at].1.last_seen)
+    }
+
     pub fn record_packet(&self, flow_key: FlowKey, bytes: usize) {
-        let flows = self.flows.lock().unwrap();
-        flows.entry(flow_key)
-            .or_insert(FlowStats::new)
-            .update(bytes);
+        let now = self.clock.now();
+        let mut flows = self.shard_for(&flow_key).lock().unwrap();
+        self.reap(&mut flows, &flow_key, None, now);
+        if let Some(stats) = flows.get_mut(&flow_key) {
+            stats.update_at(bytes, now);
+            return;
+        }
+        if !self.make_room(&mut flows, now) {
+            self.insert_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut stats = FlowStats::new_at(now);
+        stats.update_at(bytes, now);
+        flows.insert(flow_key, stats);
     }
 
+    // Looks the flow up in either direction; expired flows aren't found
     pub fn get_flow(&self, flow_key: &FlowKey) -> Option<FlowStats> {
-        let flows = self.flows.lock().unwrap();
-        flows.get(flow_key).cloned()
+        let now = self.clock.now();
+        let flows = self.shard_for(flow_key).lock().unwrap();
+        self.live(&flows, flow_key, now)
+            .or_else(|| self.live(&flows, &flow_key.reverse(), now))
+            .cloned()
+    }
+
+    pub fn flow_state(&self, flow_key: &FlowKey) -> Option<ConnState> {
+        self.get_flow(flow_key).map(|stats| stats.state())
     }
 
     pub fn cleanup_old_flows(&self, max_age_secs: u64) {
-        let mut flows = self.flows.lock().unwrap();
-        let now = std::time::Instant::now();
+        let now = self.clock.now();
 
-        flows.retain(|_, stats| {
-            now.duration_since(stats.last_seen).as_secs() < max_age_secs
-        });
+        for shard in self.shards.iter() {
+            let mut flows = shard.lock().unwrap();
+            flows.retain(|_, stats| {
+                now.saturating_duration_since(stats.last_seen).as_secs() < max_age_secs
+            });
+        }
+    }
+
+    // Drops flows that have been idle longer than the timeout for their state
+    pub fn expire_flows(&self) -> usize {
+        let now = self.clock.now();
+        let mut removed = 0;
+
+        for shard in self.shards.iter() {
+            let mut flows = shard.lock().unwrap();
+            let before = flows.len();
+            flows.retain(|key, stats| !self.is_expired(key, stats, now));
+            removed += before - flows.len();
+        }
+
+        self.expired.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
     }
 
     pub fn active_flow_count(&self) -> usize {
-        let flows = self.flows.lock().unwrap();
-        flows.len()
+        self.shards.iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
     }
-}
\ No newline at end of file
+
+    pub fn config(&self) -> &FlowTableConfig {
+        &self.config
+    }
+
+    // Approximate heap footprint; counts allocated buckets, not just live entries
+    pub fn memory_usage(&self) -> usize {
+        self.shards.iter()
+            .map(|shard| shard.lock().unwrap().capacity() * ENTRY_OVERHEAD)
+            .sum()
+    }
+
+    pub fn table_stats(&self) -> FlowTableStats {
+        FlowTableStats {
+            active_flows: self.active_flow_count(),
+            max_flows: self.config.max_flows,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            insert_failures: self.insert_failures.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            memory_bytes: self.memory_usage(),
+        }
+    }
+}
+
+impl Default for FlowTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Combinations no legitimate stack sends; conntrack marks these INVALID
+fn valid_tcp_flags(flags: TcpFlags) -> bool {
+    let syn_fin = TcpFlags::SYN | TcpFlags::FIN;
+    let syn_rst = TcpFlags::SYN | TcpFlags::RST;
+    flags.bits() != 0 && !flags.contains(syn_fin) && !flags.contains(syn_rst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::clock::ManualClock;
+    use crate::domain::packet::TcpInfo;
+
+    fn udp(src: u8, src_port: u16, dst: u8) -> Packet {
+        let mut packet = Packet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, src)));
+        packet.destination_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 1, dst));
+        packet.source_port = Some(src_port);
+        packet.destination_port = Some(53);
+        packet.protocol = Protocol::Udp;
+        packet
+    }
+
+    fn reply(packet: &Packet) -> Packet {
+        let mut reply = Packet::new(packet.destination_ip);
+        reply.destination_ip = packet.source_ip;
+        reply.source_port = packet.destination_port;
+        reply.destination_port = packet.source_port;
+        reply.protocol = packet.protocol;
+        reply
+    }
+
+    // A segment from client port 40000 to the server's port 80, or back
+    fn tcp(flags: TcpFlags, from_client: bool) -> Packet {
+        let (client, server) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)));
+        let (source, destination, ports) = match from_client {
+            true => (client, server, (40000, 80)),
+            false => (server, client, (80, 40000)),
+        };
+        let mut packet = Packet::new(source);
+        packet.destination_ip = destination;
+        packet.source_port = Some(ports.0);
+        packet.destination_port = Some(ports.1);
+        packet.protocol = Protocol::Tcp;
+        packet.tcp = Some(TcpInfo { flags, sequence: 0, acknowledgment: 0, window: 0 });
+        packet
+    }
+
+    fn tcp_state(tracker: &FlowTracker) -> Option<TcpState> {
+        tracker.get_flow(&FlowKey::from_packet(&tcp(TcpFlags::ACK, true))).and_then(|stats| stats.tcp_state)
+    }
+
+    // Client SYN, server SYN/ACK, client ACK
+    fn handshake(tracker: &FlowTracker) {
+        assert_eq!(tracker.track(&tcp(TcpFlags::SYN, true)), ConnState::New);
+        assert_eq!(tcp_state(tracker), Some(TcpState::SynSent));
+        assert_eq!(tracker.track(&tcp(TcpFlags::SYN | TcpFlags::ACK, false)), ConnState::Established);
+        assert_eq!(tcp_state(tracker), Some(TcpState::SynReceived));
+        assert_eq!(tracker.track(&tcp(TcpFlags::ACK, true)), ConnState::Established);
+        assert_eq!(tcp_state(tracker), Some(TcpState::Established));
+    }
+
+    fn tracker(max_flows: usize, eviction: EvictionPolicy, clock: &Arc<ManualClock>) -> FlowTracker {
+        let config = FlowTableConfig { max_flows, shards: 1, eviction, ..FlowTableConfig::default() };
+        FlowTracker::with_config(config).with_clock(clock.clone())
+    }
+
+    fn tracked(tracker: &FlowTracker, packet: &Packet) -> bool {
+        tracker.get_flow(&FlowKey::from_packet(packet)).is_some()
+    }
+
+    #[test]
+    fn lru_evicts_the_oldest_flows_anywhere_in_the_shard() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(64, EvictionPolicy::Lru, &clock);
+        let old: Vec<Packet> = (0..64).map(|i| udp(1, 1000 + i, 1)).collect();
+        for packet in &old {
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(flows.track(packet), ConnState::New);
+        }
+        let new: Vec<Packet> = (0..64).map(|i| udp(2, 1000 + i, 1)).collect();
+        for packet in &new {
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(flows.track(packet), ConnState::New);
+        }
+
+        let stats = flows.table_stats();
+        assert_eq!(stats.active_flows, 64);
+        assert_eq!(stats.evictions, 64);
+        // Each eviction takes the stalest of 32 random flows, so the old
+        // flows go first and the newest are never picked
+        assert!(old.iter().filter(|packet| tracked(&flows, packet)).count() < 16);
+        assert!(new[56..].iter().all(|packet| tracked(&flows, packet)));
+    }
+
+    #[test]
+    fn early_drop_only_evicts_unanswered_flows() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(4, EvictionPolicy::EarlyDrop, &clock);
+        let answered: Vec<Packet> = (0..3).map(|i| udp(1, 1000 + i, 1)).collect();
+        for packet in &answered {
+            flows.track(packet);
+            assert_eq!(flows.track(&reply(packet)), ConnState::Established);
+        }
+        let unanswered = udp(1, 2000, 1);
+        clock.advance(Duration::from_secs(1));
+        flows.track(&unanswered);
+
+        // The newest flow is the only one without a reply, so it goes
+        assert_eq!(flows.peek(&udp(3, 1, 1)), ConnState::New);
+        assert_eq!(flows.track(&udp(3, 1, 1)), ConnState::New);
+        assert!(!tracked(&flows, &unanswered));
+        assert!(answered.iter().all(|packet| tracked(&flows, packet)));
+
+        // Everything left has been answered, or is the flow just added
+        flows.track(&reply(&udp(3, 1, 1)));
+        assert_eq!(flows.peek(&udp(4, 1, 1)), ConnState::Invalid);
+        assert_eq!(flows.track(&udp(4, 1, 1)), ConnState::Invalid);
+        assert_eq!(flows.table_stats().insert_failures, 1);
+    }
+
+    #[test]
+    fn reject_never_evicts() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(2, EvictionPolicy::Reject, &clock);
+        flows.track(&udp(1, 1, 1));
+        flows.track(&udp(1, 2, 1));
+        assert_eq!(flows.track(&udp(1, 3, 1)), ConnState::Invalid);
+        assert_eq!(flows.table_stats().evictions, 0);
+        assert_eq!(flows.active_flow_count(), 2);
+    }
+
+    #[test]
+    fn expiry_keeps_the_index_consistent() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(16, EvictionPolicy::Lru, &clock);
+        for i in 0..8 {
+            flows.track(&udp(1, i, 1));
+        }
+        clock.advance(Duration::from_secs(20));
+        let fresh = udp(1, 100, 1);
+        flows.track(&fresh);
+        flows.track(&reply(&fresh));
+        clock.advance(Duration::from_secs(15));
+
+        // Unanswered UDP times out after 30s, answered after 180s
+        assert_eq!(flows.expire_flows(), 8);
+        assert_eq!(flows.active_flow_count(), 1);
+        assert_eq!(flows.track(&reply(&fresh)), ConnState::Established);
+    }
+
+    #[test]
+    fn tcp_handshake_and_close() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+        handshake(&flows);
+        assert_eq!(flows.track(&tcp(TcpFlags::PSH | TcpFlags::ACK, false)), ConnState::Established);
+
+        assert_eq!(flows.track(&tcp(TcpFlags::FIN | TcpFlags::ACK, true)), ConnState::Established);
+        assert_eq!(tcp_state(&flows), Some(TcpState::FinWait));
+        assert_eq!(flows.track(&tcp(TcpFlags::FIN | TcpFlags::ACK, false)), ConnState::Established);
+        assert_eq!(tcp_state(&flows), Some(TcpState::LastAck));
+        // The last ACK still belongs to the connection; anything after it doesn't
+        assert_eq!(flows.peek(&tcp(TcpFlags::ACK, true)), ConnState::Established);
+        assert_eq!(flows.track(&tcp(TcpFlags::ACK, true)), ConnState::Established);
+        assert_eq!(tcp_state(&flows), Some(TcpState::TimeWait));
+        assert_eq!(flows.peek(&tcp(TcpFlags::ACK, false)), ConnState::Invalid);
+        assert_eq!(flows.track(&tcp(TcpFlags::ACK, false)), ConnState::Invalid);
+
+        // A new SYN on the same ports reopens it
+        assert_eq!(flows.track(&tcp(TcpFlags::SYN, true)), ConnState::New);
+        assert_eq!(tcp_state(&flows), Some(TcpState::SynSent));
+        assert_eq!(flows.active_flow_count(), 1);
+    }
+
+    #[test]
+    fn rst_closes_the_connection() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+        handshake(&flows);
+        assert_eq!(flows.track(&tcp(TcpFlags::RST, false)), ConnState::Established);
+        assert_eq!(tcp_state(&flows), Some(TcpState::Close));
+        assert_eq!(flows.track(&tcp(TcpFlags::PSH | TcpFlags::ACK, true)), ConnState::Invalid);
+
+        // A refused connection: the RST answers the SYN
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+        flows.track(&tcp(TcpFlags::SYN, true));
+        assert_eq!(flows.track(&tcp(TcpFlags::RST | TcpFlags::ACK, false)), ConnState::Established);
+        assert_eq!(flows.track(&tcp(TcpFlags::ACK, true)), ConnState::Invalid);
+    }
+
+    #[test]
+    fn picks_up_connections_in_progress() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+        // As after a restart: the first packet seen is mid-stream
+        assert_eq!(flows.peek(&tcp(TcpFlags::PSH | TcpFlags::ACK, false)), ConnState::New);
+        assert_eq!(flows.track(&tcp(TcpFlags::PSH | TcpFlags::ACK, false)), ConnState::New);
+        assert_eq!(flows.track(&tcp(TcpFlags::ACK, true)), ConnState::Established);
+        assert_eq!(tcp_state(&flows), Some(TcpState::Established));
+
+        // Nothing to pick up from a reset, or from anything without an ACK
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+        assert_eq!(flows.track(&tcp(TcpFlags::RST | TcpFlags::ACK, true)), ConnState::Invalid);
+        assert_eq!(flows.track(&tcp(TcpFlags::FIN, true)), ConnState::Invalid);
+        assert_eq!(flows.active_flow_count(), 0);
+
+        let config = FlowTableConfig { tcp_loose: false, ..FlowTableConfig::default() };
+        let strict = FlowTracker::with_config(config).with_clock(clock.clone());
+        assert_eq!(strict.track(&tcp(TcpFlags::ACK, true)), ConnState::Invalid);
+        assert_eq!(strict.track(&tcp(TcpFlags::SYN, true)), ConnState::New);
+    }
+
+    #[test]
+    fn timeouts_follow_the_tcp_state() {
+        let clock = Arc::new(ManualClock::new());
+        let flows = tracker(16, EvictionPolicy::EarlyDrop, &clock);
+
+        // An unanswered SYN is held for tcp_syn (120s)
+        flows.track(&tcp(TcpFlags::SYN, true));
+        clock.advance(Duration::from_secs(119));
+        assert_eq!(tcp_state(&flows), Some(TcpState::SynSent));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tcp_state(&flows), None);
+        // Gone on lookup, so the late SYN/ACK has nothing to answer
+        assert_eq!(flows.peek(&tcp(TcpFlags::SYN | TcpFlags::ACK, false)), ConnState::Invalid);
+
+        // Established connections idle for days
+        handshake(&flows);
+        clock.advance(Duration::from_secs(4 * 24 * 3600));
+        assert_eq!(flows.track(&tcp(TcpFlags::ACK, true)), ConnState::Established);
+
+        // Reset ones go after tcp_close (10s)
+        flows.track(&tcp(TcpFlags::RST, true));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(tcp_state(&flows), None);
+        assert_eq!(flows.track(&tcp(TcpFlags::SYN, true)), ConnState::New);
+
+        // TIME_WAIT lasts tcp_time_wait (120s)
+        flows.track(&tcp(TcpFlags::SYN | TcpFlags::ACK, false));
+        flows.track(&tcp(TcpFlags::ACK, true));
+        flows.track(&tcp(TcpFlags::FIN | TcpFlags::ACK, true));
+        flows.track(&tcp(TcpFlags::FIN | TcpFlags::ACK, false));
+        flows.track(&tcp(TcpFlags::ACK, true));
+        clock.advance(Duration::from_secs(119));
+        assert_eq!(tcp_state(&flows), Some(TcpState::TimeWait));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(flows.expire_flows(), 1);
+        // Two reaped on lookup, one by the sweep
+        assert_eq!(flows.table_stats().expired, 3);
+    }
+
+    #[test]
+    fn expired_flows_make_room_under_any_policy() {
+        let clock = Arc::new(ManualClock::new());
+        for eviction in [EvictionPolicy::EarlyDrop, EvictionPolicy::Reject] {
+            let flows = tracker(2, eviction, &clock);
+            for port in [1, 2] {
+                flows.track(&udp(1, port, 1));
+                flows.track(&reply(&udp(1, port, 1)));
+            }
+            assert_eq!(flows.track(&udp(1, 3, 1)), ConnState::Invalid);
+
+            // Answered UDP flows time out after 180s; nobody has swept yet
+            clock.advance(Duration::from_secs(180));
+            assert_eq!(flows.peek(&udp(1, 3, 1)), ConnState::New);
+            assert_eq!(flows.track(&udp(1, 3, 1)), ConnState::New);
+            let stats = flows.table_stats();
+            assert_eq!((stats.evictions, stats.expired, stats.insert_failures), (0, 1, 1));
+        }
+    }
+}
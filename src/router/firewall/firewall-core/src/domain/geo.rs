@@ -0,0 +1,19 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl GeoInfo {
+    pub fn is_unknown(&self) -> bool {
+        self.country.is_none() && self.asn.is_none()
+    }
+}
+
+// Source of country/ASN data for an address; the MMDB reader in
+// `infrastructure::geoip` is the production implementation
+pub trait GeoLookup: Send + Sync {
+    fn lookup(&self, ip: IpAddr) -> GeoInfo;
+}
@@ -1,8 +1,14 @@
 pub mod packet;
+pub mod clock;
+pub mod decoder;
 pub mod flow;
+pub mod prefix_trie;
+pub mod geo;
 pub mod rule;
+pub mod chain;
+pub mod ruleset;
 pub mod stats;
+pub mod trace;
+pub mod alert;
 
-pub mod rate_limiter;
-pub mod token_bucket;
-pub mod rate_limit_config;
\ No newline at end of file
+pub mod rate_limiter;
\ No newline at end of file
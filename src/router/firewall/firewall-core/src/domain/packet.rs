@@ -1,26 +1,52 @@
+use crate::domain::decoder::{self, DecodeError};
+use crate::domain::flow::ConnState;
 use std::net::IpAddr;
 
 pub struct Packet {
     pub source_ip: IpAddr,
     pub destination_ip: IpAddr,
-    pub source_port: u16,
-    pub destination_port: u16,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
     pub protocol: Protocol,
+    pub ttl: u8,
+    pub dscp: u8,
+    pub ecn: u8,
+    pub ip_id: Option<u32>,
+    pub fragment_offset: u16,
+    pub more_fragments: bool,
+    pub tcp: Option<TcpInfo>,
+    pub icmp: Option<IcmpInfo>,
+    pub vlan_ids: Vec<u16>,
+    pub extension_headers: Vec<u8>,
+    pub payload_offset: usize,
     pub payload: Vec<u8>,
 }
+#[derive(Debug, Clone, Copy)]
 pub struct PacketHeader {
     pub source_ip: IpAddr,
     pub destination_ip: IpAddr,
-    pub source_port: u16,
-    pub destination_port: u16,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
     pub protocol: Protocol,
+    pub ttl: u8,
+    pub dscp: u8,
+    pub ecn: u8,
+    pub ip_id: Option<u32>,
+    pub fragment_offset: u16,
+    pub more_fragments: bool,
+    pub tcp: Option<TcpInfo>,
+    pub icmp: Option<IcmpInfo>,
+    pub vlan_id: Option<u16>,
+    // Filled in by the engine once the flow tracker has seen the packet
+    pub conn_state: Option<ConnState>,
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     Tcp,
     Udp,
     Icmp,
-    Unknown,
+    Icmpv6,
+    Other(u8),
 }
 impl Protocol {
     pub fn to_number(&self) -> u8 {
@@ -28,21 +54,132 @@ impl Protocol {
             Protocol::Tcp => 6,
             Protocol::Udp => 17,
             Protocol::Icmp => 1,
-            Protocol::Unknown => 0,
+            Protocol::Icmpv6 => 58,
+            Protocol::Other(number) => *number,
+        }
+    }
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            1 => Protocol::Icmp,
+            58 => Protocol::Icmpv6,
+            other => Protocol::Other(other),
+        }
+    }
+}
+
+// TCP control bits as they appear in the header (NS is the low bit of byte 12)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TcpFlags(u16);
+
+impl TcpFlags {
+    pub const FIN: TcpFlags = TcpFlags(0x001);
+    pub const SYN: TcpFlags = TcpFlags(0x002);
+    pub const RST: TcpFlags = TcpFlags(0x004);
+    pub const PSH: TcpFlags = TcpFlags(0x008);
+    pub const ACK: TcpFlags = TcpFlags(0x010);
+    pub const URG: TcpFlags = TcpFlags(0x020);
+    pub const ECE: TcpFlags = TcpFlags(0x040);
+    pub const CWR: TcpFlags = TcpFlags(0x080);
+    pub const NS: TcpFlags = TcpFlags(0x100);
+
+    pub fn from_bits(bits: u16) -> Self {
+        TcpFlags(bits & 0x1FF)
+    }
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+    pub fn contains(&self, other: TcpFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn intersects(&self, other: TcpFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+    // Initial SYN of a handshake, as opposed to the SYN/ACK reply
+    pub fn is_syn_only(&self) -> bool {
+        self.contains(TcpFlags::SYN) && !self.contains(TcpFlags::ACK)
+    }
+}
+
+impl std::ops::BitOr for TcpFlags {
+    type Output = TcpFlags;
+
+    fn bitor(self, rhs: TcpFlags) -> TcpFlags {
+        TcpFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TcpInfo {
+    pub flags: TcpFlags,
+    pub sequence: u32,
+    pub acknowledgment: u32,
+    pub window: u16,
+}
+
+// Type and code of an ICMP or ICMPv6 message; `Packet::protocol` tells which
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IcmpInfo {
+    pub icmp_type: u8,
+    pub code: u8,
+}
+
+impl IcmpInfo {
+    pub fn is_echo_request(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Icmp => self.icmp_type == 8,
+            Protocol::Icmpv6 => self.icmp_type == 128,
+            _ => false,
+        }
+    }
+    pub fn is_echo_reply(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Icmp => self.icmp_type == 0,
+            Protocol::Icmpv6 => self.icmp_type == 129,
+            _ => false,
+        }
+    }
+    // Errors quote the packet that triggered them (unreachable, time exceeded, ...)
+    pub fn is_error(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Icmp => matches!(self.icmp_type, 3 | 4 | 5 | 11 | 12),
+            Protocol::Icmpv6 => matches!(self.icmp_type, 1..=4),
+            _ => false,
         }
     }
 }
+
 impl Packet {
     pub fn new(source_ip: IpAddr) -> Self {
         Packet {
             source_ip,
             destination_ip: IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
-            source_port: 0,
-            destination_port: 0,
-            protocol: Protocol::Unknown,
+            source_port: None,
+            destination_port: None,
+            protocol: Protocol::Other(0),
+            ttl: 0,
+            dscp: 0,
+            ecn: 0,
+            ip_id: None,
+            fragment_offset: 0,
+            more_fragments: false,
+            tcp: None,
+            icmp: None,
+            vlan_ids: Vec::new(),
+            extension_headers: Vec::new(),
+            payload_offset: 0,
             payload: Vec::new(),
         }
     }
+    // Decodes an Ethernet II frame (optionally 802.1Q / 802.1ad tagged)
+    pub fn from_ethernet(frame: &[u8]) -> Result<Self, DecodeError> {
+        decoder::decode_ethernet(frame)
+    }
+    // Decodes a bare IPv4 or IPv6 datagram, e.g. from NFQUEUE or a TUN device
+    pub fn from_ip(datagram: &[u8]) -> Result<Self, DecodeError> {
+        decoder::decode_ip(datagram)
+    }
     pub fn header(&self) -> PacketHeader {
         PacketHeader {
             source_ip: self.source_ip,
@@ -50,8 +187,109 @@ impl Packet {
             source_port: self.source_port,
             destination_port: self.destination_port,
             protocol: self.protocol,
+            ttl: self.ttl,
+            dscp: self.dscp,
+            ecn: self.ecn,
+            ip_id: self.ip_id,
+            fragment_offset: self.fragment_offset,
+            more_fragments: self.more_fragments,
+            tcp: self.tcp,
+            icmp: self.icmp,
+            vlan_id: self.vlan_ids.last().copied(),
+            conn_state: None,
         }
     }
+    pub fn is_fragment(&self) -> bool {
+        self.fragment_offset != 0 || self.more_fragments
+    }
+    pub fn tcp_flags(&self) -> Option<TcpFlags> {
+        self.tcp.map(|tcp| tcp.flags)
+    }
 }
 
+impl PacketHeader {
+    pub fn is_fragment(&self) -> bool {
+        self.fragment_offset != 0 || self.more_fragments
+    }
+    pub fn tcp_flags(&self) -> Option<TcpFlags> {
+        self.tcp.map(|tcp| tcp.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn protocol_numbers_round_trip() {
+        for number in 0..=u8::MAX {
+            assert_eq!(Protocol::from_number(number).to_number(), number);
+        }
+        assert_eq!(Protocol::from_number(6), Protocol::Tcp);
+        assert_eq!(Protocol::from_number(58), Protocol::Icmpv6);
+        assert_eq!(Protocol::from_number(47), Protocol::Other(47));
+        // Known protocols never come back as `Other`
+        for known in [Protocol::Tcp, Protocol::Udp, Protocol::Icmp, Protocol::Icmpv6] {
+            assert_eq!(Protocol::from_number(known.to_number()), known);
+        }
+    }
+
+    #[test]
+    fn tcp_flags_keep_the_nine_control_bits() {
+        assert_eq!(TcpFlags::from_bits(0xFFFF).bits(), 0x1FF);
+        assert_eq!(TcpFlags::from_bits(0x012), TcpFlags::SYN | TcpFlags::ACK);
+        assert!(TcpFlags::from_bits(0x100).contains(TcpFlags::NS));
+        assert_eq!(TcpFlags::default().bits(), 0);
+
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        assert!(syn_ack.contains(TcpFlags::SYN));
+        assert!(!syn_ack.contains(TcpFlags::SYN | TcpFlags::FIN));
+        assert!(syn_ack.intersects(TcpFlags::SYN | TcpFlags::FIN));
+        assert!(!syn_ack.intersects(TcpFlags::RST | TcpFlags::FIN));
+
+        assert!(TcpFlags::SYN.is_syn_only());
+        assert!((TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR).is_syn_only());
+        assert!(!syn_ack.is_syn_only());
+        assert!(!TcpFlags::ACK.is_syn_only());
+    }
+
+    #[test]
+    fn icmp_types_depend_on_the_family() {
+        let echo = IcmpInfo { icmp_type: 8, code: 0 };
+        assert!(echo.is_echo_request(Protocol::Icmp));
+        assert!(!echo.is_echo_request(Protocol::Icmpv6));
+
+        let echo_v6 = IcmpInfo { icmp_type: 128, code: 0 };
+        assert!(echo_v6.is_echo_request(Protocol::Icmpv6));
+        assert!(IcmpInfo { icmp_type: 0, code: 0 }.is_echo_reply(Protocol::Icmp));
+        assert!(IcmpInfo { icmp_type: 129, code: 0 }.is_echo_reply(Protocol::Icmpv6));
+
+        let unreachable = IcmpInfo { icmp_type: 3, code: 3 };
+        assert!(unreachable.is_error(Protocol::Icmp));
+        // Type 3 is "time exceeded" under ICMPv6, also an error
+        assert!(unreachable.is_error(Protocol::Icmpv6));
+        assert!(!IcmpInfo { icmp_type: 11, code: 0 }.is_error(Protocol::Icmpv6));
+        assert!(!echo.is_error(Protocol::Icmp));
+
+        // Nothing is ICMP under another protocol
+        assert!(!echo.is_echo_request(Protocol::Udp));
+        assert!(!unreachable.is_error(Protocol::Other(1)));
+    }
+
+    #[test]
+    fn header_carries_the_transport_details() {
+        let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+        packet.protocol = Protocol::Other(132);
+        packet.vlan_ids = vec![100, 200];
+        packet.tcp = Some(TcpInfo { flags: TcpFlags::RST, sequence: 1, acknowledgment: 2, window: 3 });
+        packet.more_fragments = true;
+
+        let header = packet.header();
+        assert_eq!(header.protocol, Protocol::Other(132));
+        // The inner tag is the one the packet belongs to
+        assert_eq!(header.vlan_id, Some(200));
+        assert_eq!(header.tcp_flags(), Some(TcpFlags::RST));
+        assert!(header.is_fragment());
+        assert!(header.conn_state.is_none());
+    }
+}
@@ -0,0 +1,510 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefixParseError {
+    InvalidAddress(String),
+    InvalidLength(String),
+}
+
+impl fmt::Display for PrefixParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrefixParseError::InvalidAddress(addr) => write!(f, "invalid IP address '{}'", addr),
+            PrefixParseError::InvalidLength(len) => write!(f, "invalid prefix length '{}'", len),
+        }
+    }
+}
+
+impl std::error::Error for PrefixParseError {}
+
+impl IpPrefix {
+    // Host bits past `len` are cleared, so 10.1.2.3/8 becomes 10.0.0.0/8
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self, PrefixParseError> {
+        let max_len = max_len(&addr);
+        if len > max_len {
+            return Err(PrefixParseError::InvalidLength(len.to_string()));
+        }
+        let bits = to_bits(&addr) & mask(len);
+        Ok(Self { addr: from_bits(bits, addr.is_ipv4()), len })
+    }
+
+    pub fn host(addr: IpAddr) -> Self {
+        Self { addr, len: max_len(&addr) }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if ip.is_ipv4() != self.addr.is_ipv4() {
+            return false;
+        }
+        let m = mask(self.len);
+        to_bits(ip) & m == to_bits(&self.addr) & m
+    }
+
+    // True if every address in `other` is also in `self`
+    pub fn covers(&self, other: &IpPrefix) -> bool {
+        self.len <= other.len && self.contains(&other.addr)
+    }
+
+    pub fn overlaps(&self, other: &IpPrefix) -> bool {
+        self.covers(other) || other.covers(self)
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = PrefixParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse()
+            .map_err(|_| PrefixParseError::InvalidAddress(addr.to_string()))?;
+
+        match len {
+            Some(len) => {
+                let len: u8 = len.trim().parse()
+                    .map_err(|_| PrefixParseError::InvalidLength(len.to_string()))?;
+                IpPrefix::new(addr, len)
+            }
+            None => Ok(IpPrefix::host(addr)),
+        }
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl From<IpAddr> for IpPrefix {
+    fn from(addr: IpAddr) -> Self {
+        IpPrefix::host(addr)
+    }
+}
+
+fn max_len(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+// IPv4 addresses sit in the top 32 bits so both families share one layout
+fn to_bits(addr: &IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(*v4) as u128) << 96,
+        IpAddr::V6(v6) => u128::from(*v6),
+    }
+}
+
+fn from_bits(bits: u128, ipv4: bool) -> IpAddr {
+    if ipv4 {
+        IpAddr::V4(Ipv4Addr::from((bits >> 96) as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    }
+}
+
+fn mask(len: u8) -> u128 {
+    if len == 0 { 0 } else { !0u128 << (128 - len as u32) }
+}
+
+fn bit_at(bits: u128, index: u8) -> usize {
+    ((bits >> (127 - index as u32)) & 1) as usize
+}
+
+struct Node<T> {
+    bits: u128,
+    len: u8,
+    value: Option<T>,
+    children: [Option<Box<Node<T>>>; 2],
+}
+
+impl<T> Node<T> {
+    fn leaf(bits: u128, len: u8, value: T) -> Box<Self> {
+        Box::new(Node { bits, len, value: Some(value), children: [None, None] })
+    }
+}
+
+// Path-compressed binary (Patricia) trie keyed by IP prefix. Lookups return
+// the longest stored prefix containing the address. IPv4 and IPv6 live in
+// separate roots so a v4 /0 never matches v6 traffic.
+pub struct PrefixTrie<T> {
+    v4: Option<Box<Node<T>>>,
+    v6: Option<Box<Node<T>>>,
+    len: usize,
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn new() -> Self {
+        Self { v4: None, v6: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.v4 = None;
+        self.v6 = None;
+        self.len = 0;
+    }
+
+    fn root(&self, ipv4: bool) -> &Option<Box<Node<T>>> {
+        if ipv4 { &self.v4 } else { &self.v6 }
+    }
+
+    fn root_mut(&mut self, ipv4: bool) -> &mut Option<Box<Node<T>>> {
+        if ipv4 { &mut self.v4 } else { &mut self.v6 }
+    }
+
+    // Returns the previous value if the exact prefix was already present
+    pub fn insert(&mut self, prefix: IpPrefix, value: T) -> Option<T> {
+        let bits = to_bits(&prefix.addr);
+        let len = prefix.len;
+        let previous = insert_node(self.root_mut(prefix.is_ipv4()), bits, len, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, prefix: &IpPrefix) -> Option<T> {
+        let bits = to_bits(&prefix.addr);
+        let len = prefix.len;
+        let removed = remove_node(self.root_mut(prefix.is_ipv4()), bits, len);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn get(&self, prefix: &IpPrefix) -> Option<&T> {
+        let bits = to_bits(&prefix.addr);
+        let len = prefix.len;
+        let mut node = self.root(prefix.is_ipv4()).as_deref();
+
+        while let Some(n) = node {
+            if n.len > len || bits & mask(n.len) != n.bits {
+                return None;
+            }
+            if n.len == len {
+                return n.value.as_ref();
+            }
+            node = n.children[bit_at(bits, n.len)].as_deref();
+        }
+        None
+    }
+
+    pub fn longest_match(&self, ip: &IpAddr) -> Option<(IpPrefix, &T)> {
+        let bits = to_bits(ip);
+        let mut node = self.root(ip.is_ipv4()).as_deref();
+        let mut best = None;
+
+        while let Some(n) = node {
+            if bits & mask(n.len) != n.bits {
+                break;
+            }
+            if let Some(value) = &n.value {
+                best = Some((n, value));
+            }
+            if n.len == 128 {
+                break;
+            }
+            node = n.children[bit_at(bits, n.len)].as_deref();
+        }
+
+        best.map(|(n, value)| (self.prefix_of(n, ip.is_ipv4()), value))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+
+    // All stored prefixes, IPv4 first, each family in address order
+    pub fn iter(&self) -> Vec<(IpPrefix, &T)> {
+        let mut out = Vec::with_capacity(self.len);
+        for ipv4 in [true, false] {
+            let mut stack: Vec<&Node<T>> = self.root(ipv4).as_deref().into_iter().collect();
+            while let Some(n) = stack.pop() {
+                if let Some(value) = &n.value {
+                    out.push((self.prefix_of(n, ipv4), value));
+                }
+                stack.extend(n.children.iter().rev().filter_map(|c| c.as_deref()));
+            }
+        }
+        out
+    }
+
+    fn prefix_of(&self, node: &Node<T>, ipv4: bool) -> IpPrefix {
+        IpPrefix { addr: from_bits(node.bits, ipv4), len: node.len }
+    }
+}
+
+impl<T> FromIterator<(IpPrefix, T)> for PrefixTrie<T> {
+    fn from_iter<I: IntoIterator<Item = (IpPrefix, T)>>(iter: I) -> Self {
+        let mut trie = PrefixTrie::new();
+        for (prefix, value) in iter {
+            trie.insert(prefix, value);
+        }
+        trie
+    }
+}
+
+impl<T> Extend<(IpPrefix, T)> for PrefixTrie<T> {
+    fn extend<I: IntoIterator<Item = (IpPrefix, T)>>(&mut self, iter: I) {
+        for (prefix, value) in iter {
+            self.insert(prefix, value);
+        }
+    }
+}
+
+fn insert_node<T>(slot: &mut Option<Box<Node<T>>>, bits: u128, len: u8, value: T) -> Option<T> {
+    let node = match slot {
+        None => {
+            *slot = Some(Node::leaf(bits, len, value));
+            return None;
+        }
+        Some(node) => node,
+    };
+
+    let common = ((node.bits ^ bits).leading_zeros() as u8).min(node.len).min(len);
+
+    if common == node.len && common == len {
+        return node.value.replace(value);
+    }
+    if common == node.len {
+        let child = bit_at(bits, node.len);
+        return insert_node(&mut node.children[child], bits, len, value);
+    }
+
+    // The new prefix diverges from (or is shorter than) this node; split here
+    let existing = slot.take().unwrap();
+    let mut split = Box::new(Node {
+        bits: bits & mask(common),
+        len: common,
+        value: None,
+        children: [None, None],
+    });
+    let existing_side = bit_at(existing.bits, common);
+    split.children[existing_side] = Some(existing);
+
+    if common == len {
+        split.value = Some(value);
+    } else {
+        split.children[bit_at(bits, common)] = Some(Node::leaf(bits, len, value));
+    }
+    *slot = Some(split);
+    None
+}
+
+fn remove_node<T>(slot: &mut Option<Box<Node<T>>>, bits: u128, len: u8) -> Option<T> {
+    let node = slot.as_mut()?;
+    if node.len > len || bits & mask(node.len) != node.bits {
+        return None;
+    }
+
+    let removed = if node.len == len {
+        node.value.take()
+    } else {
+        let child = bit_at(bits, node.len);
+        remove_node(&mut node.children[child], bits, len)
+    };
+
+    // Collapse nodes that no longer carry a value or a branch
+    if removed.is_some() && node.value.is_none() {
+        match (node.children[0].is_some(), node.children[1].is_some()) {
+            (false, false) => *slot = None,
+            (true, false) => *slot = node.children[0].take(),
+            (false, true) => *slot = node.children[1].take(),
+            (true, true) => {}
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    // Nodes in both families' trees, valueless branch points included
+    fn nodes<T>(trie: &PrefixTrie<T>) -> usize {
+        let mut count = 0;
+        for ipv4 in [true, false] {
+            let mut stack: Vec<&Node<T>> = trie.root(ipv4).as_deref().into_iter().collect();
+            while let Some(node) = stack.pop() {
+                count += 1;
+                stack.extend(node.children.iter().filter_map(|c| c.as_deref()));
+            }
+        }
+        count
+    }
+
+    fn matched<'a>(trie: &'a PrefixTrie<&'a str>, addr: &str) -> Option<(String, &'a str)> {
+        trie.longest_match(&ip(addr)).map(|(prefix, value)| (prefix.to_string(), *value))
+    }
+
+    #[test]
+    fn parses_and_normalizes_prefixes() {
+        assert_eq!(prefix("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(prefix("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(prefix("192.0.2.1").prefix_len(), 32);
+        assert_eq!("10.0.0.0/33".parse::<IpPrefix>(), Err(PrefixParseError::InvalidLength("33".to_string())));
+        assert_eq!("10.0.0/8".parse::<IpPrefix>(), Err(PrefixParseError::InvalidAddress("10.0.0".to_string())));
+        assert!(prefix("10.0.0.0/8").covers(&prefix("10.1.0.0/16")));
+        assert!(!prefix("10.1.0.0/16").covers(&prefix("10.0.0.0/8")));
+        assert!(prefix("10.1.0.0/16").overlaps(&prefix("10.0.0.0/8")));
+        assert!(!prefix("10.0.0.0/8").overlaps(&prefix("11.0.0.0/8")));
+    }
+
+    #[test]
+    fn insert_splits_diverging_prefixes() {
+        let mut trie = PrefixTrie::new();
+        assert_eq!(trie.insert(prefix("10.1.0.0/16"), "a"), None);
+        assert_eq!(nodes(&trie), 1);
+        // Shares 10.0.0.0/14 with the first, so a valueless node goes above both
+        assert_eq!(trie.insert(prefix("10.2.0.0/16"), "b"), None);
+        assert_eq!(nodes(&trie), 3);
+        // Lands on the split point itself
+        assert_eq!(trie.insert(prefix("10.0.0.0/14"), "c"), None);
+        assert_eq!(nodes(&trie), 3);
+        // Shorter than the root: becomes the new root
+        assert_eq!(trie.insert(prefix("10.0.0.0/8"), "d"), None);
+        assert_eq!(nodes(&trie), 4);
+        assert_eq!(trie.len(), 4);
+
+        assert_eq!(trie.insert(prefix("10.1.0.0/16"), "e"), Some("a"));
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.get(&prefix("10.1.0.0/16")), Some(&"e"));
+        assert_eq!(trie.get(&prefix("10.1.0.0/17")), None);
+        let listed: Vec<String> = trie.iter().into_iter().map(|(p, _)| p.to_string()).collect();
+        assert_eq!(listed, ["10.0.0.0/8", "10.0.0.0/14", "10.1.0.0/16", "10.2.0.0/16"]);
+    }
+
+    #[test]
+    fn remove_collapses_nodes_left_without_a_purpose() {
+        let mut trie: PrefixTrie<&str> =
+            [(prefix("10.1.0.0/16"), "a"), (prefix("10.2.0.0/16"), "b"), (prefix("10.3.0.0/16"), "c")]
+                .into_iter()
+                .collect();
+        let before = nodes(&trie);
+
+        assert_eq!(trie.remove(&prefix("10.0.0.0/14")), None);
+        assert_eq!(trie.remove(&prefix("10.1.0.0/24")), None);
+        assert_eq!(nodes(&trie), before);
+
+        // Its branch point is left with one child and folds into it
+        assert_eq!(trie.remove(&prefix("10.2.0.0/16")), Some("b"));
+        assert_eq!(nodes(&trie), before - 2);
+        assert_eq!(trie.remove(&prefix("10.3.0.0/16")), Some("c"));
+        assert_eq!(nodes(&trie), 1);
+        assert_eq!(matched(&trie, "10.1.9.9"), Some(("10.1.0.0/16".to_string(), "a")));
+
+        assert_eq!(trie.remove(&prefix("10.1.0.0/16")), Some("a"));
+        assert_eq!(nodes(&trie), 0);
+        assert!(trie.is_empty());
+    }
+
+    #[test]
+    fn longest_match_picks_the_most_specific_prefix() {
+        let trie: PrefixTrie<&str> = [
+            (prefix("10.0.0.0/8"), "deny"),
+            (prefix("10.1.0.0/16"), "allow"),
+            (prefix("10.1.2.0/24"), "deny"),
+            (prefix("10.1.2.3/32"), "allow"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(matched(&trie, "10.9.9.9"), Some(("10.0.0.0/8".to_string(), "deny")));
+        assert_eq!(matched(&trie, "10.1.9.9"), Some(("10.1.0.0/16".to_string(), "allow")));
+        assert_eq!(matched(&trie, "10.1.2.4"), Some(("10.1.2.0/24".to_string(), "deny")));
+        assert_eq!(matched(&trie, "10.1.2.3"), Some(("10.1.2.3/32".to_string(), "allow")));
+        assert_eq!(matched(&trie, "11.0.0.1"), None);
+        assert!(!trie.contains(&ip("9.255.255.255")));
+    }
+
+    #[test]
+    fn default_routes_and_host_routes() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(prefix("0.0.0.0/0"), "v4 default");
+        trie.insert(prefix("255.255.255.255/32"), "broadcast");
+        trie.insert(prefix("::/0"), "v6 default");
+        trie.insert(prefix("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128"), "last");
+        trie.insert(prefix("::/128"), "unspecified");
+
+        assert_eq!(matched(&trie, "0.0.0.0"), Some(("0.0.0.0/0".to_string(), "v4 default")));
+        assert_eq!(matched(&trie, "255.255.255.255"), Some(("255.255.255.255/32".to_string(), "broadcast")));
+        assert_eq!(matched(&trie, "255.255.255.254"), Some(("0.0.0.0/0".to_string(), "v4 default")));
+        assert_eq!(matched(&trie, "::"), Some(("::/128".to_string(), "unspecified")));
+        assert_eq!(matched(&trie, "::1"), Some(("::/0".to_string(), "v6 default")));
+        assert_eq!(
+            matched(&trie, "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            Some(("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128".to_string(), "last"))
+        );
+
+        assert_eq!(trie.remove(&prefix("0.0.0.0/0")), Some("v4 default"));
+        assert_eq!(matched(&trie, "255.255.255.254"), None);
+        assert_eq!(matched(&trie, "255.255.255.255"), Some(("255.255.255.255/32".to_string(), "broadcast")));
+    }
+
+    #[test]
+    fn address_families_never_match_each_other() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(prefix("0.0.0.0/0"), "v4");
+        // Same bits as 10.0.0.0/8 in the shared layout
+        trie.insert(prefix("a00::/8"), "v6");
+
+        assert_eq!(matched(&trie, "10.0.0.1"), Some(("0.0.0.0/0".to_string(), "v4")));
+        assert_eq!(matched(&trie, "a00::1"), Some(("a00::/8".to_string(), "v6")));
+        assert_eq!(matched(&trie, "2001:db8::1"), None);
+        // An IPv4-mapped address is still IPv6
+        assert_eq!(matched(&trie, "::ffff:10.0.0.1"), None);
+        assert_eq!(trie.get(&prefix("a00::/8")), Some(&"v6"));
+        assert_eq!(trie.get(&prefix("10.0.0.0/8")), None);
+        assert!(!prefix("0.0.0.0/0").contains(&ip("::1")));
+
+        let listed: Vec<String> = trie.iter().into_iter().map(|(p, _)| p.to_string()).collect();
+        assert_eq!(listed, ["0.0.0.0/0", "a00::/8"]);
+        trie.clear();
+        assert!(trie.is_empty());
+        assert_eq!(matched(&trie, "10.0.0.1"), None);
+    }
+}
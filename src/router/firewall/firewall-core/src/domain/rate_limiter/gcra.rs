@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+use super::per_key::Meter;
+
+// Generic cell rate algorithm: tracks the theoretical arrival time (TAT)
+// of the next request if they came exactly `1 / rate` apart. A request is
+// let through unless it's more than the burst tolerance ahead of that
+// schedule. One timestamp per key, and with `capacity` 1 requests are
+// spaced strictly. Times are whole nanoseconds since `epoch` so requests
+// right on schedule aren't lost to rounding.
+#[derive(Debug, Clone)]
+pub struct Gcra {
+    // Emission interval, time between requests at `rate`
+    interval: u64,
+    // How far ahead of schedule a request may be
+    tolerance: u64,
+    tat: u64,
+    epoch: Instant,
+    last_used: Instant,
+}
+
+impl Gcra {
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        let interval = Duration::try_from_secs_f64(1.0 / rate)
+            .map_or(u64::MAX, |d| d.as_nanos().min(u64::MAX as u128) as u64)
+            .max(1);
+        Self {
+            interval,
+            tolerance: interval.saturating_mul(capacity.max(1.0) as u64 - 1),
+            tat: 0,
+            epoch: now,
+            last_used: now,
+        }
+    }
+
+    fn nanos(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_nanos().min(u64::MAX as u128) as u64
+    }
+
+    // Time until the next request would be let through
+    pub fn retry_after(&self, now: Instant) -> Duration {
+        let t = self.nanos(now);
+        Duration::from_nanos(self.tat.saturating_sub(self.tolerance).saturating_sub(t))
+    }
+}
+
+impl Meter for Gcra {
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let t = self.nanos(now);
+        self.last_used = self.last_used.max(now);
+        let tat = self.tat.max(t);
+        if tat - t > self.tolerance {
+            return false;
+        }
+        self.tat = tat.saturating_add(self.interval);
+        true
+    }
+
+    fn allowance(&self, now: Instant) -> f64 {
+        let t = self.nanos(now);
+        let ahead = self.tat.max(t) - t;
+        if ahead > self.tolerance {
+            return 0.0;
+        }
+        (self.tolerance - ahead) as f64 / self.interval as f64 + 1.0
+    }
+
+    fn last_used(&self) -> Instant {
+        self.last_used
+    }
+}
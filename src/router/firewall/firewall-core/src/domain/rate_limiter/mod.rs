@@ -1,3 +1,13 @@
-pub mod rate_limiter;
+pub mod per_key;
 pub mod token_bucket;
-pub mod rate_limit_config;
\ No newline at end of file
+pub mod sliding_window;
+pub mod leaky_bucket;
+pub mod gcra;
+pub mod rate_limit_config;
+
+pub use per_key::{Meter, PerKeyRateLimiter};
+pub use token_bucket::{RateLimiter, TokenBucket};
+pub use sliding_window::{SlidingWindowCounter, SlidingWindowLog};
+pub use leaky_bucket::LeakyBucket;
+pub use gcra::Gcra;
+pub use rate_limit_config::{RateLimitAlgorithm, RateLimitConfig, RateLimitKeyType};
\ No newline at end of file
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::domain::clock::{Clock, SystemClock};
+use super::token_bucket::{TokenBucket, RateLimiter};
+
+// State one limiting algorithm keeps for a single key. Time comes from the
+// caller so a limiter can run off any clock.
+pub trait Meter: Send + Sync {
+    // Lets one request through if the limit allows it
+    fn try_acquire(&mut self, now: Instant) -> bool;
+    // Requests that would pass at `now`; fractional for the smooth algorithms
+    fn allowance(&self, now: Instant) -> f64;
+    // Last time the meter was touched, for `cleanup`
+    fn last_used(&self) -> Instant;
+}
+
+// Keys a limiter tracks before it forgets the least recently used ones, so
+// a flood from spoofed addresses can't grow it without bound
+pub const DEFAULT_MAX_KEYS: usize = 65_536;
+
+type MeterFactory<M> = Box<dyn Fn(Instant) -> M + Send + Sync>;
+
+pub struct PerKeyRateLimiter<M = TokenBucket> {
+    meters: HashMap<String, M>,
+    new_meter: MeterFactory<M>,
+    clock: Arc<dyn Clock>,
+    max_keys: usize,
+}
+
+impl PerKeyRateLimiter {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        PerKeyRateLimiter::with_meter(Arc::new(SystemClock), move |now| {
+            TokenBucket::new_at(rate, capacity, now)
+        })
+    }
+}
+
+impl<M: Meter> PerKeyRateLimiter<M> {
+    // `new_meter` sets up the state for a key seen for the first time
+    pub fn with_meter(
+        clock: Arc<dyn Clock>,
+        new_meter: impl Fn(Instant) -> M + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            meters: HashMap::new(),
+            new_meter: Box::new(new_meter),
+            clock,
+            max_keys: DEFAULT_MAX_KEYS,
+        }
+    }
+
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys.max(1);
+        self
+    }
+}
+
+// Makes room for one more key once `meters` is full. A forgotten key starts
+// over with a fresh meter, so the least recently used go first, and an
+// eighth of them at a time so the sort is paid once per many new keys.
+pub(crate) fn make_room<M: Meter>(meters: &mut HashMap<String, M>, max_keys: usize) {
+    if meters.len() < max_keys {
+        return;
+    }
+    let evict = (meters.len() + 1 - max_keys).max(max_keys / 8);
+    let mut by_age: Vec<(Instant, String)> = meters.iter()
+        .map(|(key, meter)| (meter.last_used(), key.clone()))
+        .collect();
+    by_age.select_nth_unstable_by_key(evict - 1, |(last_used, _)| *last_used);
+    for (_, key) in &by_age[..evict] {
+        meters.remove(key);
+    }
+}
+
+impl<M: Meter> RateLimiter for PerKeyRateLimiter<M> {
+    fn is_allowed(&mut self, key: &str) -> bool {
+        let now = self.clock.now();
+        if !self.meters.contains_key(key) {
+            make_room(&mut self.meters, self.max_keys);
+        }
+        let meter = self.meters.entry(key.to_string())
+            .or_insert_with(|| (self.new_meter)(now));
+        meter.try_acquire(now)
+    }
+    fn current_usage(&mut self, key: &str) -> Option<f64> {
+        let now = self.clock.now();
+        self.meters.get(key).map(|m| m.allowance(now))
+    }
+    fn reset(&mut self, key: &str) {
+        self.meters.remove(key);
+    }
+    fn cleanup(&mut self, threshold_secs: u64) {
+        let now = self.clock.now();
+        self.meters.retain(|_, meter| {
+            now.saturating_duration_since(meter.last_used()).as_secs() < threshold_secs
+        })
+    }
+}
@@ -1,5 +1,9 @@
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::packet::Packet;
+use super::{Gcra, LeakyBucket, PerKeyRateLimiter, RateLimiter, SlidingWindowCounter, SlidingWindowLog, TokenBucket};
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RateLimitKeyType {
     SourceIp,
     DestinationIp,
@@ -7,11 +11,49 @@ pub enum RateLimitKeyType {
     Global,    
 }
 
-#[derive(Debug, Clone)]
+impl RateLimitKeyType {
+    pub fn key_for(&self, packet: &Packet) -> String {
+        match self {
+            RateLimitKeyType::SourceIp => packet.source_ip.to_string(),
+            RateLimitKeyType::DestinationIp => packet.destination_ip.to_string(),
+            RateLimitKeyType::Flow => {
+                format!("{}:{}-{}:{}",
+                    packet.source_ip,
+                    packet.source_port.unwrap_or(0),
+                    packet.destination_ip,
+                    packet.destination_port.unwrap_or(0)
+                )
+            }
+            RateLimitKeyType::Global => "global".to_string(),
+        }
+    }
+}
+
+// How a limiter spends `rate` and `capacity`. All of them allow `rate`
+// requests per second in the long run; they differ in how bursts are
+// handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitAlgorithm {
+    // Bursts of up to `capacity`, refilled at `rate`
+    #[default]
+    TokenBucket,
+    // Drains at `rate` and turns away what would overflow `capacity`;
+    // passes the same requests as the token bucket
+    LeakyBucket,
+    // At most `capacity` requests in any `capacity / rate` seconds
+    SlidingWindowLog,
+    // Cheaper approximation of the log from two fixed windows
+    SlidingWindowCounter,
+    // Requests spaced `1 / rate` apart with `capacity - 1` of slack
+    Gcra,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct RateLimitConfig {
     pub rate: f64,
     pub capacity: f64,
     pub key_type: RateLimitKeyType,
+    pub algorithm: RateLimitAlgorithm,
 }
 
 impl RateLimitConfig {
@@ -20,6 +62,7 @@ impl RateLimitConfig {
             rate,
             capacity,
             key_type: RateLimitKeyType::SourceIp,
+            algorithm: RateLimitAlgorithm::TokenBucket,
         }
     }
 
@@ -28,6 +71,7 @@ impl RateLimitConfig {
             rate,
             capacity,
             key_type: RateLimitKeyType::SourceIp,
+            algorithm: RateLimitAlgorithm::TokenBucket,
         }
     }
 
@@ -36,6 +80,193 @@ impl RateLimitConfig {
             rate,
             capacity,
             key_type: RateLimitKeyType::DestinationIp,
+            algorithm: RateLimitAlgorithm::TokenBucket,
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: RateLimitAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn build(&self) -> Box<dyn RateLimiter> {
+        self.build_with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn build_with_clock(&self, clock: Arc<dyn Clock>) -> Box<dyn RateLimiter> {
+        let (rate, capacity) = (self.rate, self.capacity);
+        match self.algorithm {
+            RateLimitAlgorithm::TokenBucket => Box::new(PerKeyRateLimiter::with_meter(clock, move |now| {
+                TokenBucket::new_at(rate, capacity, now)
+            })),
+            RateLimitAlgorithm::LeakyBucket => Box::new(PerKeyRateLimiter::with_meter(clock, move |now| {
+                LeakyBucket::new(rate, capacity, now)
+            })),
+            RateLimitAlgorithm::SlidingWindowLog => Box::new(PerKeyRateLimiter::with_meter(clock, move |now| {
+                SlidingWindowLog::new(rate, capacity, now)
+            })),
+            RateLimitAlgorithm::SlidingWindowCounter => Box::new(PerKeyRateLimiter::with_meter(clock, move |now| {
+                SlidingWindowCounter::new(rate, capacity, now)
+            })),
+            RateLimitAlgorithm::Gcra => Box::new(PerKeyRateLimiter::with_meter(clock, move |now| {
+                Gcra::new(rate, capacity, now)
+            })),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::clock::ManualClock;
+    use std::time::Duration;
+
+    fn limiter(algorithm: RateLimitAlgorithm, rate: f64, capacity: f64) -> (Arc<ManualClock>, Box<dyn RateLimiter>) {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimitConfig::new(rate, capacity).with_algorithm(algorithm).build_with_clock(clock.clone());
+        (clock, limiter)
+    }
+
+    // How many of `n` requests at the current time get through
+    fn passed(limiter: &mut Box<dyn RateLimiter>, n: usize) -> usize {
+        (0..n).filter(|_| limiter.is_allowed("k")).count()
+    }
+
+    #[test]
+    fn sliding_window_log_counts_the_last_window() {
+        // 4 requests per 2 seconds
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::SlidingWindowLog, 2.0, 4.0);
+        assert_eq!(passed(&mut limiter, 2), 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(passed(&mut limiter, 5), 2);
+        assert_eq!(limiter.current_usage("k"), Some(0.0));
+
+        // The first two drop out of the window exactly 2s after they came
+        clock.advance(Duration::from_millis(999));
+        assert!(!limiter.would_allow("k"));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.current_usage("k"), Some(2.0));
+        assert_eq!(passed(&mut limiter, 5), 2);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(passed(&mut limiter, 5), 4);
+    }
+
+    #[test]
+    fn sliding_window_counter_weights_the_previous_window() {
+        // 4 per 2-second window
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::SlidingWindowCounter, 2.0, 4.0);
+        assert_eq!(passed(&mut limiter, 5), 4);
+
+        // A new window starts at 2s, but all of the previous one still
+        // overlaps the sliding window
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(passed(&mut limiter, 5), 0);
+        // Halfway in, the previous window counts for 2
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.current_usage("k"), Some(2.0));
+        assert_eq!(passed(&mut limiter, 5), 2);
+
+        // Two whole windows idle forget everything
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(passed(&mut limiter, 5), 4);
+    }
+
+    #[test]
+    fn gcra_spaces_requests_after_the_burst() {
+        // One every 100ms, with a burst of 3
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::Gcra, 10.0, 3.0);
+        assert_eq!(passed(&mut limiter, 5), 3);
+        clock.advance(Duration::from_millis(99));
+        assert_eq!(passed(&mut limiter, 1), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(passed(&mut limiter, 5), 1);
+
+        // Back to a full burst after idling for it
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(limiter.current_usage("k"), Some(3.0));
+        assert_eq!(passed(&mut limiter, 5), 3);
+    }
+
+    #[test]
+    fn gcra_with_capacity_one_spaces_strictly() {
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::Gcra, 4.0, 1.0);
+        for _ in 0..10 {
+            assert_eq!(passed(&mut limiter, 3), 1);
+            clock.advance(Duration::from_millis(249));
+            assert!(!limiter.would_allow("k"));
+            clock.advance(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills_at_rate() {
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::TokenBucket, 2.0, 4.0);
+        assert_eq!(passed(&mut limiter, 5), 4);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(passed(&mut limiter, 5), 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.current_usage("k"), Some(4.0));
+    }
+
+    #[test]
+    fn leaky_bucket_drains_at_rate() {
+        let (clock, mut limiter) = limiter(RateLimitAlgorithm::LeakyBucket, 2.0, 4.0);
+        assert_eq!(passed(&mut limiter, 5), 4);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(passed(&mut limiter, 5), 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.current_usage("k"), Some(4.0));
+    }
+
+    #[test]
+    fn keys_past_the_cap_push_out_the_least_recently_used() {
+        let clock = Arc::new(ManualClock::new());
+        let mut limiter = PerKeyRateLimiter::with_meter(clock.clone(), |now| TokenBucket::new_at(1.0, 2.0, now))
+            .with_max_keys(16);
+        for i in 0..16 {
+            limiter.is_allowed(&i.to_string());
+            clock.advance(Duration::from_millis(1));
+        }
+        limiter.is_allowed("0");
+        // The two oldest of sixteen make room
+        limiter.is_allowed("new");
+        assert_eq!(limiter.current_usage("1"), None);
+        assert_eq!(limiter.current_usage("2"), None);
+        assert!(limiter.current_usage("0").is_some());
+        assert!(limiter.current_usage("3").is_some());
+        assert!(limiter.current_usage("new").is_some());
+
+        // A flood of new keys never grows it past the cap
+        for i in 0..1000 {
+            limiter.is_allowed(&format!("spoofed-{}", i));
+            assert!(limiter.current_usage(&format!("spoofed-{}", i)).is_some());
+        }
+        let tracked = (0..1000).filter(|i| limiter.current_usage(&format!("spoofed-{}", i)).is_some()).count();
+        assert!(tracked <= 16, "{}", tracked);
+    }
+
+    #[test]
+    fn keys_are_limited_separately_and_cleaned_up() {
+        for algorithm in [
+            RateLimitAlgorithm::TokenBucket,
+            RateLimitAlgorithm::LeakyBucket,
+            RateLimitAlgorithm::SlidingWindowLog,
+            RateLimitAlgorithm::SlidingWindowCounter,
+            RateLimitAlgorithm::Gcra,
+        ] {
+            let (clock, mut limiter) = limiter(algorithm, 1.0, 2.0);
+            assert!(limiter.is_allowed("a") && limiter.is_allowed("a"));
+            assert!(!limiter.is_allowed("a"), "{:?}", algorithm);
+            assert!(limiter.is_allowed("b"), "{:?}", algorithm);
+            assert_eq!(limiter.current_usage("c"), None);
+
+            clock.advance(Duration::from_secs(30));
+            assert!(limiter.is_allowed("b"));
+            clock.advance(Duration::from_secs(31));
+            limiter.cleanup(60);
+            assert_eq!(limiter.current_usage("a"), None, "{:?}", algorithm);
+            assert!(limiter.current_usage("b").is_some(), "{:?}", algorithm);
+        }
+    }
+}
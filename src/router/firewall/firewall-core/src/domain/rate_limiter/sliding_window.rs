@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use super::per_key::Meter;
+
+// Window over which `capacity` requests average out to `rate` per second
+fn window_for(rate: f64, capacity: f64) -> Duration {
+    Duration::try_from_secs_f64(capacity / rate)
+        .unwrap_or(Duration::MAX)
+        .max(Duration::from_nanos(1))
+}
+
+// At most `limit` requests in any window ending now. Exact, but keeps a
+// timestamp per request it let through.
+#[derive(Debug, Clone)]
+pub struct SlidingWindowLog {
+    limit: usize,
+    window: Duration,
+    log: VecDeque<Instant>,
+    created: Instant,
+}
+
+impl SlidingWindowLog {
+    // `capacity` requests per `capacity / rate` seconds
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        let limit = capacity.max(1.0) as usize;
+        Self {
+            limit,
+            window: window_for(rate, limit as f64),
+            log: VecDeque::with_capacity(limit),
+            created: now,
+        }
+    }
+
+    fn in_window(&self, now: Instant) -> usize {
+        self.log.iter()
+            .rev()
+            .take_while(|&&at| now.saturating_duration_since(at) < self.window)
+            .count()
+    }
+}
+
+impl Meter for SlidingWindowLog {
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        while self.log.front().is_some_and(|&at| now.saturating_duration_since(at) >= self.window) {
+            self.log.pop_front();
+        }
+        if self.log.len() >= self.limit {
+            return false;
+        }
+        self.log.push_back(now);
+        true
+    }
+
+    fn allowance(&self, now: Instant) -> f64 {
+        (self.limit - self.in_window(now)) as f64
+    }
+
+    fn last_used(&self) -> Instant {
+        self.log.back().copied().unwrap_or(self.created)
+    }
+}
+
+// Approximates the log with two fixed windows: the previous window's count
+// is weighted by how much of it still overlaps the sliding one, and a
+// request passes while that estimate is under the limit
+#[derive(Debug, Clone)]
+pub struct SlidingWindowCounter {
+    limit: f64,
+    window: Duration,
+    // Start of the current fixed window
+    start: Instant,
+    previous: f64,
+    current: f64,
+    last_used: Instant,
+}
+
+impl SlidingWindowCounter {
+    // `capacity` requests per `capacity / rate` seconds
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        let limit = capacity.max(1.0).floor();
+        Self {
+            limit,
+            window: window_for(rate, limit),
+            start: now,
+            previous: 0.0,
+            current: 0.0,
+            last_used: now,
+        }
+    }
+
+    // Window start and (previous, current) counts as of `now`
+    fn rolled(&self, now: Instant) -> (Instant, f64, f64) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed < self.window {
+            return (self.start, self.previous, self.current);
+        }
+        let into = Duration::from_nanos((elapsed.as_nanos() % self.window.as_nanos()) as u64);
+        let start = now - into;
+        if elapsed < self.window.saturating_mul(2) {
+            (start, self.current, 0.0)
+        } else {
+            (start, 0.0, 0.0)
+        }
+    }
+
+    fn estimate(&self, now: Instant) -> (Instant, f64, f64, f64) {
+        let (start, previous, current) = self.rolled(now);
+        let into = now.saturating_duration_since(start).as_secs_f64();
+        let overlap = 1.0 - into / self.window.as_secs_f64();
+        (start, previous, current, previous * overlap + current)
+    }
+}
+
+impl Meter for SlidingWindowCounter {
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let (start, previous, current, estimate) = self.estimate(now);
+        self.start = start;
+        self.previous = previous;
+        self.current = current;
+        self.last_used = self.last_used.max(now);
+        if estimate >= self.limit {
+            return false;
+        }
+        self.current += 1.0;
+        true
+    }
+
+    fn allowance(&self, now: Instant) -> f64 {
+        let (.., estimate) = self.estimate(now);
+        (self.limit - estimate).max(0.0).ceil()
+    }
+
+    fn last_used(&self) -> Instant {
+        self.last_used
+    }
+}
@@ -1,8 +1,14 @@
 use std::time::Instant;
+use super::per_key::Meter;
 
 pub trait RateLimiter: Send + Sync {
     fn is_allowed(&mut self, key: &str) -> bool;
     fn current_usage(&mut self, key: &str) -> Option<f64>;
+    // Whether `is_allowed` would pass `key` right now, without using up
+    // anything. Keys the limiter hasn't seen start with a full allowance.
+    fn would_allow(&mut self, key: &str) -> bool {
+        self.current_usage(key).is_none_or(|tokens| tokens >= 1.0)
+    }
     fn reset(&mut self, key: &str);
     fn cleanup(&mut self, threshold_secs: u64);
 }
@@ -16,17 +22,18 @@ pub struct TokenBucket {
 }
 
 impl TokenBucket {
-    pub fn new(rate: f64, capacity: f64) -> Self {
+    // Full bucket as of `now`
+    pub fn new_at(rate: f64, capacity: f64, now: Instant) -> Self {
         Self  {
             tokens: capacity,
             capacity,
             rate,
-            last_refill: Instant::now()
+            last_refill: now,
         }
     }
 
-    pub fn try_consume(&mut self, amount: f64) -> bool {
-        self.refill();
+    pub fn try_consume(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill_at(now);
 
         if self.tokens >= amount {
             self.tokens -= amount;
@@ -36,25 +43,71 @@ impl TokenBucket {
         }
     }
 
-    fn refill(&mut self) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
-        let tokens_to_add = elapsed * self.rate;
+    fn refill_at(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.last_refill = self.last_refill.max(now);
+    }
 
-        self.tokens = (self.tokens + tokens_to_add).min(self.capacity);
-        self.last_refill = now;
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        let tokens_to_add = elapsed * self.rate;
+        (self.tokens + tokens_to_add).min(self.capacity)
     }
-    pub fn current_tokens(&mut self) -> f64 {
-        self.refill();
+    pub fn last_refill(&self) -> Instant {
+        self.last_refill
+    }
+
+    pub fn current_tokens(&mut self, now: Instant) -> f64 {
+        self.refill_at(now);
         self.tokens
     }
 
-    pub fn set_rate(&mut self, new_rate: f64) {
-        if self.rate > 0.0 {
-            let ratio = new_rate / self.rate;
-            self.tokens = (self.tokens * ratio).min(new_rate);
-        }
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    // Tokens earned so far are kept; only the refill from here on changes
+    pub fn set_rate(&mut self, new_rate: f64, now: Instant) {
+        self.refill_at(now);
         self.rate = new_rate;
-        self.capacity = new_rate;
     }
-}
\ No newline at end of file
+
+    // Switches to a new rate and capacity at `now`. Time up to `now` is
+    // refilled at the old rate, and the bucket stays as full, relative to
+    // its capacity, as it was: a key that had used half its burst still has
+    // half of the new one.
+    pub fn set_limit(&mut self, rate: f64, capacity: f64, now: Instant) {
+        self.refill_at(now);
+        if self.capacity > 0.0 {
+            self.tokens = self.tokens / self.capacity * capacity;
+        } else {
+            self.tokens = capacity;
+        }
+        self.rate = rate;
+        self.capacity = capacity;
+    }
+}
+
+impl Meter for TokenBucket {
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill_at(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn allowance(&self, now: Instant) -> f64 {
+        self.tokens_at(now)
+    }
+
+    fn last_used(&self) -> Instant {
+        self.last_refill
+    }
+}
@@ -1,27 +1,350 @@
 use crate::domain::packet::{Packet, PacketHeader};
+use crate::domain::stats::RuleCounters;
+use std::any::Any;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectWith {
+    TcpReset,
+    IcmpPortUnreachable,
+    IcmpHostUnreachable,
+    IcmpAdminProhibited,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Action {
     Allow,
+    // Silent drop
     Block,
+    // Drop and tell the sender
+    Reject(RejectWith),
+    // Drop and record the packet in the log
+    DropLog,
+    // Record the packet and keep evaluating
     Log,
+    // Set the packet mark (fwmark) and keep evaluating
+    Mark(u32),
+    // Set the DSCP/QoS class and keep evaluating
+    QosClass(u8),
+    // Hand off to the named rate limiter: keep evaluating while under the
+    // limit, drop once it is exceeded
+    RateLimit(String),
+    // Drop this packet and everything else from the source host for a while
+    Quarantine(Duration),
+    // Evaluate the named chain, then carry on after this rule
+    Jump(String),
+    // Evaluate the named chain instead of the rest of this one
+    Goto(String),
+    // Leave the current chain and carry on in the one that jumped here
+    Return,
+}
+
+impl Action {
+    // Terminal actions end rule evaluation; the rest annotate the packet and
+    // let the next rule run, or move evaluation between chains. `RateLimit`
+    // is only terminal once over the limit.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(
+            self,
+            Action::Log
+                | Action::Mark(_)
+                | Action::QosClass(_)
+                | Action::RateLimit(_)
+                | Action::Jump(_)
+                | Action::Goto(_)
+                | Action::Return
+        )
+    }
+
+    // Whether the packet is let through when this is the final action
+    pub fn permits(&self) -> bool {
+        matches!(
+            self,
+            Action::Allow | Action::Log | Action::Mark(_) | Action::QosClass(_)
+        )
+    }
+}
+
+impl fmt::Display for RejectWith {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RejectWith::TcpReset => "tcp-reset",
+            RejectWith::IcmpPortUnreachable => "port-unreachable",
+            RejectWith::IcmpHostUnreachable => "host-unreachable",
+            RejectWith::IcmpAdminProhibited => "admin-prohibited",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionParseError(String);
+
+impl fmt::Display for ActionParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ActionParseError {}
+
+// Text form used by the config file: "allow", "block", "reject:tcp-reset",
+// "drop-log", "log", "mark:0x10", "qos:46", "rate-limit:ssh", "quarantine:10m",
+// "jump:guest-wifi", "goto:guest-wifi", "return"
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Allow => f.write_str("allow"),
+            Action::Block => f.write_str("block"),
+            Action::Reject(with) => write!(f, "reject:{}", with),
+            Action::DropLog => f.write_str("drop-log"),
+            Action::Log => f.write_str("log"),
+            Action::Mark(mark) => write!(f, "mark:{:#x}", mark),
+            Action::QosClass(class) => write!(f, "qos:{}", class),
+            Action::RateLimit(name) => write!(f, "rate-limit:{}", name),
+            Action::Quarantine(duration) => {
+                let secs = duration.as_secs();
+                if secs > 0 && secs % 3600 == 0 {
+                    write!(f, "quarantine:{}h", secs / 3600)
+                } else if secs > 0 && secs % 60 == 0 {
+                    write!(f, "quarantine:{}m", secs / 60)
+                } else {
+                    write!(f, "quarantine:{}s", secs)
+                }
+            }
+            Action::Jump(chain) => write!(f, "jump:{}", chain),
+            Action::Goto(chain) => write!(f, "goto:{}", chain),
+            Action::Return => f.write_str("return"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = ActionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (verb, arg) = match s.split_once(':') {
+            Some((verb, arg)) => (verb.trim(), Some(arg.trim())),
+            None => (s, None),
+        };
+        let missing = || ActionParseError(format!("action '{}' needs an argument", verb));
+
+        let action = match (verb.to_ascii_lowercase().as_str(), arg) {
+            ("allow" | "accept", None) => Action::Allow,
+            ("block" | "drop", None) => Action::Block,
+            ("drop-log", None) => Action::DropLog,
+            ("log", None) => Action::Log,
+            // Same default as iptables' REJECT target
+            ("reject", None) => Action::Reject(RejectWith::IcmpPortUnreachable),
+            ("reject", Some(with)) => Action::Reject(match with {
+                "tcp-reset" => RejectWith::TcpReset,
+                "port-unreachable" => RejectWith::IcmpPortUnreachable,
+                "host-unreachable" => RejectWith::IcmpHostUnreachable,
+                "admin-prohibited" => RejectWith::IcmpAdminProhibited,
+                other => {
+                    return Err(ActionParseError(format!("unknown reject type '{}'", other)));
+                }
+            }),
+            ("mark", Some(mark)) => Action::Mark(parse_mark(mark)?),
+            ("qos", Some(class)) => match class.parse::<u8>() {
+                Ok(class) if class < 64 => Action::QosClass(class),
+                _ => return Err(ActionParseError(format!("invalid DSCP class '{}'", class))),
+            },
+            ("rate-limit", Some(name)) if !name.is_empty() => Action::RateLimit(name.to_string()),
+            ("quarantine", Some(duration)) => Action::Quarantine(parse_duration(duration)?),
+            ("jump", Some(chain)) if !chain.is_empty() => Action::Jump(chain.to_string()),
+            ("goto", Some(chain)) if !chain.is_empty() => Action::Goto(chain.to_string()),
+            ("return", None) => Action::Return,
+            ("mark" | "qos" | "rate-limit" | "quarantine" | "jump" | "goto", _) => return Err(missing()),
+            ("allow" | "accept" | "block" | "drop" | "drop-log" | "log" | "return", Some(_)) => {
+                return Err(ActionParseError(format!("action '{}' takes no argument", verb)));
+            }
+            _ => return Err(ActionParseError(format!("unknown action '{}'", verb))),
+        };
+        Ok(action)
+    }
+}
+
+fn parse_mark(s: &str) -> Result<u32, ActionParseError> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| ActionParseError(format!("invalid mark '{}'", s)))
+}
+
+// "90", "90s", "15m" or "2h"
+fn parse_duration(s: &str) -> Result<Duration, ActionParseError> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => s.split_at(split),
+        None => (s, "s"),
+    };
+    let value: u64 = number.parse()
+        .map_err(|_| ActionParseError(format!("invalid duration '{}'", s)))?;
+    let secs = match unit {
+        "s" => Some(value),
+        "m" => value.checked_mul(60),
+        "h" => value.checked_mul(3600),
+        _ => return Err(ActionParseError(format!("invalid duration unit in '{}'", s))),
+    };
+    let secs = secs.ok_or_else(|| ActionParseError(format!("duration '{}' is too long", s)))?;
+    if secs == 0 {
+        return Err(ActionParseError("quarantine duration must be positive".to_string()));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+// Outcome of running a packet through the rules: the final action plus
+// anything non-terminal rules attached along the way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub action: Action,
+    pub mark: Option<u32>,
+    pub qos_class: Option<u8>,
+    pub logged: bool,
+    // What decided the action. Only filled in for logged verdicts, drops
+    // when alerts are on, and traces, so the fast path doesn't pay for it.
+    pub reason: Option<VerdictReason>,
+    // Decided in monitor mode: `action` is what enforcing would have done,
+    // but the packet goes through untouched
+    pub monitored: bool,
+}
+
+impl Verdict {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            mark: None,
+            qos_class: None,
+            logged: false,
+            reason: None,
+            monitored: false,
+        }
+    }
+
+    // Whether the action lets the packet through
+    pub fn permits(&self) -> bool {
+        self.action.permits()
+    }
+
+    // Whether the packet actually goes through: permitted, or only
+    // monitored
+    pub fn passes(&self) -> bool {
+        self.monitored || self.permits()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerdictReason {
+    // A rule returned a terminal action, or its rate limit was exceeded
+    Rule { id: u64, name: String, chain: String },
+    // Evaluation fell off the end of a user-defined chain with a policy
+    ChainPolicy { chain: String },
+    // No rule decided; the rule set's default action applied
+    DefaultAction,
+    // The source host was quarantined, so no rule ran
+    Quarantined,
 }
 
-pub trait Filter: Send + Sync {
-    fn quick_match(&self, header: &PacketHeader) -> bool {
+impl fmt::Display for VerdictReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerdictReason::Rule { id, name, chain } => {
+                write!(f, "rule '{}' (#{}) in chain '{}'", name, id, chain)
+            }
+            VerdictReason::ChainPolicy { chain } => write!(f, "policy of chain '{}'", chain),
+            VerdictReason::DefaultAction => f.write_str("default action"),
+            VerdictReason::Quarantined => f.write_str("source host quarantined"),
+        }
+    }
+}
+
+// `Any` lets the config layer recognize the built-in rules it can export
+pub trait Filter: Any + Send + Sync {
+    fn quick_match(&self, _header: &PacketHeader) -> bool {
         true
     }
     fn check_packet(&self, packet: &Packet) -> Option<Action>;
+    // `check_packet` for packet traces. Filters that change state when they
+    // run, like rate limits taking a token, answer from their current
+    // state instead and leave it alone.
+    fn peek_packet(&self, packet: &Packet) -> Option<Action> {
+        self.check_packet(packet)
+    }
     fn name(&self) -> &str {
         "UnnamedFilter"
     }
     fn priority(&self) -> i32 {
         0
     }
+    // Chains any of the rule's actions can jump or goto, so loops are caught
+    // before a rule set is published
+    fn jump_targets(&self) -> Vec<String> {
+        Vec::new()
+    }
+    // Forgets per-key state idle for `threshold_secs`, for filters that keep
+    // it, like rate limits
+    fn cleanup(&self, _threshold_secs: u64) {}
+}
+
+// When a rule is in effect. Unset bounds are open, so the default is
+// "always".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleSchedule {
+    pub active_from: Option<SystemTime>,
+    pub expires_at: Option<SystemTime>,
 }
 
+impl RuleSchedule {
+    // Active already, gone `ttl` after `now` (see `Clock::wall_time`)
+    pub fn expiring_in(ttl: Duration, now: SystemTime) -> Self {
+        Self {
+            active_from: None,
+            expires_at: Some(now + ttl),
+        }
+    }
+
+    pub fn starting_at(mut self, time: SystemTime) -> Self {
+        self.active_from = Some(time);
+        self
+    }
+
+    pub fn until(mut self, time: SystemTime) -> Self {
+        self.expires_at = Some(time);
+        self
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.active_from.is_some() || self.expires_at.is_some()
+    }
+
+    pub fn is_active_at(&self, now: SystemTime) -> bool {
+        self.active_from.is_none_or(|from| from <= now) && !self.is_expired_at(now)
+    }
+
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|until| until <= now)
+    }
+
+    // Time left before expiry; zero once expired, `None` if it never expires
+    pub fn remaining_at(&self, now: SystemTime) -> Option<Duration> {
+        self.expires_at.map(|until| until.duration_since(now).unwrap_or_default())
+    }
+}
+
+// Entries are cheap to clone; clones share the filter and hit counters
+#[derive(Clone)]
 pub struct RuleEntry {
     pub id: u64,
-    pub filter: Box<dyn Filter>,
+    pub filter: Arc<dyn Filter>,
     pub enabled: bool,
-    pub hit_count: u64,
-}
\ No newline at end of file
+    pub counters: Arc<RuleCounters>,
+    pub chain: Arc<str>,
+    // `Filter::jump_targets` as of when the entry was created
+    pub targets: Arc<[String]>,
+    pub schedule: RuleSchedule,
+}
@@ -0,0 +1,452 @@
+use crate::domain::chain::{self, Chain, ChainError, DEFAULT_CHAIN};
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::packet::Packet;
+use crate::domain::rule::{Action, RuleEntry};
+use arc_swap::{ArcSwap, Guard};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+// Generations kept for rollback unless configured otherwise
+pub const DEFAULT_HISTORY_LIMIT: usize = 16;
+
+// Lookup tables built alongside a generation (see `application::compiler`).
+// For a packet they give the rules of a chain that could match it; every
+// other rule would return `None` from `check_packet`.
+pub trait RuleSetIndex: Send + Sync {
+    fn candidates(&self, chain: usize, packet: &Packet) -> Candidates;
+}
+
+// Builds the index for a generation; `ranges` slices `entries` into chains
+pub type Indexer = fn(&[RuleEntry], &[Range<usize>]) -> Box<dyn RuleSetIndex>;
+
+// Rules of one chain that could match a packet, by position in the chain
+pub struct Candidates {
+    chain: usize,
+    bits: Vec<u64>,
+}
+
+impl Candidates {
+    pub fn new(chain: usize, len: usize) -> Self {
+        Self {
+            chain,
+            bits: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    pub fn chain(&self) -> usize {
+        self.chain
+    }
+
+    // First candidate at or after `position`
+    pub fn next(&self, position: usize) -> Option<usize> {
+        let mut word = position / 64;
+        let mut bits = *self.bits.get(word)? & (!0u64 << (position % 64));
+        loop {
+            if bits != 0 {
+                return Some(word * 64 + bits.trailing_zeros() as usize);
+            }
+            word += 1;
+            bits = *self.bits.get(word)?;
+        }
+    }
+
+    pub fn insert(&mut self, position: u32) {
+        self.bits[position as usize / 64] |= 1 << (position % 64);
+    }
+
+    pub fn extend(&mut self, positions: &[u32]) {
+        for &position in positions {
+            self.insert(position);
+        }
+    }
+}
+
+// One immutable generation of the rule set: the default action, the
+// user-defined chains and the rules. Rules are grouped by chain (entry
+// chain first, then the others in creation order) and sorted by priority
+// within each (highest first, insertion order among equals).
+pub struct RuleSet {
+    generation: u64,
+    entries: Vec<RuleEntry>,
+    default_action: Action,
+    chains: Vec<Chain>,
+    // Slice of `entries` per chain; index 0 is the entry chain and index
+    // `i + 1` is `chains[i]`
+    ranges: Vec<Range<usize>>,
+    // Whether any rule has a schedule, so the packet path can skip reading
+    // the clock when none does
+    scheduled: bool,
+    // Lookup tables for the packet path, if compilation is on
+    index: Option<Box<dyn RuleSetIndex>>,
+    published_at: SystemTime,
+}
+
+// Mutable copy handed to `RuleSetCell::update`
+pub struct RuleSetDraft {
+    pub entries: Vec<RuleEntry>,
+    pub default_action: Action,
+    // User-defined chains; the entry chain is implicit
+    pub chains: Vec<Chain>,
+    generation: u64,
+}
+
+impl RuleSetDraft {
+    // Generation this draft becomes if it is published
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn has_chain(&self, name: &str) -> bool {
+        name == DEFAULT_CHAIN || self.chains.iter().any(|c| c.name == name)
+    }
+
+    pub fn add_chain(&mut self, chain: Chain) -> Result<(), ChainError> {
+        if chain.name == DEFAULT_CHAIN {
+            return Err(ChainError::BuiltIn(chain.name));
+        }
+        if self.has_chain(&chain.name) {
+            return Err(ChainError::DuplicateChain(chain.name));
+        }
+        self.chains.push(chain);
+        Ok(())
+    }
+
+    // Only empty chains nothing jumps to can go
+    pub fn remove_chain(&mut self, name: &str) -> Result<(), ChainError> {
+        let index = self.user_chain(name)?;
+        if self.entries.iter().any(|e| &*e.chain == name) {
+            return Err(ChainError::NotEmpty(name.to_string()));
+        }
+        if let Some(entry) = self.entries.iter().find(|e| e.targets.iter().any(|t| t == name)) {
+            return Err(ChainError::InUse {
+                chain: name.to_string(),
+                rule: entry.filter.name().to_string(),
+            });
+        }
+        self.chains.remove(index);
+        Ok(())
+    }
+
+    pub fn set_chain_policy(&mut self, name: &str, policy: Option<Action>) -> Result<(), ChainError> {
+        let index = self.user_chain(name)?;
+        self.chains[index].policy = policy;
+        Ok(())
+    }
+
+    fn user_chain(&self, name: &str) -> Result<usize, ChainError> {
+        if name == DEFAULT_CHAIN {
+            return Err(ChainError::BuiltIn(name.to_string()));
+        }
+        self.chains.iter().position(|c| c.name == name)
+            .ok_or_else(|| ChainError::UnknownChain(name.to_string()))
+    }
+
+    // Checks the chain graph: every rule sits in a known chain, jumps only
+    // lead to user-defined chains, policies end evaluation and no chain can
+    // reach itself. Disabled rules count, so enabling one never breaks it.
+    pub fn validate(&self) -> Result<(), ChainError> {
+        // The default may annotate (log, mark, ...), but not move evaluation
+        if chain::is_flow_control(&self.default_action) {
+            return Err(ChainError::InvalidPolicy {
+                chain: DEFAULT_CHAIN.to_string(),
+                policy: self.default_action.clone(),
+            });
+        }
+        for chain in &self.chains {
+            if let Some(policy) = chain.policy.as_ref().filter(|p| !chain::valid_policy(p)) {
+                return Err(ChainError::InvalidPolicy {
+                    chain: chain.name.clone(),
+                    policy: policy.clone(),
+                });
+            }
+        }
+        for entry in &self.entries {
+            if !self.has_chain(&entry.chain) {
+                return Err(ChainError::UnknownChain(entry.chain.to_string()));
+            }
+            for target in entry.targets.iter() {
+                if target == DEFAULT_CHAIN {
+                    return Err(ChainError::BuiltIn(target.clone()));
+                }
+                if !self.has_chain(target) {
+                    return Err(ChainError::UnknownChain(target.clone()));
+                }
+            }
+        }
+
+        let edges = self.entries.iter()
+            .flat_map(|entry| entry.targets.iter().map(move |target| (&*entry.chain, target.as_str())));
+        match chain::find_loop(edges) {
+            Some(path) => Err(ChainError::Loop(path)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RuleSet {
+    pub fn empty(default_action: Action, published_at: SystemTime) -> Self {
+        Self {
+            generation: 0,
+            entries: Vec::new(),
+            default_action,
+            chains: Vec::new(),
+            ranges: vec![Range::default()],
+            scheduled: false,
+            index: None,
+            published_at,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn default_action(&self) -> &Action {
+        &self.default_action
+    }
+
+    pub fn published_at(&self) -> SystemTime {
+        self.published_at
+    }
+
+    pub fn entries(&self) -> &[RuleEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, id: u64) -> Option<&RuleEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn has_schedules(&self) -> bool {
+        self.scheduled
+    }
+
+    pub fn index(&self) -> Option<&dyn RuleSetIndex> {
+        self.index.as_deref()
+    }
+
+    // User-defined chains in creation order
+    pub fn chains(&self) -> &[Chain] {
+        &self.chains
+    }
+
+    // Index for `chain_rules`/`chain_policy`; the entry chain is 0
+    pub fn chain_index(&self, name: &str) -> Option<usize> {
+        if name == DEFAULT_CHAIN {
+            return Some(0);
+        }
+        self.chains.iter().position(|c| c.name == name).map(|i| i + 1)
+    }
+
+    pub fn chain_rules(&self, index: usize) -> &[RuleEntry] {
+        &self.entries[self.ranges[index].clone()]
+    }
+
+    // The entry chain's policy is the default action
+    pub fn chain_policy(&self, index: usize) -> Option<&Action> {
+        match index {
+            0 => Some(&self.default_action),
+            i => self.chains[i - 1].policy.as_ref(),
+        }
+    }
+}
+
+// RCU-style holder for the live rule set. Readers load the current
+// generation without locking and keep using it for as long as they hold it;
+// writers copy it, edit the copy and publish it with an atomic swap.
+// Entries share their filter and counters across generations, so a copy
+// costs one pointer clone per rule, and so does keeping old generations
+// around for rollback.
+pub struct RuleSetCell {
+    current: ArcSwap<RuleSet>,
+    // Previously published generations, oldest first. Also serializes
+    // writers so concurrent updates don't overwrite each other.
+    writer: Mutex<VecDeque<Arc<RuleSet>>>,
+    history_limit: usize,
+    // Builds the lookup tables for each generation, if compilation is on
+    indexer: RwLock<Option<Indexer>>,
+    // Called with each newly published generation
+    listeners: RwLock<Vec<Listener>>,
+    // Stamps each generation's `published_at`
+    clock: RwLock<Arc<dyn Clock>>,
+}
+
+type Listener = Arc<dyn Fn(u64) + Send + Sync>;
+
+impl RuleSetCell {
+    pub fn new(default_action: Action) -> Self {
+        Self::with_history_limit(default_action, DEFAULT_HISTORY_LIMIT)
+    }
+
+    pub fn with_history_limit(default_action: Action, history_limit: usize) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            current: ArcSwap::from_pointee(RuleSet::empty(default_action, clock.wall_time())),
+            writer: Mutex::new(VecDeque::new()),
+            history_limit,
+            indexer: RwLock::new(None),
+            listeners: RwLock::new(Vec::new()),
+            clock: RwLock::new(clock),
+        }
+    }
+
+    // Used from the next published generation on. The empty generation
+    // the cell starts with is restamped if it is still the current one.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let _writer = self.writer.lock().unwrap();
+        let current = self.current.load_full();
+        if current.generation == 0 {
+            self.current.store(Arc::new(RuleSet::empty(current.default_action.clone(), clock.wall_time())));
+        }
+        *self.clock.write().unwrap() = clock;
+    }
+
+    // Calls `listener` after every publish, outside the writer lock, so it
+    // may read the cell or update it again. Listeners can run concurrently
+    // and generations can reach them out of order; `generation()` gives the
+    // latest.
+    pub fn subscribe(&self, listener: impl Fn(u64) + Send + Sync + 'static) {
+        self.listeners.write().unwrap().push(Arc::new(listener));
+    }
+
+    // Takes effect from the next published generation; `None` turns
+    // compilation off
+    pub fn set_indexer(&self, indexer: Option<Indexer>) {
+        *self.indexer.write().unwrap() = indexer;
+    }
+
+    pub fn is_compiled(&self) -> bool {
+        self.indexer.read().unwrap().is_some()
+    }
+
+    // Cheap, lock-free read for the packet path
+    pub fn load(&self) -> Guard<Arc<RuleSet>> {
+        self.current.load()
+    }
+
+    pub fn snapshot(&self) -> Arc<RuleSet> {
+        self.current.load_full()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.current.load().generation
+    }
+
+    // Runs `edit` on a copy of the current generation and publishes the
+    // result as the next one. Nothing is published if `edit` returns `None`.
+    pub fn update<R>(&self, edit: impl FnOnce(&mut RuleSetDraft) -> Option<R>) -> Option<R> {
+        let mut history = self.writer.lock().unwrap();
+        let current = self.current.load_full();
+        let mut draft = RuleSetDraft {
+            entries: current.entries.clone(),
+            default_action: current.default_action.clone(),
+            chains: current.chains.clone(),
+            generation: current.generation + 1,
+        };
+
+        let result = edit(&mut draft)?;
+        let generation = self.publish(&mut history, draft);
+        drop(history);
+        self.notify(generation);
+        Some(result)
+    }
+
+    // Like `update`, for edits that can fail. Returns the new generation.
+    pub fn try_update<E>(&self, edit: impl FnOnce(&mut RuleSetDraft) -> Result<(), E>) -> Result<u64, E> {
+        let mut error = None;
+        let generation = self.update(|draft| match edit(draft) {
+            Ok(()) => Some(draft.generation()),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(generation.expect("update publishes unless the edit fails")),
+        }
+    }
+
+    // Republishes the rules, chains and default action of an earlier
+    // generation as a new generation. Returns the new generation, or `None`
+    // if `generation` is no longer in the history.
+    pub fn rollback(&self, generation: u64) -> Option<u64> {
+        let mut history = self.writer.lock().unwrap();
+        let target = history.iter().find(|set| set.generation == generation)?;
+        let draft = RuleSetDraft {
+            entries: target.entries.clone(),
+            default_action: target.default_action.clone(),
+            chains: target.chains.clone(),
+            generation: 0,
+        };
+        let generation = self.publish(&mut history, draft);
+        drop(history);
+        self.notify(generation);
+        Some(generation)
+    }
+
+    // Earlier generations still available to `rollback`, oldest first
+    pub fn history(&self) -> Vec<Arc<RuleSet>> {
+        self.writer.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn notify(&self, generation: u64) {
+        // Cloned so a listener can subscribe another without deadlocking
+        let listeners = self.listeners.read().unwrap().clone();
+        for listener in listeners {
+            listener(generation);
+        }
+    }
+
+    fn publish(&self, history: &mut VecDeque<Arc<RuleSet>>, draft: RuleSetDraft) -> u64 {
+        let RuleSetDraft { mut entries, default_action, chains, .. } = draft;
+        let chain_of = |entry: &RuleEntry| match &*entry.chain {
+            DEFAULT_CHAIN => 0,
+            // Unchecked rules in a missing chain sort last and never run
+            name => chains.iter().position(|c| c.name == name).map_or(usize::MAX, |i| i + 1),
+        };
+        // Stable, so rules of equal priority keep their insertion order
+        entries.sort_by_cached_key(|entry| (chain_of(entry), std::cmp::Reverse(entry.filter.priority())));
+
+        let mut ranges = Vec::with_capacity(chains.len() + 1);
+        let mut start = 0;
+        for index in 0..=chains.len() {
+            let len = entries[start..].iter().take_while(|e| chain_of(e) == index).count();
+            ranges.push(start..start + len);
+            start += len;
+        }
+
+        let scheduled = entries.iter().any(|e| e.schedule.is_scheduled());
+        let index = self.indexer.read().unwrap().map(|indexer| indexer(&entries, &ranges));
+        let previous = self.current.load_full();
+        let generation = previous.generation + 1;
+        self.current.store(Arc::new(RuleSet {
+            generation,
+            entries,
+            default_action,
+            chains,
+            ranges,
+            scheduled,
+            index,
+            published_at: self.clock.read().unwrap().wall_time(),
+        }));
+
+        if self.history_limit > 0 {
+            if history.len() == self.history_limit {
+                history.pop_front();
+            }
+            history.push_back(previous);
+        }
+        generation
+    }
+}
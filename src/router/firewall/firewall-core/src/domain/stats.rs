@@ -1,5 +1,131 @@
-use crate::domain::rule::Action;
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::flow::FlowTableStats;
+use crate::domain::rule::{Action, Verdict};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Per-verdict breakdown. Non-terminal annotations (logged, marked) are
+// counted in addition to the packet's final action.
+#[derive(Debug, Clone, Default)]
+pub struct VerdictCounts {
+    pub allowed: u64,
+    pub dropped: u64,
+    pub rejected: u64,
+    pub dropped_logged: u64,
+    pub rate_limited: u64,
+    pub quarantined: u64,
+    pub logged: u64,
+    pub marked: u64,
+}
+
+// Hits on a single rule: packets whose match came from it, with the action
+// the rule produced broken down by verdict
+#[derive(Debug, Clone, Default)]
+pub struct RuleHits {
+    pub packets: u64,
+    pub bytes: u64,
+    pub last_hit: Option<SystemTime>,
+    pub verdicts: VerdictCounts,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleStats {
+    pub id: u64,
+    pub name: String,
+    pub hits: RuleHits,
+}
+
+// Counters bumped from the packet path with relaxed atomics, so recording a
+// hit never takes a lock. Readers may see a packet counted before its bytes.
+#[derive(Debug, Default)]
+pub struct RuleCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    // Milliseconds since the Unix epoch; 0 means never hit
+    last_hit: AtomicU64,
+    allowed: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    dropped_logged: AtomicU64,
+    rate_limited: AtomicU64,
+    quarantined: AtomicU64,
+    logged: AtomicU64,
+    marked: AtomicU64,
+}
+
+impl RuleCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `action` is what the rule produced. A rate-limit action only counts
+    // as rate limited once the limiter actually dropped the packet. `now`
+    // becomes the last hit time.
+    pub fn record(&self, bytes: usize, action: &Action, limited: bool, now: SystemTime) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.last_hit.store(now.max(1), Ordering::Relaxed);
+
+        let counter = match action {
+            Action::Allow => &self.allowed,
+            Action::Block => &self.dropped,
+            Action::Reject(_) => &self.rejected,
+            Action::DropLog => &self.dropped_logged,
+            Action::RateLimit(_) if limited => &self.rate_limited,
+            Action::RateLimit(_) => return,
+            Action::Quarantine(_) => &self.quarantined,
+            Action::Log => &self.logged,
+            Action::Mark(_) | Action::QosClass(_) => &self.marked,
+            Action::Jump(_) | Action::Goto(_) | Action::Return => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> RuleHits {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let last_hit = match load(&self.last_hit) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+        RuleHits {
+            packets: load(&self.packets),
+            bytes: load(&self.bytes),
+            last_hit,
+            verdicts: VerdictCounts {
+                allowed: load(&self.allowed),
+                dropped: load(&self.dropped),
+                rejected: load(&self.rejected),
+                dropped_logged: load(&self.dropped_logged),
+                rate_limited: load(&self.rate_limited),
+                quarantined: load(&self.quarantined),
+                logged: load(&self.logged),
+                marked: load(&self.marked),
+            },
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.packets,
+            &self.bytes,
+            &self.last_hit,
+            &self.allowed,
+            &self.dropped,
+            &self.rejected,
+            &self.dropped_logged,
+            &self.rate_limited,
+            &self.quarantined,
+            &self.logged,
+            &self.marked,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct FirewallStats {
@@ -8,51 +134,89 @@ pub struct FirewallStats {
     pub blocked_packets: u64,
     pub inspected_packets: u64,
     pub packets_per_second: f64,
-    start_time: std::time::Instant,
+    pub verdicts: VerdictCounts,
+    pub flow_table: FlowTableStats,
+    pub rules: Vec<RuleStats>,
+    start_time: Instant,
 }
 
 impl FirewallStats {
-    pub fn new() -> Self {
+    // Counting from `now`, which `packets_per_second` is measured from
+    pub fn new(now: Instant) -> Self {
         Self {
             total_packets: 0,
             allowed_packets: 0,
             blocked_packets: 0,
             inspected_packets: 0,
             packets_per_second: 0.0,
-            start_time: std::time::Instant::now(),
+            verdicts: VerdictCounts::default(),
+            flow_table: FlowTableStats::default(),
+            rules: Vec::new(),
+            start_time: now,
         }
     }
 }
 
 pub trait StatsCollector: Send + Sync {
-    fn record_packet(&self, action: &Action);
+    fn record_packet(&self, verdict: &Verdict);
     fn get_stats(&self) -> FirewallStats;
     fn reset(&self);
 }
 
 pub struct InMemoryStatsCollector {
     stats: Arc<Mutex<FirewallStats>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl InMemoryStatsCollector {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
-            stats: Arc::new(Mutex::new(FirewallStats::new())),
+            stats: Arc::new(Mutex::new(FirewallStats::new(clock.now()))),
+            clock,
         }
     }
 }
 
+impl Default for InMemoryStatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StatsCollector for InMemoryStatsCollector {
-    fn record_packet(&self, action: &Action) {
+    fn record_packet(&self, verdict: &Verdict) {
         let mut stats = self.stats.lock().unwrap();
         stats.total_packets += 1;
 
-        match action {
-            Action::Allow | Action::Log => stats.allowed_packets += 1,
-            Action::Block => stats.blocked_packets += 1,
+        if verdict.permits() {
+            stats.allowed_packets += 1;
+        } else {
+            stats.blocked_packets += 1;
+        }
+
+        let counts = &mut stats.verdicts;
+        match verdict.action {
+            Action::Allow | Action::Log | Action::Mark(_) | Action::QosClass(_) => counts.allowed += 1,
+            Action::Block => counts.dropped += 1,
+            Action::Reject(_) => counts.rejected += 1,
+            Action::DropLog => counts.dropped_logged += 1,
+            Action::RateLimit(_) => counts.rate_limited += 1,
+            Action::Quarantine(_) => counts.quarantined += 1,
+            // Never a final verdict
+            Action::Jump(_) | Action::Goto(_) | Action::Return => {}
+        }
+        if verdict.logged {
+            counts.logged += 1;
+        }
+        if verdict.mark.is_some() || verdict.qos_class.is_some() {
+            counts.marked += 1;
         }
 
-        let elapsed = stats.start_time.elapsed().as_secs_f64();
+        let elapsed = self.clock.now().saturating_duration_since(stats.start_time).as_secs_f64();
         if elapsed > 0.0 {
             stats.packets_per_second = stats.total_packets as f64 / elapsed;
         }
@@ -65,6 +229,117 @@ impl StatsCollector for InMemoryStatsCollector {
 
     fn reset(&self) {
         let mut stats = self.stats.lock().unwrap();
-        *stats = FirewallStats::new();
+        *stats = FirewallStats::new(self.clock.now());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::clock::ManualClock;
+    use crate::domain::rule::RejectWith;
+
+    fn verdict(action: Action) -> Verdict {
+        Verdict::new(action)
+    }
+
+    #[test]
+    fn verdicts_are_counted_by_final_action() {
+        let clock = Arc::new(ManualClock::new());
+        let collector = InMemoryStatsCollector::with_clock(clock.clone());
+        for action in [
+            Action::Allow,
+            Action::Block,
+            Action::Reject(RejectWith::TcpReset),
+            Action::DropLog,
+            Action::RateLimit("ssh".to_string()),
+            Action::Quarantine(Duration::from_secs(60)),
+            // Non-terminal actions only reach here as the default, and let
+            // the packet through
+            Action::Log,
+            Action::Mark(1),
+        ] {
+            collector.record_packet(&verdict(action));
+        }
+
+        let stats = collector.get_stats();
+        assert_eq!(stats.total_packets, 8);
+        assert_eq!((stats.allowed_packets, stats.blocked_packets), (3, 5));
+        let counts = &stats.verdicts;
+        assert_eq!(counts.allowed, 3);
+        assert_eq!(counts.dropped, 1);
+        assert_eq!(counts.rejected, 1);
+        assert_eq!(counts.dropped_logged, 1);
+        assert_eq!(counts.rate_limited, 1);
+        assert_eq!(counts.quarantined, 1);
+        // Annotations only count when the verdict carries them
+        assert_eq!((counts.logged, counts.marked), (0, 0));
+    }
+
+    #[test]
+    fn annotations_are_counted_on_top_of_the_action() {
+        let clock = Arc::new(ManualClock::new());
+        let collector = InMemoryStatsCollector::with_clock(clock.clone());
+        let mut marked = verdict(Action::Allow);
+        marked.mark = Some(7);
+        marked.logged = true;
+        collector.record_packet(&marked);
+        let mut classed = verdict(Action::Block);
+        classed.qos_class = Some(46);
+        collector.record_packet(&classed);
+        let mut logged_drop = verdict(Action::DropLog);
+        logged_drop.logged = true;
+        collector.record_packet(&logged_drop);
+        // A monitored drop still counts as the drop enforcing would give
+        let mut monitored = verdict(Action::Block);
+        monitored.monitored = true;
+        collector.record_packet(&monitored);
+
+        let stats = collector.get_stats();
+        assert_eq!((stats.allowed_packets, stats.blocked_packets), (1, 3));
+        let counts = &stats.verdicts;
+        assert_eq!((counts.allowed, counts.dropped, counts.dropped_logged), (1, 2, 1));
+        assert_eq!((counts.logged, counts.marked), (2, 2));
+
+        clock.advance(Duration::from_secs(2));
+        collector.record_packet(&verdict(Action::Allow));
+        assert_eq!(collector.get_stats().packets_per_second, 2.5);
+        collector.reset();
+        let stats = collector.get_stats();
+        assert_eq!((stats.total_packets, stats.verdicts.allowed), (0, 0));
+    }
+
+    #[test]
+    fn rule_counters_follow_the_rule_action() {
+        let counters = RuleCounters::new();
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(counters.snapshot().last_hit.is_none());
+
+        counters.record(100, &Action::Allow, false, at);
+        counters.record(40, &Action::Block, false, at);
+        counters.record(40, &Action::Reject(RejectWith::IcmpPortUnreachable), false, at);
+        counters.record(40, &Action::DropLog, false, at);
+        counters.record(40, &Action::Quarantine(Duration::from_secs(1)), false, at);
+        counters.record(10, &Action::Log, false, at);
+        counters.record(10, &Action::Mark(1), false, at);
+        counters.record(10, &Action::QosClass(46), false, at);
+        // Under the limit a rate-limit match is only a hit
+        counters.record(10, &Action::RateLimit("ssh".to_string()), false, at);
+        counters.record(10, &Action::RateLimit("ssh".to_string()), true, at);
+        counters.record(0, &Action::Jump("lan".to_string()), false, at);
+        counters.record(0, &Action::Return, false, at + Duration::from_secs(5));
+
+        let hits = counters.snapshot();
+        assert_eq!((hits.packets, hits.bytes), (12, 310));
+        assert_eq!(hits.last_hit, Some(at + Duration::from_secs(5)));
+        let counts = &hits.verdicts;
+        assert_eq!((counts.allowed, counts.dropped, counts.rejected), (1, 1, 1));
+        assert_eq!((counts.dropped_logged, counts.quarantined), (1, 1));
+        assert_eq!((counts.logged, counts.marked, counts.rate_limited), (1, 2, 1));
+
+        counters.reset();
+        let hits = counters.snapshot();
+        assert_eq!((hits.packets, hits.bytes, hits.verdicts.allowed), (0, 0, 0));
+        assert!(hits.last_hit.is_none());
+    }
+}
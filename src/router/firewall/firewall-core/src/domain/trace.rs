@@ -0,0 +1,69 @@
+use crate::domain::flow::ConnState;
+use crate::domain::rule::{Action, Verdict};
+use std::fmt;
+
+// One rule the packet reached, in evaluation order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub rule_id: u64,
+    pub rule_name: String,
+    pub chain: String,
+    pub enabled: bool,
+    // False outside its schedule (not started yet, or expired)
+    pub active: bool,
+    // `None` when the rule was skipped before the filter ran
+    pub quick_match: Option<bool>,
+    // What `check_packet` returned; `None` for no match or no check
+    pub action: Option<Action>,
+    // A rate-limit action whose limiter is out of allowance
+    pub rate_limited: bool,
+}
+
+impl TraceStep {
+    pub fn matched(&self) -> bool {
+        self.action.is_some()
+    }
+}
+
+// Dry run of a packet through the engine: the rules it visited and the
+// verdict it would get. `verdict.reason` is always set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketTrace {
+    // Rule set generation the packet was run against
+    pub generation: u64,
+    pub conn_state: ConnState,
+    pub steps: Vec<TraceStep>,
+    pub verdict: Verdict,
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: #{} '{}' ", self.chain, self.rule_id, self.rule_name)?;
+        if !self.enabled {
+            return f.write_str("disabled");
+        }
+        if !self.active {
+            return f.write_str("inactive");
+        }
+        match (self.quick_match, &self.action) {
+            (Some(false), _) => f.write_str("no quick match"),
+            (_, None) => f.write_str("no match"),
+            (_, Some(action)) if self.rate_limited => write!(f, "{} (over limit)", action),
+            (_, Some(action)) => write!(f, "{}", action),
+        }
+    }
+}
+
+impl fmt::Display for PacketTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "generation {}, {:?}", self.generation, self.conn_state)?;
+        for step in &self.steps {
+            writeln!(f, "  {}", step)?;
+        }
+        write!(f, "=> {}", self.verdict.action)?;
+        if let Some(reason) = &self.verdict.reason {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
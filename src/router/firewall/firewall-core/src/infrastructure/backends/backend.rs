@@ -0,0 +1,482 @@
+use crate::application::config::{FirewallConfig, RuleConfig, RuleKind};
+use crate::application::match_space;
+use crate::domain::rule::Action;
+use crate::rules::ip_rules::AddressMatch;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Enforces the firewall's policy in the kernel. Backends get the whole
+// policy each time it changes and work out the difference themselves, so
+// a rule is never half-applied. Whatever a backend can't express stays
+// with the userspace engine, which runs every rule regardless.
+pub trait FirewallBackend: Send + Sync {
+    fn name(&self) -> &str;
+    // What the backend can enforce natively
+    fn capabilities(&self) -> Capabilities;
+    // Makes the kernel enforce `policy`, replacing what was synced before
+    fn sync(&self, policy: &Policy) -> Result<SyncReport, BackendError>;
+    // Removes everything the backend installed
+    fn flush(&self) -> Result<(), BackendError>;
+}
+
+// One rule generation as backends see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub generation: u64,
+    pub config: FirewallConfig,
+    // Rules without a config form (see `RuleConfig::from_filter`), by name; only
+    // the engine can run them
+    pub userspace_only: Vec<String>,
+    // Wall time of the firewall's clock when the policy was taken; rule
+    // schedules are rendered as of then
+    pub now: SystemTime,
+}
+
+impl Policy {
+    pub fn new(generation: u64, config: FirewallConfig, now: SystemTime) -> Self {
+        Self { generation, config, userspace_only: Vec::new(), now }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub generation: u64,
+    // Whether anything in the kernel changed
+    pub changed: bool,
+    // Rules the backend doesn't enforce, by name
+    pub userspace: Vec<String>,
+    // Rules only approximated, and other remarks from the backend
+    pub notes: Vec<String>,
+}
+
+impl SyncReport {
+    // Report for `policy` with the rules `capabilities` can't take listed
+    // as staying in userspace
+    pub fn for_policy(policy: &Policy, capabilities: &Capabilities) -> Self {
+        let mut userspace = policy.userspace_only.clone();
+        userspace.extend(capabilities.userspace_rules(&policy.config));
+        Self { generation: policy.generation, changed: false, userspace, notes: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BackendError {
+    // A tool couldn't be started or fed its input
+    Spawn { program: String, error: Arc<io::Error> },
+    // A tool ran and failed. The restore tools and nft apply all or
+    // nothing, so for those the kernel is as it was.
+    Command { program: String, status: Option<i32>, stderr: String },
+    // The backend can't do what was asked
+    Unsupported(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Spawn { program, error } => write!(f, "failed to run {}: {}", program, error),
+            BackendError::Command { program, status: Some(code), stderr } => {
+                write!(f, "{} exited with status {}: {}", program, code, stderr.trim())
+            }
+            BackendError::Command { program, status: None, stderr } => {
+                write!(f, "{} was killed: {}", program, stderr.trim())
+            }
+            BackendError::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BackendError::Spawn { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// What a rule matches on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchKind {
+    Protocol,
+    SourceAddress,
+    DestinationAddress,
+    SourcePort,
+    DestinationPort,
+    ConnState,
+    TimeOfDay,
+    RateLimit,
+    // Expression terms beyond the above: negation, TCP flags, TTL, or time
+    // mixed into other conditions
+    Extended,
+}
+
+impl MatchKind {
+    pub const ALL: [MatchKind; 9] = [
+        MatchKind::Protocol,
+        MatchKind::SourceAddress,
+        MatchKind::DestinationAddress,
+        MatchKind::SourcePort,
+        MatchKind::DestinationPort,
+        MatchKind::ConnState,
+        MatchKind::TimeOfDay,
+        MatchKind::RateLimit,
+        MatchKind::Extended,
+    ];
+
+    // What rules of this kind match on
+    pub fn of(kind: &RuleKind) -> BTreeSet<MatchKind> {
+        let mut kinds = BTreeSet::new();
+        match kind {
+            RuleKind::IpPrefix { match_on, .. } => {
+                if *match_on != AddressMatch::Destination {
+                    kinds.insert(MatchKind::SourceAddress);
+                }
+                if *match_on != AddressMatch::Source {
+                    kinds.insert(MatchKind::DestinationAddress);
+                }
+            }
+            RuleKind::TimeWindow { .. } => {
+                kinds.insert(MatchKind::TimeOfDay);
+            }
+            RuleKind::RateLimit { .. } => {
+                kinds.insert(MatchKind::RateLimit);
+            }
+            // A kernel limit can't change its rate with the time of day
+            RuleKind::TimeRateLimit { .. } => {
+                kinds.extend([MatchKind::TimeOfDay, MatchKind::RateLimit, MatchKind::Extended]);
+            }
+            kind => {
+                for (space, _) in match_space::clauses(kind) {
+                    let fields = [
+                        (space.protocols.is_some(), MatchKind::Protocol),
+                        (space.sources.is_some(), MatchKind::SourceAddress),
+                        (space.destinations.is_some(), MatchKind::DestinationAddress),
+                        (space.source_ports.is_some(), MatchKind::SourcePort),
+                        (space.destination_ports.is_some(), MatchKind::DestinationPort),
+                        (space.states.is_some(), MatchKind::ConnState),
+                        (!space.exact, MatchKind::Extended),
+                    ];
+                    kinds.extend(fields.into_iter().filter(|(used, _)| *used).map(|(_, kind)| kind));
+                }
+            }
+        }
+        kinds
+    }
+}
+
+// What a rule does, without its arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ActionKind {
+    Allow,
+    Block,
+    Reject,
+    DropLog,
+    Log,
+    Mark,
+    QosClass,
+    RateLimit,
+    Quarantine,
+    Jump,
+    Goto,
+    Return,
+}
+
+impl ActionKind {
+    pub const ALL: [ActionKind; 12] = [
+        ActionKind::Allow,
+        ActionKind::Block,
+        ActionKind::Reject,
+        ActionKind::DropLog,
+        ActionKind::Log,
+        ActionKind::Mark,
+        ActionKind::QosClass,
+        ActionKind::RateLimit,
+        ActionKind::Quarantine,
+        ActionKind::Jump,
+        ActionKind::Goto,
+        ActionKind::Return,
+    ];
+
+    pub fn of(action: &Action) -> Self {
+        match action {
+            Action::Allow => ActionKind::Allow,
+            Action::Block => ActionKind::Block,
+            Action::Reject(_) => ActionKind::Reject,
+            Action::DropLog => ActionKind::DropLog,
+            Action::Log => ActionKind::Log,
+            Action::Mark(_) => ActionKind::Mark,
+            Action::QosClass(_) => ActionKind::QosClass,
+            Action::RateLimit(_) => ActionKind::RateLimit,
+            Action::Quarantine(_) => ActionKind::Quarantine,
+            Action::Jump(_) => ActionKind::Jump,
+            Action::Goto(_) => ActionKind::Goto,
+            Action::Return => ActionKind::Return,
+        }
+    }
+}
+
+// Match types and actions a backend enforces natively. Rules needing
+// anything else stay in userspace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    matches: BTreeSet<MatchKind>,
+    actions: BTreeSet<ActionKind>,
+}
+
+impl Capabilities {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn all() -> Self {
+        Self {
+            matches: MatchKind::ALL.into_iter().collect(),
+            actions: ActionKind::ALL.into_iter().collect(),
+        }
+    }
+
+    pub fn with_matches(mut self, kinds: impl IntoIterator<Item = MatchKind>) -> Self {
+        self.matches.extend(kinds);
+        self
+    }
+
+    pub fn without_matches(mut self, kinds: impl IntoIterator<Item = MatchKind>) -> Self {
+        for kind in kinds {
+            self.matches.remove(&kind);
+        }
+        self
+    }
+
+    pub fn with_actions(mut self, kinds: impl IntoIterator<Item = ActionKind>) -> Self {
+        self.actions.extend(kinds);
+        self
+    }
+
+    pub fn without_actions(mut self, kinds: impl IntoIterator<Item = ActionKind>) -> Self {
+        for kind in kinds {
+            self.actions.remove(&kind);
+        }
+        self
+    }
+
+    pub fn matches(&self) -> &BTreeSet<MatchKind> {
+        &self.matches
+    }
+
+    pub fn actions(&self) -> &BTreeSet<ActionKind> {
+        &self.actions
+    }
+
+    pub fn supports_match(&self, kind: MatchKind) -> bool {
+        self.matches.contains(&kind)
+    }
+
+    pub fn supports_action(&self, action: &Action) -> bool {
+        self.actions.contains(&ActionKind::of(action))
+    }
+
+    // Whether the backend can enforce the rule by itself
+    pub fn supports(&self, rule: &RuleConfig) -> bool {
+        MatchKind::of(&rule.kind).iter().all(|kind| self.supports_match(*kind))
+            && rule.kind.actions().into_iter().all(|action| self.supports_action(action))
+    }
+
+    // Names of the policy's rules the backend can't enforce
+    pub fn userspace_rules(&self, policy: &FirewallConfig) -> Vec<String> {
+        policy.rules.iter()
+            .filter(|rule| !self.supports(rule))
+            .map(|rule| rule.name.clone())
+            .collect()
+    }
+}
+
+// Installs nothing and leaves every rule to the engine
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullBackend;
+
+impl FirewallBackend for NullBackend {
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::none()
+    }
+
+    fn sync(&self, policy: &Policy) -> Result<SyncReport, BackendError> {
+        Ok(SyncReport::for_policy(policy, &Capabilities::none()))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+// Keeps every policy it's handed, for tests. Can be told to fail.
+#[derive(Debug, Default)]
+pub struct RecordingBackend {
+    capabilities: Capabilities,
+    state: Mutex<Recording>,
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    policies: Vec<Policy>,
+    flushes: usize,
+    failure: Option<BackendError>,
+}
+
+impl RecordingBackend {
+    // Claims to support everything
+    pub fn new() -> Self {
+        Self::with_capabilities(Capabilities::all())
+    }
+
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
+        Self { capabilities, state: Mutex::new(Recording::default()) }
+    }
+
+    // Policies synced so far, oldest first
+    pub fn policies(&self) -> Vec<Policy> {
+        self.state.lock().unwrap().policies.clone()
+    }
+
+    pub fn last_policy(&self) -> Option<Policy> {
+        self.state.lock().unwrap().policies.last().cloned()
+    }
+
+    pub fn generations(&self) -> Vec<u64> {
+        self.state.lock().unwrap().policies.iter().map(|p| p.generation).collect()
+    }
+
+    pub fn flushes(&self) -> usize {
+        self.state.lock().unwrap().flushes
+    }
+
+    // Syncs and flushes fail with `error` until it's cleared with `None`;
+    // failed syncs aren't recorded
+    pub fn set_failure(&self, error: Option<BackendError>) {
+        self.state.lock().unwrap().failure = error;
+    }
+}
+
+impl FirewallBackend for RecordingBackend {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    fn sync(&self, policy: &Policy) -> Result<SyncReport, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = &state.failure {
+            return Err(error.clone());
+        }
+        let changed = state.policies.last().is_none_or(|last| last.config != policy.config);
+        state.policies.push(policy.clone());
+        Ok(SyncReport { changed, ..SyncReport::for_policy(policy, &self.capabilities) })
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = &state.failure {
+            return Err(error.clone());
+        }
+        state.flushes += 1;
+        Ok(())
+    }
+}
+
+// Where a backend stands after the last sync
+#[derive(Debug, Clone)]
+pub struct BackendStatus {
+    pub backend: String,
+    // Last generation synced successfully
+    pub generation: Option<u64>,
+    pub report: Option<SyncReport>,
+    // Error from the last attempt, if it failed
+    pub error: Option<BackendError>,
+}
+
+// Hands rule generations to a firewall's backends, one sync at a time.
+// Generations published while a sync runs are folded into the next one,
+// so a burst of changes costs one sync per backend rather than one each.
+pub struct BackendSync {
+    backends: Vec<Arc<dyn FirewallBackend>>,
+    status: Mutex<Vec<BackendStatus>>,
+}
+
+impl BackendSync {
+    pub fn new(backends: Vec<Arc<dyn FirewallBackend>>) -> Self {
+        let status = backends.iter()
+            .map(|backend| BackendStatus {
+                backend: backend.name().to_string(),
+                generation: None,
+                report: None,
+                error: None,
+            })
+            .collect();
+        Self { backends, status: Mutex::new(status) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    // Syncs the backends that haven't seen the policy's generation yet.
+    // `policy` is built under the lock, so it's the latest one.
+    pub fn sync_new(&self, policy: impl FnOnce() -> Policy) {
+        let mut status = self.status.lock().unwrap();
+        let policy = policy();
+        for (backend, status) in self.backends.iter().zip(status.iter_mut()) {
+            if status.generation.is_some_and(|g| g >= policy.generation) {
+                continue;
+            }
+            Self::sync_one(backend.as_ref(), status, &policy);
+        }
+    }
+
+    // Syncs every backend, e.g. to retry after a failure or to pick up
+    // rule schedules that started or ended
+    pub fn sync_all(&self, policy: &Policy) -> Vec<BackendStatus> {
+        let mut status = self.status.lock().unwrap();
+        for (backend, status) in self.backends.iter().zip(status.iter_mut()) {
+            Self::sync_one(backend.as_ref(), status, policy);
+        }
+        status.clone()
+    }
+
+    pub fn flush_all(&self) -> Vec<(String, Result<(), BackendError>)> {
+        let mut status = self.status.lock().unwrap();
+        self.backends.iter().zip(status.iter_mut())
+            .map(|(backend, status)| {
+                let result = backend.flush();
+                if result.is_ok() {
+                    status.generation = None;
+                    status.report = None;
+                }
+                (backend.name().to_string(), result)
+            })
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<BackendStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn sync_one(backend: &dyn FirewallBackend, status: &mut BackendStatus, policy: &Policy) {
+        match backend.sync(policy) {
+            Ok(report) => {
+                status.generation = Some(policy.generation);
+                status.report = Some(report);
+                status.error = None;
+            }
+            Err(error) => status.error = Some(error),
+        }
+    }
+}
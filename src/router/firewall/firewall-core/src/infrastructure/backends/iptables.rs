@@ -0,0 +1,1146 @@
+use crate::application::config::{FirewallConfig, RuleConfig, RuleKind};
+use crate::application::match_space::{self, Space};
+use crate::domain::chain::DEFAULT_CHAIN;
+use crate::domain::flow::ConnState;
+use crate::domain::packet::Protocol;
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rate_limiter::{RateLimitAlgorithm, RateLimitConfig, RateLimitKeyType};
+use crate::domain::rule::{Action, RejectWith};
+use crate::rules::ip_rules::AddressMatch;
+use crate::rules::time_rules::TimeWindow;
+use super::backend::{ActionKind, BackendError, Capabilities, FirewallBackend, MatchKind, Policy, SyncReport};
+use super::render::{active_rules, disjoint, identifier, AddressRange};
+use super::runner::{run_checked, CommandOutput, CommandRunner, SystemRunner};
+use chrono::{NaiveTime, Timelike};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Every ipset this backend creates is named fw4-* or fw6-*, by family
+const SET_PREFIX: &str = "fw";
+// Longest chain name iptables takes
+const MAX_CHAIN_LEN: usize = 28;
+// ipset names are up to 31 characters; two are kept back for the suffix of
+// the set a new version is built in before it's swapped in
+const MAX_SET_LEN: usize = 29;
+// hashlimit table names are up to 15 characters
+const MAX_HASHLIMIT_LEN: usize = 15;
+// Ports one multiport match takes, a range counting as two
+const MULTIPORT_SLOTS: usize = 15;
+// Comments are kept well under the 256 character limit
+const MAX_TAG_NAME_LEN: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn iptables(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "iptables",
+            IpFamily::V6 => "ip6tables",
+        }
+    }
+
+    fn restore(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "iptables-restore",
+            IpFamily::V6 => "ip6tables-restore",
+        }
+    }
+
+    fn save(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "iptables-save",
+            IpFamily::V6 => "ip6tables-save",
+        }
+    }
+
+    fn ipset_family(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "inet",
+            IpFamily::V6 => "inet6",
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "4",
+            IpFamily::V6 => "6",
+        }
+    }
+
+    // Prefix of the family's set names
+    fn set_prefix(self) -> String {
+        format!("{}{}-", SET_PREFIX, self.suffix())
+    }
+
+    fn has(self, prefix: &IpPrefix) -> bool {
+        prefix.is_ipv4() == (self == IpFamily::V4)
+    }
+}
+
+// What `sync` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IptUpdate {
+    // Same ruleset as last time; nothing ran
+    Unchanged,
+    // Only ipset contents changed; new versions were swapped in
+    Ipsets,
+    // The chains were rewritten
+    Replaced,
+}
+
+// What `reconcile` found different from the policy in the kernel. Each
+// entry reads like `ip6tables FIREWALL-web`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IptDrift {
+    // Chains missing, or whose rules aren't the ones this backend wrote
+    pub chains: Vec<String>,
+    // Hook chains lacking the jump into the backend's chain
+    pub jumps: Vec<String>,
+    // Sets missing or holding other addresses
+    pub ipsets: Vec<String>,
+    // Chains and sets of this backend the policy no longer has
+    pub stale: Vec<String>,
+}
+
+impl IptDrift {
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty() && self.jumps.is_empty() && self.ipsets.is_empty() && self.stale.is_empty()
+    }
+}
+
+impl fmt::Display for IptDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no drift");
+        }
+        let parts = [
+            ("chains", &self.chains),
+            ("jumps", &self.jumps),
+            ("ipsets", &self.ipsets),
+            ("stale", &self.stale),
+        ];
+        let mut first = true;
+        for (label, items) in parts {
+            if items.is_empty() {
+                continue;
+            }
+            if !first {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", label, items.join(", "))?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+// Keeps the policy in legacy iptables. Everything lives in one chain of
+// the filter table (FIREWALL unless set) and chains named after it, which
+// the hook chain jumps to; rules, chains and sets belonging to other tools
+// are never touched. Chains are written with `iptables-restore --noflush`,
+// one transaction per family, and large prefix lists go into ipsets that
+// are swapped in whole. Each rule carries a comment tagging it with its
+// rule's name and a digest, which is how `reconcile` tells its own rules
+// from edits made behind its back.
+//
+// A default action of allow returns to the hook chain rather than
+// accepting, so whatever else is there still runs.
+pub struct IpTablesBackend {
+    chain: String,
+    hook: String,
+    ipset_threshold: usize,
+    runner: Arc<dyn CommandRunner>,
+    applied: Mutex<Option<IptRuleset>>,
+}
+
+impl IpTablesBackend {
+    pub fn new() -> Self {
+        Self {
+            chain: "FIREWALL".to_string(),
+            hook: "INPUT".to_string(),
+            ipset_threshold: 8,
+            runner: Arc::new(SystemRunner),
+            applied: Mutex::new(None),
+        }
+    }
+
+    pub fn with_chain(mut self, chain: impl Into<String>) -> Self {
+        self.chain = chain.into();
+        self
+    }
+
+    // Built-in chain that jumps to ours: INPUT, FORWARD or OUTPUT
+    pub fn with_hook(mut self, hook: impl Into<String>) -> Self {
+        self.hook = hook.into();
+        self
+    }
+
+    // Prefix lists longer than this go into an ipset instead of a rule
+    // per prefix
+    pub fn with_ipset_threshold(mut self, threshold: usize) -> Self {
+        self.ipset_threshold = threshold;
+        self
+    }
+
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    // Rules outside their schedule at `now` are left out
+    pub fn render_at(&self, policy: &FirewallConfig, now: SystemTime) -> IptRuleset {
+        Renderer::new(self, policy).render(now)
+    }
+
+    // Applies the policy, doing as little as the change since the last
+    // sync allows. The first sync reconciles against the kernel instead,
+    // so leftovers from an earlier run are cleaned up.
+    pub fn sync_config(&self, policy: &FirewallConfig, now: SystemTime) -> Result<IptUpdate, BackendError> {
+        self.sync_ruleset(self.render_at(policy, now))
+    }
+
+    fn sync_ruleset(&self, ruleset: IptRuleset) -> Result<IptUpdate, BackendError> {
+        let mut applied = self.applied.lock().unwrap();
+        let Some(last) = applied.as_ref() else {
+            drop(applied);
+            let drift = self.reconcile_ruleset(ruleset)?;
+            return Ok(if drift.is_empty() { IptUpdate::Unchanged } else { IptUpdate::Replaced });
+        };
+
+        if *last == ruleset {
+            return Ok(IptUpdate::Unchanged);
+        }
+        let update = if last.same_layout(&ruleset) {
+            let changed: HashSet<&str> = ruleset.ipsets.iter()
+                .filter(|(name, set)| last.ipsets.get(*name) != Some(set))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            self.run("ipset", &["-exist", "restore"], Some(&ruleset.ipset_script(|name| changed.contains(name))))?;
+            IptUpdate::Ipsets
+        } else {
+            let stale = Stale {
+                v4: last.chain_names(IpFamily::V4).filter(|c| !ruleset.has_chain(IpFamily::V4, c)).collect(),
+                v6: last.chain_names(IpFamily::V6).filter(|c| !ruleset.has_chain(IpFamily::V6, c)).collect(),
+                ipsets: last.ipsets.keys().filter(|s| !ruleset.ipsets.contains_key(*s)).cloned().collect(),
+            };
+            self.apply(&ruleset, &stale)?;
+            IptUpdate::Replaced
+        };
+        *applied = Some(ruleset);
+        Ok(update)
+    }
+
+    // Reads what's in the kernel and rewrites whatever doesn't match the
+    // policy: missing or edited chains, a missing jump, sets with other
+    // contents, and chains or sets the policy dropped
+    pub fn reconcile(&self, policy: &FirewallConfig, now: SystemTime) -> Result<IptDrift, BackendError> {
+        self.reconcile_ruleset(self.render_at(policy, now))
+    }
+
+    fn reconcile_ruleset(&self, ruleset: IptRuleset) -> Result<IptDrift, BackendError> {
+        let mut applied = self.applied.lock().unwrap();
+        let state = self.read_state()?;
+        let mut drift = IptDrift::default();
+        let mut stale = Stale::default();
+
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let kernel = state.family(family);
+            for chain in ruleset.chains(family) {
+                let tags: Vec<&str> = chain.rules.iter().map(|r| r.tag.as_str()).collect();
+                let matches = kernel.chains.get(&chain.name)
+                    .is_some_and(|found| found.iter().map(|t| t.as_deref()).eq(tags.iter().map(|t| Some(*t))));
+                if !matches {
+                    drift.chains.push(format!("{} {}", family.iptables(), chain.name));
+                }
+            }
+            for name in kernel.chains.keys() {
+                if !ruleset.has_chain(family, name) {
+                    drift.stale.push(format!("{} {}", family.iptables(), name));
+                    match family {
+                        IpFamily::V4 => stale.v4.push(name.clone()),
+                        IpFamily::V6 => stale.v6.push(name.clone()),
+                    }
+                }
+            }
+            if !kernel.jump {
+                drift.jumps.push(format!("{} {}", family.iptables(), self.hook));
+            }
+        }
+
+        for (name, set) in &ruleset.ipsets {
+            let current = state.ipsets.get(name);
+            let wrong = match current {
+                None => true,
+                // Dynamic contents are the kernel's business
+                Some(_) if set.timeout => false,
+                Some(elements) => *elements != set.elements.iter().map(|e| normalized(e)).collect(),
+            };
+            if wrong {
+                drift.ipsets.push(name.clone());
+            }
+        }
+        for name in state.ipsets.keys() {
+            if !ruleset.ipsets.contains_key(name) {
+                drift.stale.push(format!("ipset {}", name));
+                stale.ipsets.push(name.clone());
+            }
+        }
+
+        if !drift.is_empty() {
+            self.apply(&ruleset, &stale)?;
+        }
+        *applied = Some(ruleset);
+        Ok(drift)
+    }
+
+    // Removes the jump, the chains and the sets
+    pub fn flush(&self) -> Result<(), BackendError> {
+        let mut applied = self.applied.lock().unwrap();
+        let state = self.read_state()?;
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let kernel = state.family(family);
+            if kernel.jump {
+                self.run(family.iptables(), &["-D", &self.hook, "-j", &self.chain], None)?;
+            }
+            if kernel.chains.is_empty() {
+                continue;
+            }
+            let mut payload = String::from("*filter\n");
+            for name in kernel.chains.keys() {
+                payload.push_str(&format!(":{} - [0:0]\n", name));
+            }
+            for name in kernel.chains.keys() {
+                payload.push_str(&format!("-X {}\n", name));
+            }
+            payload.push_str("COMMIT\n");
+            self.run(family.restore(), &["--noflush"], Some(&payload))?;
+        }
+        for name in state.ipsets.keys() {
+            self.run("ipset", &["destroy", name], None)?;
+        }
+        *applied = None;
+        Ok(())
+    }
+
+    fn apply(&self, ruleset: &IptRuleset, stale: &Stale) -> Result<(), BackendError> {
+        // Sets first: rules can't refer to sets that don't exist
+        if !ruleset.ipsets.is_empty() {
+            self.run("ipset", &["-exist", "restore"], Some(&ruleset.ipset_script(|_| true)))?;
+        }
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let stale_chains = match family {
+                IpFamily::V4 => &stale.v4,
+                IpFamily::V6 => &stale.v6,
+            };
+            self.run(family.restore(), &["--noflush"], Some(&ruleset.restore_script(family, stale_chains)))?;
+            // A failed check just means the jump is missing
+            let check = self.runner.run(family.iptables(), &["-C", &self.hook, "-j", &self.chain], None)
+                .map_err(|error| BackendError::Spawn { program: family.iptables().to_string(), error: Arc::new(error) })?;
+            if !check.success() {
+                self.run(family.iptables(), &["-I", &self.hook, "1", "-j", &self.chain], None)?;
+            }
+        }
+        // Last: sets can't be destroyed while rules refer to them
+        for name in &stale.ipsets {
+            self.run("ipset", &["destroy", name], None)?;
+        }
+        Ok(())
+    }
+
+    fn read_state(&self) -> Result<KernelState, BackendError> {
+        let mut state = KernelState::default();
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let saved = self.run(family.save(), &["-t", "filter"], None)?;
+            let kernel = match family {
+                IpFamily::V4 => &mut state.v4,
+                IpFamily::V6 => &mut state.v6,
+            };
+            let jump = format!("-A {} -j {}", self.hook, self.chain);
+            for line in saved.stdout.lines() {
+                let line = line.trim();
+                if line == jump {
+                    kernel.jump = true;
+                } else if let Some(declaration) = line.strip_prefix(':') {
+                    let name = declaration.split_whitespace().next().unwrap_or("");
+                    if self.owns_chain(name) {
+                        kernel.chains.entry(name.to_string()).or_default();
+                    }
+                } else if let Some(rule) = line.strip_prefix("-A ") {
+                    let name = rule.split_whitespace().next().unwrap_or("");
+                    if self.owns_chain(name) {
+                        kernel.chains.entry(name.to_string()).or_default().push(comment(rule));
+                    }
+                }
+            }
+        }
+
+        let saved = self.run("ipset", &["save"], None)?;
+        for line in saved.stdout.lines() {
+            let mut words = line.split_whitespace();
+            let (Some(command), Some(name)) = (words.next(), words.next()) else {
+                continue;
+            };
+            // Half-built sets from an interrupted swap are rebuilt anyway
+            let ours = [IpFamily::V4, IpFamily::V6].iter().any(|f| name.starts_with(&f.set_prefix()));
+            if !ours || name.ends_with("-t") {
+                continue;
+            }
+            match command {
+                "create" => {
+                    state.ipsets.entry(name.to_string()).or_default();
+                }
+                "add" => {
+                    if let Some(element) = words.next() {
+                        state.ipsets.entry(name.to_string()).or_default().insert(normalized(element));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    fn owns_chain(&self, name: &str) -> bool {
+        name == self.chain || name.strip_prefix(&self.chain).is_some_and(|rest| rest.starts_with('-'))
+    }
+
+    fn run(&self, program: &str, args: &[&str], stdin: Option<&str>) -> Result<CommandOutput, BackendError> {
+        run_checked(self.runner.as_ref(), program, args, stdin)
+    }
+}
+
+impl Default for IpTablesBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FirewallBackend for IpTablesBackend {
+    fn name(&self) -> &str {
+        "iptables"
+    }
+
+    // Expression rules only render when every clause is exact, and QoS
+    // classes would need the mangle table
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::all()
+            .without_matches([MatchKind::Extended])
+            .without_actions([ActionKind::QosClass])
+    }
+
+    fn sync(&self, policy: &Policy) -> Result<SyncReport, BackendError> {
+        let ruleset = self.render_at(&policy.config, policy.now);
+        let mut report = SyncReport::for_policy(policy, &self.capabilities());
+        report.notes = ruleset.notes().to_vec();
+        report.changed = self.sync_ruleset(ruleset)? != IptUpdate::Unchanged;
+        Ok(report)
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        IpTablesBackend::flush(self)
+    }
+}
+
+// Chains and sets to delete along with an apply
+#[derive(Debug, Default)]
+struct Stale {
+    v4: Vec<String>,
+    v6: Vec<String>,
+    ipsets: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct KernelState {
+    v4: KernelTables,
+    v6: KernelTables,
+    ipsets: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl KernelState {
+    fn family(&self, family: IpFamily) -> &KernelTables {
+        match family {
+            IpFamily::V4 => &self.v4,
+            IpFamily::V6 => &self.v6,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct KernelTables {
+    // Our chains with the comment of each rule in order
+    chains: BTreeMap<String, Vec<Option<String>>>,
+    jump: bool,
+}
+
+// Text after `--comment` in an iptables-save rule
+fn comment(rule: &str) -> Option<String> {
+    let rest = &rule[rule.find("--comment ")? + "--comment ".len()..];
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().map(str::to_string),
+        None => rest.split_whitespace().next().map(str::to_string),
+    }
+}
+
+// ipset prints hosts without a length
+fn normalized(element: &str) -> String {
+    element.strip_suffix("/32")
+        .or_else(|| element.strip_suffix("/128"))
+        .unwrap_or(element)
+        .to_string()
+}
+
+// A rendered policy: the chains for each family and the ipsets they use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IptRuleset {
+    v4: Vec<IptChain>,
+    v6: Vec<IptChain>,
+    ipsets: BTreeMap<String, Ipset>,
+    notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IptChain {
+    name: String,
+    rules: Vec<IptRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IptRule {
+    matches: String,
+    target: String,
+    // `rule:digest`, stored in the rule's comment
+    tag: String,
+}
+
+impl IptRule {
+    fn new(rule: &str, matches: String, target: String) -> Self {
+        let name: String = identifier(rule).chars().take(MAX_TAG_NAME_LEN).collect();
+        let tag = format!("{}:{:08x}", name, fnv1a(&format!("{} {}", matches, target)));
+        Self { matches, target, tag }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ipset {
+    family: IpFamily,
+    // `hash:net` for prefix lists, `hash:ip` for quarantined hosts
+    kind: &'static str,
+    // Entries expire on their own and are added by the kernel
+    timeout: bool,
+    elements: Vec<String>,
+}
+
+impl IptRuleset {
+    // Rules that were left out or only approximated, and why
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    // Input for `iptables-restore --noflush` (or ip6tables-restore).
+    // Declaring a chain that exists flushes it, so the payload replaces
+    // our chains and nothing else.
+    pub fn restore_payload(&self, family: IpFamily) -> String {
+        self.restore_script(family, &[])
+    }
+
+    // Input for `ipset -exist restore`: each set is built under a
+    // temporary name and swapped in, so rules never see it half-filled
+    pub fn ipset_payload(&self) -> String {
+        self.ipset_script(|_| true)
+    }
+
+    fn chains(&self, family: IpFamily) -> &[IptChain] {
+        match family {
+            IpFamily::V4 => &self.v4,
+            IpFamily::V6 => &self.v6,
+        }
+    }
+
+    fn chain_names(&self, family: IpFamily) -> impl Iterator<Item = String> + '_ {
+        self.chains(family).iter().map(|c| c.name.clone())
+    }
+
+    fn has_chain(&self, family: IpFamily, name: &str) -> bool {
+        self.chains(family).iter().any(|c| c.name == name)
+    }
+
+    // Same chains and set declarations; set contents may differ
+    fn same_layout(&self, other: &IptRuleset) -> bool {
+        self.v4 == other.v4
+            && self.v6 == other.v6
+            && self.ipsets.len() == other.ipsets.len()
+            && self.ipsets.iter().zip(&other.ipsets).all(|((a_name, a), (b_name, b))| {
+                a_name == b_name && a.family == b.family && a.kind == b.kind && a.timeout == b.timeout
+            })
+    }
+
+    fn restore_script(&self, family: IpFamily, stale: &[String]) -> String {
+        let mut out = String::new();
+        for note in &self.notes {
+            out.push_str(&format!("# {}\n", note));
+        }
+        out.push_str("*filter\n");
+        for chain in self.chains(family) {
+            out.push_str(&format!(":{} - [0:0]\n", chain.name));
+        }
+        for name in stale {
+            out.push_str(&format!(":{} - [0:0]\n", name));
+        }
+        for chain in self.chains(family) {
+            for rule in &chain.rules {
+                out.push_str(&format!("-A {} ", chain.name));
+                if !rule.matches.is_empty() {
+                    out.push_str(&rule.matches);
+                    out.push(' ');
+                }
+                out.push_str(&format!("-m comment --comment {} {}\n", rule.tag, rule.target));
+            }
+        }
+        for name in stale {
+            out.push_str(&format!("-X {}\n", name));
+        }
+        out.push_str("COMMIT\n");
+        out
+    }
+
+    fn ipset_script(&self, include: impl Fn(&str) -> bool) -> String {
+        let mut out = String::new();
+        for (name, set) in self.ipsets.iter().filter(|(name, _)| include(name)) {
+            let mut declaration = format!("{} family {}", set.kind, set.family.ipset_family());
+            if set.timeout {
+                declaration.push_str(" timeout 0");
+            }
+            out.push_str(&format!("create {} {}\n", name, declaration));
+            if set.timeout {
+                continue;
+            }
+            let building = format!("{}-t", name);
+            out.push_str(&format!("create {} {}\n", building, declaration));
+            out.push_str(&format!("flush {}\n", building));
+            for element in &set.elements {
+                out.push_str(&format!("add {} {}\n", building, element));
+            }
+            out.push_str(&format!("swap {} {}\n", building, name));
+            out.push_str(&format!("destroy {}\n", building));
+        }
+        out
+    }
+}
+
+// Conditions of one iptables rule
+#[derive(Debug, Clone, Default)]
+struct RuleMatch {
+    protocol: Option<String>,
+    parts: Vec<String>,
+}
+
+// One rule an action renders to, with what it needs of the match
+struct Statement {
+    protocol: Option<&'static str>,
+    matches: Option<String>,
+    target: String,
+}
+
+impl Statement {
+    fn target(target: impl Into<String>) -> Self {
+        Self { protocol: None, matches: None, target: target.into() }
+    }
+}
+
+struct Renderer<'a> {
+    backend: &'a IpTablesBackend,
+    policy: &'a FirewallConfig,
+    family: IpFamily,
+    ruleset: IptRuleset,
+    chain_names: BTreeMap<String, String>,
+    // Set names taken in the current family's pass
+    names: HashSet<String>,
+    // Limiter to its hashlimit table, shared by both families
+    hashlimits: HashMap<String, String>,
+    quarantine: bool,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(backend: &'a IpTablesBackend, policy: &'a FirewallConfig) -> Self {
+        Self {
+            backend,
+            policy,
+            family: IpFamily::V4,
+            ruleset: IptRuleset { v4: Vec::new(), v6: Vec::new(), ipsets: BTreeMap::new(), notes: Vec::new() },
+            chain_names: BTreeMap::new(),
+            names: HashSet::new(),
+            hashlimits: HashMap::new(),
+            quarantine: false,
+        }
+    }
+
+    fn render(mut self, now: SystemTime) -> IptRuleset {
+        let entry = &self.backend.chain;
+        let mut taken: HashSet<String> = HashSet::from([entry.clone()]);
+        self.chain_names.insert(DEFAULT_CHAIN.to_string(), entry.clone());
+        for name in self.policy.chains.keys() {
+            let chain = unique(&mut taken, &format!("{}-{}", entry, identifier(name)), MAX_CHAIN_LEN);
+            self.chain_names.insert(name.clone(), chain);
+        }
+
+        for family in [IpFamily::V4, IpFamily::V6] {
+            self.family = family;
+            self.names.clear();
+            self.quarantine = false;
+
+            let mut chains = Vec::new();
+            let names = std::iter::once(DEFAULT_CHAIN).chain(self.policy.chains.keys().map(String::as_str));
+            for chain in names {
+                let mut rules = Vec::new();
+                for rule in active_rules(self.policy, chain, now) {
+                    if let Err(reason) = self.rule(rule, &mut rules) {
+                        self.note(format!("rule '{}' in chain '{}' left out: {}", rule.name, rule.chain, reason));
+                    }
+                }
+                self.tail(chain, &mut rules);
+                chains.push(IptChain { name: self.chain_names[chain].clone(), rules });
+            }
+
+            // Quarantined hosts are dropped before any rule runs
+            if self.quarantine {
+                let matches = format!("-m set --match-set {} src", self.quarantine_set());
+                chains[0].rules.insert(0, IptRule::new("quarantine", matches, "-j DROP".to_string()));
+            }
+            match family {
+                IpFamily::V4 => self.ruleset.v4 = chains,
+                IpFamily::V6 => self.ruleset.v6 = chains,
+            }
+        }
+        self.ruleset
+    }
+
+    fn note(&mut self, note: String) {
+        if !self.ruleset.notes.contains(&note) {
+            self.ruleset.notes.push(note);
+        }
+    }
+
+    // What happens at the end of a chain
+    fn tail(&mut self, chain: &str, rules: &mut Vec<IptRule>) {
+        let action = if chain == DEFAULT_CHAIN {
+            match &self.policy.default_action {
+                // Back to the hook chain and whatever else is there
+                Action::Allow => return,
+                action => action.clone(),
+            }
+        } else {
+            match self.policy.chains.get(chain).and_then(|c| c.policy.clone()) {
+                Some(policy) => policy,
+                None => return,
+            }
+        };
+        let label = if chain == DEFAULT_CHAIN { "default".to_string() } else { format!("{}-policy", chain) };
+        match self.statements(&action, &label) {
+            Ok(statements) => self.emit(rules, &label, &RuleMatch::default(), &statements),
+            Err(reason) => self.note(format!("policy of chain '{}' left out: {}", chain, reason)),
+        }
+    }
+
+    fn rule(&mut self, rule: &RuleConfig, rules: &mut Vec<IptRule>) -> Result<(), String> {
+        let base = identifier(&rule.name);
+        let mut out = Vec::new();
+        match &rule.kind {
+            RuleKind::IpPrefix { prefixes, match_on } => {
+                self.prefix_table(&base, &rule.name, prefixes, *match_on, &mut out)?;
+            }
+            RuleKind::TimeWindow { windows, action } => {
+                let statements = self.statements(action, &rule.name)?;
+                for window in windows {
+                    let m = RuleMatch { protocol: None, parts: vec![time_match(window)] };
+                    self.emit(&mut out, &rule.name, &m, &statements);
+                }
+            }
+            RuleKind::RateLimit { limit } => {
+                let statements = vec![self.limiter(&rule.name, limit, &format!("rule '{}'", rule.name))];
+                self.emit(&mut out, &rule.name, &RuleMatch::default(), &statements);
+            }
+            RuleKind::TimeRateLimit { .. } => {
+                return Err("the limit changes with the time of day, which iptables limits can't follow".to_string());
+            }
+            kind => {
+                let clauses = match_space::clauses(kind);
+                if clauses.iter().any(|(space, _)| !space.exact) {
+                    return Err("matches on conditions iptables rendering doesn't cover \
+                                (negation, TCP flags, TTL or time inside an expression)".to_string());
+                }
+                for (i, (space, action)) in clauses.iter().enumerate() {
+                    let statements = self.statements(action, &rule.name)?;
+                    let name = if clauses.len() > 1 { format!("{}-{}", base, i + 1) } else { base.clone() };
+                    for m in self.space_matches(&name, space) {
+                        self.emit(&mut out, &rule.name, &m, &statements);
+                    }
+                }
+            }
+        }
+        rules.extend(out);
+        Ok(())
+    }
+
+    fn emit(&self, rules: &mut Vec<IptRule>, rule: &str, m: &RuleMatch, statements: &[Statement]) {
+        for statement in statements {
+            let mut parts = m.parts.clone();
+            match (statement.protocol, &m.protocol) {
+                (Some(wanted), Some(protocol)) if wanted != protocol => continue,
+                (Some(wanted), None) => parts.push(format!("-p {}", wanted)),
+                _ => {}
+            }
+            parts.extend(statement.matches.clone());
+            rules.push(IptRule::new(rule, parts.join(" "), statement.target.clone()));
+        }
+    }
+
+    fn statements(&mut self, action: &Action, rule: &str) -> Result<Vec<Statement>, String> {
+        let v4 = self.family == IpFamily::V4;
+        // The kernel caps log prefixes at 29 characters
+        let name: String = rule.chars().filter(|c| *c != '"' && *c != '\\').take(26).collect();
+        let log = format!("-j LOG --log-prefix \"{}: \"", name);
+        Ok(match action {
+            Action::Allow => vec![Statement::target("-j ACCEPT")],
+            Action::Block => vec![Statement::target("-j DROP")],
+            Action::Reject(RejectWith::TcpReset) => vec![
+                Statement { protocol: Some("tcp"), ..Statement::target("-j REJECT --reject-with tcp-reset") },
+                Statement::target(if v4 {
+                    "-j REJECT --reject-with icmp-port-unreachable"
+                } else {
+                    "-j REJECT --reject-with icmp6-port-unreachable"
+                }),
+            ],
+            Action::Reject(with) => {
+                let reason = match (with, v4) {
+                    (RejectWith::IcmpHostUnreachable, true) => "icmp-host-unreachable",
+                    (RejectWith::IcmpHostUnreachable, false) => "icmp6-addr-unreachable",
+                    (RejectWith::IcmpAdminProhibited, true) => "icmp-admin-prohibited",
+                    (RejectWith::IcmpAdminProhibited, false) => "icmp6-adm-prohibited",
+                    (_, true) => "icmp-port-unreachable",
+                    (_, false) => "icmp6-port-unreachable",
+                };
+                vec![Statement::target(format!("-j REJECT --reject-with {}", reason))]
+            }
+            Action::DropLog => vec![Statement::target(log), Statement::target("-j DROP")],
+            Action::Log => vec![Statement::target(log)],
+            Action::Mark(mark) => vec![Statement::target(format!("-j MARK --set-mark {:#x}", mark))],
+            Action::QosClass(_) => return Err("DSCP can only be set in the mangle table".to_string()),
+            Action::RateLimit(name) => {
+                let limit = self.policy.rate_limiters.get(name)
+                    .ok_or_else(|| format!("no rate limiter named '{}'", name))?;
+                vec![self.limiter(name, limit, &format!("rate limiter '{}'", name))]
+            }
+            Action::Quarantine(duration) => {
+                self.quarantine = true;
+                let set = self.quarantine_set();
+                vec![
+                    Statement::target(format!(
+                        "-j SET --add-set {} src --exist --timeout {}", set, duration.as_secs().max(1)
+                    )),
+                    Statement::target("-j DROP"),
+                ]
+            }
+            Action::Jump(chain) => vec![Statement::target(format!("-j {}", self.chain(chain)?))],
+            Action::Goto(chain) => vec![Statement::target(format!("-g {}", self.chain(chain)?))],
+            Action::Return => vec![Statement::target("-j RETURN")],
+        })
+    }
+
+    fn chain(&self, name: &str) -> Result<String, String> {
+        self.chain_names.get(name)
+            .cloned()
+            .ok_or_else(|| format!("no chain named '{}'", name))
+    }
+
+    fn quarantine_set(&mut self) -> String {
+        let name = format!("{}quarantine", self.family.set_prefix());
+        self.ruleset.ipsets.entry(name.clone()).or_insert(Ipset {
+            family: self.family,
+            kind: "hash:ip",
+            timeout: true,
+            elements: Vec::new(),
+        });
+        name
+    }
+
+    // Drops what goes over `limit`, per key like the engine's limiter.
+    // Rules using one named limiter share its hashlimit table, as they
+    // share the limiter.
+    fn limiter(&mut self, key: &str, limit: &RateLimitConfig, owner: &str) -> Statement {
+        if !matches!(limit.algorithm, RateLimitAlgorithm::TokenBucket | RateLimitAlgorithm::LeakyBucket) {
+            self.note(format!("{} approximated with a token bucket", owner));
+        }
+        let table = match self.hashlimits.get(owner) {
+            Some(table) => table.clone(),
+            None => {
+                let mut taken: HashSet<String> = self.hashlimits.values().cloned().collect();
+                let table = unique(&mut taken, &format!("{}-{}", SET_PREFIX, identifier(key)), MAX_HASHLIMIT_LEN);
+                self.hashlimits.insert(owner.to_string(), table.clone());
+                table
+            }
+        };
+        let mode = match limit.key_type {
+            RateLimitKeyType::Global => None,
+            RateLimitKeyType::SourceIp => Some("srcip"),
+            RateLimitKeyType::DestinationIp => Some("dstip"),
+            RateLimitKeyType::Flow => Some("srcip,srcport,dstip,dstport"),
+        };
+        let mut matches = format!(
+            "-m hashlimit --hashlimit-above {} --hashlimit-burst {}",
+            hashlimit_rate(limit.rate), limit.capacity.ceil().max(1.0) as u64
+        );
+        if let Some(mode) = mode {
+            matches.push_str(&format!(" --hashlimit-mode {}", mode));
+        }
+        matches.push_str(&format!(" --hashlimit-name {}", table));
+        Statement { protocol: None, matches: Some(matches), target: "-j DROP".to_string() }
+    }
+
+    // Every combination of the space's addresses, protocols and port
+    // lists is one rule; long address lists go into an ipset instead
+    fn space_matches(&mut self, base: &str, space: &Space) -> Vec<RuleMatch> {
+        let Some(sources) = self.addresses(base, "src", "-s", space.sources.as_deref()) else {
+            return Vec::new();
+        };
+        let Some(destinations) = self.addresses(base, "dst", "-d", space.destinations.as_deref()) else {
+            return Vec::new();
+        };
+        let has_ports = space.source_ports.is_some() || space.destination_ports.is_some();
+        let protocols: Vec<Option<String>> = match &space.protocols {
+            Some(protocols) => {
+                let names: Vec<Option<String>> = protocols.iter()
+                    .filter_map(|p| protocol_name(p, self.family))
+                    .map(Some)
+                    .collect();
+                if names.is_empty() {
+                    return Vec::new();
+                }
+                names
+            }
+            // Only TCP and UDP packets carry ports
+            None if has_ports => vec![Some("tcp".to_string()), Some("udp".to_string())],
+            None => vec![None],
+        };
+        let source_ports = multiport("--sports", space.source_ports.as_deref());
+        let destination_ports = multiport("--dports", space.destination_ports.as_deref());
+        let states = space.states.as_ref().map(|states| {
+            let mut names: Vec<&str> = states.iter().map(state_name).collect();
+            names.sort_by_key(|name| ["INVALID", "NEW", "RELATED", "ESTABLISHED"].iter().position(|n| n == name));
+            names.dedup();
+            format!("-m conntrack --ctstate {}", names.join(","))
+        });
+
+        let mut matches = Vec::new();
+        for source in &sources {
+            for destination in &destinations {
+                for protocol in &protocols {
+                    for sport in &source_ports {
+                        for dport in &destination_ports {
+                            let parts = [
+                                source.clone(),
+                                destination.clone(),
+                                protocol.as_ref().map(|p| format!("-p {}", p)),
+                                sport.clone(),
+                                dport.clone(),
+                                states.clone(),
+                            ];
+                            matches.push(RuleMatch {
+                                protocol: protocol.clone(),
+                                parts: parts.into_iter().flatten().collect(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        matches
+    }
+
+    // Alternatives for one side: `[None]` for any address, `None` when the
+    // side lists only addresses of the other family
+    fn addresses(&mut self, base: &str, side: &str, flag: &str, prefixes: Option<&[IpPrefix]>) -> Option<Vec<Option<String>>> {
+        let Some(prefixes) = prefixes else {
+            return Some(vec![None]);
+        };
+        let cidrs: Vec<String> = prefixes.iter()
+            .filter(|p| self.family.has(p))
+            .flat_map(|p| AddressRange::of(p).cidrs())
+            .collect();
+        if cidrs.is_empty() {
+            return None;
+        }
+        if cidrs.len() <= self.backend.ipset_threshold {
+            return Some(cidrs.into_iter().map(|c| Some(format!("{} {}", flag, c))).collect());
+        }
+        let set = self.net_set(&format!("{}-{}", base, side), cidrs);
+        Some(vec![Some(format!("-m set --match-set {} {}", set, side))])
+    }
+
+    fn net_set(&mut self, wanted: &str, elements: Vec<String>) -> String {
+        let name = unique(&mut self.names, &format!("{}{}", self.family.set_prefix(), wanted), MAX_SET_LEN);
+        self.ruleset.ipsets.insert(name.clone(), Ipset {
+            family: self.family,
+            kind: "hash:net",
+            timeout: false,
+            elements,
+        });
+        name
+    }
+
+    // Longest-prefix tables are cut into disjoint ranges, each with the
+    // action of its most specific prefix, so the rules can go in any order
+    // and a packet still meets only the one its prefix picks
+    fn prefix_table(
+        &mut self,
+        base: &str,
+        rule: &str,
+        prefixes: &[(IpPrefix, Action)],
+        match_on: AddressMatch,
+        rules: &mut Vec<IptRule>,
+    ) -> Result<(), String> {
+        let sides: &[(&str, &str)] = match match_on {
+            AddressMatch::Source => &[("src", "-s")],
+            AddressMatch::Destination => &[("dst", "-d")],
+            AddressMatch::Either => &[("src", "-s"), ("dst", "-d")],
+        };
+        if match_on == AddressMatch::Either && prefixes.windows(2).any(|w| w[0].1 != w[1].1) {
+            self.note(format!(
+                "rule '{}' checks the source address before the destination, \
+                 rather than by the more specific prefix", rule
+            ));
+        }
+
+        let family = self.family;
+        let mut by_action: Vec<(Action, Vec<String>)> = Vec::new();
+        for (range, action) in disjoint(prefixes.iter().filter(|(p, _)| family.has(p))) {
+            let cidrs = range.cidrs();
+            match by_action.iter_mut().find(|(a, _)| *a == action) {
+                Some((_, all)) => all.extend(cidrs),
+                None => by_action.push((action, cidrs)),
+            }
+        }
+
+        for (side, flag) in sides {
+            for (i, (action, cidrs)) in by_action.iter().enumerate() {
+                let statements = self.statements(action, rule)?;
+                let parts: Vec<String> = if cidrs.len() > self.backend.ipset_threshold {
+                    let set = self.net_set(&format!("{}-{}{}", base, side, i + 1), cidrs.clone());
+                    vec![format!("-m set --match-set {} {}", set, side)]
+                } else {
+                    cidrs.iter().map(|c| format!("{} {}", flag, c)).collect()
+                };
+                for part in parts {
+                    let m = RuleMatch { protocol: None, parts: vec![part] };
+                    self.emit(rules, rule, &m, &statements);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// `wanted` cut to `max` characters, numbered if already taken
+fn unique(taken: &mut HashSet<String>, wanted: &str, max: usize) -> String {
+    let mut name: String = wanted.chars().take(max).collect();
+    let mut n = 2;
+    while !taken.insert(name.clone()) {
+        let suffix = format!("-{}", n);
+        name = wanted.chars().take(max - suffix.len()).collect::<String>() + &suffix;
+        n += 1;
+    }
+    name
+}
+
+fn fnv1a(text: &str) -> u32 {
+    text.bytes().fold(0x811c_9dc5, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+fn protocol_name(protocol: &Protocol, family: IpFamily) -> Option<String> {
+    match (protocol, family) {
+        (Protocol::Tcp, _) => Some("tcp".to_string()),
+        (Protocol::Udp, _) => Some("udp".to_string()),
+        (Protocol::Icmp, IpFamily::V4) => Some("icmp".to_string()),
+        (Protocol::Icmpv6, IpFamily::V6) => Some("ipv6-icmp".to_string()),
+        (Protocol::Icmp | Protocol::Icmpv6, _) => None,
+        (Protocol::Other(number), _) => Some(number.to_string()),
+    }
+}
+
+fn state_name(state: &ConnState) -> &'static str {
+    match state {
+        ConnState::New => "NEW",
+        ConnState::Established => "ESTABLISHED",
+        ConnState::Related => "RELATED",
+        ConnState::Invalid => "INVALID",
+    }
+}
+
+// One multiport match per 15 slots of the list
+fn multiport(option: &str, ports: Option<&[RangeInclusive<u16>]>) -> Vec<Option<String>> {
+    let Some(ports) = ports else {
+        return vec![None];
+    };
+    let mut out = Vec::new();
+    let mut chunk: Vec<String> = Vec::new();
+    let mut slots = 0;
+    for range in ports {
+        let (text, cost) = if range.start() == range.end() {
+            (range.start().to_string(), 1)
+        } else {
+            (format!("{}:{}", range.start(), range.end()), 2)
+        };
+        if slots + cost > MULTIPORT_SLOTS {
+            out.push(Some(format!("-m multiport {} {}", option, chunk.join(","))));
+            chunk.clear();
+            slots = 0;
+        }
+        chunk.push(text);
+        slots += cost;
+    }
+    if !chunk.is_empty() {
+        out.push(Some(format!("-m multiport {} {}", option, chunk.join(","))));
+    }
+    out
+}
+
+fn time_match(window: &TimeWindow) -> String {
+    // The time match handles windows past midnight itself
+    let mut text = format!(
+        "-m time --timestart {} --timestop {}",
+        clock_text(window.start_time()), clock_text(window.end_time())
+    );
+    if !window.days().is_empty() {
+        let days: Vec<String> = window.days().iter().map(|d| d.to_string()).collect();
+        text.push_str(&format!(" --weekdays {}", days.join(",")));
+    }
+    // Local time, as the engine uses
+    text.push_str(" --kerneltz");
+    text
+}
+
+fn clock_text(time: NaiveTime) -> String {
+    if time.second() == 0 {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%H:%M:%S").to_string()
+    }
+}
+
+// Smallest unit that gives a whole number, since hashlimit only takes
+// integers
+fn hashlimit_rate(rate: f64) -> String {
+    for (unit, seconds) in [("sec", 1.0), ("min", 60.0), ("hour", 3600.0)] {
+        let n = rate * seconds;
+        if n >= 1.0 && (n - n.round()).abs() < 1e-6 {
+            return format!("{}/{}", n.round() as u64, unit);
+        }
+    }
+    format!("{}/day", (rate * 86400.0).round().max(1.0) as u64)
+}
@@ -0,0 +1,5 @@
+pub mod backend;
+pub mod nftables;
+pub mod iptables;
+pub mod runner;
+mod render;
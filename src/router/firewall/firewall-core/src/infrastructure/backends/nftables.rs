@@ -0,0 +1,940 @@
+use crate::application::config::{FirewallConfig, RuleConfig, RuleKind};
+use crate::application::match_space::{self, Space};
+use crate::domain::chain::DEFAULT_CHAIN;
+use crate::domain::flow::ConnState;
+use crate::domain::packet::Protocol;
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rate_limiter::{RateLimitAlgorithm, RateLimitConfig, RateLimitKeyType};
+use crate::domain::rule::{Action, RejectWith};
+use crate::rules::ip_rules::AddressMatch;
+use crate::rules::time_rules::TimeWindow;
+use super::backend::{BackendError, Capabilities, FirewallBackend, MatchKind, Policy, SyncReport};
+use super::render::{active_rules, disjoint, identifier};
+use super::runner::{run_checked, CommandRunner, SystemRunner};
+use chrono::{NaiveTime, Timelike};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Idle time after which a per-key rate limit or quarantine entry is dropped
+const DYNAMIC_SET_TIMEOUT: &str = "10m";
+const DYNAMIC_SET_SIZE: u32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NftHook {
+    Input,
+    Forward,
+    Output,
+}
+
+impl NftHook {
+    fn name(self) -> &'static str {
+        match self {
+            NftHook::Input => "input",
+            NftHook::Forward => "forward",
+            NftHook::Output => "output",
+        }
+    }
+}
+
+// What `sync` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NftUpdate {
+    // Same ruleset as last time; nft wasn't run
+    Unchanged,
+    // Only set and map elements changed; they were swapped in place so
+    // rules, counters and dynamic sets were left alone
+    Elements(String),
+    // The table was replaced as a whole
+    Replaced(String),
+}
+
+impl NftUpdate {
+    // Script handed to `nft -f`, if any
+    pub fn script(&self) -> Option<&str> {
+        match self {
+            NftUpdate::Unchanged => None,
+            NftUpdate::Elements(script) | NftUpdate::Replaced(script) => Some(script),
+        }
+    }
+}
+
+// Keeps one nftables table in line with the firewall policy. Each `sync`
+// renders the policy, compares it with what was applied last and hands
+// `nft -f` the smallest script that gets there; one script is one
+// transaction, so the kernel never sees a half-applied policy. A dry-run
+// backend renders and diffs the same way but only records the scripts.
+pub struct NfTablesBackend {
+    table: String,
+    hook: NftHook,
+    priority: i32,
+    nft: String,
+    runner: Arc<dyn CommandRunner>,
+    dry_run: bool,
+    applied: Mutex<Applied>,
+}
+
+#[derive(Default)]
+struct Applied {
+    ruleset: Option<NftRuleset>,
+    script: Option<String>,
+}
+
+impl NfTablesBackend {
+    // Table `inet firewall` on the input hook
+    pub fn new() -> Self {
+        Self {
+            table: "firewall".to_string(),
+            hook: NftHook::Input,
+            priority: 0,
+            nft: "nft".to_string(),
+            runner: Arc::new(SystemRunner),
+            dry_run: false,
+            applied: Mutex::new(Applied::default()),
+        }
+    }
+
+    // Never runs nft; see `last_script`
+    pub fn dry_run() -> Self {
+        Self { dry_run: true, ..Self::new() }
+    }
+
+    pub fn with_table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    pub fn with_hook(mut self, hook: NftHook) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_nft_path(mut self, path: impl Into<String>) -> Self {
+        self.nft = path.into();
+        self
+    }
+
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    // Rules outside their schedule at `now` are left out
+    pub fn render_at(&self, policy: &FirewallConfig, now: SystemTime) -> NftRuleset {
+        Renderer::new(self, policy).render(now)
+    }
+
+    pub fn sync_config(&self, policy: &FirewallConfig, now: SystemTime) -> Result<NftUpdate, BackendError> {
+        self.apply(self.render_at(policy, now))
+    }
+
+    pub fn apply(&self, ruleset: NftRuleset) -> Result<NftUpdate, BackendError> {
+        let mut applied = self.applied.lock().unwrap();
+        let update = match &applied.ruleset {
+            Some(last) if *last == ruleset => return Ok(NftUpdate::Unchanged),
+            Some(last) if last.same_layout(&ruleset) => NftUpdate::Elements(ruleset.element_updates(last)),
+            _ => NftUpdate::Replaced(ruleset.to_script()),
+        };
+        if let Some(script) = update.script() {
+            self.run(script)?;
+            applied.script = Some(script.to_string());
+        }
+        applied.ruleset = Some(ruleset);
+        Ok(update)
+    }
+
+    // Last script applied, or that would have been in dry-run mode
+    pub fn last_script(&self) -> Option<String> {
+        self.applied.lock().unwrap().script.clone()
+    }
+
+    // Removes the table; the next `sync` recreates it
+    pub fn flush(&self) -> Result<(), BackendError> {
+        let script = format!("table inet {0}\ndelete table inet {0}\n", self.table);
+        let mut applied = self.applied.lock().unwrap();
+        self.run(&script)?;
+        applied.ruleset = None;
+        applied.script = Some(script);
+        Ok(())
+    }
+
+    fn run(&self, script: &str) -> Result<(), BackendError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        run_checked(self.runner.as_ref(), &self.nft, &["-f", "-"], Some(script)).map(|_| ())
+    }
+}
+
+impl Default for NfTablesBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FirewallBackend for NfTablesBackend {
+    fn name(&self) -> &str {
+        "nftables"
+    }
+
+    // Expression rules only render when every clause is exact
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::all().without_matches([MatchKind::Extended])
+    }
+
+    fn sync(&self, policy: &Policy) -> Result<SyncReport, BackendError> {
+        let ruleset = self.render_at(&policy.config, policy.now);
+        let mut report = SyncReport::for_policy(policy, &self.capabilities());
+        report.notes = ruleset.notes().to_vec();
+        report.changed = self.apply(ruleset)? != NftUpdate::Unchanged;
+        Ok(report)
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        NfTablesBackend::flush(self)
+    }
+}
+
+// A rendered policy: one `inet` table with its sets, maps, named limits
+// and chains. `Display` gives the table as `nft list` would print it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NftRuleset {
+    table: String,
+    sets: BTreeMap<String, NftSet>,
+    limits: BTreeMap<String, String>,
+    chains: Vec<NftChain>,
+    notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NftSet {
+    map: bool,
+    // e.g. `ipv4_addr` or `ipv4_addr : verdict`
+    key_type: String,
+    flags: Vec<&'static str>,
+    // Extra declaration lines: auto-merge, size, timeout
+    options: Vec<String>,
+    elements: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NftChain {
+    name: String,
+    // Hook line for the base chain
+    base: Option<String>,
+    rules: Vec<String>,
+}
+
+impl NftRuleset {
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    // Rules that were left out or only approximated, and why
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    // Replaces the table in one transaction: create it if missing so the
+    // delete can't fail, delete it, define it again
+    pub fn to_script(&self) -> String {
+        let mut script = String::new();
+        for note in &self.notes {
+            script.push_str(&format!("# {}\n", note));
+        }
+        script.push_str(&format!("table inet {0}\ndelete table inet {0}\n", self.table));
+        script.push_str(&self.to_string());
+        script
+    }
+
+    // Same chains, rules, limits and set declarations; elements may differ
+    fn same_layout(&self, other: &NftRuleset) -> bool {
+        self.table == other.table
+            && self.chains == other.chains
+            && self.limits == other.limits
+            && self.sets.len() == other.sets.len()
+            && self.sets.iter().zip(&other.sets).all(|((a_name, a), (b_name, b))| {
+                a_name == b_name
+                    && a.map == b.map
+                    && a.key_type == b.key_type
+                    && a.flags == b.flags
+                    && a.options == b.options
+            })
+    }
+
+    fn element_updates(&self, old: &NftRuleset) -> String {
+        let mut script = String::new();
+        for (name, set) in &self.sets {
+            if old.sets.get(name).is_some_and(|o| o.elements == set.elements) {
+                continue;
+            }
+            let kind = if set.map { "map" } else { "set" };
+            script.push_str(&format!("flush {} inet {} {}\n", kind, self.table, name));
+            if !set.elements.is_empty() {
+                script.push_str(&format!(
+                    "add element inet {} {} {{ {} }}\n",
+                    self.table, name, set.elements.join(", ")
+                ));
+            }
+        }
+        script
+    }
+}
+
+impl fmt::Display for NftRuleset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "table inet {} {{", self.table)?;
+        for (name, spec) in &self.limits {
+            writeln!(f, "\tlimit {} {{\n\t\t{}\n\t}}\n", name, spec)?;
+        }
+        for (name, set) in &self.sets {
+            writeln!(f, "\t{} {} {{", if set.map { "map" } else { "set" }, name)?;
+            writeln!(f, "\t\ttype {}", set.key_type)?;
+            if !set.flags.is_empty() {
+                writeln!(f, "\t\tflags {}", set.flags.join(","))?;
+            }
+            for option in &set.options {
+                writeln!(f, "\t\t{}", option)?;
+            }
+            if !set.elements.is_empty() {
+                writeln!(f, "\t\telements = {{ {} }}", set.elements.join(", "))?;
+            }
+            writeln!(f, "\t}}\n")?;
+        }
+        for (i, chain) in self.chains.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "\tchain {} {{", chain.name)?;
+            if let Some(base) = &chain.base {
+                writeln!(f, "\t\t{}", base)?;
+            }
+            for rule in &chain.rules {
+                writeln!(f, "\t\t{}", rule)?;
+            }
+            writeln!(f, "\t}}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(prefix: &IpPrefix) -> Self {
+        if prefix.is_ipv4() { Family::V4 } else { Family::V6 }
+    }
+
+    // Payload expression prefix: `ip saddr`, `ip6 saddr`
+    fn proto(self) -> &'static str {
+        match self {
+            Family::V4 => "ip",
+            Family::V6 => "ip6",
+        }
+    }
+
+    fn addr_type(self) -> &'static str {
+        match self {
+            Family::V4 => "ipv4_addr",
+            Family::V6 => "ipv6_addr",
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Family::V4 => "4",
+            Family::V6 => "6",
+        }
+    }
+
+    fn nfproto(self) -> &'static str {
+        match self {
+            Family::V4 => "meta nfproto ipv4",
+            Family::V6 => "meta nfproto ipv6",
+        }
+    }
+}
+
+// Conditions of one nft rule; `family` is set once an expression needs it
+#[derive(Debug, Clone, Default)]
+struct Match {
+    family: Option<Family>,
+    exprs: Vec<String>,
+}
+
+// One statement an action renders to, with what it needs of the match
+struct Statement {
+    family: Option<Family>,
+    guard: Option<&'static str>,
+    text: String,
+}
+
+impl Statement {
+    fn new(text: impl Into<String>) -> Self {
+        Self { family: None, guard: None, text: text.into() }
+    }
+
+    fn for_family(family: Family, text: impl Into<String>) -> Self {
+        Self { family: Some(family), ..Self::new(text) }
+    }
+}
+
+struct Renderer<'a> {
+    backend: &'a NfTablesBackend,
+    policy: &'a FirewallConfig,
+    ruleset: NftRuleset,
+    // Chain names in the config to their nft names
+    chain_names: BTreeMap<String, String>,
+    names: HashSet<String>,
+    quarantine: bool,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(backend: &'a NfTablesBackend, policy: &'a FirewallConfig) -> Self {
+        Self {
+            backend,
+            policy,
+            ruleset: NftRuleset {
+                table: backend.table.clone(),
+                sets: BTreeMap::new(),
+                limits: BTreeMap::new(),
+                chains: Vec::new(),
+                notes: Vec::new(),
+            },
+            chain_names: BTreeMap::new(),
+            names: HashSet::new(),
+            quarantine: false,
+        }
+    }
+
+    fn render(mut self, now: SystemTime) -> NftRuleset {
+        let entry = self.unique(self.backend.hook.name());
+        self.chain_names.insert(DEFAULT_CHAIN.to_string(), entry);
+        for name in self.policy.chains.keys() {
+            let nft_name = self.unique(&identifier(name));
+            self.chain_names.insert(name.clone(), nft_name);
+        }
+
+        let chains = std::iter::once(DEFAULT_CHAIN)
+            .chain(self.policy.chains.keys().map(String::as_str));
+        for chain in chains {
+            let mut lines = Vec::new();
+            for rule in active_rules(self.policy, chain, now) {
+                if let Err(reason) = self.rule(rule, &mut lines) {
+                    self.ruleset.notes.push(format!(
+                        "rule '{}' in chain '{}' left out: {}", rule.name, rule.chain, reason
+                    ));
+                }
+            }
+            self.tail(chain, &mut lines);
+            let name = self.chain_names[chain].clone();
+            self.ruleset.chains.push(NftChain { name, base: None, rules: lines });
+        }
+
+        // Quarantined hosts are dropped before any rule runs
+        if self.quarantine {
+            let entry = &mut self.ruleset.chains[0].rules;
+            entry.insert(0, "ip6 saddr @quarantine6 drop".to_string());
+            entry.insert(0, "ip saddr @quarantine4 drop".to_string());
+        }
+        let policy = match self.policy.default_action {
+            Action::Block => "drop",
+            _ => "accept",
+        };
+        self.ruleset.chains[0].base = Some(format!(
+            "type filter hook {} priority {}; policy {};",
+            self.backend.hook.name(), self.backend.priority, policy
+        ));
+        self.ruleset
+    }
+
+    // What happens at the end of a chain
+    fn tail(&mut self, chain: &str, lines: &mut Vec<String>) {
+        let action = if chain == DEFAULT_CHAIN {
+            // Allow and drop are the base chain's policy
+            match &self.policy.default_action {
+                Action::Allow | Action::Block => return,
+                action => action.clone(),
+            }
+        } else {
+            match self.policy.chains.get(chain).and_then(|c| c.policy.clone()) {
+                Some(policy) => policy,
+                None => return,
+            }
+        };
+        let label = if chain == DEFAULT_CHAIN { "default".to_string() } else { format!("{} policy", chain) };
+        match self.statements(&action, &label) {
+            Ok(statements) => self.emit(lines, &Match::default(), &statements),
+            Err(reason) => self.ruleset.notes.push(format!("policy of chain '{}' left out: {}", chain, reason)),
+        }
+    }
+
+    fn rule(&mut self, rule: &RuleConfig, lines: &mut Vec<String>) -> Result<(), String> {
+        let base = self.unique(&identifier(&rule.name));
+        let mut out = Vec::new();
+        match &rule.kind {
+            RuleKind::IpPrefix { prefixes, match_on } => {
+                self.prefix_table(&base, &rule.name, prefixes, *match_on, &mut out)?;
+            }
+            RuleKind::TimeWindow { windows, action } => {
+                let statements = self.statements(action, &rule.name)?;
+                for window in windows {
+                    for m in window_matches(window) {
+                        self.emit(&mut out, &m, &statements);
+                    }
+                }
+            }
+            RuleKind::RateLimit { limit } => {
+                let statements = self.limiter(&format!("rl_{}", base), limit, &format!("rule '{}'", rule.name));
+                self.emit(&mut out, &Match::default(), &statements);
+            }
+            RuleKind::TimeRateLimit { .. } => {
+                return Err("the limit changes with the time of day, which nftables limits can't follow".to_string());
+            }
+            kind => {
+                let clauses = match_space::clauses(kind);
+                if clauses.iter().any(|(space, _)| !space.exact) {
+                    return Err("matches on conditions nftables rendering doesn't cover \
+                                (negation, TCP flags, TTL or time inside an expression)".to_string());
+                }
+                let numbered = clauses.len() > 1;
+                for (i, (space, action)) in clauses.iter().enumerate() {
+                    let statements = self.statements(action, &rule.name)?;
+                    let name = if numbered { format!("{}_{}", base, i + 1) } else { base.clone() };
+                    for m in self.space_matches(&name, space) {
+                        self.emit(&mut out, &m, &statements);
+                    }
+                }
+            }
+        }
+        lines.extend(out);
+        Ok(())
+    }
+
+    // Writes one line per statement the match can carry
+    fn emit(&self, lines: &mut Vec<String>, m: &Match, statements: &[Statement]) {
+        for statement in statements {
+            let mut exprs = Vec::new();
+            match (m.family, statement.family) {
+                (Some(a), Some(b)) if a != b => continue,
+                (None, Some(family)) => exprs.push(family.nfproto().to_string()),
+                _ => {}
+            }
+            exprs.extend(m.exprs.iter().cloned());
+            exprs.extend(statement.guard.map(str::to_string));
+            exprs.push(statement.text.clone());
+            lines.push(exprs.join(" "));
+        }
+    }
+
+    fn statements(&mut self, action: &Action, rule: &str) -> Result<Vec<Statement>, String> {
+        let log = format!("log prefix \"{}: \"", quoted(rule));
+        Ok(match action {
+            Action::Allow => vec![Statement::new("accept")],
+            Action::Block => vec![Statement::new("drop")],
+            Action::Reject(RejectWith::TcpReset) => vec![
+                Statement { guard: Some("meta l4proto tcp"), ..Statement::new("reject with tcp reset") },
+                Statement::new("reject with icmpx type port-unreachable"),
+            ],
+            Action::Reject(with) => vec![Statement::new(format!("reject with icmpx type {}", with))],
+            Action::DropLog => vec![Statement::new(format!("{} drop", log))],
+            Action::Log => vec![Statement::new(log)],
+            Action::Mark(mark) => vec![Statement::new(format!("meta mark set {:#x}", mark))],
+            Action::QosClass(dscp) => vec![
+                Statement::for_family(Family::V4, format!("ip dscp set {}", dscp)),
+                Statement::for_family(Family::V6, format!("ip6 dscp set {}", dscp)),
+            ],
+            Action::RateLimit(name) => {
+                let limit = self.policy.rate_limiters.get(name)
+                    .ok_or_else(|| format!("no rate limiter named '{}'", name))?;
+                let base = format!("rl_{}", identifier(name));
+                self.limiter(&base, limit, &format!("rate limiter '{}'", name))
+            }
+            Action::Quarantine(duration) => {
+                self.quarantine_sets();
+                let timeout = duration.as_secs().max(1);
+                [Family::V4, Family::V6].into_iter()
+                    .map(|family| Statement::for_family(family, format!(
+                        "update @quarantine{} {{ {} saddr timeout {}s }} drop",
+                        family.suffix(), family.proto(), timeout
+                    )))
+                    .collect()
+            }
+            Action::Jump(chain) => vec![Statement::new(format!("jump {}", self.chain(chain)?))],
+            Action::Goto(chain) => vec![Statement::new(format!("goto {}", self.chain(chain)?))],
+            Action::Return => vec![Statement::new("return")],
+        })
+    }
+
+    fn chain(&self, name: &str) -> Result<String, String> {
+        self.chain_names.get(name)
+            .cloned()
+            .ok_or_else(|| format!("no chain named '{}'", name))
+    }
+
+    fn quarantine_sets(&mut self) {
+        if self.quarantine {
+            return;
+        }
+        self.quarantine = true;
+        for family in [Family::V4, Family::V6] {
+            self.ruleset.sets.insert(format!("quarantine{}", family.suffix()), NftSet {
+                map: false,
+                key_type: family.addr_type().to_string(),
+                flags: vec!["dynamic", "timeout"],
+                options: vec![format!("size {}", DYNAMIC_SET_SIZE)],
+                elements: Vec::new(),
+            });
+        }
+    }
+
+    // Drops what goes over `limit`, per key like the engine's limiter.
+    // Limits are shared by name: `base` is derived from the limiter's name
+    // for named limiters and from the rule's for rate-limit rules.
+    fn limiter(&mut self, base: &str, limit: &RateLimitConfig, owner: &str) -> Vec<Statement> {
+        if !matches!(limit.algorithm, RateLimitAlgorithm::TokenBucket | RateLimitAlgorithm::LeakyBucket) {
+            let note = format!("{} approximated with a token bucket", owner);
+            if !self.ruleset.notes.contains(&note) {
+                self.ruleset.notes.push(note);
+            }
+        }
+        let spec = format!(
+            "rate over {} burst {} packets",
+            nft_rate(limit.rate), limit.capacity.ceil().max(1.0) as u64
+        );
+
+        let (fields, key_type): (&[&str], fn(Family) -> String) = match limit.key_type {
+            RateLimitKeyType::Global => {
+                self.ruleset.limits.insert(base.to_string(), spec);
+                return vec![Statement::new(format!("limit name \"{}\" drop", base))];
+            }
+            RateLimitKeyType::SourceIp => (&["saddr"], |f| f.addr_type().to_string()),
+            RateLimitKeyType::DestinationIp => (&["daddr"], |f| f.addr_type().to_string()),
+            RateLimitKeyType::Flow => (&["saddr", "sport", "daddr", "dport"], |f| {
+                format!("{0} . inet_service . {0} . inet_service", f.addr_type())
+            }),
+        };
+        let flow = fields.len() > 1;
+        if flow {
+            let note = format!("{} only limits TCP and UDP flows", owner);
+            if !self.ruleset.notes.contains(&note) {
+                self.ruleset.notes.push(note);
+            }
+        }
+
+        [Family::V4, Family::V6].into_iter()
+            .map(|family| {
+                let set = format!("{}{}", base, family.suffix());
+                self.ruleset.sets.entry(set.clone()).or_insert_with(|| NftSet {
+                    map: false,
+                    key_type: key_type(family),
+                    flags: vec!["dynamic", "timeout"],
+                    options: vec![
+                        format!("size {}", DYNAMIC_SET_SIZE),
+                        format!("timeout {}", DYNAMIC_SET_TIMEOUT),
+                    ],
+                    elements: Vec::new(),
+                });
+                let key: Vec<String> = fields.iter()
+                    .map(|field| match *field {
+                        "sport" | "dport" => format!("th {}", field),
+                        address => format!("{} {}", family.proto(), address),
+                    })
+                    .collect();
+                Statement {
+                    family: Some(family),
+                    guard: flow.then_some("meta l4proto { tcp, udp }"),
+                    text: format!("update @{} {{ {} limit {} }} drop", set, key.join(" . "), spec),
+                }
+            })
+            .collect()
+    }
+
+    // The rule's matches, split by address family where prefixes are
+    // involved
+    fn space_matches(&mut self, base: &str, space: &Space) -> Vec<Match> {
+        let mut common = Vec::new();
+        match &space.protocols {
+            Some(protocols) => common.push(format!("meta l4proto {}", protocol_list(protocols))),
+            // Only TCP and UDP packets carry ports
+            None if space.source_ports.is_some() || space.destination_ports.is_some() => {
+                common.push("meta l4proto { tcp, udp }".to_string());
+            }
+            None => {}
+        }
+
+        let mut matches = Vec::new();
+        for family in [None, Some(Family::V4), Some(Family::V6)] {
+            let side = |prefixes: &Option<Vec<IpPrefix>>| -> Option<Option<Vec<IpPrefix>>> {
+                match (prefixes, family) {
+                    (None, _) => Some(None),
+                    (Some(_), None) => None,
+                    (Some(list), Some(f)) => {
+                        let list: Vec<IpPrefix> = list.iter().filter(|p| Family::of(p) == f).copied().collect();
+                        (!list.is_empty()).then_some(Some(list))
+                    }
+                }
+            };
+            let (Some(sources), Some(destinations)) = (side(&space.sources), side(&space.destinations)) else {
+                continue;
+            };
+            // Family-free matches only when no prefixes are involved
+            if family.is_some() && sources.is_none() && destinations.is_none() {
+                continue;
+            }
+
+            let mut exprs = common.clone();
+            for (field, prefixes) in [("saddr", &sources), ("daddr", &destinations)] {
+                if let (Some(prefixes), Some(family)) = (prefixes, family) {
+                    let value = self.address_value(&format!("{}_{}{}", base, field, family.suffix()), family, prefixes);
+                    exprs.push(format!("{} {} {}", family.proto(), field, value));
+                }
+            }
+            for (field, ports) in [("sport", &space.source_ports), ("dport", &space.destination_ports)] {
+                if let Some(ports) = ports {
+                    let value = self.port_value(&format!("{}_{}", base, field), ports);
+                    exprs.push(format!("th {} {}", field, value));
+                }
+            }
+            if let Some(states) = &space.states {
+                let names: Vec<&str> = states.iter().map(state_name).collect();
+                exprs.push(format!("ct state {}", braced(&names)));
+            }
+            matches.push(Match { family, exprs });
+        }
+        matches
+    }
+
+    // Inline for one prefix, a named set otherwise
+    fn address_value(&mut self, name: &str, family: Family, prefixes: &[IpPrefix]) -> String {
+        if let [prefix] = prefixes {
+            return prefix_text(prefix);
+        }
+        let name = self.unique(name);
+        self.ruleset.sets.insert(name.clone(), NftSet {
+            map: false,
+            key_type: family.addr_type().to_string(),
+            flags: vec!["interval"],
+            options: vec!["auto-merge".to_string()],
+            elements: prefixes.iter().map(prefix_text).collect(),
+        });
+        format!("@{}", name)
+    }
+
+    fn port_value(&mut self, name: &str, ports: &[RangeInclusive<u16>]) -> String {
+        if let [range] = ports {
+            return port_text(range);
+        }
+        let name = self.unique(name);
+        self.ruleset.sets.insert(name.clone(), NftSet {
+            map: false,
+            key_type: "inet_service".to_string(),
+            flags: vec!["interval"],
+            options: vec!["auto-merge".to_string()],
+            elements: ports.iter().map(port_text).collect(),
+        });
+        format!("@{}", name)
+    }
+
+    // Longest-prefix tables become a verdict map per family and side. nft
+    // intervals can't overlap, so nested prefixes are cut into disjoint
+    // ranges, each with the action of its most specific prefix. Actions a
+    // map can't hold get one set per action instead.
+    fn prefix_table(
+        &mut self,
+        base: &str,
+        rule: &str,
+        prefixes: &[(IpPrefix, Action)],
+        match_on: AddressMatch,
+        lines: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let sides: &[&str] = match match_on {
+            AddressMatch::Source => &["saddr"],
+            AddressMatch::Destination => &["daddr"],
+            AddressMatch::Either => &["saddr", "daddr"],
+        };
+        if match_on == AddressMatch::Either && prefixes.windows(2).any(|w| w[0].1 != w[1].1) {
+            self.ruleset.notes.push(format!(
+                "rule '{}' checks the source address before the destination, \
+                 rather than by the more specific prefix", rule
+            ));
+        }
+
+        for family in [Family::V4, Family::V6] {
+            let segments: Vec<(String, Action)> = disjoint(prefixes.iter().filter(|(p, _)| Family::of(p) == family))
+                .into_iter()
+                .map(|(range, action)| (range.to_string(), action))
+                .collect();
+            if segments.is_empty() {
+                continue;
+            }
+            let verdicts: Option<Vec<String>> = segments.iter()
+                .map(|(range, action)| Some(format!("{} : {}", range, self.verdict(action)?)))
+                .collect();
+
+            for side in sides {
+                let name = format!("{}_{}{}", base, side, family.suffix());
+                if let Some(elements) = &verdicts {
+                    let name = self.unique(&name);
+                    self.ruleset.sets.insert(name.clone(), NftSet {
+                        map: true,
+                        key_type: format!("{} : verdict", family.addr_type()),
+                        flags: vec!["interval"],
+                        options: Vec::new(),
+                        elements: elements.clone(),
+                    });
+                    lines.push(format!("{} {} vmap @{}", family.proto(), side, name));
+                    continue;
+                }
+
+                let mut by_action: Vec<(&Action, Vec<String>)> = Vec::new();
+                for (range, action) in &segments {
+                    match by_action.iter_mut().find(|(a, _)| *a == action) {
+                        Some((_, ranges)) => ranges.push(range.clone()),
+                        None => by_action.push((action, vec![range.clone()])),
+                    }
+                }
+                for (i, (action, ranges)) in by_action.into_iter().enumerate() {
+                    let statements = self.statements(action, rule)?;
+                    let set = self.unique(&format!("{}_{}", name, i + 1));
+                    self.ruleset.sets.insert(set.clone(), NftSet {
+                        map: false,
+                        key_type: family.addr_type().to_string(),
+                        flags: vec!["interval"],
+                        options: Vec::new(),
+                        elements: ranges,
+                    });
+                    let m = Match {
+                        family: Some(family),
+                        exprs: vec![format!("{} {} @{}", family.proto(), side, set)],
+                    };
+                    self.emit(lines, &m, &statements);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Actions a verdict map can hold
+    fn verdict(&self, action: &Action) -> Option<String> {
+        match action {
+            Action::Allow => Some("accept".to_string()),
+            Action::Block => Some("drop".to_string()),
+            Action::Return => Some("return".to_string()),
+            Action::Jump(chain) => Some(format!("jump {}", self.chain_names.get(chain)?)),
+            Action::Goto(chain) => Some(format!("goto {}", self.chain_names.get(chain)?)),
+            _ => None,
+        }
+    }
+
+    fn unique(&mut self, wanted: &str) -> String {
+        let mut name = wanted.to_string();
+        let mut n = 2;
+        while !self.names.insert(name.clone()) {
+            name = format!("{}_{}", wanted, n);
+            n += 1;
+        }
+        name
+    }
+}
+
+fn prefix_text(prefix: &IpPrefix) -> String {
+    let full = if prefix.is_ipv4() { 32 } else { 128 };
+    if prefix.prefix_len() == full { prefix.addr().to_string() } else { prefix.to_string() }
+}
+
+fn port_text(range: &RangeInclusive<u16>) -> String {
+    if range.start() == range.end() {
+        range.start().to_string()
+    } else {
+        format!("{}-{}", range.start(), range.end())
+    }
+}
+
+fn window_matches(window: &TimeWindow) -> Vec<Match> {
+    let days = (!window.days().is_empty()).then(|| {
+        let days: Vec<String> = window.days().iter().map(|d| d.num_days_from_sunday().to_string()).collect();
+        format!("meta day {}", braced(&days))
+    });
+    let hours = |start: NaiveTime, end: NaiveTime| format!("meta hour \"{}\"-\"{}\"", clock_text(start), clock_text(end));
+    let ranges = if window.start_time() <= window.end_time() {
+        vec![hours(window.start_time(), window.end_time())]
+    } else {
+        // Past midnight: the day check applies to both halves, as in
+        // `TimeWindow::contains`
+        vec![
+            hours(window.start_time(), NaiveTime::from_hms_opt(23, 59, 59).unwrap()),
+            hours(NaiveTime::MIN, window.end_time()),
+        ]
+    };
+    ranges.into_iter()
+        .map(|hours| Match {
+            family: None,
+            exprs: days.iter().cloned().chain(std::iter::once(hours)).collect(),
+        })
+        .collect()
+}
+
+fn clock_text(time: NaiveTime) -> String {
+    if time.second() == 0 {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%H:%M:%S").to_string()
+    }
+}
+
+// Smallest unit that gives a whole number, since nft only takes integers
+fn nft_rate(rate: f64) -> String {
+    for (unit, seconds) in [("second", 1.0), ("minute", 60.0), ("hour", 3600.0)] {
+        let n = rate * seconds;
+        if n >= 1.0 && (n - n.round()).abs() < 1e-6 {
+            return format!("{}/{}", n.round() as u64, unit);
+        }
+    }
+    format!("{}/day", (rate * 86400.0).round().max(1.0) as u64)
+}
+
+fn protocol_list(protocols: &[Protocol]) -> String {
+    let names: Vec<String> = protocols.iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp => "tcp".to_string(),
+            Protocol::Udp => "udp".to_string(),
+            Protocol::Icmp => "icmp".to_string(),
+            Protocol::Icmpv6 => "ipv6-icmp".to_string(),
+            Protocol::Other(number) => number.to_string(),
+        })
+        .collect();
+    braced(&names)
+}
+
+fn state_name(state: &ConnState) -> &'static str {
+    match state {
+        ConnState::New => "new",
+        ConnState::Established => "established",
+        ConnState::Related => "related",
+        ConnState::Invalid => "invalid",
+    }
+}
+
+// `x` for one value, `{ x, y }` for several
+fn braced<T: AsRef<str>>(values: &[T]) -> String {
+    match values {
+        [value] => value.as_ref().to_string(),
+        _ => format!("{{ {} }}", values.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+fn quoted(text: &str) -> String {
+    text.chars().filter(|c| *c != '"' && *c != '\\').collect()
+}
@@ -0,0 +1,172 @@
+use crate::application::config::{FirewallConfig, RuleConfig};
+use crate::domain::prefix_trie::IpPrefix;
+use crate::domain::rule::Action;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::SystemTime;
+
+// Pieces shared by the backends that render a `FirewallConfig` into kernel
+// rules
+
+// Rules of `chain` that are enabled and in schedule at `now`, in the order
+// the engine evaluates them
+pub(crate) fn active_rules<'a>(policy: &'a FirewallConfig, chain: &str, now: SystemTime) -> Vec<&'a RuleConfig> {
+    let mut rules: Vec<&RuleConfig> = policy.rules.iter()
+        .filter(|r| r.chain == chain && r.enabled && r.schedule.is_active_at(now))
+        .collect();
+    rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    rules
+}
+
+// Lower case letters, digits and underscores, starting with a letter
+pub(crate) fn identifier(name: &str) -> String {
+    let mut id: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if !id.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        id.insert(0, 'r');
+    }
+    id
+}
+
+// Inclusive range of addresses of one family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AddressRange {
+    pub start: u128,
+    pub end: u128,
+    pub v4: bool,
+}
+
+impl AddressRange {
+    pub fn of(prefix: &IpPrefix) -> Self {
+        let (addr, bits) = match prefix.addr() {
+            IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => (u128::from(addr), 128),
+        };
+        let host_bits = bits - u32::from(prefix.prefix_len());
+        let mask = if host_bits == 0 { 0 } else { u128::MAX >> (128 - host_bits) };
+        Self { start: addr & !mask, end: addr | mask, v4: bits == 32 }
+    }
+
+    fn bits(&self) -> u32 {
+        if self.v4 { 32 } else { 128 }
+    }
+
+    fn addr(&self, n: u128) -> IpAddr {
+        if self.v4 {
+            IpAddr::from(Ipv4Addr::from(n as u32))
+        } else {
+            IpAddr::from(Ipv6Addr::from(n))
+        }
+    }
+
+    // Prefix length if the range is exactly one prefix
+    fn prefix_len(start: u128, end: u128, bits: u32) -> Option<u32> {
+        let host_bits = match (end - start).checked_add(1) {
+            None => 128,
+            Some(n) if n.is_power_of_two() && start.is_multiple_of(n) => n.trailing_zeros(),
+            Some(_) => return None,
+        };
+        Some(bits - host_bits)
+    }
+
+    // Fewest prefixes covering exactly the range, for tools that only take
+    // prefixes. Hosts print without a length.
+    pub fn cidrs(&self) -> Vec<String> {
+        let bits = self.bits();
+        let mut out = Vec::new();
+        let mut start = self.start;
+        loop {
+            // Largest aligned block at `start` that stays inside the range
+            let block_end = |host_bits: u32| {
+                start.saturating_add(if host_bits >= 128 { u128::MAX } else { (1u128 << host_bits) - 1 })
+            };
+            let mut host_bits = if start == 0 { bits } else { start.trailing_zeros().min(bits) };
+            while host_bits > 0 && block_end(host_bits) > self.end {
+                host_bits -= 1;
+            }
+            let last = block_end(host_bits);
+            out.push(self.prefix_text(start, bits - host_bits));
+            if last >= self.end {
+                return out;
+            }
+            start = last + 1;
+        }
+    }
+
+    fn prefix_text(&self, start: u128, len: u32) -> String {
+        if len == self.bits() {
+            self.addr(start).to_string()
+        } else {
+            format!("{}/{}", self.addr(start), len)
+        }
+    }
+}
+
+// One prefix as a prefix, anything else as `first-last`
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Self::prefix_len(self.start, self.end, self.bits()) {
+            Some(len) => f.write_str(&self.prefix_text(self.start, len)),
+            None => write!(f, "{}-{}", self.addr(self.start), self.addr(self.end)),
+        }
+    }
+}
+
+// Disjoint ranges of a longest-prefix table, in address order, each with
+// the action of the most specific prefix covering it. Kernel sets and
+// rules can then match them in any order and still pick what the table
+// would. All prefixes must be of one family.
+pub(crate) fn disjoint<'a>(prefixes: impl Iterator<Item = &'a (IpPrefix, Action)>) -> Vec<(AddressRange, Action)> {
+    // Keyed by (start, length): outer prefixes sort before the ones nested
+    // in them, and a prefix listed twice keeps its later action, as in the
+    // table
+    let mut spans: BTreeMap<(u128, u8), (u128, &Action)> = BTreeMap::new();
+    let mut v4 = true;
+    for (prefix, action) in prefixes {
+        let range = AddressRange::of(prefix);
+        v4 = range.v4;
+        spans.insert((range.start, prefix.prefix_len()), (range.end, action));
+    }
+
+    let mut out: Vec<(u128, u128, &Action)> = Vec::new();
+    let mut push = |start: u128, end: u128, action: &'a Action| {
+        if start > end {
+            return;
+        }
+        match out.last_mut() {
+            Some(last) if last.2 == action && last.1.checked_add(1) == Some(start) => last.1 = end,
+            _ => out.push((start, end, action)),
+        }
+    };
+
+    // Prefixes either nest or don't overlap, so a stack of the ones
+    // containing the cursor is enough
+    let mut stack: Vec<(u128, &Action)> = Vec::new();
+    let mut cursor = 0u128;
+    for (&(start, _), &(end, action)) in &spans {
+        while let Some(&(top_end, top_action)) = stack.last()
+            && top_end < start
+        {
+            push(cursor, top_end, top_action);
+            cursor = top_end.saturating_add(1);
+            stack.pop();
+        }
+        if let Some(&(_, top_action)) = stack.last()
+            && cursor < start
+        {
+            push(cursor, start - 1, top_action);
+        }
+        stack.push((end, action));
+        cursor = start;
+    }
+    while let Some((top_end, top_action)) = stack.pop() {
+        push(cursor, top_end, top_action);
+        cursor = top_end.saturating_add(1);
+    }
+
+    out.into_iter()
+        .map(|(start, end, action)| (AddressRange { start, end, v4 }, action.clone()))
+        .collect()
+}
@@ -0,0 +1,155 @@
+use super::backend::BackendError;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    // `None` if the process was killed by a signal
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+
+    pub fn ok(stdout: impl Into<String>) -> Self {
+        Self { status: Some(0), stdout: stdout.into(), stderr: String::new() }
+    }
+
+    pub fn failed(status: i32, stderr: impl Into<String>) -> Self {
+        Self { status: Some(status), stdout: String::new(), stderr: stderr.into() }
+    }
+}
+
+// How backends run nft, iptables-restore, ipset and friends. Swapping the
+// runner lets tests see exactly what would run without touching the kernel.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, program: &str, args: &[&str], stdin: Option<&str>) -> io::Result<CommandOutput>;
+}
+
+// Runs a command, turning a failure to start it or a non-zero exit into
+// a `BackendError`
+pub(crate) fn run_checked(
+    runner: &dyn CommandRunner,
+    program: &str,
+    args: &[&str],
+    stdin: Option<&str>,
+) -> Result<CommandOutput, BackendError> {
+    let output = runner.run(program, args, stdin)
+        .map_err(|error| BackendError::Spawn { program: program.to_string(), error: Arc::new(error) })?;
+    if output.success() {
+        Ok(output)
+    } else {
+        Err(BackendError::Command { program: program.to_string(), status: output.status, stderr: output.stderr })
+    }
+}
+
+// Runs commands for real
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str], stdin: Option<&str>) -> io::Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(input) = stdin {
+            // Dropped at the end of the statement, closing the pipe
+            child.stdin.take().expect("stdin is piped").write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandCall {
+    pub program: String,
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+}
+
+// Records every command instead of running it and answers with canned
+// output, for snapshot tests. Commands without a queued reply succeed with
+// no output.
+#[derive(Debug, Default)]
+pub struct ScriptedRunner {
+    calls: Mutex<Vec<CommandCall>>,
+    replies: Mutex<HashMap<String, VecDeque<CommandOutput>>>,
+}
+
+impl ScriptedRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Queues the reply to the next call of `command`: the program, or the
+    // program and its arguments joined by spaces (`iptables -C INPUT -j X`).
+    // The longer form wins when both are queued.
+    pub fn reply(&self, command: &str, output: CommandOutput) {
+        self.replies.lock().unwrap()
+            .entry(command.to_string())
+            .or_default()
+            .push_back(output);
+    }
+
+    pub fn calls(&self) -> Vec<CommandCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    // Every call as a shell transcript, stdin inline as a heredoc
+    pub fn transcript(&self) -> String {
+        let mut out = String::new();
+        for call in self.calls.lock().unwrap().iter() {
+            out.push_str("$ ");
+            out.push_str(&call.program);
+            for arg in &call.args {
+                out.push(' ');
+                out.push_str(arg);
+            }
+            match &call.stdin {
+                Some(stdin) => {
+                    out.push_str(" <<EOF\n");
+                    out.push_str(stdin);
+                    if !stdin.ends_with('\n') {
+                        out.push('\n');
+                    }
+                    out.push_str("EOF\n");
+                }
+                None => out.push('\n'),
+            }
+        }
+        out
+    }
+}
+
+impl CommandRunner for ScriptedRunner {
+    fn run(&self, program: &str, args: &[&str], stdin: Option<&str>) -> io::Result<CommandOutput> {
+        self.calls.lock().unwrap().push(CommandCall {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            stdin: stdin.map(str::to_string),
+        });
+        let full = std::iter::once(program).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+        let mut replies = self.replies.lock().unwrap();
+        let reply = [full.as_str(), program].into_iter()
+            .find_map(|key| replies.get_mut(key).and_then(VecDeque::pop_front));
+        Ok(reply.unwrap_or_else(|| CommandOutput::ok("")))
+    }
+}
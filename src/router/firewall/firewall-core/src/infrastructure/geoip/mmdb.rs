@@ -0,0 +1,322 @@
+use crate::domain::geo::{GeoInfo, GeoLookup};
+use arc_swap::ArcSwap;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+pub enum GeoIpError {
+    Io { path: PathBuf, source: std::io::Error },
+    Database { path: PathBuf, message: String },
+}
+
+impl fmt::Display for GeoIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoIpError::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            GeoIpError::Database { path, message } => {
+                write!(f, "invalid MMDB file {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeoIpError {}
+
+type MmdbReader = Reader<Vec<u8>>;
+
+// One MMDB file on disk and the mtime of the copy last loaded from it
+struct MmdbFile {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl MmdbFile {
+    fn open(path: &Path) -> Result<(Self, MmdbReader), GeoIpError> {
+        let (reader, modified) = load(path)?;
+        let file = Self {
+            path: path.to_path_buf(),
+            modified: Mutex::new(modified),
+        };
+        Ok((file, reader))
+    }
+
+    fn changed_on_disk(&self) -> bool {
+        let on_disk = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        on_disk.is_some() && on_disk != *self.modified.lock().unwrap()
+    }
+}
+
+fn load(path: &Path) -> Result<(MmdbReader, Option<SystemTime>), GeoIpError> {
+    let bytes = std::fs::read(path).map_err(|source| GeoIpError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let reader = Reader::from_source(bytes).map_err(|e| GeoIpError::Database {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    Ok((reader, modified))
+}
+
+type Staged<'a> = Option<(&'a MmdbFile, (MmdbReader, Option<SystemTime>))>;
+
+fn stage(file: Option<&MmdbFile>) -> Result<Staged<'_>, GeoIpError> {
+    file.map(|file| load(&file.path).map(|loaded| (file, loaded))).transpose()
+}
+
+// The readers serving lookups, swapped as one so a lookup never mixes a new
+// country file with an old ASN file
+#[derive(Default)]
+struct GeoReaders {
+    country: Option<Arc<MmdbReader>>,
+    asn: Option<Arc<MmdbReader>>,
+    generation: u64,
+}
+
+// Two-generation cache: lookups hit `hot` first, then `cold`. When `hot`
+// fills up it becomes `cold` and the old `cold` is dropped, which keeps
+// recently used entries without tracking per-entry recency.
+// Entries are only kept for the readers generation they were looked up in.
+struct GeoCache {
+    hot: HashMap<IpAddr, GeoInfo>,
+    cold: HashMap<IpAddr, GeoInfo>,
+    capacity: usize,
+    generation: u64,
+}
+
+impl GeoCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            hot: HashMap::new(),
+            cold: HashMap::new(),
+            capacity: capacity.max(1),
+            generation: 0,
+        }
+    }
+
+    fn get(&mut self, ip: &IpAddr) -> Option<GeoInfo> {
+        if let Some(info) = self.hot.get(ip) {
+            return Some(info.clone());
+        }
+        let info = self.cold.remove(ip)?;
+        self.insert(*ip, info.clone());
+        Some(info)
+    }
+
+    fn insert(&mut self, ip: IpAddr, info: GeoInfo) {
+        if self.hot.len() >= self.capacity {
+            self.cold = std::mem::take(&mut self.hot);
+        }
+        self.hot.insert(ip, info);
+    }
+
+    // A lookup that raced a reload answers from the old readers; it isn't
+    // cached
+    fn insert_for(&mut self, generation: u64, ip: IpAddr, info: GeoInfo) {
+        if generation == self.generation {
+            self.insert(ip, info);
+        }
+    }
+
+    fn clear(&mut self, generation: u64) {
+        self.hot.clear();
+        self.cold.clear();
+        self.generation = generation;
+    }
+}
+
+// GeoLite2/GeoIP2 reader. Country and ASN data come from separate files
+// (GeoLite2-Country.mmdb and GeoLite2-ASN.mmdb); either may be omitted.
+pub struct MmdbGeoDatabase {
+    country: Option<MmdbFile>,
+    asn: Option<MmdbFile>,
+    readers: ArcSwap<GeoReaders>,
+    cache: Mutex<GeoCache>,
+}
+
+impl MmdbGeoDatabase {
+    pub fn open_country(path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        let (file, reader) = MmdbFile::open(path.as_ref())?;
+        Ok(Self {
+            country: Some(file),
+            asn: None,
+            readers: ArcSwap::from_pointee(GeoReaders {
+                country: Some(Arc::new(reader)),
+                ..GeoReaders::default()
+            }),
+            cache: Mutex::new(GeoCache::new(DEFAULT_CACHE_CAPACITY)),
+        })
+    }
+
+    pub fn open_asn(path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        let (file, reader) = MmdbFile::open(path.as_ref())?;
+        Ok(Self {
+            country: None,
+            asn: Some(file),
+            readers: ArcSwap::from_pointee(GeoReaders {
+                asn: Some(Arc::new(reader)),
+                ..GeoReaders::default()
+            }),
+            cache: Mutex::new(GeoCache::new(DEFAULT_CACHE_CAPACITY)),
+        })
+    }
+
+    pub fn with_asn_database(mut self, path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        let (file, reader) = MmdbFile::open(path.as_ref())?;
+        self.asn = Some(file);
+        self.readers = ArcSwap::from_pointee(GeoReaders {
+            country: self.readers.load().country.clone(),
+            asn: Some(Arc::new(reader)),
+            generation: 0,
+        });
+        Ok(self)
+    }
+
+    pub fn with_cache_capacity(self, capacity: usize) -> Self {
+        *self.cache.lock().unwrap() = GeoCache::new(capacity);
+        self
+    }
+
+    // Re-reads every configured file and swaps them in without interrupting
+    // lookups. If any file fails to load, none is swapped.
+    pub fn reload(&self) -> Result<(), GeoIpError> {
+        self.swap_in(true, true)
+    }
+
+    // Reloads only the files whose mtime moved; returns whether anything
+    // changed. As with `reload`, a bad file leaves every reader in place.
+    pub fn reload_if_changed(&self) -> Result<bool, GeoIpError> {
+        let changed = |file: &Option<MmdbFile>| file.as_ref().is_some_and(MmdbFile::changed_on_disk);
+        let (country, asn) = (changed(&self.country), changed(&self.asn));
+        if !country && !asn {
+            return Ok(false);
+        }
+        self.swap_in(country, asn)?;
+        Ok(true)
+    }
+
+    // Parses the chosen files fully before touching anything, so a bad
+    // download leaves the previous databases in service
+    fn swap_in(&self, country: bool, asn: bool) -> Result<(), GeoIpError> {
+        let country = stage(self.country.as_ref().filter(|_| country))?;
+        let asn = stage(self.asn.as_ref().filter(|_| asn))?;
+
+        let current = self.readers.load_full();
+        let mut next = GeoReaders {
+            country: current.country.clone(),
+            asn: current.asn.clone(),
+            generation: current.generation + 1,
+        };
+        for (slot, staged) in [(&mut next.country, country), (&mut next.asn, asn)] {
+            if let Some((file, (reader, modified))) = staged {
+                *slot = Some(Arc::new(reader));
+                *file.modified.lock().unwrap() = modified;
+            }
+        }
+        let generation = next.generation;
+        self.readers.store(Arc::new(next));
+        self.cache.lock().unwrap().clear(generation);
+        Ok(())
+    }
+
+    // Calls `reload_if_changed` every `interval` on a background thread, for
+    // as long as the returned watcher is kept
+    pub fn start_watcher(
+        self: &Arc<Self>,
+        interval: Duration,
+        on_error: impl Fn(&GeoIpError) + Send + 'static,
+    ) -> GeoIpWatcher {
+        GeoIpWatcher::spawn(Arc::clone(self), interval, on_error)
+    }
+
+    fn lookup_uncached(&self, readers: &GeoReaders, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo::default();
+
+        if let Some(reader) = &readers.country {
+            let record: Result<geoip2::Country, MaxMindDBError> = reader.lookup(ip);
+            if let Ok(record) = record {
+                // Fall back to the registration country for anycast/satellite ranges
+                info.country = record.country
+                    .and_then(|c| c.iso_code)
+                    .or_else(|| record.registered_country.and_then(|c| c.iso_code))
+                    .map(|code| code.to_ascii_uppercase());
+            }
+        }
+
+        if let Some(reader) = &readers.asn {
+            let record: Result<geoip2::Asn, MaxMindDBError> = reader.lookup(ip);
+            if let Ok(record) = record {
+                info.asn = record.autonomous_system_number;
+            }
+        }
+
+        info
+    }
+}
+
+// Background thread that picks up new database files. Stops when dropped.
+pub struct GeoIpWatcher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GeoIpWatcher {
+    fn spawn(
+        database: Arc<MmdbGeoDatabase>,
+        interval: Duration,
+        on_error: impl Fn(&GeoIpError) + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("geoip-watcher".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = database.reload_if_changed() {
+                        on_error(&e);
+                    }
+                }
+            })
+            .expect("failed to spawn geoip watcher thread");
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    // Same as dropping the handle
+    pub fn stop(self) {}
+}
+
+impl Drop for GeoIpWatcher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl GeoLookup for MmdbGeoDatabase {
+    fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        if let Some(info) = self.cache.lock().unwrap().get(&ip) {
+            return info;
+        }
+        let readers = self.readers.load_full();
+        let info = self.lookup_uncached(&readers, ip);
+        self.cache.lock().unwrap().insert_for(readers.generation, ip, info.clone());
+        info
+    }
+}
@@ -0,0 +1,3 @@
+pub mod mmdb;
+
+pub use mmdb::{GeoIpError, GeoIpWatcher, MmdbGeoDatabase};
@@ -0,0 +1,3 @@
+pub mod backends;
+pub mod geoip;
+pub mod mqtt;
\ No newline at end of file
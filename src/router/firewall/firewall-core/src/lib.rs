@@ -1,50 +1,36 @@
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
 
 // Domain Layer: Core Business Layer
-pub mod domain {
-    pub mod packet;
-    pub mod flow;
-    pub mod rule;
-    pub mod stats;
-    pub mod rate_limiter;
-}
+pub mod domain;
 
 //Application Layer: Use cases
-pub mod Application {
-    pub mod engine;
-    pub mod rule_manager;
-}
+pub mod application;
 
 // Infrastructure Layer: External Integrations
-pub mod  Infrastructure {
-    pub mod backends;
-    pub mod mqtt;
-}
+pub mod infrastructure;
 
 // Rules: Filter Trait
-pub mod rules {
-    pub mod ip_rules;
-    pub mod port_rules;
-    pub mod geo_rules;
-    pub mod time_rules;
-    pub mod rate_limit_rules;
-}
+pub mod rules;
 
 pub struct Firewall {
     processor: Arc<PacketProcessor>,
     rule_manager: Arc<RuleManager>,
     flow_tracker: Arc<FlowTracker>,
     stats_collector: Arc<dyn StatsCollector>,
+    clock: Arc<dyn Clock>,
+    backends: Arc<BackendSync>,
 }
 
 impl Firewall {
     pub fn new(default_action: Action) -> Self {
         let flow_tracker = Arc::new(FlowTracker::new());
         let stats_collector: Arc<dyn StatsCollector> = Arc::new(InMemoryStatsCollector::new());
-        let rule_manager = Arc::new(RuleManager::new());
+        let rule_manager = Arc::new(RuleManager::with_default_action(default_action));
 
         let processor = Arc::new(PacketProcessor::new(
-            default_action,
             Arc::clone(&flow_tracker),
             Arc::clone(&stats_collector),
             rule_manager.rules_ref(),
@@ -54,15 +40,72 @@ impl Firewall {
             rule_manager,
             flow_tracker,
             stats_collector,
+            clock: Arc::new(SystemClock),
+            backends: Arc::new(BackendSync::new(Vec::new())),
         }
     }
 
+    // In monitor mode this is the action enforcing would take
     pub fn process_packet(&self, packet: &Packet) -> Action {
+        self.processor.process(packet).action
+    }
+    // Same as `process_packet`, but keeps the mark/QoS/log annotations
+    pub fn process_verdict(&self, packet: &Packet) -> Verdict {
         self.processor.process(packet)
     }
-    pub fn add_rule(&self, filter: Box<dyn Filter>) -> u64 {
+    // Offline replay: `timestamp` is when the packet was captured, and
+    // drives a `PacketClock`. Other clocks ignore it.
+    pub fn replay_packet(&self, packet: &Packet, timestamp: SystemTime) -> Verdict {
+        self.clock.observe(timestamp);
+        self.processor.process(packet)
+    }
+    pub fn process_frame(&self, frame: &[u8]) -> Result<Verdict, DecodeError> {
+        let packet = Packet::from_ethernet(frame)?;
+        Ok(self.processor.process(&packet))
+    }
+    // Which rules the packet would hit and why it gets its verdict. Nothing
+    // is recorded: no flow, stats, rule counters or rate-limit tokens.
+    pub fn trace_packet(&self, packet: &Packet) -> PacketTrace {
+        self.processor.trace(packet)
+    }
+    pub fn add_rule(&self, filter: Box<dyn Filter>) -> Result<u64, ChainError> {
         self.rule_manager.add_rule(filter)
     }
+    pub fn add_rule_to(&self, chain: &str, filter: Box<dyn Filter>) -> Result<u64, ChainError> {
+        self.rule_manager.add_rule_to(chain, filter)
+    }
+    // All or nothing, in one generation
+    pub fn add_rules(&self, filters: impl IntoIterator<Item = Box<dyn Filter>>) -> Result<Vec<u64>, ChainError> {
+        self.rule_manager.add_rules(filters)
+    }
+    // Rule that removes itself after `ttl`, e.g. a temporary block
+    pub fn add_temporary_rule(&self, filter: Box<dyn Filter>, ttl: Duration) -> Result<u64, ChainError> {
+        self.rule_manager.add_temporary_rule(filter, ttl)
+    }
+    pub fn add_scheduled_rule(
+        &self,
+        chain: &str,
+        filter: Box<dyn Filter>,
+        schedule: RuleSchedule,
+    ) -> Result<u64, ChainError> {
+        self.rule_manager.add_scheduled_rule(chain, filter, schedule)
+    }
+    pub fn set_rule_ttl(&self, id: u64, ttl: Option<Duration>) -> bool {
+        self.rule_manager.set_ttl(id, ttl)
+    }
+    pub fn extend_rule_ttl(&self, id: u64, by: Duration) -> Option<SystemTime> {
+        self.rule_manager.extend_ttl(id, by)
+    }
+    pub fn shorten_rule_ttl(&self, id: u64, by: Duration) -> Option<SystemTime> {
+        self.rule_manager.shorten_ttl(id, by)
+    }
+    pub fn reap_expired_rules(&self) -> Vec<u64> {
+        self.rule_manager.reap_expired()
+    }
+    // Reaps expired rules every `interval` until the returned handle drops
+    pub fn start_rule_sweeper(&self, interval: Duration) -> RuleSweeper {
+        RuleSweeper::spawn(Arc::clone(&self.rule_manager), interval)
+    }
     pub fn remove_rule(&self, id: u64) -> bool {
         self.rule_manager.remove_rule(id)
     }
@@ -72,26 +115,179 @@ impl Firewall {
     pub fn list_rules(&self) -> Vec<RuleInfo> {
         self.rule_manager.list_rules()
     }
+    pub fn list_chains(&self) -> Vec<ChainInfo> {
+        self.rule_manager.list_chains()
+    }
+    pub fn create_chain(&self, chain: Chain) -> Result<(), ChainError> {
+        self.rule_manager.create_chain(chain)
+    }
+    pub fn delete_chain(&self, name: &str) -> Result<(), ChainError> {
+        self.rule_manager.delete_chain(name)
+    }
+    pub fn set_chain_policy(&self, name: &str, policy: Option<Action>) -> Result<(), ChainError> {
+        self.rule_manager.set_chain_policy(name, policy)
+    }
+    pub fn mode(&self) -> ProcessingMode {
+        self.processor.mode()
+    }
+    pub fn set_mode(&self, mode: ProcessingMode) {
+        self.processor.set_mode(mode)
+    }
+    pub fn rule_generation(&self) -> u64 {
+        self.rule_manager.generation()
+    }
+    // Stages rule changes and publishes them together on commit
+    pub fn begin_transaction(&self) -> RuleTransaction<'_> {
+        self.rule_manager.begin_transaction()
+    }
+    pub fn rollback_rules(&self, generation: u64) -> Result<u64, TransactionError> {
+        self.rule_manager.rollback(generation)
+    }
+    pub fn rule_history(&self) -> Vec<GenerationInfo> {
+        self.rule_manager.history()
+    }
+    // Static check of the live rules for ones that can't work as written
+    pub fn analyze_rules(&self) -> AnalysisReport {
+        self.rule_manager.analyze()
+    }
+    pub fn reset_rule_counters(&self, id: u64) -> bool {
+        self.rule_manager.reset_counters(id)
+    }
+    pub fn reset_all_rule_counters(&self) {
+        self.rule_manager.reset_all_counters()
+    }
+
+    // Replaces the rule set, chains, default action and configured rate
+    // limiters. Returns the new rule ids in config order.
+    // Limiters only the new rules can use go in before them, so they're
+    // there for the first packet; ones the current rules may use are only
+    // replaced once the new rules are live. A config that fails to load
+    // leaves the limiters as they were. Every `rate-limit:<name>` has to
+    // name a limiter in the config or one already registered.
+    pub fn apply_config(&self, config: &FirewallConfig) -> Result<Vec<u64>, ConfigError> {
+        if let Some(name) = config.rate_limit_references()
+            .find(|name| !config.rate_limiters.contains_key(*name) && !self.processor.has_rate_limiter(name))
+        {
+            return Err(ConfigError::UndefinedRateLimiter(name.to_string()));
+        }
+        let (added, replaced): (Vec<_>, Vec<_>) = config.rate_limiters.iter()
+            .partition(|(name, _)| !self.processor.has_rate_limiter(name));
+        for (name, limit) in &added {
+            self.processor.register_rate_limiter(name.as_str(), (*limit).clone());
+        }
+        match self.rule_manager.load_config(config) {
+            Ok(ids) => {
+                for (name, limit) in replaced {
+                    self.processor.register_rate_limiter(name.as_str(), limit.clone());
+                }
+                Ok(ids)
+            }
+            Err(e) => {
+                for (name, _) in added {
+                    self.processor.remove_rate_limiter(name);
+                }
+                Err(ConfigError::Chain(e))
+            }
+        }
+    }
+    pub fn load_config(&self, path: impl AsRef<Path>) -> Result<Vec<u64>, ConfigError> {
+        let config = FirewallConfig::load(path)?;
+        self.apply_config(&config)
+    }
+    pub fn export_config(&self) -> Result<FirewallConfig, ConfigError> {
+        Ok(FirewallConfig {
+            default_action: self.rule_manager.default_action(),
+            rate_limiters: self.processor.rate_limiter_configs(),
+            chains: self.rule_manager.chain_configs(),
+            rules: self.rule_manager.export_config()?,
+        })
+    }
+
+    pub fn register_rate_limiter(&self, name: impl Into<String>, config: RateLimitConfig) {
+        self.processor.register_rate_limiter(name, config)
+    }
+    pub fn quarantine_host(&self, ip: IpAddr, duration: Duration) {
+        self.processor.quarantine_host(ip, duration)
+    }
+    pub fn release_host(&self, ip: &IpAddr) -> bool {
+        self.processor.release_host(ip)
+    }
+    pub fn quarantined_hosts(&self) -> Vec<(IpAddr, Duration)> {
+        self.processor.quarantined_hosts()
+    }
+
+    // The clock everything time-dependent reads; hand it to rules built by
+    // hand (`TimeWindowRule::with_clock` and the like)
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
 
     pub fn get_stats(&self) -> FirewallStats {
-        self.stats_collector.get_stats()
+        let mut stats = self.stats_collector.get_stats();
+        stats.flow_table = self.flow_tracker.table_stats();
+        stats.rules = self.rule_manager.rule_stats();
+        stats
     }
 
     pub fn active_flows(&self) -> usize {
         self.flow_tracker.active_flow_count()
     }
 
+    // Clears the global counters and every rule's hit counters
+    pub fn reset_stats(&self) {
+        self.stats_collector.reset();
+        self.rule_manager.reset_all_counters();
+    }
+
     pub fn cleanup_old_flows(&self, max_age_secs: u64) {
         self.flow_tracker.cleanup_old_flows(max_age_secs)
     }
 
+    pub fn expire_flows(&self) -> usize {
+        self.flow_tracker.expire_flows()
+    }
+
+    // Forgets rate-limit keys idle for `threshold_secs`, in the named
+    // limiters and in rules that keep their own
+    pub fn cleanup_rate_limiters(&self, threshold_secs: u64) {
+        self.processor.cleanup_rate_limiters(threshold_secs);
+        self.rule_manager.cleanup_rules(threshold_secs);
+    }
+
+    // The current rule generation as backends get it
+    pub fn policy(&self) -> Policy {
+        current_policy(&self.rule_manager, &self.processor)
+    }
+    // Pushes the current policy to every backend, even ones already at this
+    // generation: to retry after a failure, pick up rate limiters
+    // registered since, or rule schedules that started or ended
+    pub fn sync_backends(&self) -> Vec<BackendStatus> {
+        self.backends.sync_all(&self.policy())
+    }
+    pub fn flush_backends(&self) -> Vec<(String, Result<(), BackendError>)> {
+        self.backends.flush_all()
+    }
+    pub fn backend_status(&self) -> Vec<BackendStatus> {
+        self.backends.status()
+    }
+}
+
+fn current_policy(rule_manager: &RuleManager, processor: &PacketProcessor) -> Policy {
+    let (generation, mut config, userspace_only) = rule_manager.export_generation();
+    config.rate_limiters = processor.rate_limiter_configs();
+    Policy { generation, config, userspace_only, now: rule_manager.clock().wall_time() }
 }
+
 pub struct FirewallBuilder {
     default_action: Action,
     stats_collector: Option<Arc<dyn StatsCollector>>,
-    // Could add more options later:
-    // max_flows: Option<usize>,
-    // flow_timeout: Option<u64>,
+    flow_table: FlowTableConfig,
+    history_limit: usize,
+    compile_rules: bool,
+    clock: Arc<dyn Clock>,
+    backends: Vec<Arc<dyn FirewallBackend>>,
+    mode: ProcessingMode,
+    alert_sink: Option<Arc<dyn AlertSink>>,
 }
 
 impl FirewallBuilder {
@@ -99,6 +295,13 @@ impl FirewallBuilder {
         Self {
             default_action,
             stats_collector: None,
+            flow_table: FlowTableConfig::default(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            compile_rules: false,
+            clock: Arc::new(SystemClock),
+            backends: Vec::new(),
+            mode: ProcessingMode::Enforce,
+            alert_sink: None,
         }
     }
 
@@ -107,36 +310,153 @@ impl FirewallBuilder {
         self
     }
 
+    pub fn with_max_flows(mut self, max_flows: usize) -> Self {
+        self.flow_table.max_flows = max_flows;
+        self
+    }
+
+    pub fn with_flow_timeouts(mut self, timeouts: FlowTimeouts) -> Self {
+        self.flow_table.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_flow_shards(mut self, shards: usize) -> Self {
+        self.flow_table.shards = shards;
+        self
+    }
+
+    pub fn with_eviction_policy(mut self, eviction: EvictionPolicy) -> Self {
+        self.flow_table.eviction = eviction;
+        self
+    }
+
+    // How many earlier rule generations are kept for rollback
+    pub fn with_rule_history(mut self, generations: usize) -> Self {
+        self.history_limit = generations;
+        self
+    }
+
+    // Index the rules so each packet is only checked against the ones that
+    // could match it. Verdicts are the same either way.
+    pub fn with_compiled_rules(mut self, enabled: bool) -> Self {
+        self.compile_rules = enabled;
+        self
+    }
+
+    // Time source for flows, rate limiters, quarantines, rule schedules
+    // and rules loaded from a config; `ManualClock` for tests,
+    // `PacketClock` to replay captures
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // `Monitor` to run passively: verdicts are recorded and alerted on
+    // but never meant to drop anything
+    pub fn with_mode(mut self, mode: ProcessingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_alert_sink(mut self, sink: Arc<dyn AlertSink>) -> Self {
+        self.alert_sink = Some(sink);
+        self
+    }
+
+    // Kernel backend kept in step with the rules: every new generation is
+    // synced to it from the thread that published it. Can be given more
+    // than once.
+    pub fn with_backend(mut self, backend: Arc<dyn FirewallBackend>) -> Self {
+        self.backends.push(backend);
+        self
+    }
+
     pub fn build(self) -> Firewall {
-        let flow_tracker = Arc::new(FlowTracker::new());
+        let flow_tracker = Arc::new(
+            FlowTracker::with_config(self.flow_table).with_clock(Arc::clone(&self.clock)),
+        );
 
         // Use custom or default stats collector
         let stats_collector = self.stats_collector
-            .unwrap_or_else(|| Arc::new(InMemoryStatsCollector::new()));
+            .unwrap_or_else(|| Arc::new(InMemoryStatsCollector::with_clock(Arc::clone(&self.clock))));
 
-        let rule_manager = Arc::new(RuleManager::new());
+        let rule_manager = Arc::new(
+            RuleManager::with_history_limit(self.default_action, self.history_limit)
+                .with_clock(Arc::clone(&self.clock)),
+        );
+        rule_manager.set_compiled(self.compile_rules);
 
-        let processor = Arc::new(PacketProcessor::new(
-            self.default_action,
+        let mut processor = PacketProcessor::new(
             Arc::clone(&flow_tracker),
             Arc::clone(&stats_collector),
             rule_manager.rules_ref(),
-        ));
+        )
+        .with_clock(Arc::clone(&self.clock))
+        .with_mode(self.mode);
+        if let Some(sink) = self.alert_sink {
+            processor = processor.with_alert_sink(sink);
+        }
+        let processor = Arc::new(processor);
+
+        let backends = Arc::new(BackendSync::new(self.backends));
+        if !backends.is_empty() {
+            // Weak, or the rule set would keep its own manager alive
+            let manager = Arc::downgrade(&rule_manager);
+            let engine = Arc::downgrade(&processor);
+            let sync = Arc::clone(&backends);
+            rule_manager.on_publish(move |_| {
+                let (Some(manager), Some(engine)) = (Weak::upgrade(&manager), Weak::upgrade(&engine)) else {
+                    return;
+                };
+                sync.sync_new(|| current_policy(&manager, &engine));
+            });
+        }
 
         Firewall {
             processor,
             rule_manager,
             flow_tracker,
             stats_collector,
+            clock: self.clock,
+            backends,
         }
     }
 }
 // ReExports
-pub use domain::packet::{Packet, Protocol, PacketHeader};
-pub use domain::rule::{Filter, Action, RuleEntry};
-pub use domain::flow::{FlowKey, FlowStats, FlowTracker};
-pub use domain::stats::{FirewallStats, StatsCollector, InMemoryStatsCollector};
-pub use application::engine::PacketProcessor;
-pub use application::rule_manager::{RuleManager, RuleInfo};
+pub use domain::packet::{Packet, Protocol, PacketHeader, TcpFlags, TcpInfo, IcmpInfo};
+pub use domain::decoder::{DecodeError, Layer};
+pub use domain::prefix_trie::{IpPrefix, PrefixTrie, PrefixParseError};
+pub use domain::geo::{GeoInfo, GeoLookup};
+pub use domain::rule::{
+    Filter, Action, ActionParseError, RejectWith, Verdict, VerdictReason, RuleEntry, RuleSchedule,
+};
+pub use domain::trace::{PacketTrace, TraceStep};
+pub use domain::alert::{Alert, AlertSink, InMemoryAlertSink};
+pub use domain::ruleset::{RuleSet, RuleSetCell, RuleSetDraft, DEFAULT_HISTORY_LIMIT};
+pub use domain::chain::{Chain, ChainError, DEFAULT_CHAIN};
+pub use domain::flow::{
+    FlowKey, FlowStats, FlowTracker, FlowTimeouts, ConnState, TcpState,
+    FlowTableConfig, FlowTableStats, EvictionPolicy,
+};
+pub use domain::stats::{
+    FirewallStats, StatsCollector, InMemoryStatsCollector, VerdictCounts,
+    RuleCounters, RuleHits, RuleStats,
+};
+pub use domain::rate_limiter::{RateLimitAlgorithm, RateLimitConfig, RateLimitKeyType};
+pub use domain::clock::{Clock, SystemClock, ManualClock, PacketClock};
+pub use application::engine::{PacketProcessor, ProcessingMode};
+pub use application::rule_manager::{RuleManager, RuleInfo, ChainInfo, GenerationInfo};
+pub use application::transaction::{RuleTransaction, TransactionError};
+pub use application::sweeper::RuleSweeper;
+pub use application::analyzer::{analyze, AnalysisReport, Finding, RuleRef};
+pub use application::config::{FirewallConfig, ChainConfig, RuleConfig, RuleKind, ConfigError};
+pub use infrastructure::geoip::{MmdbGeoDatabase, GeoIpError, GeoIpWatcher};
+pub use infrastructure::backends::backend::{
+    FirewallBackend, Policy, SyncReport, BackendError, Capabilities, MatchKind, ActionKind,
+    NullBackend, RecordingBackend, BackendSync, BackendStatus,
+};
+pub use infrastructure::backends::nftables::{NfTablesBackend, NftHook, NftRuleset, NftUpdate};
+pub use infrastructure::backends::iptables::{IpTablesBackend, IpFamily, IptRuleset, IptUpdate, IptDrift};
+pub use infrastructure::backends::runner::{CommandRunner, CommandOutput, CommandCall, SystemRunner, ScriptedRunner};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
\ No newline at end of file
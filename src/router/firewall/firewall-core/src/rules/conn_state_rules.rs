@@ -0,0 +1,120 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::application::config::rule_config::protocol_list;
+use crate::domain::chain;
+use crate::domain::flow::ConnState;
+use crate::domain::packet::{Packet, PacketHeader, Protocol};
+use crate::domain::rule::{Action, Filter};
+use std::collections::HashSet;
+
+pub struct ConnStateRule {
+    name: String,
+    states: HashSet<ConnState>,
+    protocols: HashSet<Protocol>,
+    action: Action,
+    priority: i32,
+}
+
+impl ConnStateRule {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            states: HashSet::new(),
+            protocols: HashSet::new(),
+            action: Action::Allow,
+            priority: 100,
+        }
+    }
+
+    pub fn match_state(mut self, state: ConnState) -> Self {
+        self.states.insert(state);
+        self
+    }
+
+    pub fn match_states(mut self, states: impl IntoIterator<Item = ConnState>) -> Self {
+        self.states.extend(states);
+        self
+    }
+
+    pub fn for_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocols.insert(protocol);
+        self
+    }
+
+    // Return traffic for connections we already let through
+    pub fn allow_established(self) -> Self {
+        self.match_states(vec![ConnState::Established, ConnState::Related])
+            .with_action(Action::Allow)
+    }
+
+    pub fn block_new(self) -> Self {
+        self.match_state(ConnState::New)
+            .with_action(Action::Block)
+    }
+
+    pub fn block_invalid(self) -> Self {
+        self.match_state(ConnState::Invalid)
+            .with_action(Action::Block)
+    }
+
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn matches_protocol(&self, protocol: &Protocol) -> bool {
+        self.protocols.is_empty() || self.protocols.contains(protocol)
+    }
+}
+
+impl Filter for ConnStateRule {
+    fn quick_match(&self, header: &PacketHeader) -> bool {
+        match header.conn_state {
+            Some(state) => self.states.contains(&state) && self.matches_protocol(&header.protocol),
+            None => false,
+        }
+    }
+
+    fn check_packet(&self, _packet: &Packet) -> Option<Action> {
+        Some(self.action.clone())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+}
+
+impl ConnStateRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        let order = |state: &ConnState| match state {
+            ConnState::New => 0,
+            ConnState::Established => 1,
+            ConnState::Related => 2,
+            ConnState::Invalid => 3,
+        };
+        let mut states: Vec<ConnState> = self.states.iter().copied().collect();
+        states.sort_by_key(order);
+
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::ConnState {
+                states,
+                protocols: protocol_list(&self.protocols),
+                action: self.action.clone(),
+            },
+        ))
+    }
+}
@@ -0,0 +1,339 @@
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::flow::ConnState;
+use crate::domain::packet::{PacketHeader, Protocol, TcpFlags};
+use crate::domain::prefix_trie::PrefixTrie;
+use crate::rules::expr_rules::parser::{self, ExprParseError};
+use crate::rules::ip_rules::AddressMatch;
+use crate::rules::time_rules::TimeWindow;
+use chrono::{Datelike, Weekday};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+// Header fields compared against numbers, ranges and sets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumericField {
+    SourcePort,
+    DestinationPort,
+    // Either port
+    Port,
+    Ttl,
+    Dscp,
+    Ecn,
+    Vlan,
+    IcmpType,
+    IcmpCode,
+}
+
+impl NumericField {
+    // Largest value the field can hold, used to check literals
+    pub fn max(&self) -> u32 {
+        match self {
+            NumericField::SourcePort | NumericField::DestinationPort | NumericField::Port => 65535,
+            NumericField::Vlan => 4095,
+            NumericField::Dscp => 63,
+            NumericField::Ecn => 3,
+            NumericField::Ttl | NumericField::IcmpType | NumericField::IcmpCode => 255,
+        }
+    }
+
+    fn values(&self, header: &PacketHeader) -> (Option<u32>, Option<u32>) {
+        let one = |v: Option<u32>| (v, None);
+        match self {
+            NumericField::SourcePort => one(header.source_port.map(u32::from)),
+            NumericField::DestinationPort => one(header.destination_port.map(u32::from)),
+            NumericField::Port => (
+                header.source_port.map(u32::from),
+                header.destination_port.map(u32::from),
+            ),
+            NumericField::Ttl => one(Some(u32::from(header.ttl))),
+            NumericField::Dscp => one(Some(u32::from(header.dscp))),
+            NumericField::Ecn => one(Some(u32::from(header.ecn))),
+            NumericField::Vlan => one(header.vlan_id.map(u32::from)),
+            NumericField::IcmpType => one(header.icmp.map(|i| u32::from(i.icmp_type))),
+            NumericField::IcmpCode => one(header.icmp.map(|i| u32::from(i.code))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagMatch {
+    // Every listed flag is set
+    All(TcpFlags),
+    // At least one listed flag is set
+    Any(TcpFlags),
+    // The flags are exactly these
+    Exact(TcpFlags),
+}
+
+// Compiled expression tree. Fields missing from a packet (ports on ICMP,
+// flags on UDP, ...) never match.
+pub enum Node {
+    Const(bool),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Not(Box<Node>),
+    Protocol(Vec<Protocol>),
+    Address(AddressMatch, PrefixTrie<()>),
+    Number(NumericField, Vec<RangeInclusive<u32>>),
+    TcpFlags(FlagMatch),
+    ConnState(Vec<ConnState>),
+    Time(Vec<TimeWindow>),
+    Day(Vec<Weekday>),
+    Fragment,
+}
+
+impl Node {
+    pub fn matches(&self, header: &PacketHeader) -> bool {
+        self.matches_with(header, &SystemClock)
+    }
+
+    // `time` and `day` read `clock`
+    pub fn matches_with(&self, header: &PacketHeader, clock: &dyn Clock) -> bool {
+        match self {
+            Node::Const(value) => *value,
+            Node::And(nodes) => nodes.iter().all(|n| n.matches_with(header, clock)),
+            Node::Or(nodes) => nodes.iter().any(|n| n.matches_with(header, clock)),
+            Node::Not(node) => !node.matches_with(header, clock),
+            Node::Protocol(protocols) => protocols.contains(&header.protocol),
+            Node::Address(side, set) => {
+                let source = matches!(side, AddressMatch::Source | AddressMatch::Either)
+                    && set.contains(&header.source_ip);
+                let destination = matches!(side, AddressMatch::Destination | AddressMatch::Either)
+                    && set.contains(&header.destination_ip);
+                source || destination
+            }
+            Node::Number(field, ranges) => {
+                let (first, second) = field.values(header);
+                let hit = |value: Option<u32>| value.is_some_and(|v| ranges.iter().any(|r| r.contains(&v)));
+                hit(first) || hit(second)
+            }
+            Node::TcpFlags(flags) => match header.tcp_flags() {
+                Some(actual) => match flags {
+                    FlagMatch::All(wanted) => actual.contains(*wanted),
+                    FlagMatch::Any(wanted) => actual.intersects(*wanted),
+                    FlagMatch::Exact(wanted) => actual == *wanted,
+                },
+                None => false,
+            },
+            Node::ConnState(states) => header.conn_state.is_some_and(|s| states.contains(&s)),
+            Node::Time(windows) => {
+                let now = clock.local_time();
+                windows.iter().any(|w| w.contains(&now))
+            }
+            Node::Day(days) => days.contains(&clock.local_time().weekday()),
+            Node::Fragment => header.is_fragment(),
+        }
+    }
+
+    // Three-valued `matches_with`: `None` when the answer hangs on a
+    // connection state the header doesn't carry
+    fn eval(&self, header: &PacketHeader, clock: &dyn Clock) -> Option<bool> {
+        match self {
+            Node::And(nodes) => {
+                let values: Vec<Option<bool>> = nodes.iter().map(|n| n.eval(header, clock)).collect();
+                if values.contains(&Some(false)) {
+                    Some(false)
+                } else if values.contains(&None) {
+                    None
+                } else {
+                    Some(true)
+                }
+            }
+            Node::Or(nodes) => {
+                let values: Vec<Option<bool>> = nodes.iter().map(|n| n.eval(header, clock)).collect();
+                if values.contains(&Some(true)) {
+                    Some(true)
+                } else if values.contains(&None) {
+                    None
+                } else {
+                    Some(false)
+                }
+            }
+            Node::Not(node) => node.eval(header, clock).map(|v| !v),
+            Node::ConnState(_) if header.conn_state.is_none() => None,
+            leaf => Some(leaf.matches_with(header, clock)),
+        }
+    }
+}
+
+// A parsed match expression, e.g.
+//
+//   tcp dport 23 and src 10.0.0.0/8 and not (time 09:00-17:00 and day mon-fri)
+//
+// Cloning is cheap; the tree is shared. Two expressions are equal when
+// their source text is.
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Arc<Node>,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ExprParseError> {
+        let root = parser::parse(source)?;
+        Ok(Self {
+            source: source.trim().to_string(),
+            root: Arc::new(root),
+        })
+    }
+
+    pub fn matches(&self, header: &PacketHeader) -> bool {
+        self.root.matches(header)
+    }
+
+    pub fn matches_with(&self, header: &PacketHeader, clock: &dyn Clock) -> bool {
+        self.root.matches_with(header, clock)
+    }
+
+    // Like `matches_with`, but a header without a connection state leaves
+    // `ct.state` open: false only if no state could make it match
+    pub fn may_match(&self, header: &PacketHeader, clock: &dyn Clock) -> bool {
+        self.root.eval(header, clock) != Some(false)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Expression").field(&self.source).finish()
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Expression {
+    type Err = ExprParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Expression::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::packet::{IcmpInfo, Packet, TcpInfo};
+    use crate::domain::rule::{Action, Filter};
+    use crate::rules::expr_rules::ExpressionRule;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn tcp(src: [u8; 4], dport: u16, flags: TcpFlags) -> Packet {
+        let mut packet = Packet::new(IpAddr::V4(Ipv4Addr::from(src)));
+        packet.destination_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        packet.protocol = Protocol::Tcp;
+        packet.source_port = Some(40000);
+        packet.destination_port = Some(dport);
+        packet.tcp = Some(TcpInfo { flags, sequence: 0, acknowledgment: 0, window: 0 });
+        packet
+    }
+
+    fn icmp(icmp_type: u8) -> Packet {
+        let mut packet = Packet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        packet.protocol = Protocol::Icmp;
+        packet.icmp = Some(IcmpInfo { icmp_type, code: 0 });
+        packet
+    }
+
+    fn matches(source: &str, packet: &Packet) -> bool {
+        Expression::parse(source).unwrap().matches(&packet.header())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let ssh = tcp([10, 0, 0, 1], 22, TcpFlags::SYN);
+        let web_from_outside = tcp([8, 8, 8, 8], 80, TcpFlags::SYN);
+        // dport 22 or (dport 80 and src 10/8)
+        let source = "dport 22 or dport 80 and src 10.0.0.0/8";
+        assert!(matches(source, &ssh));
+        assert!(!matches(source, &web_from_outside));
+        // Parentheses and the implicit and regroup it
+        let source = "(dport 22 or dport 80) src 10.0.0.0/8";
+        assert!(matches(source, &ssh));
+        assert!(!matches(source, &web_from_outside));
+        assert!(matches("not dport 22 and dport 80", &web_from_outside));
+        assert!(!matches("not (dport 22 or dport 80)", &web_from_outside));
+    }
+
+    #[test]
+    fn sets_and_ranges() {
+        let packet = |port| tcp([10, 0, 0, 1], port, TcpFlags::ACK);
+        let set = "dport { 22, 8000-8100, 443 }";
+        for (port, expected) in [(22, true), (443, true), (8000, true), (8100, true), (7999, false), (8101, false)] {
+            assert_eq!(matches(set, &packet(port)), expected, "port {}", port);
+        }
+        assert!(matches("dport >= 1024", &packet(1024)));
+        assert!(!matches("dport > 1024", &packet(1024)));
+        assert!(matches("dport < 1024", &packet(1023)));
+        assert!(matches("dport <= 65535", &packet(65535)));
+        assert!(!matches("dport != { 22, 443 }", &packet(443)));
+        assert!(matches("port 40000", &packet(80)));
+        assert!(matches("tcp.flags syn|ack", &tcp([10, 0, 0, 1], 80, TcpFlags::SYN | TcpFlags::ACK)));
+        assert!(!matches("tcp.flags == syn", &tcp([10, 0, 0, 1], 80, TcpFlags::SYN | TcpFlags::ACK)));
+        assert!(matches("tcp.flags { syn, fin }", &tcp([10, 0, 0, 1], 80, TcpFlags::FIN)));
+    }
+
+    #[test]
+    fn fields_the_packet_lacks_never_match() {
+        let ping = icmp(8);
+        assert!(!matches("dport 22", &ping));
+        assert!(!matches("dport { 0-65535 }", &ping));
+        assert!(!matches("tcp.flags syn", &ping));
+        assert!(!matches("vlan 10", &ping));
+        // ... so their negations do
+        assert!(matches("dport != 22", &ping));
+        assert!(matches("not dport 22", &ping));
+        assert!(matches("not tcp.flags syn", &ping));
+        assert!(matches("icmp icmp.type 8", &ping));
+        assert!(!matches("icmp.type 8", &tcp([10, 0, 0, 1], 22, TcpFlags::SYN)));
+    }
+
+    #[test]
+    fn conn_state_is_left_open_without_a_tracker() {
+        let expression = Expression::parse("ct.state new and dport 22").unwrap();
+        let mut header = tcp([10, 0, 0, 1], 22, TcpFlags::SYN).header();
+        assert!(!expression.matches_with(&header, &SystemClock));
+        assert!(expression.may_match(&header, &SystemClock));
+
+        header.conn_state = Some(ConnState::Established);
+        assert!(!expression.may_match(&header, &SystemClock));
+        header.conn_state = Some(ConnState::New);
+        assert!(expression.may_match(&header, &SystemClock));
+
+        // The rest of the expression still decides
+        let header = tcp([10, 0, 0, 1], 80, TcpFlags::SYN).header();
+        assert!(!expression.may_match(&header, &SystemClock));
+        let either = Expression::parse("ct.state new or dport 22").unwrap();
+        assert!(either.may_match(&header, &SystemClock));
+        let negated = Expression::parse("not ct.state established and dport 80").unwrap();
+        assert!(negated.may_match(&header, &SystemClock));
+    }
+
+    #[test]
+    fn check_packet_evaluates_the_expression() {
+        let rule = ExpressionRule::parse("ssh", "tcp dport 22 and src 10.0.0.0/8")
+            .unwrap()
+            .with_action(Action::Allow);
+        assert_eq!(rule.check_packet(&tcp([10, 1, 2, 3], 22, TcpFlags::SYN)), Some(Action::Allow));
+        assert_eq!(rule.check_packet(&tcp([10, 1, 2, 3], 23, TcpFlags::SYN)), None);
+        assert_eq!(rule.check_packet(&tcp([8, 8, 8, 8], 22, TcpFlags::SYN)), None);
+        assert_eq!(rule.check_packet(&icmp(8)), None);
+    }
+}
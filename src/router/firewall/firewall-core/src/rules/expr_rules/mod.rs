@@ -0,0 +1,7 @@
+pub mod expression;
+pub mod parser;
+pub mod rule;
+
+pub use expression::Expression;
+pub use parser::ExprParseError;
+pub use rule::ExpressionRule;
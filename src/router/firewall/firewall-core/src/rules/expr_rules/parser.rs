@@ -0,0 +1,610 @@
+use crate::domain::flow::ConnState;
+use crate::domain::packet::{Protocol, TcpFlags};
+use crate::domain::prefix_trie::{IpPrefix, PrefixTrie};
+use crate::rules::expr_rules::expression::{FlagMatch, Node, NumericField};
+use crate::rules::ip_rules::AddressMatch;
+use crate::rules::time_rules::TimeWindow;
+use chrono::{NaiveTime, Timelike, Weekday};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprParseError {
+    // Byte offset into the expression
+    pub offset: usize,
+    // 1-based, counted in characters
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for ExprParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Word(String),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Bang,
+    AndAnd,
+    OrOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    End,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Word(word) => write!(f, "'{}'", word),
+            Tok::LParen => f.write_str("'('"),
+            Tok::RParen => f.write_str("')'"),
+            Tok::LBrace => f.write_str("'{'"),
+            Tok::RBrace => f.write_str("'}'"),
+            Tok::Comma => f.write_str("','"),
+            Tok::Bang => f.write_str("'!'"),
+            Tok::AndAnd => f.write_str("'&&'"),
+            Tok::OrOr => f.write_str("'||'"),
+            Tok::Eq => f.write_str("'=='"),
+            Tok::Ne => f.write_str("'!='"),
+            Tok::Lt => f.write_str("'<'"),
+            Tok::Le => f.write_str("'<='"),
+            Tok::Gt => f.write_str("'>'"),
+            Tok::Ge => f.write_str("'>='"),
+            Tok::End => f.write_str("end of expression"),
+        }
+    }
+}
+
+struct Token {
+    tok: Tok,
+    offset: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"(){},!=<>&".contains(c)
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExprParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        let mut two = |tok| {
+            chars.next();
+            tok
+        };
+        let tok = match (c, next) {
+            (c, _) if c.is_whitespace() => continue,
+            ('(', _) => Tok::LParen,
+            (')', _) => Tok::RParen,
+            ('{', _) => Tok::LBrace,
+            ('}', _) => Tok::RBrace,
+            (',', _) => Tok::Comma,
+            ('!', Some('=')) => two(Tok::Ne),
+            ('!', _) => Tok::Bang,
+            ('=', Some('=')) => two(Tok::Eq),
+            ('=', _) => Tok::Eq,
+            ('<', Some('=')) => two(Tok::Le),
+            ('<', _) => Tok::Lt,
+            ('>', Some('=')) => two(Tok::Ge),
+            ('>', _) => Tok::Gt,
+            ('&', Some('&')) => two(Tok::AndAnd),
+            ('|', Some('|')) => two(Tok::OrOr),
+            ('&', _) => {
+                return Err(error_at(source, offset, "expected '&&' or 'and'".to_string()));
+            }
+            _ => {
+                // Words run until a delimiter; a single '|' joins TCP flags
+                let mut end = offset + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    let doubled_pipe = c == '|' && source[i + 1..].starts_with('|');
+                    if !is_word_char(c) || doubled_pipe {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                Tok::Word(source[offset..end].to_string())
+            }
+        };
+        tokens.push(Token { tok, offset });
+    }
+
+    tokens.push(Token { tok: Tok::End, offset: source.len() });
+    Ok(tokens)
+}
+
+fn error_at(source: &str, offset: usize, message: String) -> ExprParseError {
+    let column = source[..offset.min(source.len())].chars().count() + 1;
+    ExprParseError { offset, column, message }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// How deep parentheses and negations may nest. The parser recurses once per
+// level, so this keeps a hostile expression from overflowing the stack.
+const MAX_NESTING: usize = 64;
+
+pub(crate) fn parse(source: &str) -> Result<Node, ExprParseError> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?,
+        pos: 0,
+        depth: 0,
+    };
+    if parser.peek() == &Tok::End {
+        return Err(parser.error("empty expression"));
+    }
+    let node = parser.or()?;
+    match parser.peek() {
+        Tok::End => Ok(node),
+        Tok::RParen => Err(parser.error("unmatched ')'")),
+        other => Err(parser.error(format!("unexpected {}", other))),
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    // Open parentheses and negations around the current term
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].offset
+    }
+
+    fn advance(&mut self) -> Tok {
+        let tok = self.tokens[self.pos].tok.clone();
+        if tok != Tok::End {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn error(&self, message: impl Into<String>) -> ExprParseError {
+        error_at(self.source, self.offset(), message.into())
+    }
+
+    fn error_at(&self, offset: usize, message: impl Into<String>) -> ExprParseError {
+        error_at(self.source, offset, message.into())
+    }
+
+    // Runs `parse` one nesting level down, `offset` being where the level opens
+    fn nested<T>(
+        &mut self,
+        offset: usize,
+        parse: impl FnOnce(&mut Self) -> Result<T, ExprParseError>,
+    ) -> Result<T, ExprParseError> {
+        if self.depth >= MAX_NESTING {
+            return Err(self.error_at(
+                offset,
+                format!("expression nests more than {} levels deep", MAX_NESTING),
+            ));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Tok::Word(w) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn or(&mut self) -> Result<Node, ExprParseError> {
+        let mut nodes = vec![self.and()?];
+        while self.peek() == &Tok::OrOr || self.is_word("or") {
+            self.advance();
+            nodes.push(self.and()?);
+        }
+        Ok(if nodes.len() == 1 { nodes.pop().unwrap() } else { Node::Or(nodes) })
+    }
+
+    // Adjacent terms are joined with an implicit `and`, as in nftables:
+    // "tcp dport 22" is "tcp and dport 22"
+    fn and(&mut self) -> Result<Node, ExprParseError> {
+        let mut nodes = vec![self.unary()?];
+        loop {
+            if self.peek() == &Tok::AndAnd || self.is_word("and") {
+                self.advance();
+            } else if !self.starts_term() {
+                break;
+            }
+            nodes.push(self.unary()?);
+        }
+        Ok(if nodes.len() == 1 { nodes.pop().unwrap() } else { Node::And(nodes) })
+    }
+
+    fn starts_term(&self) -> bool {
+        match self.peek() {
+            Tok::LParen | Tok::Bang => true,
+            Tok::Word(_) => !self.is_word("or"),
+            _ => false,
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprParseError> {
+        if self.peek() == &Tok::Bang || self.is_word("not") {
+            let offset = self.offset();
+            self.advance();
+            let node = self.nested(offset, Self::unary)?;
+            return Ok(Node::Not(Box::new(node)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprParseError> {
+        let offset = self.offset();
+        match self.advance() {
+            Tok::LParen => {
+                let node = self.nested(offset, Self::or)?;
+                if self.advance() != Tok::RParen {
+                    return Err(self.error_at(offset, "unclosed '('"));
+                }
+                Ok(node)
+            }
+            Tok::Word(word) if ["and", "or"].iter().any(|op| word.eq_ignore_ascii_case(op)) => {
+                Err(self.error_at(offset, format!("expected a match, found '{}'", word)))
+            }
+            Tok::Word(word) => self.predicate(&word.to_ascii_lowercase(), offset),
+            Tok::End => Err(self.error_at(offset, "expected a match after an operator")),
+            other => Err(self.error_at(offset, format!("expected a match, found {}", other))),
+        }
+    }
+
+    fn predicate(&mut self, word: &str, offset: usize) -> Result<Node, ExprParseError> {
+        let field = match word {
+            "any" | "true" => return Ok(Node::Const(true)),
+            "none" | "false" => return Ok(Node::Const(false)),
+            "tcp" => return Ok(Node::Protocol(vec![Protocol::Tcp])),
+            "udp" => return Ok(Node::Protocol(vec![Protocol::Udp])),
+            "icmp" => return Ok(Node::Protocol(vec![Protocol::Icmp])),
+            "icmpv6" => return Ok(Node::Protocol(vec![Protocol::Icmpv6])),
+            "frag" | "fragment" => return Ok(Node::Fragment),
+            "proto" | "protocol" => return self.protocol(),
+            "src" | "saddr" => return self.address(AddressMatch::Source),
+            "dst" | "daddr" => return self.address(AddressMatch::Destination),
+            "addr" | "host" => return self.address(AddressMatch::Either),
+            "tcp.flags" => return self.tcp_flags(),
+            "ct.state" => return self.conn_state(),
+            "ct" if self.is_word("state") => {
+                self.advance();
+                return self.conn_state();
+            }
+            "time" => return self.time(),
+            "day" => return self.day(),
+            "sport" => Some(NumericField::SourcePort),
+            "dport" => Some(NumericField::DestinationPort),
+            "port" => Some(NumericField::Port),
+            "ttl" => Some(NumericField::Ttl),
+            "dscp" => Some(NumericField::Dscp),
+            "ecn" => Some(NumericField::Ecn),
+            "vlan" => Some(NumericField::Vlan),
+            "icmp.type" => Some(NumericField::IcmpType),
+            "icmp.code" => Some(NumericField::IcmpCode),
+            _ => None,
+        };
+        match field {
+            Some(field) => self.number(field),
+            None => Err(self.error_at(offset, format!("unknown match '{}'", word))),
+        }
+    }
+
+    fn operator(&mut self, ordered: bool) -> Result<Op, ExprParseError> {
+        let op = match self.peek() {
+            Tok::Eq => Op::Eq,
+            Tok::Ne => Op::Ne,
+            Tok::Lt => Op::Lt,
+            Tok::Le => Op::Le,
+            Tok::Gt => Op::Gt,
+            Tok::Ge => Op::Ge,
+            _ if self.is_word("in") => Op::Eq,
+            // No operator means equality / membership
+            _ => return Ok(Op::Eq),
+        };
+        if !ordered && !matches!(op, Op::Eq | Op::Ne) {
+            return Err(self.error("only ==, != and 'in' apply to this match"));
+        }
+        self.advance();
+        Ok(op)
+    }
+
+    // One value or a `{ a, b, c }` set; each item comes back with its offset
+    fn values(&mut self) -> Result<Vec<(String, usize)>, ExprParseError> {
+        let offset = self.offset();
+        match self.advance() {
+            Tok::Word(word) => Ok(vec![(word, offset)]),
+            Tok::LBrace => {
+                let mut items = Vec::new();
+                loop {
+                    let offset = self.offset();
+                    match self.advance() {
+                        Tok::Word(word) => items.push((word, offset)),
+                        Tok::RBrace if !items.is_empty() => break,
+                        Tok::RBrace => return Err(self.error_at(offset, "empty set")),
+                        other => {
+                            return Err(self.error_at(offset, format!("expected a set element, found {}", other)));
+                        }
+                    }
+                    let offset = self.offset();
+                    match self.advance() {
+                        Tok::Comma if self.peek() == &Tok::RBrace => {
+                            self.advance();
+                            break;
+                        }
+                        Tok::Comma => {}
+                        Tok::RBrace => break,
+                        Tok::End => return Err(self.error_at(offset, "unclosed '{'")),
+                        other => {
+                            return Err(self.error_at(offset, format!("expected ',' or '}}', found {}", other)));
+                        }
+                    }
+                }
+                Ok(items)
+            }
+            Tok::End => Err(self.error_at(offset, "expected a value")),
+            other => Err(self.error_at(offset, format!("expected a value, found {}", other))),
+        }
+    }
+
+    fn negate_if(op: Op, node: Node) -> Node {
+        if op == Op::Ne { Node::Not(Box::new(node)) } else { node }
+    }
+
+    fn number(&mut self, field: NumericField) -> Result<Node, ExprParseError> {
+        let op = self.operator(true)?;
+        let max = field.max();
+        let items = self.values()?;
+
+        let parse = |text: &str, offset: usize| -> Result<u32, ExprParseError> {
+            match text.parse::<u32>() {
+                Ok(value) if value <= max => Ok(value),
+                Ok(value) => Err(self.error_at(offset, format!("{} is out of range 0-{}", value, max))),
+                Err(_) => Err(self.error_at(offset, format!("invalid number '{}'", text))),
+            }
+        };
+
+        if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge) {
+            let [(text, offset)] = items.as_slice() else {
+                return Err(self.error("ordered comparisons take a single number"));
+            };
+            let value = parse(text, *offset)?;
+            let range = match op {
+                Op::Lt if value == 0 => None,
+                Op::Lt => Some(0..=value - 1),
+                Op::Le => Some(0..=value),
+                Op::Gt if value == max => None,
+                Op::Gt => Some(value + 1..=max),
+                _ => Some(value..=max),
+            };
+            return Ok(Node::Number(field, range.into_iter().collect()));
+        }
+
+        let mut ranges: Vec<RangeInclusive<u32>> = Vec::with_capacity(items.len());
+        for (text, offset) in &items {
+            let range = match text.split_once('-') {
+                Some((low, high)) => {
+                    let (low, high) = (parse(low, *offset)?, parse(high, *offset)?);
+                    if low > high {
+                        return Err(self.error_at(*offset, format!("range '{}' is reversed", text)));
+                    }
+                    low..=high
+                }
+                None => {
+                    let value = parse(text, *offset)?;
+                    value..=value
+                }
+            };
+            ranges.push(range);
+        }
+        Ok(Self::negate_if(op, Node::Number(field, ranges)))
+    }
+
+    fn protocol(&mut self) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let protocols = self.values()?.into_iter()
+            .map(|(text, offset)| match text.to_ascii_lowercase().as_str() {
+                "tcp" => Ok(Protocol::Tcp),
+                "udp" => Ok(Protocol::Udp),
+                "icmp" => Ok(Protocol::Icmp),
+                "icmpv6" => Ok(Protocol::Icmpv6),
+                other => other.parse::<u8>()
+                    .map(Protocol::from_number)
+                    .map_err(|_| self.error_at(offset, format!("unknown protocol '{}'", text))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::negate_if(op, Node::Protocol(protocols)))
+    }
+
+    fn address(&mut self, side: AddressMatch) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let mut set = PrefixTrie::new();
+        for (text, offset) in self.values()? {
+            let prefix: IpPrefix = text.parse()
+                .map_err(|e| self.error_at(offset, format!("{}", e)))?;
+            set.insert(prefix, ());
+        }
+        Ok(Self::negate_if(op, Node::Address(side, set)))
+    }
+
+    // "tcp.flags syn|ack" needs both, "tcp.flags { syn, rst }" either,
+    // "tcp.flags == syn" exactly SYN
+    fn tcp_flags(&mut self) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let exact = matches!(self.tokens[self.pos - 1].tok, Tok::Eq | Tok::Ne);
+        let set = self.peek() == &Tok::LBrace;
+        let items = self.values()?;
+
+        let mut flags = TcpFlags::default();
+        for (text, offset) in &items {
+            for name in text.split('|') {
+                flags = flags | match name.to_ascii_lowercase().as_str() {
+                    "fin" => TcpFlags::FIN,
+                    "syn" => TcpFlags::SYN,
+                    "rst" => TcpFlags::RST,
+                    "psh" => TcpFlags::PSH,
+                    "ack" => TcpFlags::ACK,
+                    "urg" => TcpFlags::URG,
+                    "ece" => TcpFlags::ECE,
+                    "cwr" => TcpFlags::CWR,
+                    "ns" => TcpFlags::NS,
+                    _ => return Err(self.error_at(*offset, format!("unknown TCP flag '{}'", name))),
+                };
+            }
+        }
+
+        let flag_match = if exact && !set {
+            FlagMatch::Exact(flags)
+        } else if set {
+            FlagMatch::Any(flags)
+        } else {
+            FlagMatch::All(flags)
+        };
+        Ok(Self::negate_if(op, Node::TcpFlags(flag_match)))
+    }
+
+    fn conn_state(&mut self) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let states = self.values()?.into_iter()
+            .map(|(text, offset)| match text.to_ascii_lowercase().as_str() {
+                "new" => Ok(ConnState::New),
+                "established" => Ok(ConnState::Established),
+                "related" => Ok(ConnState::Related),
+                "invalid" => Ok(ConnState::Invalid),
+                _ => Err(self.error_at(offset, format!("unknown connection state '{}'", text))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::negate_if(op, Node::ConnState(states)))
+    }
+
+    // "time 22:00-06:00" wraps past midnight like `TimeWindow`
+    fn time(&mut self) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let mut windows = Vec::new();
+        for (text, offset) in self.values()? {
+            let invalid = || self.error_at(offset, format!("invalid time range '{}', expected HH:MM-HH:MM", text));
+            let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+            let start = NaiveTime::parse_from_str(start, "%H:%M").map_err(|_| invalid())?;
+            let end = NaiveTime::parse_from_str(end, "%H:%M").map_err(|_| invalid())?;
+            windows.push(TimeWindow::new(start.hour(), start.minute(), end.hour(), end.minute()));
+        }
+        Ok(Self::negate_if(op, Node::Time(windows)))
+    }
+
+    // "day sat", "day mon-fri" or "day { sat, sun }"
+    fn day(&mut self) -> Result<Node, ExprParseError> {
+        let op = self.operator(false)?;
+        let mut days = Vec::new();
+        for (text, offset) in self.values()? {
+            let parse = |name: &str| name.parse::<Weekday>()
+                .map_err(|_| self.error_at(offset, format!("unknown day '{}'", name)));
+            match text.split_once('-') {
+                Some((first, last)) => {
+                    let (mut day, last) = (parse(first)?, parse(last)?);
+                    days.push(day);
+                    while day != last {
+                        day = day.succ();
+                        days.push(day);
+                    }
+                }
+                None => days.push(parse(&text)?),
+            }
+        }
+        Ok(Self::negate_if(op, Node::Day(days)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> ExprParseError {
+        match parse(source) {
+            Ok(_) => panic!("'{}' parsed", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let e = error("tcp dport 70000");
+        assert_eq!((e.offset, e.column), (10, 11));
+        assert_eq!(e.message, "70000 is out of range 0-65535");
+
+        let e = error("tcp and bogus 1");
+        assert_eq!((e.offset, e.message.as_str()), (8, "unknown match 'bogus'"));
+
+        let e = error("dport { 22, 80");
+        assert_eq!(e.offset, 14);
+        assert_eq!(e.message, "unclosed '{'");
+
+        let e = error("(tcp or udp");
+        assert_eq!((e.offset, e.message.as_str()), (0, "unclosed '('"));
+
+        let e = error("tcp)");
+        assert_eq!((e.offset, e.message.as_str()), (3, "unmatched ')'"));
+
+        let e = error("tcp and");
+        assert_eq!(e.offset, 7);
+
+        let e = error("dport { }");
+        assert_eq!((e.offset, e.message.as_str()), (8, "empty set"));
+
+        assert_eq!(error("").message, "empty expression");
+        assert_eq!(error("src 10.0.0.1 & dst 10.0.0.2").offset, 13);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        // 'é' is two bytes
+        let e = error("src é");
+        assert_eq!((e.offset, e.column), (4, 5));
+        // The tokenizer rejects the lone '&' before 'é' reaches the parser
+        let e = error("src é & dst");
+        assert_eq!((e.offset, e.column), (7, 7));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deep = |depth: usize| format!("{}tcp{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&deep(MAX_NESTING)).is_ok());
+        let e = error(&deep(MAX_NESTING + 1));
+        assert_eq!(e.offset, MAX_NESTING);
+        assert!(e.message.contains("levels deep"));
+
+        assert!(parse(&format!("{}tcp", "! ".repeat(MAX_NESTING))).is_ok());
+        assert!(error(&format!("{}tcp", "not ".repeat(MAX_NESTING + 1))).message.contains("levels deep"));
+        // Far past the limit fails cleanly instead of overflowing the stack
+        error(&"(".repeat(100_000));
+        error(&"!".repeat(100_000));
+    }
+}
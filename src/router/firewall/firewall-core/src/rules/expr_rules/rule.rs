@@ -0,0 +1,97 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::domain::chain;
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::packet::{Packet, PacketHeader};
+use crate::domain::rule::{Action, Filter};
+use crate::rules::expr_rules::expression::Expression;
+use crate::rules::expr_rules::parser::ExprParseError;
+use std::sync::Arc;
+
+// Rule whose match is a composed expression rather than a fixed matcher:
+//
+//   ExpressionRule::parse("telnet-from-lan",
+//       "tcp dport { 23, 2323 } and src 10.0.0.0/8 and not time 09:00-17:00")?
+pub struct ExpressionRule {
+    name: String,
+    expression: Expression,
+    action: Action,
+    clock: Arc<dyn Clock>,
+    priority: i32,
+}
+
+impl ExpressionRule {
+    pub fn new(name: impl Into<String>, expression: Expression) -> Self {
+        Self {
+            name: name.into(),
+            expression,
+            action: Action::Block,
+            clock: Arc::new(SystemClock),
+            priority: 50,
+        }
+    }
+
+    pub fn parse(name: impl Into<String>, expression: &str) -> Result<Self, ExprParseError> {
+        Ok(Self::new(name, Expression::parse(expression)?))
+    }
+
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // Clock for `time` and `day` matches
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn expression(&self) -> &Expression {
+        &self.expression
+    }
+}
+
+impl Filter for ExpressionRule {
+    // The whole expression runs here, where the engine has filled in the
+    // connection state
+    fn quick_match(&self, header: &PacketHeader) -> bool {
+        self.expression.matches_with(header, self.clock.as_ref())
+    }
+
+    // A bare packet has no connection state, so `ct.state` is taken on
+    // trust from `quick_match`; the rest of the expression must hold
+    fn check_packet(&self, packet: &Packet) -> Option<Action> {
+        self.expression
+            .may_match(&packet.header(), self.clock.as_ref())
+            .then(|| self.action.clone())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+}
+
+impl ExpressionRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::Expression {
+                expression: self.expression.clone(),
+                action: self.action.clone(),
+            },
+        ))
+    }
+}
@@ -1,21 +1,143 @@
-use crate::traits::{Filter, Action};
-use crate::packets::Packet;
+use crate::domain::chain;
+use crate::domain::geo::{GeoInfo, GeoLookup};
+use crate::domain::packet::{Packet, PacketHeader};
+use crate::domain::rule::{Action, Filter};
+use crate::rules::ip_rules::AddressMatch;
+use std::collections::HashSet;
+use std::sync::Arc;
 
-pub struct GeoRules {
-    pub country_codes: Vec<String>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoListMode {
+    // Listed countries/ASNs get the rule's action
+    Deny,
+    // Anything outside the listed countries/ASNs gets the rule's action
+    Allow,
 }
 
-impl Filter for GeoRules {
-    fn check_packet(
-        &self, 
-        packet: &Packet
-    ) -> Option<Action> {
-        for codes in country_codes {
-            if packet.source_ip == self.country_codes {
-                Option::Some(Action::Block);
-            } else {
-                Option::None;
-            }
+pub struct GeoRule {
+    name: String,
+    database: Arc<dyn GeoLookup>,
+    countries: HashSet<String>,
+    asns: HashSet<u32>,
+    mode: GeoListMode,
+    match_on: AddressMatch,
+    match_unknown: bool,
+    action: Action,
+    priority: i32,
+}
+
+impl GeoRule {
+    pub fn new(name: impl Into<String>, database: Arc<dyn GeoLookup>) -> Self {
+        Self {
+            name: name.into(),
+            database,
+            countries: HashSet::new(),
+            asns: HashSet::new(),
+            mode: GeoListMode::Deny,
+            match_on: AddressMatch::Source,
+            match_unknown: false,
+            action: Action::Block,
+            priority: 70,
+        }
+    }
+
+    // ISO 3166-1 alpha-2 code, e.g. "RU"; case-insensitive
+    pub fn add_country(mut self, code: impl AsRef<str>) -> Self {
+        self.countries.insert(code.as_ref().trim().to_ascii_uppercase());
+        self
+    }
+
+    pub fn add_countries<S: AsRef<str>>(mut self, codes: impl IntoIterator<Item = S>) -> Self {
+        self.countries.extend(codes.into_iter().map(|c| c.as_ref().trim().to_ascii_uppercase()));
+        self
+    }
+
+    pub fn add_asn(mut self, asn: u32) -> Self {
+        self.asns.insert(asn);
+        self
+    }
+
+    pub fn add_asns(mut self, asns: impl IntoIterator<Item = u32>) -> Self {
+        self.asns.extend(asns);
+        self
+    }
+
+    pub fn deny_listed(mut self) -> Self {
+        self.mode = GeoListMode::Deny;
+        self
+    }
+
+    pub fn allow_only_listed(mut self) -> Self {
+        self.mode = GeoListMode::Allow;
+        self
+    }
+
+    pub fn match_on(mut self, match_on: AddressMatch) -> Self {
+        self.match_on = match_on;
+        self
+    }
+
+    // Private and unallocated ranges have no geo data. By default they never
+    // match, so LAN traffic isn't caught by an allow-list.
+    pub fn match_unknown(mut self, enabled: bool) -> Self {
+        self.match_unknown = enabled;
+        self
+    }
+
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn is_listed(&self, info: &GeoInfo) -> bool {
+        let country = info.country.as_ref().is_some_and(|c| self.countries.contains(c));
+        let asn = info.asn.is_some_and(|a| self.asns.contains(&a));
+        country || asn
+    }
+
+    fn matches(&self, info: &GeoInfo) -> bool {
+        if info.is_unknown() {
+            return self.match_unknown;
+        }
+        match self.mode {
+            GeoListMode::Deny => self.is_listed(info),
+            GeoListMode::Allow => !self.is_listed(info),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Filter for GeoRule {
+    fn quick_match(&self, _header: &PacketHeader) -> bool {
+        !self.countries.is_empty() || !self.asns.is_empty()
+    }
+
+    fn check_packet(&self, packet: &Packet) -> Option<Action> {
+        let source = matches!(self.match_on, AddressMatch::Source | AddressMatch::Either)
+            && self.matches(&self.database.lookup(packet.source_ip));
+        let destination = matches!(self.match_on, AddressMatch::Destination | AddressMatch::Either)
+            && self.matches(&self.database.lookup(packet.destination_ip));
+
+        if source || destination {
+            Some(self.action.clone())
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+}
@@ -1,20 +1,182 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::domain::chain;
+use crate::domain::packet::{Packet, PacketHeader};
+use crate::domain::prefix_trie::{IpPrefix, PrefixTrie};
+use crate::domain::rule::{Action, Filter};
 use std::net::IpAddr;
-use crate::domain::packet::Packet;
-use crate::domain::rule::{Filter, Action};
+use std::sync::{Arc, RwLock};
 
-pub struct IpRules {
-    pub ip_addr: IpAddr,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMatch {
+    Source,
+    Destination,
+    Either,
 }
 
-impl Filter for IpRules {
-    fn check_packet (
-        &self, 
-        packet: &Packet
-    ) -> Option<Action> {
-        if packet.source_ip == self.ip_addr {
-            Option::Some(Action::Block)
-        } else {
-            Option::None
+// Shared handle to a prefix rule's table, kept by callers that need to
+// update a rule after it has been handed to the `RuleManager`
+#[derive(Clone)]
+pub struct PrefixSetHandle {
+    prefixes: Arc<RwLock<PrefixTrie<Action>>>,
+}
+
+impl PrefixSetHandle {
+    pub fn insert(&self, prefix: IpPrefix, action: Action) -> Option<Action> {
+        self.prefixes.write().unwrap().insert(prefix, action)
+    }
+
+    pub fn remove(&self, prefix: &IpPrefix) -> Option<Action> {
+        self.prefixes.write().unwrap().remove(prefix)
+    }
+
+    // Applies a batch under a single write lock
+    pub fn extend(&self, entries: impl IntoIterator<Item = (IpPrefix, Action)>) {
+        self.prefixes.write().unwrap().extend(entries);
+    }
+
+    // Builds the new table off-lock and swaps it in
+    pub fn replace_all(&self, entries: impl IntoIterator<Item = (IpPrefix, Action)>) {
+        let table: PrefixTrie<Action> = entries.into_iter().collect();
+        *self.prefixes.write().unwrap() = table;
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefixes.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.read().unwrap().is_empty()
+    }
+
+    pub fn entries(&self) -> Vec<(IpPrefix, Action)> {
+        self.prefixes.read().unwrap()
+            .iter()
+            .into_iter()
+            .map(|(prefix, action)| (prefix, action.clone()))
+            .collect()
+    }
+}
+
+// Matches source and/or destination addresses against a set of IPv4/IPv6
+// prefixes. Each prefix carries its own action and the most specific match
+// wins, so "block 10.0.0.0/8 but allow 10.1.0.0/16" works as expected.
+pub struct IpPrefixRule {
+    name: String,
+    prefixes: PrefixSetHandle,
+    match_on: AddressMatch,
+    priority: i32,
+}
+
+impl IpPrefixRule {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            prefixes: PrefixSetHandle {
+                prefixes: Arc::new(RwLock::new(PrefixTrie::new())),
+            },
+            match_on: AddressMatch::Source,
+            priority: 85,
         }
     }
-} 
+
+    pub fn add_prefix(self, prefix: IpPrefix, action: Action) -> Self {
+        self.prefixes.insert(prefix, action);
+        self
+    }
+
+    pub fn add_prefixes(self, entries: impl IntoIterator<Item = (IpPrefix, Action)>) -> Self {
+        self.prefixes.extend(entries);
+        self
+    }
+
+    pub fn block(self, prefix: IpPrefix) -> Self {
+        self.add_prefix(prefix, Action::Block)
+    }
+
+    pub fn block_all(self, prefixes: impl IntoIterator<Item = IpPrefix>) -> Self {
+        self.add_prefixes(prefixes.into_iter().map(|p| (p, Action::Block)))
+    }
+
+    pub fn allow(self, prefix: IpPrefix) -> Self {
+        self.add_prefix(prefix, Action::Allow)
+    }
+
+    pub fn allow_all(self, prefixes: impl IntoIterator<Item = IpPrefix>) -> Self {
+        self.add_prefixes(prefixes.into_iter().map(|p| (p, Action::Allow)))
+    }
+
+    pub fn match_on(mut self, match_on: AddressMatch) -> Self {
+        self.match_on = match_on;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn handle(&self) -> PrefixSetHandle {
+        self.prefixes.clone()
+    }
+
+    pub fn address_match(&self) -> AddressMatch {
+        self.match_on
+    }
+
+    fn lookup(&self, source: &IpAddr, destination: &IpAddr) -> Option<Action> {
+        let table = self.prefixes.prefixes.read().unwrap();
+
+        let source_hit = match self.match_on {
+            AddressMatch::Source | AddressMatch::Either => table.longest_match(source),
+            AddressMatch::Destination => None,
+        };
+        let destination_hit = match self.match_on {
+            AddressMatch::Destination | AddressMatch::Either => table.longest_match(destination),
+            AddressMatch::Source => None,
+        };
+
+        // With both sides matching, the more specific prefix decides
+        match (source_hit, destination_hit) {
+            (Some((s, s_action)), Some((d, d_action))) => {
+                if d.prefix_len() > s.prefix_len() { Some(d_action.clone()) } else { Some(s_action.clone()) }
+            }
+            (Some((_, action)), None) | (None, Some((_, action))) => Some(action.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+impl Filter for IpPrefixRule {
+    fn quick_match(&self, _header: &PacketHeader) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    fn check_packet(&self, packet: &Packet) -> Option<Action> {
+        self.lookup(&packet.source_ip, &packet.destination_ip)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets(self.prefixes.entries().iter().map(|(_, action)| action))
+    }
+}
+
+impl IpPrefixRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::IpPrefix {
+                prefixes: self.prefixes.entries(),
+                match_on: self.match_on,
+            },
+        ))
+    }
+}
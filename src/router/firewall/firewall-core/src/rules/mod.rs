@@ -1,9 +1,15 @@
 pub mod ip_rules;
 pub mod port_rules;
+pub mod geo_rules;
 pub mod time_rules;
 pub mod rate_limit_rules;
+pub mod conn_state_rules;
+pub mod expr_rules;
 
 pub use ip_rules::*;
 pub use port_rules::*;
+pub use geo_rules::*;
 pub use time_rules::*;
-pub use rate_limit_rules::*;
\ No newline at end of file
+pub use rate_limit_rules::*;
+pub use conn_state_rules::*;
+pub use expr_rules::*;
\ No newline at end of file
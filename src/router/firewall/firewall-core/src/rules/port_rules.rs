@@ -1,4 +1,8 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::application::config::rule_config::{port_list, protocol_list};
+use crate::domain::chain;
 use crate::domain::packet::{Packet, PacketHeader, Protocol};
+use crate::rules::ip_rules::AddressMatch;
 use crate::domain::rule::{Action, Filter};
 use std::collections::HashSet;
 use std::ops::RangeInclusive;
@@ -118,24 +122,22 @@ impl Filter for PortBlocklistRule {
 
         let mut matched = false;
 
-        if self.match_source {
-            if let Some(port) = packet.source_port {
-                if self.is_port_blocked(port) {
-                    matched = true;
-                }
-            }
+        if self.match_source
+            && let Some(port) = packet.source_port
+            && self.is_port_blocked(port)
+        {
+            matched = true;
         }
 
-        if self.match_destination {
-            if let Some(port) = packet.destination_port {
-                if self.is_port_blocked(port) {
-                    matched = true;
-                }
-            }
+        if self.match_destination
+            && let Some(port) = packet.destination_port
+            && self.is_port_blocked(port)
+        {
+            matched = true;
         }
 
         if matched {
-            Some(self.action)
+            Some(self.action.clone())
         } else {
             None
         }
@@ -148,6 +150,32 @@ impl Filter for PortBlocklistRule {
     fn priority(&self) -> i32 {
         self.priority
     }
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+}
+
+impl PortBlocklistRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        let match_on = match (self.match_source, self.match_destination) {
+            (true, true) => AddressMatch::Either,
+            (true, false) => AddressMatch::Source,
+            (false, true) => AddressMatch::Destination,
+            // Matches nothing; there is no config spelling for that
+            (false, false) => return None,
+        };
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::PortBlocklist {
+                ports: port_list(&self.blocked_ports, &self.blocked_ranges),
+                protocols: protocol_list(&self.protocols),
+                match_on,
+                action: self.action.clone(),
+            },
+        ))
+    }
 }
 
 pub struct PortAllowlistRule {
@@ -237,10 +265,10 @@ impl Filter for PortAllowlistRule {
             return None;
         }
 
-        if let Some(port) = packet.destination_port {
-            if !self.is_port_allowed(port) {
-                return Some(Action::Block);
-            }
+        if let Some(port) = packet.destination_port
+            && !self.is_port_allowed(port)
+        {
+            return Some(Action::Block);
         }
 
         None
@@ -254,6 +282,19 @@ impl Filter for PortAllowlistRule {
         self.priority
     }
 }
+
+impl PortAllowlistRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::PortAllowlist {
+                ports: port_list(&self.allowed_ports, &self.allowed_ranges),
+                protocols: protocol_list(&self.protocols),
+            },
+        ))
+    }
+}
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Service {
     Http,       // TCP 80
@@ -376,7 +417,7 @@ impl Filter for WellKnownServicesRule {
         if let Some(dst_port) = packet.destination_port {
             for service in &self.services {
                 if dst_port == service.port() && packet.protocol == service.protocol() {
-                    return Some(self.action);
+                    return Some(self.action.clone());
                 }
             }
         }
@@ -390,4 +431,23 @@ impl Filter for WellKnownServicesRule {
     fn priority(&self) -> i32 {
         self.priority
     }
-}
\ No newline at end of file
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+}
+
+impl WellKnownServicesRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        let mut services: Vec<Service> = self.services.iter().copied().collect();
+        services.sort_by_key(|s| (s.port(), s.protocol().to_number()));
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::Services {
+                services,
+                action: self.action.clone(),
+            },
+        ))
+    }
+}
@@ -1,2 +1,2 @@
-pub mod rate_limit_rules;
+pub mod rate_limit_rule;
 pub mod time_based_limit_rule;
\ No newline at end of file
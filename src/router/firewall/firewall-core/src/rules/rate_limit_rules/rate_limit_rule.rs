@@ -1,5 +1,6 @@
+use crate::application::config::{RuleConfig, RuleKind};
 use crate::domain::packet::{Packet, PacketHeader};
-use crate::domain::rate_limiter::{RateLimiter, PerKeyRateLimiter, RateLimitConfig, RateLimitKeyType};
+use crate::domain::rate_limiter::{RateLimiter, RateLimitConfig};
 use crate::domain::rule::{Action, Filter};
 use std::sync::{Arc, Mutex};
 
@@ -12,10 +13,7 @@ pub struct RateLimitRule {
 
 impl RateLimitRule {
     pub fn new(name: impl Into<String>, config: RateLimitConfig) -> Self {
-        let limiter = Box::new(PerKeyRateLimiter::new(
-            config.rate,
-            config.capacity,
-        ));
+        let limiter = config.build();
 
         Self {
             name: name.into(),
@@ -43,24 +41,7 @@ impl RateLimitRule {
     }
     
     fn extract_key(&self, packet: &Packet) -> String {
-        match self.config.key_type {
-            RateLimitKeyType::SourceIp => packet.source_ip.to_string(),
-            RateLimitKeyType::DestinationIp => packet.destination_ip.to_string(),
-            RateLimitKeyType::Flow => {
-                format!("{}:{}-{}:{}",
-                    packet.source_ip,
-                    packet.source_port.unwrap_or(0),
-                    packet.destination_ip,
-                    packet.destination_port.unwrap_or(0)
-                )
-            }
-            RateLimitKeyType::Global => "global".to_string(),
-        }
-    }
-    
-    pub fn cleanup(&self, threshold_secs: u64) {
-        let mut limiter = self.limiter.lock().unwrap();
-        limiter.cleanup(threshold_secs);
+        self.config.key_type.key_for(packet)
     }
     
     pub fn get_usage(&self, key: &str) -> Option<f64> {
@@ -84,6 +65,12 @@ impl Filter for RateLimitRule {
             Some(Action::Block) 
         }
     }
+
+    fn peek_packet(&self, packet: &Packet) -> Option<Action> {
+        let key = self.extract_key(packet);
+        let mut limiter = self.limiter.lock().unwrap();
+        (!limiter.would_allow(&key)).then_some(Action::Block)
+    }
     
     fn name(&self) -> &str {
         &self.name
@@ -92,4 +79,19 @@ impl Filter for RateLimitRule {
     fn priority(&self) -> i32 {
         self.priority
     }
-}
\ No newline at end of file
+
+    fn cleanup(&self, threshold_secs: u64) {
+        let mut limiter = self.limiter.lock().unwrap();
+        limiter.cleanup(threshold_secs);
+    }
+}
+
+impl RateLimitRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::RateLimit { limit: self.config.clone() },
+        ))
+    }
+}
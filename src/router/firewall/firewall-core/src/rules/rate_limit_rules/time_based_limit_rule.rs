@@ -1,50 +1,136 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::domain::chain;
+use crate::domain::packet::{Packet, PacketHeader};
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::rate_limiter::per_key::{self, DEFAULT_MAX_KEYS};
+use crate::domain::rate_limiter::{Meter, RateLimitKeyType, TokenBucket};
+use crate::domain::rule::{Action, Filter};
 use crate::rules::time_rules::TimeWindow;
-use crate::domain::rate_limiter::TokenBucket;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+// Requests per second and how many may come at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl WindowLimit {
+    // A burst of one second's worth, as in the config loader
+    pub fn new(rate: f64) -> Self {
+        Self { rate, burst: rate.max(1.0) }
+    }
+
+    pub fn with_burst(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+// What one key is held to right now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectiveLimit {
+    pub limit: WindowLimit,
+    // Requests the key could send at once
+    pub available: f64,
+}
+
+// Token bucket rate limit whose rate and burst follow a schedule, e.g. a
+// tight limit on MQTT logins overnight and a looser one in office hours.
+// The first window containing the current time applies, the default limit
+// outside all of them. When the limit changes, each key's bucket keeps its
+// fill level relative to the burst rather than starting over full or empty.
 pub struct TimeBasedRateLimitRule {
     name: String,
-    limits: Vec<(TimeWindow, f64)>,
-    default_rate: f64,
-    buckets: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+    limits: Vec<(TimeWindow, WindowLimit)>,
+    default_limit: WindowLimit,
+    key_type: RateLimitKeyType,
+    action: Action,
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    max_keys: usize,
+    clock: Arc<dyn Clock>,
     priority: i32,
 }
 
 impl TimeBasedRateLimitRule {
     pub fn new(name: impl Into<String>, default_rate: f64) -> Self {
+        Self::with_default_limit(name, WindowLimit::new(default_rate))
+    }
+
+    pub fn with_default_limit(name: impl Into<String>, limit: WindowLimit) -> Self {
         Self {
             name: name.into(),
             limits: Vec::new(),
-            default_rate,
+            default_limit: limit,
+            key_type: RateLimitKeyType::SourceIp,
+            action: Action::Block,
             buckets: Arc::new(Mutex::new(HashMap::new())),
+            max_keys: DEFAULT_MAX_KEYS,
+            clock: Arc::new(SystemClock),
             priority: 65,
         }
     }
-    pub fn add_time_limit(mut self, window: TimeWindow, rate: f64) -> Self {
-        self.limits.push((window, rate));
+
+    pub fn add_time_limit(self, window: TimeWindow, rate: f64) -> Self {
+        self.add_window_limit(window, WindowLimit::new(rate))
+    }
+
+    pub fn add_window_limit(mut self, window: TimeWindow, limit: WindowLimit) -> Self {
+        self.limits.push((window, limit));
+        self
+    }
+
+    // Source address unless set
+    pub fn with_key(mut self, key_type: RateLimitKeyType) -> Self {
+        self.key_type = key_type;
         self
     }
-    
+
+    // Given to packets over the limit; blocked unless set
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
     pub fn with_priority(mut self, priority: i32) -> Self {
         self.priority = priority;
         self
     }
-    fn current_rate(&self) -> f64 {
-        for (window, rate) in &self.limits {
-            if window.is_now_in_window() {
-                return *rate;
-            }
-        }
-        self.default_rate
+
+    // Keys tracked before the least recently used are forgotten
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys.max(1);
+        self
     }
-    pub fn cleanup(&self, threshold_secs: u64) {
-        let mut buckets = self.buckets.lock().unwrap();
-        let now = std::time::Instant::now();
-        
-        buckets.retain(|_, bucket| {
-            now.duration_since(bucket.last_refill).as_secs() < threshold_secs
-        });
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Limit the schedule sets at this moment
+    pub fn current_limit(&self) -> WindowLimit {
+        let now = self.clock.local_time();
+        self.limits.iter()
+            .find(|(window, _)| window.contains(&now))
+            .map_or(self.default_limit, |(_, limit)| *limit)
+    }
+
+    // `None` for keys without a bucket yet; they start with a full burst
+    pub fn effective_limit(&self, key: &str) -> Option<EffectiveLimit> {
+        let limit = self.current_limit();
+        let now = self.clock.now();
+        let mut bucket = self.buckets.lock().unwrap().get(key)?.clone();
+        apply(&mut bucket, limit, now);
+        Some(EffectiveLimit { limit, available: bucket.allowance(now) })
+    }
+}
+
+// Moves the bucket onto `limit` if the schedule changed since it was used
+fn apply(bucket: &mut TokenBucket, limit: WindowLimit, now: Instant) {
+    if bucket.rate() != limit.rate || bucket.capacity() != limit.burst {
+        bucket.set_limit(limit.rate, limit.burst, now);
     }
 }
 
@@ -52,29 +138,212 @@ impl Filter for TimeBasedRateLimitRule {
     fn quick_match(&self, _header: &PacketHeader) -> bool {
         true
     }
-    
+
     fn check_packet(&self, packet: &Packet) -> Option<Action> {
-        let source_ip = packet.source_ip;
-        let current_rate = self.current_rate();
-        
+        let key = self.key_type.key_for(packet);
+        let limit = self.current_limit();
+        let now = self.clock.now();
+
         let mut buckets = self.buckets.lock().unwrap();
-        let bucket = buckets.entry(source_ip).or_insert_with(|| {
-            TokenBucket::new(current_rate, current_rate)
+        if !buckets.contains_key(&key) {
+            per_key::make_room(&mut buckets, self.max_keys);
+        }
+        let bucket = buckets.entry(key).or_insert_with(|| {
+            TokenBucket::new_at(limit.rate, limit.burst, now)
         });
-        bucket.set_rate(current_rate);
-        
-        if bucket.try_consume(1.0) {
-            None  
+        apply(bucket, limit, now);
+
+        if bucket.try_acquire(now) {
+            None
         } else {
-            Some(Action::Block) 
+            Some(self.action.clone())
         }
     }
-    
+
+    // Tries a copy of the bucket so the real one keeps its tokens
+    fn peek_packet(&self, packet: &Packet) -> Option<Action> {
+        let key = self.key_type.key_for(packet);
+        let limit = self.current_limit();
+        let now = self.clock.now();
+        let buckets = self.buckets.lock().unwrap();
+        let mut bucket = buckets.get(&key)
+            .cloned()
+            .unwrap_or_else(|| TokenBucket::new_at(limit.rate, limit.burst, now));
+        apply(&mut bucket, limit, now);
+        (!bucket.try_acquire(now)).then(|| self.action.clone())
+    }
+
     fn name(&self) -> &str {
         &self.name
     }
-    
+
     fn priority(&self) -> i32 {
         self.priority
     }
-}
\ No newline at end of file
+
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
+    }
+
+    fn cleanup(&self, threshold_secs: u64) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = self.clock.now();
+
+        buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.last_used()).as_secs() < threshold_secs
+        });
+    }
+}
+
+impl TimeBasedRateLimitRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::TimeRateLimit {
+                windows: self.limits.clone(),
+                default: self.default_limit,
+                key: self.key_type.clone(),
+                action: self.action.clone(),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::clock::ManualClock;
+    use chrono::{Local, TimeZone};
+    use std::time::{Duration, SystemTime};
+
+    // Monday, a second before office hours start
+    fn clock() -> Arc<ManualClock> {
+        let start = Local.with_ymd_and_hms(2024, 5, 6, 8, 59, 59).unwrap();
+        Arc::new(ManualClock::starting_at(SystemTime::from(start)))
+    }
+
+    fn office_hours() -> WindowLimit {
+        WindowLimit::with_burst(10.0, 20.0)
+    }
+
+    fn rule(clock: &Arc<ManualClock>) -> TimeBasedRateLimitRule {
+        TimeBasedRateLimitRule::with_default_limit("logins", WindowLimit::with_burst(1.0, 4.0))
+            .add_window_limit(TimeWindow::new(9, 0, 17, 0).weekdays(), office_hours())
+            .with_clock(clock.clone())
+    }
+
+    fn packet() -> Packet {
+        Packet::new("192.0.2.1".parse().unwrap())
+    }
+
+    #[test]
+    fn window_boundary_keeps_the_fill_level() {
+        let clock = clock();
+        let rule = rule(&clock);
+        assert_eq!(rule.check_packet(&packet()), None);
+        assert_eq!(rule.check_packet(&packet()), None);
+
+        // One token back at the old rate, so 3 of 4 become 15 of 20
+        clock.advance(Duration::from_secs(1));
+        let limit = rule.effective_limit("192.0.2.1").unwrap();
+        assert_eq!(limit, EffectiveLimit { limit: office_hours(), available: 15.0 });
+        for _ in 0..15 {
+            assert_eq!(rule.check_packet(&packet()), None);
+        }
+        assert_eq!(rule.check_packet(&packet()), Some(Action::Block));
+
+        // Drained just before 17:00 and refilled by a quarter on the way
+        // out, the bucket keeps a quarter of the smaller burst
+        clock.advance(Duration::from_millis(8 * 3600 * 1000 - 250));
+        for _ in 0..20 {
+            assert_eq!(rule.check_packet(&packet()), None);
+        }
+        clock.advance(Duration::from_millis(500));
+        let limit = rule.effective_limit("192.0.2.1").unwrap();
+        assert_eq!(limit, EffectiveLimit { limit: WindowLimit::with_burst(1.0, 4.0), available: 1.0 });
+        assert_eq!(rule.check_packet(&packet()), None);
+        assert_eq!(rule.check_packet(&packet()), Some(Action::Block));
+    }
+
+    #[test]
+    fn effective_limit_follows_the_schedule() {
+        let clock = clock();
+        let rule = rule(&clock);
+        assert_eq!(rule.current_limit(), WindowLimit::with_burst(1.0, 4.0));
+        // Keys start with a full burst, so unseen ones have no entry
+        assert_eq!(rule.effective_limit("192.0.2.1"), None);
+
+        rule.check_packet(&packet());
+        assert_eq!(
+            rule.effective_limit("192.0.2.1"),
+            Some(EffectiveLimit { limit: WindowLimit::with_burst(1.0, 4.0), available: 3.0 })
+        );
+        // Reading it doesn't move the bucket onto the new limit early
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(rule.current_limit(), office_hours());
+        assert_eq!(rule.effective_limit("192.0.2.1").unwrap().available, 20.0);
+        assert_eq!(rule.buckets.lock().unwrap()["192.0.2.1"].rate(), 1.0);
+    }
+
+    #[test]
+    fn peek_leaves_the_tokens() {
+        let clock = clock();
+        let rule = rule(&clock);
+        for _ in 0..10 {
+            assert_eq!(rule.peek_packet(&packet()), None);
+        }
+        assert_eq!(rule.effective_limit("192.0.2.1"), None);
+
+        for _ in 0..4 {
+            assert_eq!(rule.check_packet(&packet()), None);
+        }
+        assert_eq!(rule.peek_packet(&packet()), Some(Action::Block));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(rule.peek_packet(&packet()), Some(Action::Block));
+        assert_eq!(rule.effective_limit("192.0.2.1").unwrap().available, 0.5);
+    }
+
+    #[test]
+    fn overflow_gets_the_configured_action() {
+        let clock = clock();
+        let rule = rule(&clock).with_action(Action::Log);
+        for _ in 0..4 {
+            assert_eq!(rule.check_packet(&packet()), None);
+        }
+        assert_eq!(rule.peek_packet(&packet()), Some(Action::Log));
+        assert_eq!(rule.check_packet(&packet()), Some(Action::Log));
+    }
+
+    #[test]
+    fn keys_are_capped_and_cleaned_up() {
+        let clock = clock();
+        let rule = rule(&clock).with_max_keys(8);
+        let from = |i: u8| Packet::new(format!("192.0.2.{}", i).parse().unwrap());
+        for i in 0..8 {
+            rule.check_packet(&from(i));
+            clock.advance(Duration::from_millis(1));
+        }
+        // A new key pushes out the least recently used
+        rule.check_packet(&from(0));
+        rule.check_packet(&from(8));
+        assert_eq!(rule.buckets.lock().unwrap().len(), 8);
+        assert_eq!(rule.effective_limit("192.0.2.1"), None);
+        assert!(rule.effective_limit("192.0.2.0").is_some());
+
+        clock.advance(Duration::from_secs(60));
+        rule.check_packet(&from(9));
+        rule.cleanup(30);
+        let buckets = rule.buckets.lock().unwrap();
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), ["192.0.2.9"]);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let clock = clock();
+        let config = rule(&clock).with_key(RateLimitKeyType::Global).with_action(Action::Log).to_config().unwrap();
+        assert_eq!(config.priority, 65);
+        let rebuilt = config.build_with_clock(clock.clone());
+        assert_eq!(RuleConfig::from_filter(&*rebuilt), Some(config));
+    }
+}
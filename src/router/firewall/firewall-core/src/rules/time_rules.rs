@@ -1,15 +1,19 @@
+use crate::application::config::{RuleConfig, RuleKind};
+use crate::domain::chain;
+use crate::domain::clock::{Clock, SystemClock};
 use crate::domain::packet::{Packet, PacketHeader};
 use crate::domain::rule::{Action, Filter};
-use chrono::{Datelike, Local, NaiveTime, Weekday};
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use std::sync::Arc;
 
 pub struct TimeWindowRule {
     name: String,
     windows: Vec<TimeWindow>,
     action: Action,
-
+    clock: Arc<dyn Clock>,
     priority: i32,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TimeWindow {
     start_time: NaiveTime,
     end_time: NaiveTime,
@@ -42,8 +46,16 @@ impl TimeWindow {
     pub fn weekends(self) -> Self {
         self.on_days(vec![Weekday::Sat, Weekday::Sun])
     }
-    pub fn is_now_in_window(&self) -> bool {
-        let now = Local::now();
+    pub fn start_time(&self) -> NaiveTime {
+        self.start_time
+    }
+    pub fn end_time(&self) -> NaiveTime {
+        self.end_time
+    }
+    pub fn days(&self) -> &[Weekday] {
+        &self.days
+    }
+    pub fn contains(&self, now: &DateTime<Local>) -> bool {
         let current_time = now.time();
         let current_day = now.weekday();
 
@@ -67,6 +79,7 @@ impl TimeWindowRule {
             name: name.into(),
             windows: Vec::new(),
             action: Action::Block,
+            clock: Arc::new(SystemClock),
             priority: 60,
         }
     }
@@ -96,9 +109,14 @@ impl TimeWindowRule {
         self.priority = priority;
         self
     }
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
     fn is_within_time_window(&self) -> bool {
+        let now = self.clock.local_time();
         for window in &self.windows {
-            if window.is_now_in_window() {
+            if window.contains(&now) {
                 return true;
             }
         }
@@ -111,7 +129,7 @@ impl Filter for TimeWindowRule {
         self.is_within_time_window()
     }
     fn check_packet(&self, _packet: &Packet) -> Option<Action> {
-        Some(self.action)
+        Some(self.action.clone())
     }
     fn name(&self) -> &str {
         &self.name
@@ -119,59 +137,20 @@ impl Filter for TimeWindowRule {
     fn priority(&self) -> i32 {
         self.priority
     }
-}
-pub struct RateLimitByTimeRule {
-    name: String,
-    limits: Vec<(TimeWindow, u32)>,
-    default_limit: u32,
-    priority: i32,
-}
-
-impl RateLimitByTimeRule {
-    pub fn new(name: impl Into<String>, default_limit: u32) -> Self {
-        Self {
-            name: name.into(),
-            limits: Vec::new(),
-            default_limit,
-            priority: 50,
-        }
-    }
-
-    pub fn add_limit(mut self, window: TimeWindow, limit: u32) -> Self {
-        self.limits.push((window, limit));
-        self
-    }
-
-    pub fn with_priority(mut self, priority: i32) -> Self {
-        self.priority = priority;
-        self
-    }
-
-    pub fn current_limit(&self) -> u32 {
-        for (window, limit) in &self.limits {
-            if window.is_now_in_window() {
-                return *limit;
-            }
-        }
-        self.default_limit
+    fn jump_targets(&self) -> Vec<String> {
+        chain::jump_targets([&self.action])
     }
 }
 
-impl Filter for RateLimitByTimeRule {
-    fn quick_match(&self, _header: &PacketHeader) -> bool {
-        true
-    }
-
-    fn check_packet(&self, _packet: &Packet) -> Option<Action> {
-        let _current_limit = self.current_limit();
-        None
-    }
-
-    fn name(&self) -> &str {
-        &self.name
-    }
-
-    fn priority(&self) -> i32 {
-        self.priority
+impl TimeWindowRule {
+    pub fn to_config(&self) -> Option<RuleConfig> {
+        Some(RuleConfig::new(
+            self.name.clone(),
+            self.priority,
+            RuleKind::TimeWindow {
+                windows: self.windows.clone(),
+                action: self.action.clone(),
+            },
+        ))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,144 @@
+use firewall_core::rules::{AddressMatch, Service};
+use firewall_core::{
+    Action, ChainError, ConfigError, FirewallBuilder, FirewallConfig, Protocol, RateLimitAlgorithm, RateLimitConfig,
+    RuleConfig, RuleKind,
+};
+
+fn telnet_rule(chain: &str) -> RuleConfig {
+    let mut rule = RuleConfig::new(
+        "no-telnet",
+        0,
+        RuleKind::PortBlocklist {
+            ports: vec![23..=23],
+            protocols: vec![Protocol::Tcp],
+            match_on: AddressMatch::Destination,
+            action: Action::Block,
+        },
+    );
+    rule.chain = chain.to_string();
+    rule
+}
+
+#[test]
+fn failed_load_keeps_the_old_limiters() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    let mut good = FirewallConfig::new(Action::Allow);
+    good.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(1.0, 5.0));
+    good.rules.push(telnet_rule("input"));
+    firewall.apply_config(&good).unwrap();
+
+    let mut bad = FirewallConfig::new(Action::Allow);
+    bad.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(100.0, 500.0));
+    bad.rate_limiters.insert("web".to_string(), RateLimitConfig::new(10.0, 10.0));
+    bad.rules.push(telnet_rule("missing"));
+    let err = firewall.apply_config(&bad).unwrap_err();
+    assert!(matches!(err, ConfigError::Chain(ChainError::UnknownChain(ref chain)) if chain == "missing"));
+
+    let exported = firewall.export_config().unwrap();
+    assert_eq!(exported.rate_limiters, good.rate_limiters);
+    assert_eq!(exported.rules, good.rules);
+}
+
+#[test]
+fn rejects_undefined_rate_limiters() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    let mut config = FirewallConfig::new(Action::Allow);
+    config.rules.push(RuleConfig::new(
+        "ssh",
+        0,
+        RuleKind::Services { services: vec![Service::Ssh], action: Action::RateLimit("ssh".to_string()) },
+    ));
+    let err = firewall.apply_config(&config).unwrap_err();
+    assert!(matches!(err, ConfigError::UndefinedRateLimiter(ref name) if name == "ssh"));
+    assert!(firewall.list_rules().is_empty());
+
+    let mut defaulted = FirewallConfig::new(Action::RateLimit("global".to_string()));
+    defaulted.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(1.0, 5.0));
+    let err = firewall.apply_config(&defaulted).unwrap_err();
+    assert!(matches!(err, ConfigError::UndefinedRateLimiter(ref name) if name == "global"));
+
+    // Defined by the config itself, or registered beforehand
+    config.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(1.0, 5.0));
+    firewall.apply_config(&config).unwrap();
+    firewall.register_rate_limiter("global", RateLimitConfig::new(10.0, 10.0));
+    firewall.apply_config(&defaulted).unwrap();
+}
+
+#[test]
+fn successful_load_replaces_and_adds_limiters() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    let mut first = FirewallConfig::new(Action::Allow);
+    first.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(1.0, 5.0));
+    firewall.apply_config(&first).unwrap();
+
+    let mut second = FirewallConfig::new(Action::Allow);
+    second.rate_limiters.insert("ssh".to_string(), RateLimitConfig::new(2.0, 5.0));
+    second.rate_limiters.insert("web".to_string(), RateLimitConfig::new(10.0, 10.0));
+    firewall.apply_config(&second).unwrap();
+
+    assert_eq!(firewall.export_config().unwrap().rate_limiters, second.rate_limiters);
+}
+
+#[test]
+fn limiter_algorithms_survive_load_and_export() {
+    let source = r#"
+[rate_limiters]
+ssh = { rate = 1.0, burst = 5, algorithm = "leaky_bucket" }
+web = { rate = 10.0, algorithm = "gcra" }
+dns = { rate = 50.0 }
+"#;
+    let config = FirewallConfig::from_toml(source).unwrap();
+    let algorithm = |name: &str| config.rate_limiters[name].algorithm;
+    assert_eq!(algorithm("ssh"), RateLimitAlgorithm::LeakyBucket);
+    assert_eq!(algorithm("web"), RateLimitAlgorithm::Gcra);
+    assert_eq!(algorithm("dns"), RateLimitAlgorithm::TokenBucket);
+    assert_eq!(FirewallConfig::from_toml(&config.to_toml().unwrap()).unwrap().rate_limiters, config.rate_limiters);
+
+    let unknown = source.replace("gcra", "fixed_window");
+    assert!(FirewallConfig::from_toml(&unknown).unwrap_err().to_string().contains("invalid algorithm 'fixed_window'"));
+}
+
+#[test]
+fn time_rate_limit_survives_load_apply_and_export() {
+    let source = r#"
+default_action = "allow"
+
+[[rule]]
+name = "mqtt-logins"
+type = "time_rate_limit"
+rate = 1.0
+burst = 5
+key = "global"
+action = "log"
+windows = [
+    { start = "08:00", end = "18:00", days = ["weekdays"], rate = 10.0, burst = 20 },
+    { start = "22:00", end = "06:00", rate = 0.5 },
+]
+"#;
+    let config = FirewallConfig::from_toml(source).unwrap();
+    let RuleKind::TimeRateLimit { windows, default, action, .. } = &config.rules[0].kind else {
+        panic!("expected a time_rate_limit rule, got {:?}", config.rules[0].kind);
+    };
+    assert_eq!(windows.len(), 2);
+    assert_eq!((windows[1].1.rate, windows[1].1.burst), (0.5, 1.0));
+    assert_eq!((default.rate, default.burst), (1.0, 5.0));
+    assert_eq!(*action, Action::Log);
+    assert_eq!(config.rules[0].priority, 65);
+
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    firewall.apply_config(&config).unwrap();
+    let exported = firewall.export_config().unwrap();
+    assert_eq!(exported.rules, config.rules);
+    assert_eq!(FirewallConfig::from_toml(&exported.to_toml().unwrap()).unwrap().rules, config.rules);
+
+    // Windows of a time_rate_limit rule need a rate, and only those take one
+    let missing = source.replace(", rate = 0.5", "");
+    assert!(FirewallConfig::from_toml(&missing).unwrap_err().to_string().contains("window needs a 'rate'"));
+    let misplaced = r#"
+[[rule]]
+name = "night"
+type = "time_window"
+windows = [{ start = "22:00", end = "06:00", rate = 1.0 }]
+"#;
+    assert!(FirewallConfig::from_toml(misplaced).unwrap_err().to_string().contains("take no 'rate'"));
+}
@@ -0,0 +1,122 @@
+// Backends hear about every published generation once, and about nothing
+// else: failed changes, no-ops and repeats of a generation they've synced
+// don't reach them.
+
+use firewall_core::rules::PortBlocklistRule;
+use firewall_core::{
+    Action, BackendError, BackendSync, Chain, Filter, FirewallBackend, FirewallBuilder, FirewallConfig, Policy,
+    RecordingBackend, RuleConfig, RuleKind,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+fn block(port: u16) -> Box<dyn Filter> {
+    Box::new(PortBlocklistRule::new(format!("block-{}", port)).add_port(port))
+}
+
+fn policy(generation: u64) -> Policy {
+    Policy::new(generation, FirewallConfig::new(Action::Allow), SystemTime::UNIX_EPOCH)
+}
+
+fn failure() -> BackendError {
+    BackendError::Unsupported("down".to_string())
+}
+
+#[test]
+fn each_published_generation_is_synced_once() {
+    let first = Arc::new(RecordingBackend::new());
+    let second = Arc::new(RecordingBackend::new());
+    let firewall = FirewallBuilder::new(Action::Allow)
+        .with_backend(first.clone())
+        .with_backend(second.clone())
+        .build();
+    assert!(first.generations().is_empty());
+
+    let telnet = firewall.add_rule(block(23)).unwrap();
+    let mut transaction = firewall.begin_transaction();
+    transaction.add_rule(block(445));
+    transaction.add_rule(block(3389));
+    transaction.create_chain(Chain::new("lan"));
+    transaction.commit().unwrap();
+    assert_eq!(first.generations(), [1, 2]);
+
+    // Changes that publish nothing
+    assert!(firewall.add_rule_to("missing", block(22)).is_err());
+    assert!(!firewall.remove_rule(999));
+    assert!(!firewall.set_rule_enabled(999, false));
+    let mut bad = FirewallConfig::new(Action::Allow);
+    bad.rules.push(RuleConfig { chain: "missing".to_string(), ..RuleConfig::new("x", 0, RuleKind::PortAllowlist {
+        ports: vec![22..=22],
+        protocols: Vec::new(),
+    }) });
+    assert!(firewall.apply_config(&bad).is_err());
+    assert_eq!(first.generations(), [1, 2]);
+
+    firewall.remove_rule(telnet);
+    let rolled_back = firewall.rollback_rules(2).unwrap();
+    assert_eq!(first.generations(), [1, 2, 3, rolled_back]);
+    assert_eq!(second.generations(), first.generations());
+    assert_eq!(first.last_policy().unwrap().config.rules.len(), 3);
+}
+
+#[test]
+fn concurrent_changes_never_sync_a_generation_twice() {
+    let backend = Arc::new(RecordingBackend::new());
+    let firewall = Arc::new(FirewallBuilder::new(Action::Allow).with_backend(backend.clone()).build());
+    let workers: Vec<_> = (0..8u16)
+        .map(|worker| {
+            let firewall = Arc::clone(&firewall);
+            thread::spawn(move || {
+                for i in 0..25 {
+                    firewall.add_rule(block(1000 + worker * 100 + i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    // Bursts fold into one sync, but every sync is of a newer generation and
+    // the last one is the latest
+    let generations = backend.generations();
+    assert!(generations.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", generations);
+    assert_eq!(generations.last(), Some(&firewall.rule_generation()));
+    assert_eq!(backend.last_policy().unwrap().config.rules.len(), 200);
+}
+
+#[test]
+fn backend_sync_skips_generations_a_backend_has() {
+    let backend = Arc::new(RecordingBackend::new());
+    let sync = BackendSync::new(vec![backend.clone() as Arc<dyn FirewallBackend>]);
+
+    sync.sync_new(|| policy(1));
+    sync.sync_new(|| policy(1));
+    sync.sync_new(|| policy(3));
+    // Late notification of an older generation
+    sync.sync_new(|| policy(2));
+    assert_eq!(backend.generations(), [1, 3]);
+    assert_eq!(sync.status()[0].generation, Some(3));
+
+    // A failed sync is retried by the next notification, even for the same
+    // generation
+    backend.set_failure(Some(failure()));
+    sync.sync_new(|| policy(4));
+    assert!(matches!(sync.status()[0].error, Some(BackendError::Unsupported(_))));
+    backend.set_failure(None);
+    sync.sync_new(|| policy(4));
+    assert_eq!(backend.generations(), [1, 3, 4]);
+    assert!(sync.status()[0].error.is_none());
+
+    // `sync_all` resyncs regardless, e.g. for a schedule that ended
+    sync.sync_all(&policy(4));
+    assert_eq!(backend.generations(), [1, 3, 4, 4]);
+
+    // After a flush the backend has nothing, so the same generation goes
+    // out again
+    sync.flush_all();
+    sync.sync_new(|| policy(4));
+    assert_eq!(backend.generations(), [1, 3, 4, 4, 4]);
+    assert_eq!(backend.flushes(), 1);
+}
@@ -0,0 +1,160 @@
+// User-defined chains: jumps come back, gotos don't, returns end a chain
+// early, and rule sets that could loop are refused before they're live.
+
+use firewall_core::domain::chain::MAX_CHAIN_HOPS;
+use firewall_core::rules::PortBlocklistRule;
+use firewall_core::{Action, Chain, ChainError, Filter, Firewall, FirewallBuilder, Packet, Protocol};
+
+fn tcp(destination_port: u16) -> Packet {
+    let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+    packet.destination_ip = "198.51.100.1".parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(40000);
+    packet.destination_port = Some(destination_port);
+    packet
+}
+
+// Matches `port` with `action`, at `priority` within its chain
+fn on_port(name: &str, port: u16, action: Action, priority: i32) -> Box<dyn Filter> {
+    Box::new(PortBlocklistRule::new(name).add_port(port).with_action(action).with_priority(priority))
+}
+
+fn jump(chain: &str) -> Action {
+    Action::Jump(chain.to_string())
+}
+
+fn goto(chain: &str) -> Action {
+    Action::Goto(chain.to_string())
+}
+
+// Jumps to `target` without reporting it, as a filter from outside the crate
+// might, so only the hop limit stands between it and a loop
+struct Unreported {
+    target: String,
+}
+
+impl Filter for Unreported {
+    fn check_packet(&self, _packet: &Packet) -> Option<Action> {
+        Some(jump(&self.target))
+    }
+}
+
+fn firewall(chains: &[&str]) -> Firewall {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    for chain in chains {
+        firewall.create_chain(Chain::new(*chain)).unwrap();
+    }
+    firewall
+}
+
+#[test]
+fn jump_returns_to_the_rule_after_it() {
+    let firewall = firewall(&["lan"]);
+    firewall.add_rule(on_port("to-lan", 22, jump("lan"), 20)).unwrap();
+    firewall.add_rule(on_port("no-ssh", 22, Action::Block, 10)).unwrap();
+    firewall.add_rule_to("lan", on_port("mark-ssh", 22, Action::Mark(7), 0)).unwrap();
+
+    // Falls off the end of `lan` without a policy and carries on
+    let verdict = firewall.process_verdict(&tcp(22));
+    assert_eq!((verdict.action, verdict.mark), (Action::Block, Some(7)));
+
+    // With a policy, the end of `lan` decides instead
+    firewall.set_chain_policy("lan", Some(Action::Allow)).unwrap();
+    assert_eq!(firewall.process_packet(&tcp(22)), Action::Allow);
+    assert_eq!(firewall.process_packet(&tcp(80)), Action::Allow);
+}
+
+#[test]
+fn goto_does_not_come_back() {
+    let firewall = firewall(&["lan", "ssh"]);
+    firewall.add_rule(on_port("to-lan", 22, jump("lan"), 20)).unwrap();
+    firewall.add_rule(on_port("no-ssh", 22, Action::Block, 10)).unwrap();
+    firewall.add_rule_to("lan", on_port("to-ssh", 22, goto("ssh"), 10)).unwrap();
+    firewall.add_rule_to("lan", on_port("log-ssh", 22, Action::Log, 0)).unwrap();
+    firewall.add_rule_to("ssh", on_port("mark-ssh", 22, Action::Mark(7), 0)).unwrap();
+
+    // `ssh` ends where `lan` would have, back in the entry chain, and the
+    // rest of `lan` never runs
+    let verdict = firewall.process_verdict(&tcp(22));
+    assert_eq!((verdict.action, verdict.mark, verdict.logged), (Action::Block, Some(7), false));
+}
+
+#[test]
+fn return_leaves_the_chain_early() {
+    let firewall = firewall(&["lan"]);
+    firewall.add_rule(on_port("to-lan", 22, jump("lan"), 20)).unwrap();
+    firewall.add_rule(on_port("no-ssh", 22, Action::Block, 10)).unwrap();
+    firewall.add_rule_to("lan", on_port("back", 22, Action::Return, 10)).unwrap();
+    firewall.add_rule_to("lan", on_port("allow-ssh", 22, Action::Allow, 0)).unwrap();
+    assert_eq!(firewall.process_packet(&tcp(22)), Action::Block);
+
+    // In the entry chain it ends evaluation with the default action
+    firewall.add_rule(on_port("done", 80, Action::Return, 30)).unwrap();
+    firewall.add_rule(on_port("no-http", 80, Action::Block, 0)).unwrap();
+    assert_eq!(firewall.process_packet(&tcp(80)), Action::Allow);
+}
+
+#[test]
+fn hops_past_the_limit_are_skipped() {
+    // input -> c1 -> c2 -> ... each one jump further down
+    let depth = MAX_CHAIN_HOPS + 1;
+    let names: Vec<String> = (1..=depth).map(|i| format!("c{}", i)).collect();
+    let firewall = firewall(&names.iter().map(String::as_str).collect::<Vec<_>>());
+    firewall.add_rule(on_port("enter", 22, jump("c1"), 0)).unwrap();
+    for pair in names.windows(2) {
+        firewall.add_rule_to(&pair[0], on_port("deeper", 22, jump(&pair[1]), 0)).unwrap();
+    }
+    // Reached in exactly `MAX_CHAIN_HOPS` jumps, after the jump out of it
+    // has been skipped
+    let block = firewall.add_rule_to(&names[MAX_CHAIN_HOPS - 1], on_port("block", 22, Action::Block, -1)).unwrap();
+    assert_eq!(firewall.process_packet(&tcp(22)), Action::Block);
+
+    // One hop further is never reached
+    firewall.remove_rule(block);
+    firewall.add_rule_to(&names[depth - 1], on_port("block", 22, Action::Block, 0)).unwrap();
+    assert_eq!(firewall.process_packet(&tcp(22)), Action::Allow);
+}
+
+#[test]
+fn unreported_loops_stop_at_the_hop_limit() {
+    let firewall = firewall(&["a", "b"]);
+    firewall.add_rule(on_port("to-a", 22, jump("a"), 0)).unwrap();
+    firewall.add_rule_to("a", Box::new(Unreported { target: "b".to_string() })).unwrap();
+    firewall.add_rule_to("b", Box::new(Unreported { target: "a".to_string() })).unwrap();
+    // The loop is cut short and evaluation ends with the default action
+    assert_eq!(firewall.process_packet(&tcp(22)), Action::Allow);
+}
+
+#[test]
+fn chain_changes_that_break_the_graph_are_refused() {
+    let firewall = firewall(&["a", "b"]);
+    let generation = firewall.rule_generation();
+
+    assert_eq!(firewall.create_chain(Chain::new("a")), Err(ChainError::DuplicateChain("a".to_string())));
+    assert_eq!(firewall.create_chain(Chain::new("input")), Err(ChainError::BuiltIn("input".to_string())));
+    assert_eq!(
+        firewall.add_rule_to("c", on_port("x", 22, Action::Block, 0)),
+        Err(ChainError::UnknownChain("c".to_string()))
+    );
+    assert_eq!(
+        firewall.set_chain_policy("a", Some(Action::Log)),
+        Err(ChainError::InvalidPolicy { chain: "a".to_string(), policy: Action::Log })
+    );
+    assert_eq!(
+        firewall.add_rule_to("a", on_port("x", 22, jump("c"), 0)),
+        Err(ChainError::UnknownChain("c".to_string()))
+    );
+    assert_eq!(firewall.rule_generation(), generation);
+
+    firewall.add_rule_to("a", on_port("a-to-b", 22, jump("b"), 0)).unwrap();
+    let err = firewall.add_rule_to("b", on_port("b-to-a", 22, goto("a"), 0)).unwrap_err();
+    assert_eq!(err, ChainError::Loop(vec!["a".to_string(), "b".to_string(), "a".to_string()]));
+
+    assert_eq!(firewall.delete_chain("a"), Err(ChainError::NotEmpty("a".to_string())));
+    assert_eq!(
+        firewall.delete_chain("b"),
+        Err(ChainError::InUse { chain: "b".to_string(), rule: "a-to-b".to_string() })
+    );
+    assert_eq!(firewall.delete_chain("input"), Err(ChainError::BuiltIn("input".to_string())));
+    assert_eq!(firewall.list_rules().len(), 1);
+}
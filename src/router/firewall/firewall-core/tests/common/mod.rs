@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+// Compares `actual` with tests/golden/<name>. Run with UPDATE_GOLDEN=1 to
+// rewrite the file instead, then review the diff.
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with UPDATE_GOLDEN=1 to create it)", path.display(), e));
+    assert!(
+        expected == actual,
+        "{} is out of date (run with UPDATE_GOLDEN=1 to update it)\n--- expected\n{}\n--- actual\n{}",
+        path.display(),
+        expected,
+        actual
+    );
+}
@@ -0,0 +1,120 @@
+#!/usr/bin/env python3
+"""Writes the tiny GeoLite2-style databases used by tests/geoip.rs.
+
+Only documentation ranges are mapped, so the files carry no real geo data.
+Run from this directory after changing a table below; the output is
+deterministic, so an unchanged table rewrites identical files.
+"""
+
+import ipaddress
+import struct
+
+BUILD_EPOCH = 1_700_000_000
+
+COUNTRY = {
+    "192.0.2.0/24": {"country": {"iso_code": "DE"}, "registered_country": {"iso_code": "DE"}},
+    "198.51.100.0/24": {"country": {"iso_code": "RU"}, "registered_country": {"iso_code": "RU"}},
+    # Anycast-style entry: no country, only where it was registered
+    "203.0.113.0/24": {"registered_country": {"iso_code": "us"}},
+    "2001:db8:1::/48": {"country": {"iso_code": "FR"}, "registered_country": {"iso_code": "FR"}},
+}
+
+# The same database after an update moved one range to another country
+COUNTRY_UPDATED = dict(COUNTRY)
+COUNTRY_UPDATED["192.0.2.0/24"] = {"country": {"iso_code": "NL"}, "registered_country": {"iso_code": "NL"}}
+
+ASN = {
+    "192.0.2.0/24": {"autonomous_system_number": 64500, "autonomous_system_organization": "Example Transit"},
+    "198.51.100.0/25": {"autonomous_system_number": 64501, "autonomous_system_organization": "Example Hosting"},
+    "2001:db8:1::/48": {"autonomous_system_number": 64502, "autonomous_system_organization": "Example Mobile"},
+}
+
+
+def control(kind, size):
+    if size < 29:
+        head, extra = size, b""
+    elif size < 29 + 256:
+        head, extra = 29, bytes([size - 29])
+    else:
+        head, extra = 30, struct.pack(">H", size - 29 - 256)
+    if kind < 8:
+        return bytes([(kind << 5) | head]) + extra
+    return bytes([head, kind - 7]) + extra
+
+
+def encode(value):
+    if isinstance(value, dict):
+        out = control(7, len(value))
+        for key in sorted(value):
+            out += encode(key) + encode(value[key])
+        return out
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(v) for v in value)
+    if isinstance(value, str):
+        raw = value.encode()
+        return control(2, len(raw)) + raw
+    if isinstance(value, tuple):
+        # (kind, number) for unsigned integers of a given type
+        kind, number = value
+        raw = number.to_bytes(8, "big").lstrip(b"\0")
+        return control(kind, len(raw)) + raw
+    if isinstance(value, int):
+        raw = value.to_bytes(4, "big").lstrip(b"\0")
+        return control(6, len(raw)) + raw
+    raise TypeError(value)
+
+
+def write(path, database_type, table):
+    # Binary trie over 128-bit addresses; IPv4 lives under ::/96
+    nodes = [[None, None]]
+    data = b""
+    offsets = {}
+    for network, record in table.items():
+        net = ipaddress.ip_network(network)
+        # An IPv4 address as an integer is already its ::a.b.c.d form
+        bits, length = int(net.network_address), net.prefixlen + (96 if net.version == 4 else 0)
+        key = encode(record)
+        if key not in offsets:
+            offsets[key] = len(data)
+            data += key
+        node = 0
+        for i in range(length):
+            bit = (bits >> (127 - i)) & 1
+            if i == length - 1:
+                assert nodes[node][bit] is None, network
+                nodes[node][bit] = ("data", offsets[key])
+            else:
+                child = nodes[node][bit]
+                if child is None:
+                    nodes.append([None, None])
+                    child = nodes[node][bit] = ("node", len(nodes) - 1)
+                assert child[0] == "node", network
+                node = child[1]
+
+    count = len(nodes)
+
+    def record(entry):
+        if entry is None:
+            return count
+        kind, value = entry
+        return value if kind == "node" else count + 16 + value
+
+    tree = b"".join(record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big") for left, right in nodes)
+    metadata = encode({
+        "binary_format_major_version": (5, 2),
+        "binary_format_minor_version": (5, 0),
+        "build_epoch": (9, BUILD_EPOCH),
+        "database_type": database_type,
+        "description": {"en": "firewall-core test fixture"},
+        "ip_version": (5, 6),
+        "languages": ["en"],
+        "node_count": count,
+        "record_size": (5, 24),
+    })
+    with open(path, "wb") as f:
+        f.write(tree + b"\0" * 16 + data + b"\xab\xcd\xefMaxMind.com" + metadata)
+
+
+write("country.mmdb", "GeoLite2-Country", COUNTRY)
+write("country-updated.mmdb", "GeoLite2-Country", COUNTRY_UPDATED)
+write("asn.mmdb", "GeoLite2-ASN", ASN)
@@ -0,0 +1,204 @@
+// The fixtures under tests/fixtures/geoip are written by generate.py there
+// and only map documentation ranges:
+//   192.0.2.0/24     DE (NL in country-updated.mmdb), AS64500
+//   198.51.100.0/24  RU, AS64501 for the lower half only
+//   203.0.113.0/24   registered in "us", no country of its own
+//   2001:db8:1::/48  FR, AS64502
+
+use firewall_core::rules::{AddressMatch, GeoRule};
+use firewall_core::{
+    Action, Chain, ChainError, Filter, FirewallBuilder, GeoInfo, GeoLookup, MmdbGeoDatabase, Packet, RuleConfig,
+};
+use std::fs::{self, File};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/geoip").join(name)
+}
+
+// A scratch copy of the fixtures, so reload tests can replace files
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("firewall-geoip-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy(fixture("country.mmdb"), dir.join("country.mmdb")).unwrap();
+    fs::copy(fixture("asn.mmdb"), dir.join("asn.mmdb")).unwrap();
+    dir
+}
+
+// Replaces `path` and moves its mtime on, since a quick test can otherwise
+// rewrite a file within the filesystem's timestamp resolution
+fn replace(path: &Path, contents: &[u8]) {
+    let previous = fs::metadata(path).unwrap().modified().unwrap();
+    fs::write(path, contents).unwrap();
+    File::options().write(true).open(path).unwrap().set_modified(previous + Duration::from_secs(10)).unwrap();
+}
+
+fn database() -> Arc<MmdbGeoDatabase> {
+    Arc::new(
+        MmdbGeoDatabase::open_country(fixture("country.mmdb"))
+            .unwrap()
+            .with_asn_database(fixture("asn.mmdb"))
+            .unwrap(),
+    )
+}
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+fn info(country: Option<&str>, asn: Option<u32>) -> GeoInfo {
+    GeoInfo { country: country.map(str::to_string), asn }
+}
+
+fn from(source: &str) -> Packet {
+    let mut packet = Packet::new(ip(source));
+    packet.destination_ip = ip("10.0.0.1");
+    packet
+}
+
+#[test]
+fn looks_up_country_and_asn() {
+    let database = database();
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("DE"), Some(64500)));
+    assert_eq!(database.lookup(ip("198.51.100.1")), info(Some("RU"), Some(64501)));
+    assert_eq!(database.lookup(ip("198.51.100.200")), info(Some("RU"), None));
+    assert_eq!(database.lookup(ip("203.0.113.9")), info(Some("US"), None));
+    assert_eq!(database.lookup(ip("2001:db8:1::1")), info(Some("FR"), Some(64502)));
+    assert!(database.lookup(ip("10.0.0.1")).is_unknown());
+    assert!(database.lookup(ip("2001:db8:2::1")).is_unknown());
+
+    // Either file on its own
+    let country = MmdbGeoDatabase::open_country(fixture("country.mmdb")).unwrap();
+    assert_eq!(country.lookup(ip("192.0.2.1")), info(Some("DE"), None));
+    let asn = MmdbGeoDatabase::open_asn(fixture("asn.mmdb")).unwrap();
+    assert_eq!(asn.lookup(ip("192.0.2.1")), info(None, Some(64500)));
+}
+
+#[test]
+fn rejects_a_file_that_isnt_a_database() {
+    assert!(MmdbGeoDatabase::open_country(fixture("generate.py")).is_err());
+    assert!(MmdbGeoDatabase::open_asn(fixture("missing.mmdb")).is_err());
+}
+
+#[test]
+fn deny_list_matches_listed_countries_and_asns() {
+    let rule = GeoRule::new("deny", database()).add_country("ru").add_asn(64502).deny_listed();
+    assert_eq!(rule.check_packet(&from("198.51.100.200")), Some(Action::Block));
+    assert_eq!(rule.check_packet(&from("2001:db8:1::1")), Some(Action::Block));
+    assert_eq!(rule.check_packet(&from("192.0.2.1")), None);
+    assert_eq!(rule.check_packet(&from("203.0.113.9")), None);
+    assert_eq!(rule.check_packet(&from("10.0.0.2")), None);
+}
+
+#[test]
+fn allow_list_matches_everything_else() {
+    let rule = GeoRule::new("allow", database())
+        .add_countries(["DE", "US"])
+        .allow_only_listed()
+        .with_action(Action::DropLog);
+    assert_eq!(rule.check_packet(&from("192.0.2.1")), None);
+    assert_eq!(rule.check_packet(&from("203.0.113.9")), None);
+    assert_eq!(rule.check_packet(&from("198.51.100.1")), Some(Action::DropLog));
+    assert_eq!(rule.check_packet(&from("2001:db8:1::1")), Some(Action::DropLog));
+    // The LAN has no geo data and isn't caught unless asked for
+    assert_eq!(rule.check_packet(&from("10.0.0.2")), None);
+    let rule = rule.match_unknown(true);
+    assert_eq!(rule.check_packet(&from("10.0.0.2")), Some(Action::DropLog));
+
+    let by_asn = GeoRule::new("asn", database()).add_asn(64500).allow_only_listed();
+    assert_eq!(by_asn.check_packet(&from("192.0.2.1")), None);
+    assert_eq!(by_asn.check_packet(&from("198.51.100.1")), Some(Action::Block));
+}
+
+#[test]
+fn matches_the_chosen_address() {
+    let rule = GeoRule::new("deny", database()).add_country("DE").match_on(AddressMatch::Destination);
+    let mut outbound = Packet::new(ip("10.0.0.1"));
+    outbound.destination_ip = ip("192.0.2.1");
+    assert_eq!(rule.check_packet(&outbound), Some(Action::Block));
+    assert_eq!(rule.check_packet(&from("192.0.2.1")), None);
+}
+
+#[test]
+fn jumps_are_checked_without_a_config_form() {
+    let jump_to = |chain: &str| {
+        GeoRule::new("geo", database()).add_country("RU").with_action(Action::Jump(chain.to_string()))
+    };
+    let rule = jump_to("geo");
+    assert!(RuleConfig::from_filter(&rule).is_none());
+    assert_eq!(rule.jump_targets(), ["geo"]);
+
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    firewall.create_chain(Chain::new("geo")).unwrap();
+    firewall.add_rule(Box::new(rule)).unwrap();
+    let err = firewall.add_rule_to("geo", Box::new(jump_to("geo"))).unwrap_err();
+    assert_eq!(err, ChainError::Loop(vec!["geo".to_string(), "geo".to_string()]));
+    assert_eq!(firewall.list_rules().len(), 1);
+}
+
+#[test]
+fn reload_swaps_in_the_new_file() {
+    let dir = scratch("reload");
+    let database = MmdbGeoDatabase::open_country(dir.join("country.mmdb"))
+        .unwrap()
+        .with_asn_database(dir.join("asn.mmdb"))
+        .unwrap();
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("DE"), Some(64500)));
+    assert!(!database.reload_if_changed().unwrap());
+
+    replace(&dir.join("country.mmdb"), &fs::read(fixture("country-updated.mmdb")).unwrap());
+    // The cached answer goes with the old file
+    assert!(database.reload_if_changed().unwrap());
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("NL"), Some(64500)));
+    assert!(!database.reload_if_changed().unwrap());
+
+    replace(&dir.join("country.mmdb"), &fs::read(fixture("country.mmdb")).unwrap());
+    database.reload().unwrap();
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("DE"), Some(64500)));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_reload_keeps_both_databases() {
+    let dir = scratch("failed");
+    let database = MmdbGeoDatabase::open_country(dir.join("country.mmdb"))
+        .unwrap()
+        .with_asn_database(dir.join("asn.mmdb"))
+        .unwrap();
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("DE"), Some(64500)));
+
+    // A good country update next to a truncated ASN download
+    replace(&dir.join("country.mmdb"), &fs::read(fixture("country-updated.mmdb")).unwrap());
+    replace(&dir.join("asn.mmdb"), &fs::read(fixture("asn.mmdb")).unwrap()[..100]);
+    assert!(database.reload().is_err());
+    assert!(database.reload_if_changed().is_err());
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("DE"), Some(64500)));
+    assert_eq!(database.lookup(ip("2001:db8:1::1")), info(Some("FR"), Some(64502)));
+
+    // Once the download completes both files are picked up together
+    replace(&dir.join("asn.mmdb"), &fs::read(fixture("asn.mmdb")).unwrap());
+    assert!(database.reload_if_changed().unwrap());
+    assert_eq!(database.lookup(ip("192.0.2.1")), info(Some("NL"), Some(64500)));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn watcher_picks_up_a_replaced_file() {
+    let dir = scratch("watcher");
+    let database = Arc::new(MmdbGeoDatabase::open_country(dir.join("country.mmdb")).unwrap());
+    let rule = GeoRule::new("deny", database.clone()).add_country("NL");
+    assert_eq!(rule.check_packet(&from("192.0.2.1")), None);
+
+    let watcher = database.start_watcher(Duration::from_millis(10), |e| panic!("{}", e));
+    replace(&dir.join("country.mmdb"), &fs::read(fixture("country-updated.mmdb")).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while rule.check_packet(&from("192.0.2.1")).is_none() {
+        assert!(Instant::now() < deadline, "the watcher never reloaded");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    watcher.stop();
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::assert_golden;
+use firewall_core::rules::{AddressMatch, Service};
+use firewall_core::{
+    Action, ChainConfig, CommandOutput, FirewallConfig, IpFamily, IpPrefix, IpTablesBackend, IptUpdate, Protocol,
+    RejectWith, RuleConfig, RuleKind, ScriptedRunner,
+};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn backend(runner: &Arc<ScriptedRunner>) -> IpTablesBackend {
+    IpTablesBackend::new().with_ipset_threshold(2).with_runner(runner.clone())
+}
+
+fn prefixes(list: &[&str], action: Action) -> Vec<(IpPrefix, Action)> {
+    list.iter().map(|p| (p.parse().unwrap(), action.clone())).collect()
+}
+
+fn blocked_sources(list: &[&str]) -> RuleConfig {
+    RuleConfig::new(
+        "blocked-sources",
+        80,
+        RuleKind::IpPrefix { prefixes: prefixes(list, Action::Block), match_on: AddressMatch::Source },
+    )
+}
+
+fn policy() -> FirewallConfig {
+    let mut config = FirewallConfig::new(Action::Allow);
+    config.chains.insert("lan".to_string(), ChainConfig { policy: Some(Action::Allow) });
+    config.rules = vec![
+        RuleConfig::new(
+            "quarantine-telnet",
+            100,
+            RuleKind::Services { services: vec![Service::Telnet], action: Action::Quarantine(Duration::from_secs(600)) },
+        ),
+        blocked_sources(&["192.0.2.0/24", "198.51.100.0/24", "203.0.113.7/32", "2001:db8::/32", "2001:db8:1::/48"]),
+        RuleConfig::new(
+            "to-lan",
+            70,
+            RuleKind::IpPrefix {
+                prefixes: prefixes(&["10.0.0.0/8"], Action::Jump("lan".to_string())),
+                match_on: AddressMatch::Source,
+            },
+        ),
+        RuleConfig::new(
+            "no-smb",
+            60,
+            RuleKind::PortBlocklist {
+                ports: vec![137..=139, 445..=445],
+                protocols: vec![Protocol::Tcp],
+                match_on: AddressMatch::Destination,
+                action: Action::Reject(RejectWith::TcpReset),
+            },
+        ),
+        RuleConfig {
+            chain: "lan".to_string(),
+            ..RuleConfig::new("lan-dns", 0, RuleKind::Services { services: vec![Service::Dns], action: Action::Allow })
+        },
+    ];
+    config
+}
+
+// iptables-save output holding what the backend wrote for `policy`
+fn saved(policy: &FirewallConfig, family: IpFamily) -> String {
+    let payload = IpTablesBackend::new().with_ipset_threshold(2).render_at(policy, now()).restore_payload(family);
+    let mut out = String::from("# Generated by iptables-save\n*filter\n:INPUT ACCEPT [0:0]\n:FORWARD ACCEPT [0:0]\n");
+    out.push_str(":OUTPUT ACCEPT [0:0]\n:DOCKER - [0:0]\n");
+    out.extend(payload.lines().filter(|l| l.starts_with(':')).map(|l| format!("{}\n", l)));
+    out.push_str("-A INPUT -i lo -j ACCEPT\n-A INPUT -j FIREWALL\n-A FORWARD -j DOCKER\n");
+    out.extend(payload.lines().filter(|l| l.starts_with("-A")).map(|l| format!("{}\n", l)));
+    out.push_str("COMMIT\n");
+    out
+}
+
+fn ipset_saved(sets: &[(&str, &str, &[&str])]) -> String {
+    let mut out = String::from("create docker hash:ip family inet hashsize 1024 maxelem 65536\n");
+    for (name, family, elements) in sets {
+        out.push_str(&format!("create {} hash:net family {} hashsize 1024 maxelem 65536\n", name, family));
+        for element in *elements {
+            out.push_str(&format!("add {} {}\n", name, element));
+        }
+    }
+    out
+}
+
+#[test]
+fn first_sync_reconciles_with_the_kernel() {
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.reply("iptables -C INPUT -j FIREWALL", CommandOutput::failed(1, "iptables: Bad rule"));
+    runner.reply("ip6tables -C INPUT -j FIREWALL", CommandOutput::failed(1, "ip6tables: Bad rule"));
+    assert_eq!(backend(&runner).sync_config(&policy(), now()).unwrap(), IptUpdate::Replaced);
+    assert_golden("iptables_first_sync.txt", &runner.transcript());
+}
+
+#[test]
+fn ipset_only_change_swaps_the_sets() {
+    let runner = Arc::new(ScriptedRunner::new());
+    let backend = backend(&runner);
+    backend.sync_config(&policy(), now()).unwrap();
+    runner.clear();
+
+    let mut changed = policy();
+    changed.rules[1] = blocked_sources(&["192.0.2.0/24", "198.18.0.0/15", "203.0.113.0/24", "2001:db8::/32"]);
+    assert_eq!(backend.sync_config(&changed, now()).unwrap(), IptUpdate::Ipsets);
+    assert_golden("iptables_ipsets.txt", &runner.transcript());
+
+    runner.clear();
+    assert_eq!(backend.sync_config(&changed, now()).unwrap(), IptUpdate::Unchanged);
+    assert!(runner.calls().is_empty());
+}
+
+#[test]
+fn layout_change_removes_stale_chains_and_sets() {
+    let runner = Arc::new(ScriptedRunner::new());
+    let backend = backend(&runner);
+    backend.sync_config(&policy(), now()).unwrap();
+    runner.clear();
+
+    // No more user chain, and the v6 prefixes are gone
+    let mut changed = policy();
+    changed.chains.clear();
+    changed.rules.retain(|r| r.chain == "input" && r.name != "to-lan");
+    changed.rules[1] = blocked_sources(&["192.0.2.0/24", "198.51.100.0/24"]);
+    assert_eq!(backend.sync_config(&changed, now()).unwrap(), IptUpdate::Replaced);
+    assert_golden("iptables_layout_change.txt", &runner.transcript());
+}
+
+#[test]
+fn reconcile_repairs_drift() {
+    let policy = policy();
+    let runner = Arc::new(ScriptedRunner::new());
+    // v4: someone added a rule to our chain by hand
+    let v4 = saved(&policy, IpFamily::V4).replace(
+        "-A FIREWALL-lan ",
+        "-A FIREWALL-lan -s 198.18.0.1/32 -j ACCEPT\n-A FIREWALL-lan ",
+    );
+    // v6: the jump was deleted, and a chain of an old policy is left over
+    let v6 = saved(&policy, IpFamily::V6)
+        .replace("-A INPUT -j FIREWALL\n", "")
+        .replace(":DOCKER - [0:0]\n", ":DOCKER - [0:0]\n:FIREWALL-old - [0:0]\n");
+    runner.reply("iptables-save -t filter", CommandOutput::ok(v4));
+    runner.reply("ip6tables-save -t filter", CommandOutput::ok(v6));
+    runner.reply(
+        "ipset save",
+        CommandOutput::ok(ipset_saved(&[
+            ("fw4-blocked_sources-src1", "inet", &["192.0.2.0/24", "203.0.113.7"]),
+            ("fw4-quarantine", "inet", &["198.51.100.9"]),
+            ("fw4-old", "inet", &["10.0.0.0/8"]),
+        ])),
+    );
+    runner.reply("ip6tables -C INPUT -j FIREWALL", CommandOutput::failed(1, "ip6tables: Bad rule"));
+
+    let drift = backend(&runner).reconcile(&policy, now()).unwrap();
+    assert_eq!(
+        drift.to_string(),
+        "chains: iptables FIREWALL-lan; jumps: ip6tables INPUT; \
+         ipsets: fw4-blocked_sources-src1, fw6-quarantine; \
+         stale: ip6tables FIREWALL-old, ipset fw4-old"
+    );
+    assert_golden("iptables_reconcile.txt", &runner.transcript());
+}
+
+#[test]
+fn flush_removes_jumps_chains_and_sets() {
+    let policy = policy();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.reply("iptables-save -t filter", CommandOutput::ok(saved(&policy, IpFamily::V4)));
+    runner.reply("ip6tables-save -t filter", CommandOutput::ok(saved(&policy, IpFamily::V6)));
+    runner.reply(
+        "ipset save",
+        CommandOutput::ok(ipset_saved(&[
+            ("fw4-blocked_sources-src1", "inet", &["192.0.2.0/24"]),
+            ("fw4-quarantine", "inet", &[]),
+            ("fw6-quarantine", "inet6", &[]),
+        ])),
+    );
+    backend(&runner).flush().unwrap();
+    assert_golden("iptables_flush.txt", &runner.transcript());
+}
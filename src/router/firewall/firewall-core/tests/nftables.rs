@@ -0,0 +1,188 @@
+mod common;
+
+use chrono::Weekday;
+use common::assert_golden;
+use firewall_core::rules::{AddressMatch, Expression, Service, TimeWindow};
+use firewall_core::{
+    Action, ChainConfig, FirewallConfig, IpPrefix, NfTablesBackend, NftUpdate, Protocol, RateLimitAlgorithm,
+    RateLimitConfig, RateLimitKeyType, RejectWith, RuleConfig, RuleKind, RuleSchedule,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn prefix(text: &str) -> IpPrefix {
+    text.parse().unwrap()
+}
+
+fn in_chain(mut rule: RuleConfig, chain: &str) -> RuleConfig {
+    rule.chain = chain.to_string();
+    rule
+}
+
+// One of everything the renderer handles differently
+fn policy() -> FirewallConfig {
+    let mut config = FirewallConfig::new(Action::Block);
+    config.rate_limiters.insert("ssh".to_string(), RateLimitConfig::per_source_ip(0.5, 5.0));
+    config.chains.insert("lan".to_string(), ChainConfig { policy: Some(Action::Allow) });
+    config.rules = vec![
+        RuleConfig::new(
+            "quarantine-telnet",
+            100,
+            RuleKind::Services { services: vec![Service::Telnet], action: Action::Quarantine(Duration::from_secs(3600)) },
+        ),
+        RuleConfig::new(
+            "ssh-limit",
+            90,
+            RuleKind::Services { services: vec![Service::Ssh], action: Action::RateLimit("ssh".to_string()) },
+        ),
+        // Nested prefixes: cut into disjoint ranges for the verdict map
+        RuleConfig::new(
+            "sources",
+            80,
+            RuleKind::IpPrefix {
+                prefixes: vec![
+                    (prefix("10.0.0.0/8"), Action::Jump("lan".to_string())),
+                    (prefix("10.1.0.0/16"), Action::Block),
+                    (prefix("10.1.2.0/24"), Action::Allow),
+                    (prefix("fd00::/8"), Action::Jump("lan".to_string())),
+                    (prefix("fd00:bad::/32"), Action::Block),
+                ],
+                match_on: AddressMatch::Source,
+            },
+        ),
+        // Reject can't go in a verdict map, so it gets sets instead
+        RuleConfig::new(
+            "reject-bogons",
+            70,
+            RuleKind::IpPrefix {
+                prefixes: vec![
+                    (prefix("192.0.2.0/24"), Action::Reject(RejectWith::IcmpAdminProhibited)),
+                    (prefix("198.51.100.0/24"), Action::Reject(RejectWith::IcmpAdminProhibited)),
+                    (prefix("198.51.100.7/32"), Action::Allow),
+                ],
+                match_on: AddressMatch::Destination,
+            },
+        ),
+        RuleConfig::new(
+            "no-smb",
+            60,
+            RuleKind::PortBlocklist {
+                ports: vec![137..=139, 445..=445],
+                protocols: vec![Protocol::Tcp],
+                match_on: AddressMatch::Destination,
+                action: Action::Reject(RejectWith::TcpReset),
+            },
+        ),
+        RuleConfig::new(
+            "night",
+            50,
+            RuleKind::TimeWindow {
+                windows: vec![
+                    TimeWindow::new(22, 0, 6, 30).weekdays(),
+                    TimeWindow::new(12, 0, 13, 0).on_days(vec![Weekday::Sat]),
+                ],
+                action: Action::DropLog,
+            },
+        ),
+        RuleConfig::new(
+            "flood",
+            40,
+            RuleKind::RateLimit {
+                limit: RateLimitConfig {
+                    key_type: RateLimitKeyType::Flow,
+                    ..RateLimitConfig::new(100.0, 200.0).with_algorithm(RateLimitAlgorithm::Gcra)
+                },
+            },
+        ),
+        RuleConfig::new(
+            "web",
+            30,
+            RuleKind::Expression {
+                expression: Expression::parse("tcp dport { 80, 443, 8000-8080 } ct.state new").unwrap(),
+                action: Action::Mark(0x10),
+            },
+        ),
+        // Negation doesn't render, so this one is left out with a note
+        RuleConfig::new(
+            "not-ssh",
+            20,
+            RuleKind::Expression {
+                expression: Expression::parse("not tcp dport 22").unwrap(),
+                action: Action::Log,
+            },
+        ),
+        RuleConfig {
+            schedule: RuleSchedule { active_from: None, expires_at: Some(now() - Duration::from_secs(1)) },
+            ..RuleConfig::new(
+                "expired",
+                10,
+                RuleKind::Services { services: vec![Service::Rdp], action: Action::Block },
+            )
+        },
+        in_chain(
+            RuleConfig::new(
+                "lan-dns",
+                0,
+                RuleKind::Services { services: vec![Service::Dns], action: Action::Allow },
+            ),
+            "lan",
+        ),
+        in_chain(
+            RuleConfig::new(
+                "lan-servers",
+                0,
+                RuleKind::IpPrefix {
+                    prefixes: vec![(prefix("10.9.0.0/16"), Action::Return)],
+                    match_on: AddressMatch::Either,
+                },
+            ),
+            "lan",
+        ),
+    ];
+    config
+}
+
+fn policy_rule(config: &mut FirewallConfig, name: &str, edit: impl FnOnce(&mut RuleKind)) {
+    let rule = config.rules.iter_mut().find(|r| r.name == name).unwrap();
+    edit(&mut rule.kind);
+}
+
+#[test]
+fn full_script() {
+    let ruleset = NfTablesBackend::dry_run().render_at(&policy(), now());
+    assert_golden("nftables_full.nft", &ruleset.to_script());
+}
+
+#[test]
+fn element_only_changes_are_patched_in_place() {
+    let backend = NfTablesBackend::dry_run();
+    let update = backend.apply(backend.render_at(&policy(), now())).unwrap();
+    assert!(matches!(update, NftUpdate::Replaced(_)));
+
+    // Same layout, different elements: more prefixes and ports, and a
+    // prefix moved to another verdict
+    let mut changed = policy();
+    policy_rule(&mut changed, "sources", |kind| {
+        if let RuleKind::IpPrefix { prefixes, .. } = kind {
+            prefixes.push((prefix("172.16.0.0/12"), Action::Block));
+            prefixes.retain(|(prefix, _)| prefix.to_string() != "10.1.2.0/24");
+            prefixes.push((prefix("10.1.3.0/24"), Action::Allow));
+        }
+    });
+    policy_rule(&mut changed, "no-smb", |kind| {
+        if let RuleKind::PortBlocklist { ports, .. } = kind {
+            ports.push(3389..=3389);
+        }
+    });
+    let update = backend.apply(backend.render_at(&changed, now())).unwrap();
+    let NftUpdate::Elements(script) = update else {
+        panic!("expected an element update, got {:?}", update);
+    };
+    assert_golden("nftables_elements.nft", &script);
+    assert_eq!(backend.last_script().as_deref(), Some(script.as_str()));
+
+    assert_eq!(backend.apply(backend.render_at(&changed, now())).unwrap(), NftUpdate::Unchanged);
+}
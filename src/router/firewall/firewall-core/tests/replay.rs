@@ -0,0 +1,88 @@
+// Replaying a capture through a `PacketClock` must give the verdicts,
+// expiries, stats and kernel renders the capture saw live, however long
+// ago or however fast it is replayed.
+
+use firewall_core::rules::Service;
+use firewall_core::{
+    Action, Firewall, FirewallBuilder, FirewallConfig, NfTablesBackend, Packet, PacketClock, Protocol, RuleConfig,
+    RuleKind, RuleSchedule,
+};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn start() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn at(secs: f64) -> SystemTime {
+    start() + Duration::from_secs_f64(secs)
+}
+
+fn tcp(source_port: u16, destination_port: u16) -> Packet {
+    let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+    packet.destination_ip = "198.51.100.1".parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(source_port);
+    packet.destination_port = Some(destination_port);
+    packet
+}
+
+fn no_telnet() -> RuleConfig {
+    RuleConfig::new("no-telnet", 0, RuleKind::Services { services: vec![Service::Telnet], action: Action::Block })
+}
+
+fn firewall(backend: Option<Arc<NfTablesBackend>>) -> Firewall {
+    let mut builder = FirewallBuilder::new(Action::Allow).with_clock(Arc::new(PacketClock::new()));
+    if let Some(backend) = backend {
+        builder = builder.with_backend(backend);
+    }
+    builder.build()
+}
+
+#[test]
+fn schedules_and_renders_follow_the_capture() {
+    let backend = Arc::new(NfTablesBackend::dry_run());
+    let firewall = firewall(Some(backend.clone()));
+    firewall.replay_packet(&tcp(40000, 80), at(0.0));
+
+    let mut config = FirewallConfig::new(Action::Allow);
+    config.rules.push(RuleConfig {
+        schedule: RuleSchedule::expiring_in(Duration::from_secs(600), at(0.0)),
+        ..no_telnet()
+    });
+    firewall.apply_config(&config).unwrap();
+    assert!(backend.last_script().unwrap().contains("dport 23"));
+
+    assert_eq!(firewall.replay_packet(&tcp(40001, 23), at(300.0)).action, Action::Block);
+    assert_eq!(firewall.replay_packet(&tcp(40002, 23), at(660.0)).action, Action::Allow);
+
+    // Rendered as of the capture, not of today, so the rule is gone now
+    // rather than from the start
+    assert_eq!(firewall.policy().now, at(660.0));
+    firewall.sync_backends();
+    assert!(!backend.last_script().unwrap().contains("dport 23"));
+
+    firewall.apply_config(&FirewallConfig::new(Action::Allow)).unwrap();
+    let published: Vec<SystemTime> = firewall.rule_history().iter().map(|g| g.published_at).collect();
+    assert_eq!(published[1..], [at(0.0)]);
+}
+
+#[test]
+fn ttls_stats_and_flows_follow_the_capture() {
+    let firewall = firewall(None);
+    for i in 0..11 {
+        firewall.replay_packet(&tcp(40000 + i, 80), at(f64::from(i) * 0.5));
+    }
+    // Eleven packets in five seconds of capture
+    assert_eq!(firewall.get_stats().packets_per_second, 2.2);
+
+    let filter = no_telnet().build_with_clock(firewall.clock());
+    firewall.add_temporary_rule(filter, Duration::from_secs(60)).unwrap();
+    assert_eq!(firewall.replay_packet(&tcp(41000, 23), at(64.0)).action, Action::Block);
+    assert_eq!(firewall.replay_packet(&tcp(41001, 23), at(66.0)).action, Action::Allow);
+
+    // Only the telnet flows were seen in the last half minute
+    assert_eq!(firewall.active_flows(), 13);
+    firewall.cleanup_old_flows(30);
+    assert_eq!(firewall.active_flows(), 2);
+}
@@ -0,0 +1,102 @@
+// Every rule counts the packets and bytes it decided or annotated, and when
+// it last did, broken down by the action it produced.
+
+use firewall_core::rules::{PortBlocklistRule, Service, WellKnownServicesRule};
+use firewall_core::{Action, Firewall, FirewallBuilder, ManualClock, Packet, Protocol, RateLimitConfig, RuleInfo};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn start() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn tcp(destination_port: u16, payload: usize) -> Packet {
+    let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+    packet.destination_ip = "198.51.100.1".parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(40000);
+    packet.destination_port = Some(destination_port);
+    packet.payload = vec![0; payload];
+    packet
+}
+
+fn rule(firewall: &Firewall, id: u64) -> RuleInfo {
+    firewall.list_rules().into_iter().find(|rule| rule.id == id).unwrap()
+}
+
+fn setup() -> (Firewall, Arc<ManualClock>, [u64; 3]) {
+    let clock = Arc::new(ManualClock::starting_at(start()));
+    let firewall = FirewallBuilder::new(Action::Allow).with_clock(clock.clone()).build();
+    // No refill while the clock stands still: two packets, then over
+    firewall.register_rate_limiter("web", RateLimitConfig::new(1.0, 2.0));
+    let telnet = firewall
+        .add_rule(Box::new(PortBlocklistRule::new("no-telnet").add_port(23).with_priority(30)))
+        .unwrap();
+    let log = firewall.add_rule(Box::new(
+        WellKnownServicesRule::new("log-http").add_service(Service::Http).with_action(Action::Log).with_priority(20),
+    )).unwrap();
+    let limit = firewall.add_rule(Box::new(
+        WellKnownServicesRule::new("limit-http")
+            .add_service(Service::Http)
+            .with_action(Action::RateLimit("web".to_string()))
+            .with_priority(10),
+    )).unwrap();
+    (firewall, clock, [telnet, log, limit])
+}
+
+#[test]
+fn counts_packets_bytes_and_verdicts_per_rule() {
+    let (firewall, clock, [telnet, log, limit]) = setup();
+    assert_eq!(rule(&firewall, telnet).hits.last_hit, None);
+
+    assert_eq!(firewall.process_packet(&tcp(23, 100)), Action::Block);
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(firewall.process_packet(&tcp(23, 50)), Action::Block);
+    // Not a rule's doing, so nobody's hit
+    assert_eq!(firewall.process_packet(&tcp(443, 1000)), Action::Allow);
+
+    let hits = rule(&firewall, telnet).hits;
+    assert_eq!((hits.packets, hits.bytes), (2, 150));
+    assert_eq!(hits.verdicts.dropped, 2);
+    assert_eq!(hits.last_hit, Some(start() + Duration::from_secs(5)));
+    assert_eq!(rule(&firewall, telnet).hit_count, 2);
+
+    // Every http packet is logged by one rule and handed to the limiter by
+    // the next, which only counts a drop once it's over the limit
+    for _ in 0..3 {
+        firewall.process_packet(&tcp(80, 10));
+    }
+    let logged = rule(&firewall, log).hits;
+    assert_eq!((logged.packets, logged.bytes, logged.verdicts.logged), (3, 30, 3));
+    let limited = rule(&firewall, limit).hits;
+    assert_eq!((limited.packets, limited.bytes), (3, 30));
+    assert_eq!(limited.verdicts.rate_limited, 1);
+    assert_eq!(limited.verdicts.allowed, 0);
+}
+
+#[test]
+fn counters_survive_new_generations_and_reset_on_request() {
+    let (firewall, _clock, [telnet, log, _]) = setup();
+    firewall.process_packet(&tcp(23, 100));
+    firewall.process_packet(&tcp(80, 10));
+
+    // Other rules coming and going don't touch them
+    let extra = firewall.add_rule(Box::new(PortBlocklistRule::new("no-smb").add_port(445))).unwrap();
+    firewall.remove_rule(extra);
+    assert_eq!(rule(&firewall, telnet).hits.packets, 1);
+
+    // A disabled rule keeps its counts but adds nothing to them
+    firewall.set_rule_enabled(telnet, false);
+    firewall.process_packet(&tcp(23, 100));
+    firewall.set_rule_enabled(telnet, true);
+    assert_eq!(rule(&firewall, telnet).hits.bytes, 100);
+
+    assert!(firewall.reset_rule_counters(telnet));
+    let hits = rule(&firewall, telnet).hits;
+    assert_eq!((hits.packets, hits.bytes, hits.last_hit), (0, 0, None));
+    assert_eq!(rule(&firewall, log).hits.packets, 1);
+    assert!(!firewall.reset_rule_counters(999));
+
+    firewall.reset_all_rule_counters();
+    assert_eq!(rule(&firewall, log).hits.packets, 0);
+}
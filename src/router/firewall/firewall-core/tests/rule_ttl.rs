@@ -0,0 +1,127 @@
+// Temporary rules: their expiry can be pushed back or brought forward, they
+// stop matching once it passes, and reaping takes them out of the rule set.
+
+use firewall_core::rules::PortBlocklistRule;
+use firewall_core::{Action, Filter, Firewall, FirewallBuilder, ManualClock, Packet, Protocol, RuleInfo};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn start() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn minutes(n: u64) -> Duration {
+    Duration::from_secs(n * 60)
+}
+
+fn tcp(destination_port: u16) -> Packet {
+    let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+    packet.destination_ip = "198.51.100.1".parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(40000);
+    packet.destination_port = Some(destination_port);
+    packet
+}
+
+fn block(port: u16) -> Box<dyn Filter> {
+    Box::new(PortBlocklistRule::new(format!("block-{}", port)).add_port(port))
+}
+
+fn rule(firewall: &Firewall, id: u64) -> Option<RuleInfo> {
+    firewall.list_rules().into_iter().find(|rule| rule.id == id)
+}
+
+fn setup() -> (Firewall, Arc<ManualClock>) {
+    let clock = Arc::new(ManualClock::starting_at(start()));
+    let firewall = FirewallBuilder::new(Action::Allow).with_clock(clock.clone()).build();
+    (firewall, clock)
+}
+
+#[test]
+fn extend_pushes_the_expiry_back() {
+    let (firewall, clock) = setup();
+    let ban = firewall.add_temporary_rule(block(23), minutes(15)).unwrap();
+    let permanent = firewall.add_rule(block(445)).unwrap();
+
+    assert_eq!(firewall.extend_rule_ttl(ban, minutes(15)), Some(start() + minutes(30)));
+    clock.advance(minutes(20));
+    assert_eq!(rule(&firewall, ban).unwrap().ttl, Some(minutes(10)));
+    assert_eq!(firewall.process_packet(&tcp(23)), Action::Block);
+
+    // Once passed, the extension counts from now rather than from the old
+    // expiry
+    clock.advance(minutes(20));
+    assert_eq!(firewall.process_packet(&tcp(23)), Action::Allow);
+    assert_eq!(firewall.extend_rule_ttl(ban, minutes(5)), Some(start() + minutes(45)));
+    assert_eq!(firewall.process_packet(&tcp(23)), Action::Block);
+
+    // Nothing to extend
+    let generation = firewall.rule_generation();
+    assert_eq!(firewall.extend_rule_ttl(permanent, minutes(5)), None);
+    assert_eq!(firewall.extend_rule_ttl(999, minutes(5)), None);
+    assert_eq!(firewall.rule_generation(), generation);
+}
+
+#[test]
+fn shorten_brings_the_expiry_forward() {
+    let (firewall, clock) = setup();
+    let ban = firewall.add_temporary_rule(block(23), minutes(15)).unwrap();
+    let permanent = firewall.add_rule(block(445)).unwrap();
+
+    assert_eq!(firewall.shorten_rule_ttl(ban, minutes(10)), Some(start() + minutes(5)));
+    clock.advance(minutes(4));
+    assert_eq!(firewall.process_packet(&tcp(23)), Action::Block);
+    clock.advance(minutes(1));
+    assert_eq!(firewall.process_packet(&tcp(23)), Action::Allow);
+
+    // Past the epoch it stops there rather than wrapping around
+    assert_eq!(firewall.shorten_rule_ttl(ban, Duration::MAX), Some(UNIX_EPOCH));
+    assert_eq!(firewall.shorten_rule_ttl(permanent, minutes(5)), None);
+    assert_eq!(firewall.shorten_rule_ttl(999, minutes(5)), None);
+}
+
+#[test]
+fn reaping_removes_only_expired_rules() {
+    let (firewall, clock) = setup();
+    let short = firewall.add_temporary_rule(block(23), minutes(5)).unwrap();
+    let long = firewall.add_temporary_rule(block(445), minutes(60)).unwrap();
+    let permanent = firewall.add_rule(block(3389)).unwrap();
+
+    // Nothing expired, nothing published
+    let generation = firewall.rule_generation();
+    assert!(firewall.reap_expired_rules().is_empty());
+    assert_eq!(firewall.rule_generation(), generation);
+
+    clock.advance(minutes(5));
+    assert_eq!(firewall.reap_expired_rules(), [short]);
+    assert_eq!(firewall.rule_generation(), generation + 1);
+    assert!(rule(&firewall, short).is_none());
+
+    clock.advance(minutes(120));
+    assert_eq!(firewall.reap_expired_rules(), [long]);
+    assert!(rule(&firewall, permanent).is_some());
+    assert_eq!(firewall.list_rules().len(), 1);
+}
+
+#[test]
+fn sweeper_reaps_in_the_background() {
+    let (firewall, clock) = setup();
+    let ban = firewall.add_temporary_rule(block(23), minutes(5)).unwrap();
+    let sweeper = firewall.start_rule_sweeper(Duration::from_millis(5));
+    clock.advance(minutes(5));
+    for _ in 0..200 {
+        if rule(&firewall, ban).is_none() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(rule(&firewall, ban).is_none());
+
+    // Stopped, it leaves the next expired rule where it is
+    sweeper.stop();
+    let ban = firewall.add_temporary_rule(block(23), minutes(5)).unwrap();
+    clock.advance(minutes(5));
+    thread::sleep(Duration::from_millis(20));
+    assert!(rule(&firewall, ban).is_some());
+}
@@ -0,0 +1,111 @@
+// Tracing a packet shows what processing it would do without doing any of
+// it: no rate-limit tokens, flows, quarantines, stats or rule counters.
+
+use firewall_core::rules::rate_limit_rule::RateLimitRule;
+use firewall_core::rules::{PortBlocklistRule, Service, WellKnownServicesRule};
+use firewall_core::{Action, ConnState, Firewall, FirewallBuilder, Packet, Protocol, RateLimitConfig, TcpFlags, TcpInfo};
+use std::time::Duration;
+
+fn segment(source: (&str, u16), destination: (&str, u16), flags: TcpFlags) -> Packet {
+    let mut packet = Packet::new(source.0.parse().unwrap());
+    packet.destination_ip = destination.0.parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(source.1);
+    packet.destination_port = Some(destination.1);
+    packet.tcp = Some(TcpInfo { flags, sequence: 0, acknowledgment: 0, window: 0 });
+    packet.payload = vec![0; 10];
+    packet
+}
+
+fn tcp(destination_port: u16, flags: TcpFlags) -> Packet {
+    segment(("192.0.2.1", 40000), ("198.51.100.1", destination_port), flags)
+}
+
+fn reply(source_port: u16, flags: TcpFlags) -> Packet {
+    segment(("198.51.100.1", source_port), ("192.0.2.1", 40000), flags)
+}
+
+// One token per source and next to no refill, so a single real packet
+// uses it up
+fn limit() -> RateLimitConfig {
+    RateLimitConfig::new(0.001, 1.0)
+}
+
+fn firewall() -> Firewall {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    firewall.register_rate_limiter("web", limit());
+    firewall.add_rule(Box::new(
+        WellKnownServicesRule::new("limit-http")
+            .add_service(Service::Http)
+            .with_action(Action::RateLimit("web".to_string())),
+    )).unwrap();
+    firewall.add_rule(Box::new(RateLimitRule::new("limit-all", limit()).with_priority(0))).unwrap();
+    firewall.add_rule(Box::new(
+        PortBlocklistRule::new("quarantine-telnet")
+            .add_port(23)
+            .with_action(Action::Quarantine(Duration::from_secs(60))),
+    )).unwrap();
+    firewall
+}
+
+#[test]
+fn tracing_leaves_limiters_alone() {
+    let firewall = firewall();
+    let packet = tcp(80, TcpFlags::SYN);
+    for _ in 0..5 {
+        let trace = firewall.trace_packet(&packet);
+        assert_eq!(trace.verdict.action, Action::Allow);
+        assert!(trace.steps.iter().all(|step| !step.rate_limited));
+    }
+
+    // Both limiters still have their token for the real packet, and only
+    // then run out
+    assert_eq!(firewall.process_packet(&packet), Action::Allow);
+    let trace = firewall.trace_packet(&packet);
+    assert_eq!(trace.verdict.action, Action::RateLimit("web".to_string()));
+    let limited: Vec<&str> = trace.steps.iter()
+        .filter(|step| step.rate_limited)
+        .map(|step| step.rule_name.as_str())
+        .collect();
+    assert_eq!(limited, ["limit-http"]);
+}
+
+#[test]
+fn tracing_records_nothing() {
+    let firewall = firewall();
+    let generation = firewall.rule_generation();
+    let trace = firewall.trace_packet(&tcp(23, TcpFlags::SYN));
+    assert_eq!(trace.generation, generation);
+    assert_eq!(trace.verdict.action, Action::Quarantine(Duration::from_secs(60)));
+    firewall.trace_packet(&tcp(80, TcpFlags::SYN));
+
+    assert!(firewall.quarantined_hosts().is_empty());
+    assert_eq!(firewall.active_flows(), 0);
+    assert_eq!(firewall.get_stats().total_packets, 0);
+    assert!(firewall.list_rules().iter().all(|rule| rule.hits.packets == 0 && rule.hits.last_hit.is_none()));
+    assert_eq!(firewall.rule_generation(), generation);
+}
+
+#[test]
+fn tracing_sees_the_flow_without_moving_it() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    let syn = tcp(443, TcpFlags::SYN);
+    assert_eq!(firewall.trace_packet(&syn).conn_state, ConnState::New);
+    assert_eq!(firewall.active_flows(), 0);
+
+    firewall.process_packet(&syn);
+    let syn_ack = reply(443, TcpFlags::SYN | TcpFlags::ACK);
+    let ack = tcp(443, TcpFlags::ACK);
+    // The answer would establish the flow, but a trace of it doesn't
+    for _ in 0..3 {
+        assert_eq!(firewall.trace_packet(&syn_ack).conn_state, ConnState::Established);
+    }
+    assert_eq!(firewall.trace_packet(&ack).conn_state, ConnState::New);
+
+    // Nor does a traced RST close it
+    firewall.process_packet(&syn_ack);
+    firewall.trace_packet(&tcp(443, TcpFlags::RST));
+    assert_eq!(firewall.trace_packet(&ack).conn_state, ConnState::Established);
+    assert_eq!(firewall.process_verdict(&ack).action, Action::Allow);
+    assert_eq!(firewall.active_flows(), 1);
+}
@@ -0,0 +1,128 @@
+// Every way of changing the rules publishes a whole, valid generation or
+// nothing at all, and earlier generations stay available for rollback up
+// to the history limit.
+
+use firewall_core::rules::PortBlocklistRule;
+use firewall_core::{Action, Chain, ChainError, Filter, Firewall, FirewallBuilder, Packet, Protocol, TransactionError};
+use std::time::Duration;
+
+fn tcp(destination_port: u16) -> Packet {
+    let mut packet = Packet::new("192.0.2.1".parse().unwrap());
+    packet.destination_ip = "198.51.100.1".parse().unwrap();
+    packet.protocol = Protocol::Tcp;
+    packet.source_port = Some(40000);
+    packet.destination_port = Some(destination_port);
+    packet
+}
+
+fn block(port: u16) -> Box<dyn Filter> {
+    Box::new(PortBlocklistRule::new(format!("block-{}", port)).add_port(port))
+}
+
+fn jump(port: u16, chain: &str) -> Box<dyn Filter> {
+    Box::new(PortBlocklistRule::new(format!("jump-{}", port)).add_port(port).with_action(Action::Jump(chain.into())))
+}
+
+fn names(firewall: &Firewall) -> Vec<String> {
+    let mut names: Vec<String> = firewall.list_rules().into_iter().map(|rule| rule.name).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn rollback_republishes_generation_n() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    firewall.add_rule(block(23)).unwrap();
+    let two = firewall.add_rule(block(445)).unwrap();
+    let mut transaction = firewall.begin_transaction();
+    transaction.remove_rule(two);
+    transaction.add_rule(block(3389));
+    transaction.set_default_action(Action::Block);
+    assert_eq!(transaction.commit(), Ok(3));
+    assert_eq!(firewall.process_packet(&tcp(80)), Action::Block);
+
+    // Back to the rules and default action of generation 2, as a new one
+    assert_eq!(firewall.rollback_rules(2), Ok(4));
+    assert_eq!(firewall.rule_generation(), 4);
+    assert_eq!(names(&firewall), ["block-23", "block-445"]);
+    assert_eq!(firewall.process_packet(&tcp(445)), Action::Block);
+    assert_eq!(firewall.process_packet(&tcp(80)), Action::Allow);
+
+    // Generation 3 is in the history now, so the rollback can be undone
+    assert_eq!(firewall.rollback_rules(3), Ok(5));
+    assert_eq!(names(&firewall), ["block-23", "block-3389"]);
+    assert_eq!(firewall.rollback_rules(99), Err(TransactionError::UnknownGeneration(99)));
+}
+
+#[test]
+fn aborted_transactions_change_nothing() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    let telnet = firewall.add_rule(block(23)).unwrap();
+    let generation = firewall.rule_generation();
+
+    // Discarded, dropped, or failing on any one of its changes
+    let mut transaction = firewall.begin_transaction();
+    transaction.remove_rule(telnet);
+    transaction.discard();
+    {
+        let mut transaction = firewall.begin_transaction();
+        transaction.set_default_action(Action::Block);
+    }
+    let mut transaction = firewall.begin_transaction();
+    transaction.add_rule(block(445));
+    transaction.remove_rule(telnet);
+    transaction.remove_rule(999);
+    assert_eq!(transaction.commit(), Err(TransactionError::UnknownRule(999)));
+    let mut transaction = firewall.begin_transaction();
+    transaction.create_chain(Chain::new("lan"));
+    transaction.add_rule(jump(22, "wan"));
+    assert_eq!(transaction.commit(), Err(TransactionError::Chain(ChainError::UnknownChain("wan".to_string()))));
+
+    assert_eq!(firewall.rule_generation(), generation);
+    assert_eq!(names(&firewall), ["block-23"]);
+    assert!(firewall.list_chains().iter().all(|chain| chain.name != "lan"));
+    assert_eq!(firewall.process_packet(&tcp(80)), Action::Allow);
+}
+
+#[test]
+fn every_add_is_validated_before_it_is_published() {
+    let firewall = FirewallBuilder::new(Action::Allow).build();
+    firewall.create_chain(Chain::new("lan")).unwrap();
+    let generation = firewall.rule_generation();
+
+    let missing = ChainError::UnknownChain("wan".to_string());
+    assert_eq!(firewall.add_rule(jump(22, "wan")), Err(missing.clone()));
+    assert_eq!(firewall.add_temporary_rule(jump(22, "wan"), Duration::from_secs(60)), Err(missing.clone()));
+    // One bad rule keeps the whole batch out
+    assert_eq!(firewall.add_rules(vec![block(23), jump(22, "wan")]), Err(missing));
+    assert_eq!(firewall.rule_generation(), generation);
+    assert!(firewall.list_rules().is_empty());
+
+    let ids = firewall.add_rules(vec![block(23), jump(22, "lan")]).unwrap();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(firewall.rule_generation(), generation + 1);
+}
+
+#[test]
+fn history_keeps_the_last_n_generations() {
+    let firewall = FirewallBuilder::new(Action::Allow).with_rule_history(3).build();
+    for port in 1..=5 {
+        firewall.add_rule(block(port)).unwrap();
+    }
+
+    // The live generation isn't part of it
+    let history = firewall.rule_history();
+    let generations: Vec<u64> = history.iter().map(|info| info.generation).collect();
+    assert_eq!(generations, [2, 3, 4]);
+    assert_eq!(history.iter().map(|info| info.rule_count).collect::<Vec<_>>(), [2, 3, 4]);
+    assert_eq!(firewall.rollback_rules(1), Err(TransactionError::UnknownGeneration(1)));
+    assert_eq!(firewall.rollback_rules(2), Ok(6));
+    assert_eq!(firewall.list_rules().len(), 2);
+
+    // With no history there is nothing to roll back to
+    let firewall = FirewallBuilder::new(Action::Allow).with_rule_history(0).build();
+    firewall.add_rule(block(23)).unwrap();
+    firewall.add_rule(block(445)).unwrap();
+    assert!(firewall.rule_history().is_empty());
+    assert_eq!(firewall.rollback_rules(1), Err(TransactionError::UnknownGeneration(1)));
+}
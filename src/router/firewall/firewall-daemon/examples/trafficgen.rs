@@ -0,0 +1,94 @@
+// Minimal traffic generator for the namespace tests: TCP listeners on one
+// side, connection attempts from the other, and a tally of how each port
+// answered.
+//
+//   trafficgen listen ADDR PORT...
+//   trafficgen probe ADDR PORT... [--count N] [--timeout-ms MS]
+//
+// `probe` prints one line per port: `PORT open=N refused=N filtered=N`.
+// Refused means a reset came back; filtered means nothing did.
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("listen") => listen(&args[1..]),
+        Some("probe") => probe(&args[1..]),
+        _ => Err("usage: trafficgen listen|probe ADDR PORT...".to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("trafficgen: {}", e);
+        exit(2);
+    }
+}
+
+fn listen(args: &[String]) -> Result<(), String> {
+    let (addr, ports, _) = parse(args)?;
+    let mut workers = Vec::new();
+    for port in ports {
+        let listener = TcpListener::bind(SocketAddr::new(addr, port))
+            .map_err(|e| format!("bind {}: {}", port, e))?;
+        // Accepting is all a probe needs; the stream closes on drop
+        workers.push(thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        }));
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn probe(args: &[String]) -> Result<(), String> {
+    let (addr, ports, options) = parse(args)?;
+    for port in ports {
+        let (mut open, mut refused, mut filtered) = (0, 0, 0);
+        for _ in 0..options.count {
+            match TcpStream::connect_timeout(&SocketAddr::new(addr, port), options.timeout) {
+                Ok(_) => open += 1,
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => refused += 1,
+                Err(_) => filtered += 1,
+            }
+        }
+        println!("{} open={} refused={} filtered={}", port, open, refused, filtered);
+    }
+    Ok(())
+}
+
+struct Options {
+    count: usize,
+    timeout: Duration,
+}
+
+fn parse(args: &[String]) -> Result<(IpAddr, Vec<u16>, Options), String> {
+    let mut options = Options { count: 1, timeout: Duration::from_secs(1) };
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or(format!("{} needs a number", flag))
+        };
+        match arg.as_str() {
+            "--count" => options.count = value(arg)? as usize,
+            "--timeout-ms" => options.timeout = Duration::from_millis(value(arg)?),
+            _ => positional.push(arg),
+        }
+    }
+    let (addr, ports) = positional.split_first().ok_or("missing address")?;
+    let addr = addr.parse().map_err(|_| format!("invalid address {}", addr))?;
+    let ports = ports.iter()
+        .map(|p| p.parse().map_err(|_| format!("invalid port {}", p)))
+        .collect::<Result<Vec<u16>, _>>()?;
+    if ports.is_empty() {
+        return Err("no ports given".to_string());
+    }
+    Ok((addr, ports, options))
+}
@@ -0,0 +1,44 @@
+use firewall_core::{Alert, AlertSink};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Writes alerts to the log. A scan or a flood can raise thousands a second,
+// so past `per_second` the rest of that second is counted and summarised
+// instead.
+pub struct LogAlertSink {
+    per_second: u32,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl LogAlertSink {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second,
+            window: Mutex::new(Window { started: Instant::now(), logged: 0, suppressed: 0 }),
+        }
+    }
+}
+
+impl AlertSink for LogAlertSink {
+    fn raise(&self, alert: &Alert) {
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() >= Duration::from_secs(1) {
+            if window.suppressed > 0 {
+                log::warn!("{} more alerts suppressed", window.suppressed);
+            }
+            *window = Window { started: Instant::now(), logged: 0, suppressed: 0 };
+        }
+        if window.logged < self.per_second {
+            window.logged += 1;
+            log::warn!("{}", alert);
+        } else {
+            window.suppressed += 1;
+        }
+    }
+}
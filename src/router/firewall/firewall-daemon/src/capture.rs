@@ -0,0 +1,578 @@
+use firewall_core::{Firewall, Packet};
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// Passive datapath: frames copied off an interface (a mirror port, say)
+// through an AF_PACKET TPACKET_V3 ring. The engine sees every frame but
+// the kernel never waits on it, so nothing can be dropped from here.
+
+// linux/if_packet.h
+const PACKET_ADD_MEMBERSHIP: i32 = 1;
+const PACKET_RX_RING: i32 = 5;
+const PACKET_STATISTICS: i32 = 6;
+const PACKET_VERSION: i32 = 10;
+const TPACKET_V3: i32 = 2;
+const PACKET_MR_PROMISC: u16 = 1;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+// linux/if_ether.h
+const ETH_P_ALL: u16 = 0x0003;
+
+// Offsets into struct tpacket_block_desc and struct tpacket3_hdr
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_FIRST_PKT: usize = 16;
+const FRAME_NEXT: usize = 0;
+const FRAME_SEC: usize = 4;
+const FRAME_NSEC: usize = 8;
+const FRAME_SNAPLEN: usize = 12;
+const FRAME_MAC: usize = 24;
+
+// How long the worker waits for a block before checking whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[repr(C)]
+struct TpacketReq3 {
+    block_size: u32,
+    block_nr: u32,
+    frame_size: u32,
+    frame_nr: u32,
+    retire_blk_tov: u32,
+    sizeof_priv: u32,
+    feature_req_word: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStatsV3 {
+    packets: u32,
+    drops: u32,
+    freeze_q_cnt: u32,
+}
+
+#[repr(C)]
+struct PacketMreq {
+    ifindex: i32,
+    kind: u16,
+    alen: u16,
+    address: [u8; 8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BpfInstruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+// Classic BPF program run by the kernel before a frame reaches the ring.
+// Written as `tcpdump -ddd` prints it, lines or commas between
+// instructions, which is also what iptables' `--bytecode` takes:
+//   tcpdump -ddd -i eth0 'tcp or udp' | tr '\n' ','
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BpfProgram(pub Vec<BpfInstruction>);
+
+impl BpfProgram {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.split([',', '\n']).map(str::trim).filter(|line| !line.is_empty());
+        let count: usize = lines.next()
+            .and_then(|line| line.parse().ok())
+            .ok_or("BPF bytecode must start with the instruction count")?;
+        let mut program = Vec::with_capacity(count);
+        for line in lines {
+            let fields: Vec<u32> = line.split_whitespace()
+                .map(|field| field.parse().map_err(|_| format!("invalid BPF instruction '{}'", line)))
+                .collect::<Result<_, _>>()?;
+            let [code, jt, jf, k] = fields[..] else {
+                return Err(format!("BPF instruction '{}' needs four fields", line));
+            };
+            let field = |value: u32, max: u32| if value <= max { Ok(value) } else { Err(format!("BPF instruction '{}' is out of range", line)) };
+            program.push(BpfInstruction {
+                code: field(code, u16::MAX.into())? as u16,
+                jt: field(jt, u8::MAX.into())? as u8,
+                jf: field(jf, u8::MAX.into())? as u8,
+                k,
+            });
+        }
+        if program.len() != count || count == 0 {
+            return Err(format!("BPF bytecode promises {} instructions but has {}", count, program.len()));
+        }
+        Ok(Self(program))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub interface: String,
+    pub filter: Option<BpfProgram>,
+    // See frames not addressed to this host, as on a mirror port
+    pub promiscuous: bool,
+    // The ring is `block_count` blocks of `block_size` bytes; the kernel
+    // hands a block over once it's full or `block_timeout` passes
+    pub block_size: u32,
+    pub block_count: u32,
+    pub block_timeout: Duration,
+}
+
+impl CaptureConfig {
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self {
+            interface: interface.into(),
+            filter: None,
+            promiscuous: true,
+            block_size: 1 << 20,
+            block_count: 16,
+            block_timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+// The mmapped ring, unmapped on drop
+struct Ring {
+    base: *mut u8,
+    len: usize,
+}
+
+// Only the capture that owns it touches the memory
+unsafe impl Send for Ring {}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.cast(), self.len) };
+    }
+}
+
+// AF_PACKET socket with a TPACKET_V3 receive ring
+pub struct Capture {
+    // Declared first so the ring is unmapped before the socket closes
+    ring: Ring,
+    fd: OwnedFd,
+    interface: String,
+    block_size: usize,
+    block_count: usize,
+    current: usize,
+}
+
+impl Capture {
+    pub fn open(config: &CaptureConfig) -> io::Result<Self> {
+        let name = CString::new(config.interface.as_str())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name contains NUL"))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Protocol 0 receives nothing until `bind` sets ETH_P_ALL along
+        // with the interface, so the ring never holds another interface's
+        // frames
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Attached before binding, so every frame that arrives is filtered
+        if let Some(program) = &config.filter {
+            let mut filter: Vec<libc::sock_filter> = program.0.iter()
+                .map(|insn| libc::sock_filter { code: insn.code, jt: insn.jt, jf: insn.jf, k: insn.k })
+                .collect();
+            let fprog = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+            set_option(&fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)?;
+        }
+
+        set_option(&fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V3)?;
+        // Frame size only sizes the v1/v2 layout; v3 packs frames tightly
+        let frame_size = 2048;
+        let request = TpacketReq3 {
+            block_size: config.block_size,
+            block_nr: config.block_count,
+            frame_size,
+            frame_nr: config.block_size / frame_size * config.block_count,
+            retire_blk_tov: config.block_timeout.as_millis().max(1) as u32,
+            sizeof_priv: 0,
+            feature_req_word: 0,
+        };
+        set_option(&fd, libc::SOL_PACKET, PACKET_RX_RING, &request)?;
+
+        let len = config.block_size as usize * config.block_count as usize;
+        let base = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ring = Ring { base: base.cast(), len };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_ALL.to_be();
+        addr.sll_ifindex = ifindex as i32;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if config.promiscuous {
+            let membership = PacketMreq { ifindex: ifindex as i32, kind: PACKET_MR_PROMISC, alen: 0, address: [0; 8] };
+            set_option(&fd, libc::SOL_PACKET, PACKET_ADD_MEMBERSHIP, &membership)?;
+        }
+
+        Ok(Self {
+            ring,
+            fd,
+            interface: config.interface.clone(),
+            block_size: config.block_size as usize,
+            block_count: config.block_count as usize,
+            current: 0,
+        })
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    // Waits up to `timeout` for the kernel to fill the next block, then
+    // calls `frame` for each frame in it with its capture time
+    pub fn next_block(&mut self, timeout: Duration, mut frame: impl FnMut(&[u8], SystemTime)) -> io::Result<bool> {
+        if !self.block_ready() {
+            let mut poll = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN | libc::POLLERR, revents: 0 };
+            let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) };
+            if ready < 0 {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) };
+            }
+            if !self.block_ready() {
+                return Ok(false);
+            }
+        }
+
+        // The kernel leaves a block alone until it's handed back
+        let block = unsafe { slice::from_raw_parts(self.block_ptr(), self.block_size) };
+        walk_block(block, &mut frame);
+
+        self.status().store(TP_STATUS_KERNEL, Ordering::Release);
+        self.current = (self.current + 1) % self.block_count;
+        Ok(true)
+    }
+
+    // Frames the kernel dropped because the ring was full, since the last
+    // call
+    pub fn kernel_drops(&self) -> io::Result<u32> {
+        let mut stats = TpacketStatsV3::default();
+        let mut len = mem::size_of::<TpacketStatsV3>() as libc::socklen_t;
+        let read = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                PACKET_STATISTICS,
+                (&mut stats as *mut TpacketStatsV3).cast(),
+                &mut len,
+            )
+        };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stats.drops)
+    }
+
+    fn block_ptr(&self) -> *mut u8 {
+        unsafe { self.ring.base.add(self.current * self.block_size) }
+    }
+
+    fn status(&self) -> &AtomicU32 {
+        // Shared with the kernel; blocks are page aligned
+        unsafe { &*(self.block_ptr().add(BLOCK_STATUS) as *const AtomicU32) }
+    }
+
+    fn block_ready(&self) -> bool {
+        self.status().load(Ordering::Acquire) & TP_STATUS_USER != 0
+    }
+}
+
+// Calls `frame` for each frame of a TPACKET_V3 block. Offsets come from
+// the kernel, but a frame that would run past the block is skipped and a
+// header past it ends the walk.
+fn walk_block(block: &[u8], mut frame: impl FnMut(&[u8], SystemTime)) {
+    let count = read_u32(block, BLOCK_NUM_PKTS);
+    let mut offset = read_u32(block, BLOCK_FIRST_PKT) as usize;
+    for _ in 0..count {
+        let Some(header) = block.get(offset..) else {
+            break;
+        };
+        let mac = offset + read_u16(header, FRAME_MAC) as usize;
+        let snaplen = read_u32(header, FRAME_SNAPLEN) as usize;
+        let captured = SystemTime::UNIX_EPOCH
+            + Duration::new(read_u32(header, FRAME_SEC).into(), read_u32(header, FRAME_NSEC));
+        if let Some(data) = block.get(mac..mac + snaplen) {
+            frame(data, captured);
+        }
+        let next = read_u32(header, FRAME_NEXT) as usize;
+        if next == 0 {
+            break;
+        }
+        offset += next;
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    buf.get(at..at + 4).map_or(0, |b| u32::from_ne_bytes(b.try_into().unwrap()))
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    buf.get(at..at + 2).map_or(0, |b| u16::from_ne_bytes(b.try_into().unwrap()))
+}
+
+fn set_option<T>(fd: &OwnedFd, level: i32, name: i32, value: &T) -> io::Result<()> {
+    let set = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    frames: AtomicU64,
+    // Frames that weren't IP, or were cut short
+    undecodable: AtomicU64,
+    // Frames lost because the engine fell behind
+    kernel_drops: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl fmt::Display for CaptureStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} frames ({} undecodable), {} dropped by the kernel, {} errors",
+            load(&self.frames),
+            load(&self.undecodable),
+            load(&self.kernel_drops),
+            load(&self.errors),
+        )
+    }
+}
+
+// Feeds one interface into the engine on its own thread. Stops when
+// dropped.
+pub struct CaptureWorker {
+    stop: Arc<AtomicBool>,
+    stats: Arc<CaptureStats>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl CaptureWorker {
+    pub fn start(engine: Arc<Firewall>, config: &CaptureConfig) -> io::Result<Self> {
+        let capture = Capture::open(config)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.interface, e)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(CaptureStats::default());
+        let worker = {
+            let stop = Arc::clone(&stop);
+            let stats = Arc::clone(&stats);
+            thread::Builder::new()
+                .name(format!("capture-{}", config.interface))
+                .spawn(move || run(capture, &engine, &stop, &stats))?
+        };
+        Ok(Self { stop, stats, worker: Some(worker) })
+    }
+
+    pub fn stats(&self) -> &CaptureStats {
+        &self.stats
+    }
+}
+
+impl Drop for CaptureWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run(mut capture: Capture, engine: &Firewall, stop: &AtomicBool, stats: &CaptureStats) {
+    while !stop.load(Ordering::Relaxed) {
+        let result = capture.next_block(POLL_INTERVAL, |frame, captured| {
+            stats.frames.fetch_add(1, Ordering::Relaxed);
+            match Packet::from_ethernet(frame) {
+                // Capture time drives a `PacketClock`; other clocks ignore it
+                Ok(packet) => {
+                    engine.replay_packet(&packet, captured);
+                }
+                Err(_) => {
+                    stats.undecodable.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        match result {
+            Ok(false) => {}
+            Ok(true) => match capture.kernel_drops() {
+                Ok(drops) => {
+                    stats.kernel_drops.fetch_add(drops.into(), Ordering::Relaxed);
+                }
+                Err(e) => log::debug!("capture {}: no ring statistics: {}", capture.interface(), e),
+            },
+            Err(e) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                log::error!("capture {}: {}", capture.interface(), e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u16, jt: u8, jf: u8, k: u32) -> BpfInstruction {
+        BpfInstruction { code, jt, jf, k }
+    }
+
+    #[test]
+    fn parses_tcpdump_output_split_by_commas_or_lines() {
+        let expected = BpfProgram(vec![
+            insn(40, 0, 0, 12),
+            insn(21, 0, 1, 2048),
+            insn(6, 0, 0, 262144),
+            insn(6, 0, 0, 0),
+        ]);
+        let lines = "4\n40 0 0 12\n21 0 1 2048\n6 0 0 262144\n6 0 0 0\n";
+        assert_eq!(BpfProgram::parse(lines), Ok(expected.clone()));
+        // As iptables --bytecode takes it, and with stray blanks
+        let commas = " 4,40 0 0 12,21 0 1 2048, 6 0 0 262144 ,\n6 0 0 0,";
+        assert_eq!(BpfProgram::parse(commas), Ok(expected));
+    }
+
+    #[test]
+    fn rejects_a_count_that_doesnt_match() {
+        for text in ["3,6 0 0 0", "1,6 0 0 0,6 0 0 1", "0", ""] {
+            assert!(BpfProgram::parse(text).is_err(), "{:?}", text);
+        }
+        assert_eq!(
+            BpfProgram::parse("2,6 0 0 0"),
+            Err("BPF bytecode promises 2 instructions but has 1".to_string())
+        );
+        assert!(BpfProgram::parse("x,6 0 0 0").unwrap_err().contains("instruction count"));
+    }
+
+    #[test]
+    fn rejects_malformed_and_out_of_range_fields() {
+        let widest = BpfProgram(vec![insn(u16::MAX, 255, 255, u32::MAX)]);
+        assert_eq!(BpfProgram::parse("1,65535 255 255 4294967295"), Ok(widest));
+        for line in ["65536 0 0 0", "6 256 0 0", "6 0 256 0", "6 0 0 4294967296", "6 0 0 -1"] {
+            let err = BpfProgram::parse(&format!("1,{}", line)).unwrap_err();
+            assert!(err.contains(line), "{}", err);
+        }
+        assert!(BpfProgram::parse("1,6 0 0").unwrap_err().contains("needs four fields"));
+        assert!(BpfProgram::parse("1,6 0 0 0 0").unwrap_err().contains("needs four fields"));
+    }
+
+    // A block as the kernel lays it out: descriptor, then frames linked by
+    // their offset to the next
+    struct Block(Vec<u8>);
+
+    impl Block {
+        fn new(len: usize, count: u32, first: u32) -> Self {
+            let mut block = Self(vec![0; len]);
+            block.put(BLOCK_NUM_PKTS, &count.to_ne_bytes());
+            block.put(BLOCK_FIRST_PKT, &first.to_ne_bytes());
+            block
+        }
+
+        fn frame(&mut self, at: usize, next: u32, secs: u32, nanos: u32, mac: u16, data: &[u8]) {
+            self.put(at + FRAME_NEXT, &next.to_ne_bytes());
+            self.put(at + FRAME_SEC, &secs.to_ne_bytes());
+            self.put(at + FRAME_NSEC, &nanos.to_ne_bytes());
+            self.put(at + FRAME_SNAPLEN, &(data.len() as u32).to_ne_bytes());
+            self.put(at + FRAME_MAC, &mac.to_ne_bytes());
+            if at + mac as usize + data.len() <= self.0.len() {
+                self.put(at + mac as usize, data);
+            }
+        }
+
+        fn put(&mut self, at: usize, bytes: &[u8]) {
+            self.0[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+
+        fn walk(&self) -> Vec<(Vec<u8>, SystemTime)> {
+            let mut frames = Vec::new();
+            walk_block(&self.0, |data, captured| frames.push((data.to_vec(), captured)));
+            frames
+        }
+    }
+
+    fn time(secs: u32, nanos: u32) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(secs.into(), nanos)
+    }
+
+    #[test]
+    fn walks_the_frames_of_a_block() {
+        let mut block = Block::new(512, 3, 48);
+        block.frame(48, 96, 1_700_000_000, 5, 34, b"first");
+        block.frame(144, 80, 1_700_000_001, 0, 40, b"second frame");
+        block.frame(224, 0, 1_700_000_002, 999_999_999, 32, b"third");
+        assert_eq!(
+            block.walk(),
+            vec![
+                (b"first".to_vec(), time(1_700_000_000, 5)),
+                (b"second frame".to_vec(), time(1_700_000_001, 0)),
+                (b"third".to_vec(), time(1_700_000_002, 999_999_999)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_the_count_or_the_last_link() {
+        let mut block = Block::new(256, 1, 48);
+        block.frame(48, 64, 1, 0, 32, b"a");
+        block.frame(112, 0, 2, 0, 32, b"b");
+        assert_eq!(block.walk().len(), 1);
+
+        // Count says more than the links lead to
+        let mut block = Block::new(256, 5, 48);
+        block.frame(48, 64, 1, 0, 32, b"a");
+        block.frame(112, 0, 2, 0, 32, b"b");
+        assert_eq!(block.walk().len(), 2);
+
+        assert!(Block::new(256, 0, 48).walk().is_empty());
+    }
+
+    #[test]
+    fn skips_frames_that_run_past_the_block() {
+        let mut block = Block::new(256, 3, 48);
+        block.frame(48, 64, 1, 0, 32, b"kept");
+        // Snap length past the end, then a link out of the block
+        block.frame(112, 64, 2, 0, 140, b"truncated frame");
+        block.frame(176, 4096, 3, 0, 32, b"last");
+        let frames: Vec<Vec<u8>> = block.walk().into_iter().map(|(data, _)| data).collect();
+        assert_eq!(frames, vec![b"kept".to_vec(), b"last".to_vec()]);
+
+        // First frame offset outside the block
+        assert!(Block::new(64, 1, 4096).walk().is_empty());
+    }
+}
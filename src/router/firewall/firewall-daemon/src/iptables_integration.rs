@@ -0,0 +1,68 @@
+use firewall_core::{BackendStatus, Firewall, FirewallBackend, IpTablesBackend};
+use std::sync::Arc;
+
+// Mirrors the engine's policy into iptables, so the kernel enforces it on
+// traffic the daemon never sees. The engine syncs the backend itself on
+// every rule change once it's built with `backend()`.
+pub struct IpTablesSync {
+    backend: Arc<IpTablesBackend>,
+}
+
+impl IpTablesSync {
+    pub fn new() -> Self {
+        Self { backend: Arc::new(IpTablesBackend::new()) }
+    }
+
+    // For `FirewallBuilder::with_backend`
+    pub fn backend(&self) -> Arc<dyn FirewallBackend> {
+        self.backend.clone()
+    }
+
+    // Pushes the current policy; cheap when nothing changed
+    pub fn sync(&self, engine: &Firewall) -> Result<(), String> {
+        for status in engine.sync_backends() {
+            report(&status)?;
+        }
+        Ok(())
+    }
+
+    // Puts back whatever was changed behind the daemon's back
+    pub fn reconcile(&self, engine: &Firewall) -> Result<(), String> {
+        // Syncs published since the last check that failed
+        for status in engine.backend_status() {
+            if let Some(e) = &status.error {
+                log::error!("{}: last sync failed: {}", status.backend, e);
+            }
+        }
+        let policy = engine.policy();
+        let drift = self.backend.reconcile(&policy.config, policy.now).map_err(|e| e.to_string())?;
+        if !drift.is_empty() {
+            log::warn!("Corrected iptables drift: {}", drift);
+        }
+        Ok(())
+    }
+}
+
+impl Default for IpTablesSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn report(status: &BackendStatus) -> Result<(), String> {
+    if let Some(e) = &status.error {
+        return Err(format!("{}: {}", status.backend, e));
+    }
+    if let Some(report) = &status.report {
+        for note in &report.notes {
+            log::warn!("{}: {}", status.backend, note);
+        }
+        for rule in &report.userspace {
+            log::info!("{}: rule {} is enforced in userspace only", status.backend, rule);
+        }
+        if report.changed {
+            log::info!("Applied generation {} to {}", report.generation, status.backend);
+        }
+    }
+    Ok(())
+}
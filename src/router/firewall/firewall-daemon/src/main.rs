@@ -116,11 +116,34 @@
 use std::thread;
 use std::time::Duration;
 
+mod alerts;
+mod capture;
 mod iptables_integration;
-
-use iptables_integration::Firewall;
+mod nfqueue;
+mod policy;
+mod reject;
+
+use alerts::LogAlertSink;
+use capture::{BpfProgram, CaptureConfig, CaptureWorker};
+use firewall_core::{Action, FirewallBuilder, ProcessingMode};
+use iptables_integration::IpTablesSync;
+use nfqueue::{NfQueueConfig, NfQueueWorkers};
+use policy::PolicyFile;
 use simplelog::*;
 use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const DEFAULT_CONFIG: &str = "/etc/firewall/firewall.toml";
+const USAGE: &str = "usage: firewall-daemon [CONFIG] [--nfqueue N|FIRST:LAST [--fail-open]]
+       firewall-daemon [CONFIG] --capture IFACE [--bpf BYTECODE|@FILE] [--no-promisc]";
+// Alerts written to the log per second before the rest are summarised
+const ALERTS_PER_SECOND: u32 = 20;
+// Rate-limit keys idle this long are forgotten and start over with a full
+// allowance
+const RATE_LIMIT_IDLE_SECS: u64 = 600;
+// How late a temporary rule may stay in force after it expires
+const RULE_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
 
 fn main() {
     CombinedLogger::init(vec![
@@ -131,30 +154,159 @@ fn main() {
 
     log::info!("Starting the Router Node...");
 
-    match Firewall::new() {
-        Ok(firewall) => {
-            log::info!("Firewall initialized.");
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            log::error!("{}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config_path = args.config.unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+
+    // The iptables chain enforces the policy only when nothing else does.
+    // With NFQUEUE the queue's verdicts are the enforcement, and a chain as
+    // well would drop and rate-limit every packet twice. A passive tap sees
+    // a copy of the traffic, so verdicts are only recorded and alerted on.
+    let kernel = (args.capture.is_none() && args.nfqueue.is_none()).then(IpTablesSync::new);
+    let mut builder = FirewallBuilder::new(Action::Allow)
+        .with_alert_sink(Arc::new(LogAlertSink::new(ALERTS_PER_SECOND)));
+    if let Some(kernel) = &kernel {
+        builder = builder.with_backend(kernel.backend());
+    }
+    if args.capture.is_some() {
+        builder = builder.with_mode(ProcessingMode::Monitor);
+    }
+    let engine = Arc::new(builder.build());
+    // Held for the life of the daemon; dropping it stops the sweeps
+    let _sweeper = engine.start_rule_sweeper(RULE_SWEEP_INTERVAL);
+    // Running without the policy would let everything through
+    let mut policy = PolicyFile::new(&config_path);
+    if let Err(e) = policy.load(&engine) {
+        log::error!("Failed to load {}: {}", config_path.display(), e);
+        std::process::exit(1);
+    }
 
-            if let Err(e) = firewall.block_ip("192.168.1.100") {
-                log::error!("Failed to block IP: {}", e);
-            }
+    // Loading already synced the rules; this also covers an empty policy
+    // and the rate limiters
+    match kernel.as_ref().map(|kernel| kernel.sync(&engine)) {
+        Some(Err(e)) => log::error!("Failed to apply the policy to iptables: {}", e),
+        _ => log::info!("Firewall initialized."),
+    }
 
-            if let Err(e) = firewall.allow_port(8080) {
-                log::error!("Failed to allow port: {}", e);
-            }
+    // Packets the kernel queues to userspace get their verdict here
+    let datapath = args.nfqueue.map(|config| match NfQueueWorkers::start(Arc::clone(&engine), &config) {
+        Ok(workers) => {
+            log::info!("Serving NFQUEUE {:?}", config.queues);
+            workers
+        }
+        Err(e) => {
+            log::error!("Failed to bind NFQUEUE {:?}: {}", config.queues, e);
+            std::process::exit(1);
+        }
+    });
 
-            if let Err(e) = firewall.list_rules() {
-                log::error!("Failed to list rules: {}", e);
-            }
+    let tap = args.capture.map(|config| match CaptureWorker::start(Arc::clone(&engine), &config) {
+        Ok(worker) => {
+            log::info!("Monitoring {} passively; nothing will be dropped", config.interface);
+            worker
         }
         Err(e) => {
-            log::error!("Failed to initialize the firewall: {}", e);
+            log::error!("Failed to capture on {}", e);
+            std::process::exit(1);
         }
-    }
+    });
 
     // Keep the program running
     log::info!("Router Node is now running. Press Ctrl+C to stop.");
     loop {
         thread::sleep(Duration::from_secs(60));
+        // Lookups already ignore flows past their timeout; this frees them
+        let expired = engine.expire_flows();
+        if expired > 0 {
+            log::debug!("Expired {} idle flows", expired);
+        }
+        engine.cleanup_rate_limiters(RATE_LIMIT_IDLE_SECS);
+        match policy.reload_if_changed(&engine) {
+            Ok(true) => log::info!("Reloaded {}", config_path.display()),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Failed to reload {}, keeping generation {}: {}",
+                config_path.display(),
+                engine.rule_generation(),
+                e
+            ),
+        }
+        if let Some(kernel) = &kernel
+            && let Err(e) = kernel.reconcile(&engine)
+        {
+            log::error!("Failed to reconcile iptables: {}", e);
+        }
+        if let Some(workers) = &datapath {
+            log::info!("nfqueue: {}", workers.stats());
+        }
+        if let Some(worker) = &tap {
+            log::info!("capture: {}", worker.stats());
+        }
+    }
+}
+
+struct Args {
+    config: Option<PathBuf>,
+    nfqueue: Option<NfQueueConfig>,
+    capture: Option<CaptureConfig>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args { config: None, nfqueue: None, capture: None };
+        let mut fail_open = false;
+        let mut filter = None;
+        let mut promiscuous = true;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--nfqueue" => {
+                    let queues = args.next().ok_or("--nfqueue needs a queue number")?;
+                    parsed.nfqueue = Some(NfQueueConfig {
+                        queues: NfQueueConfig::parse_queues(&queues)?,
+                        ..NfQueueConfig::default()
+                    });
+                }
+                "--fail-open" => fail_open = true,
+                "--capture" => {
+                    let interface = args.next().ok_or("--capture needs an interface")?;
+                    parsed.capture = Some(CaptureConfig::new(interface));
+                }
+                "--bpf" => {
+                    let bytecode = args.next().ok_or("--bpf needs bytecode or @FILE")?;
+                    let bytecode = match bytecode.strip_prefix('@') {
+                        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?,
+                        None => bytecode,
+                    };
+                    filter = Some(BpfProgram::parse(&bytecode)?);
+                }
+                "--no-promisc" => promiscuous = false,
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                _ if parsed.config.is_none() => parsed.config = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+        if parsed.nfqueue.is_some() && parsed.capture.is_some() {
+            return Err("--nfqueue and --capture can't be combined".to_string());
+        }
+        if let Some(config) = &mut parsed.nfqueue {
+            config.fail_open = fail_open;
+        }
+        match &mut parsed.capture {
+            Some(config) => {
+                config.filter = filter;
+                config.promiscuous = promiscuous;
+            }
+            None if filter.is_some() || !promiscuous => {
+                return Err("--bpf and --no-promisc need --capture".to_string());
+            }
+            None => {}
+        }
+        Ok(parsed)
     }
-}
\ No newline at end of file
+}
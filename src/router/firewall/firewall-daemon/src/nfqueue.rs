@@ -0,0 +1,909 @@
+use crate::reject::{self, RejectSender};
+use firewall_core::{Action, Firewall, Packet, RejectWith, Verdict};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Userspace datapath: the kernel hands packets to the engine through
+// nfnetlink_queue and waits for a verdict. Send traffic here with e.g.
+//   iptables -A FORWARD -j NFQUEUE --queue-balance 0:3
+// and run one worker per queue.
+
+// linux/netlink.h
+const NETLINK_NETFILTER: i32 = 12;
+const SOL_NETLINK: i32 = 270;
+const NETLINK_NO_ENOBUFS: i32 = 5;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// linux/netfilter/nfnetlink.h
+const NFGENMSG_LEN: usize = 4;
+const NFNL_SUBSYS_QUEUE: u16 = 3;
+
+// linux/netfilter/nfnetlink_queue.h
+const NFQNL_MSG_PACKET: u16 = 0;
+const NFQNL_MSG_VERDICT: u16 = 1;
+const NFQNL_MSG_CONFIG: u16 = 2;
+const NFQNL_MSG_VERDICT_BATCH: u16 = 3;
+const NFQA_PACKET_HDR: u16 = 1;
+const NFQA_VERDICT_HDR: u16 = 2;
+const NFQA_MARK: u16 = 3;
+const NFQA_PAYLOAD: u16 = 10;
+const NFQA_CFG_CMD: u16 = 1;
+const NFQA_CFG_PARAMS: u16 = 2;
+const NFQA_CFG_QUEUE_MAXLEN: u16 = 3;
+const NFQA_CFG_MASK: u16 = 4;
+const NFQA_CFG_FLAGS: u16 = 5;
+const NFQNL_CFG_CMD_BIND: u8 = 1;
+const NFQNL_CFG_CMD_UNBIND: u8 = 2;
+const NFQNL_COPY_PACKET: u8 = 2;
+const NFQA_CFG_F_FAIL_OPEN: u32 = 0x1;
+
+// linux/netfilter.h
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+// How long a worker blocks before checking whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct NfQueueConfig {
+    // One worker per queue; match the NFQUEUE rule's `--queue-balance`
+    pub queues: RangeInclusive<u16>,
+    // Packets read before verdicts go back in one write
+    pub batch_size: usize,
+    // Packets the kernel holds for a queue before it drops, or accepts
+    // with `fail_open`
+    pub max_len: u32,
+    // Accept instead of drop when a queue is full
+    pub fail_open: bool,
+}
+
+impl Default for NfQueueConfig {
+    fn default() -> Self {
+        Self { queues: 0..=0, batch_size: 64, max_len: 1024, fail_open: false }
+    }
+}
+
+impl NfQueueConfig {
+    // `n` or `first:last`, as iptables' `--queue-num` and `--queue-balance`
+    pub fn parse_queues(text: &str) -> Result<RangeInclusive<u16>, String> {
+        let number = |s: &str| s.trim().parse::<u16>().map_err(|_| format!("invalid queue number '{}'", s));
+        let queues = match text.split_once(':') {
+            Some((first, last)) => number(first)?..=number(last)?,
+            None => number(text)?..=number(text)?,
+        };
+        if queues.is_empty() {
+            return Err(format!("empty queue range '{}'", text));
+        }
+        Ok(queues)
+    }
+}
+
+// What the kernel is told to do with a queued packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NfVerdict {
+    // `mark` replaces the packet mark; without one it's left alone
+    Accept { mark: Option<u32> },
+    Drop,
+    // Dropped in the kernel; the daemon sends the RST or ICMP error itself
+    Reject(RejectWith),
+}
+
+impl NfVerdict {
+    // Monitored verdicts pass untouched
+    pub fn of(verdict: &Verdict) -> Self {
+        if verdict.monitored {
+            NfVerdict::Accept { mark: None }
+        } else if verdict.permits() {
+            NfVerdict::Accept { mark: verdict.mark }
+        } else if let Action::Reject(with) = verdict.action {
+            NfVerdict::Reject(with)
+        } else {
+            NfVerdict::Drop
+        }
+    }
+
+    // What goes to the kernel: the verdict code and the mark to set
+    fn wire(self) -> (u32, Option<u32>) {
+        match self {
+            NfVerdict::Accept { mark } => (NF_ACCEPT, mark),
+            NfVerdict::Drop | NfVerdict::Reject(_) => (NF_DROP, None),
+        }
+    }
+}
+
+// A packet waiting in the kernel for its verdict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedPacket {
+    pub id: u32,
+    // IPv4 or IPv6 header onwards
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Packet(QueuedPacket),
+    // Reply to a request sent with `NLM_F_ACK`; `errno` is 0 on success
+    Ack { seq: u32, errno: i32 },
+}
+
+// Netlink messages for the queue subsystem, back to back in one buffer
+struct MessageWriter {
+    buf: Vec<u8>,
+    start: usize,
+}
+
+impl MessageWriter {
+    fn new() -> Self {
+        Self { buf: Vec::new(), start: 0 }
+    }
+
+    fn begin(&mut self, msg: u16, flags: u16, seq: u32, queue: u16) {
+        self.start = self.buf.len();
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.buf.extend_from_slice(&(NFNL_SUBSYS_QUEUE << 8 | msg).to_ne_bytes());
+        self.buf.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        self.buf.extend_from_slice(&seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg: AF_UNSPEC, version 0, queue number in network order
+        self.buf.extend_from_slice(&[0, 0]);
+        self.buf.extend_from_slice(&queue.to_be_bytes());
+    }
+
+    fn attr(&mut self, kind: u16, payload: &[u8]) {
+        self.buf.extend_from_slice(&((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(payload);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn end(&mut self) {
+        let len = (self.buf.len() - self.start) as u32;
+        self.buf[self.start..self.start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// Verdicts for packets in the order they were read, ids ascending. A run
+// of verdicts that look the same to the kernel goes out as one batch
+// verdict, which covers every packet up to its id that has no verdict yet.
+pub fn encode_verdicts(queue: u16, seq: u32, verdicts: &[(u32, NfVerdict)]) -> Vec<u8> {
+    let mut writer = MessageWriter::new();
+    for run in verdicts.chunk_by(|a, b| a.1.wire() == b.1.wire()) {
+        let (id, verdict) = run[run.len() - 1];
+        let (code, mark) = verdict.wire();
+        let msg = if run.len() > 1 { NFQNL_MSG_VERDICT_BATCH } else { NFQNL_MSG_VERDICT };
+        writer.begin(msg, 0, seq, queue);
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&code.to_be_bytes());
+        header[4..].copy_from_slice(&id.to_be_bytes());
+        writer.attr(NFQA_VERDICT_HDR, &header);
+        if let Some(mark) = mark {
+            writer.attr(NFQA_MARK, &mark.to_be_bytes());
+        }
+        writer.end();
+    }
+    writer.finish()
+}
+
+// Binds the socket to `queue` and sets it up to copy whole packets
+pub fn encode_bind(queue: u16, seq: u32, config: &NfQueueConfig) -> Vec<u8> {
+    let mut writer = MessageWriter::new();
+    writer.begin(NFQNL_MSG_CONFIG, NLM_F_ACK, seq, queue);
+    // command, padding, protocol family (unused since Linux 3.8)
+    writer.attr(NFQA_CFG_CMD, &[NFQNL_CFG_CMD_BIND, 0, 0, 0]);
+    let mut params = [0u8; 5];
+    params[..4].copy_from_slice(&0xffffu32.to_be_bytes());
+    params[4] = NFQNL_COPY_PACKET;
+    writer.attr(NFQA_CFG_PARAMS, &params);
+    writer.attr(NFQA_CFG_QUEUE_MAXLEN, &config.max_len.to_be_bytes());
+    let flags = if config.fail_open { NFQA_CFG_F_FAIL_OPEN } else { 0 };
+    writer.attr(NFQA_CFG_MASK, &NFQA_CFG_F_FAIL_OPEN.to_be_bytes());
+    writer.attr(NFQA_CFG_FLAGS, &flags.to_be_bytes());
+    writer.end();
+    writer.finish()
+}
+
+fn encode_unbind(queue: u16, seq: u32) -> Vec<u8> {
+    let mut writer = MessageWriter::new();
+    writer.begin(NFQNL_MSG_CONFIG, 0, seq, queue);
+    writer.attr(NFQA_CFG_CMD, &[NFQNL_CFG_CMD_UNBIND, 0, 0, 0]);
+    writer.end();
+    writer.finish()
+}
+
+// Splits one datagram from the socket into messages. Anything other than
+// queued packets and acks is skipped, and so is a message whose body is
+// malformed; only lengths that hide where the next message starts fail
+// the datagram.
+pub fn parse_messages(mut buf: &[u8]) -> io::Result<Vec<Message>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed netlink message");
+    let mut messages = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+        let seq = u32::from_ne_bytes(buf[8..12].try_into().unwrap());
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Err(malformed());
+        }
+        let body = &buf[NLMSG_HDRLEN..len];
+        if kind == NLMSG_ERROR {
+            match body.get(..4) {
+                Some(errno) => {
+                    messages.push(Message::Ack { seq, errno: -i32::from_ne_bytes(errno.try_into().unwrap()) });
+                }
+                None => log::warn!("nfqueue: skipping an ack too short for its errno"),
+            }
+        } else if kind == NFNL_SUBSYS_QUEUE << 8 | NFQNL_MSG_PACKET {
+            match body.get(NFGENMSG_LEN..).map(parse_packet) {
+                Some(Ok(packet)) => messages.push(Message::Packet(packet)),
+                // Still answered, so it doesn't sit in the queue: the empty
+                // payload won't decode and the packet is dropped
+                Some(Err(Some(id))) => {
+                    log::warn!("nfqueue: malformed attributes on packet {}, dropping it", id);
+                    messages.push(Message::Packet(QueuedPacket { id, payload: Vec::new() }));
+                }
+                Some(Err(None)) | None => log::warn!("nfqueue: skipping a queued packet without a readable id"),
+            }
+        }
+        buf = buf.get(align(len)..).unwrap_or(&[]);
+    }
+    Ok(messages)
+}
+
+// On a malformed attribute, the packet id if one was read before it
+fn parse_packet(mut attrs: &[u8]) -> Result<QueuedPacket, Option<u32>> {
+    let mut id = None;
+    let mut payload = Vec::new();
+    while attrs.len() >= NLA_HDRLEN {
+        let len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap()) & NLA_TYPE_MASK;
+        let value = attrs.get(NLA_HDRLEN..len).ok_or(id)?;
+        match kind {
+            // packet id, hardware protocol, hook
+            NFQA_PACKET_HDR => id = Some(u32::from_be_bytes(value.get(..4).ok_or(id)?.try_into().unwrap())),
+            NFQA_PAYLOAD => payload = value.to_vec(),
+            _ => {}
+        }
+        attrs = attrs.get(align(len)..).unwrap_or(&[]);
+    }
+    Ok(QueuedPacket { id: id.ok_or(None)?, payload })
+}
+
+// Netlink socket bound to one queue. Unbinds when dropped.
+pub struct NfQueue {
+    fd: OwnedFd,
+    queue: u16,
+    seq: u32,
+    buf: Vec<u8>,
+    // Messages read past the end of the last batch
+    pending: Vec<QueuedPacket>,
+}
+
+impl NfQueue {
+    pub fn open(queue: u16, config: &NfQueueConfig) -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_NETFILTER) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // An overrun loses packets either way; don't also fail the next read
+        set_option(&fd, SOL_NETLINK, NETLINK_NO_ENOBUFS, &1i32)?;
+        let timeout = libc::timeval {
+            tv_sec: POLL_INTERVAL.as_secs() as libc::time_t,
+            tv_usec: POLL_INTERVAL.subsec_micros() as libc::suseconds_t,
+        };
+        set_option(&fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout)?;
+
+        let mut socket = Self { fd, queue, seq: 0, buf: vec![0; 0x20000], pending: Vec::new() };
+        let seq = socket.next_seq();
+        socket.send(&encode_bind(queue, seq, config))?;
+        socket.wait_ack(seq)?;
+        Ok(socket)
+    }
+
+    pub fn queue(&self) -> u16 {
+        self.queue
+    }
+
+    // Blocks until packets arrive or the poll interval passes, then takes
+    // whatever else is already waiting, up to `max` packets
+    pub fn recv_batch(&mut self, max: usize) -> io::Result<Vec<QueuedPacket>> {
+        let mut packets = mem::take(&mut self.pending);
+        let mut flags = if packets.is_empty() { 0 } else { libc::MSG_DONTWAIT };
+        while packets.len() < max {
+            match self.recv(flags) {
+                Ok(messages) => packets.extend(messages.into_iter().filter_map(|message| match message {
+                    Message::Packet(packet) => Some(packet),
+                    Message::Ack { .. } => None,
+                })),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !packets.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+            flags = libc::MSG_DONTWAIT;
+        }
+        if packets.len() > max {
+            self.pending = packets.split_off(max);
+        }
+        Ok(packets)
+    }
+
+    pub fn send_verdicts(&mut self, verdicts: &[(u32, NfVerdict)]) -> io::Result<()> {
+        if verdicts.is_empty() {
+            return Ok(());
+        }
+        let seq = self.next_seq();
+        self.send(&encode_verdicts(self.queue, seq, verdicts))
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    fn wait_ack(&mut self, seq: u32) -> io::Result<()> {
+        loop {
+            for message in self.recv(0)? {
+                match message {
+                    Message::Ack { seq: acked, errno } if acked == seq => {
+                        return if errno == 0 { Ok(()) } else { Err(io::Error::from_raw_os_error(errno)) };
+                    }
+                    Message::Packet(packet) => self.pending.push(packet),
+                    Message::Ack { .. } => {}
+                }
+            }
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), message.as_ptr().cast(), message.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, flags: i32) -> io::Result<Vec<Message>> {
+        let read = unsafe { libc::recv(self.fd.as_raw_fd(), self.buf.as_mut_ptr().cast(), self.buf.len(), flags) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        parse_messages(&self.buf[..read as usize])
+    }
+}
+
+impl Drop for NfQueue {
+    fn drop(&mut self) {
+        let seq = self.next_seq();
+        let _ = self.send(&encode_unbind(self.queue, seq));
+    }
+}
+
+fn set_option<T>(fd: &OwnedFd, level: i32, name: i32, value: &T) -> io::Result<()> {
+    let set = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Totals across every worker
+#[derive(Debug, Default)]
+pub struct NfQueueStats {
+    packets: AtomicU64,
+    accepted: AtomicU64,
+    dropped: AtomicU64,
+    // Dropped with a reply to the sender; counted apart from `dropped`
+    rejected: AtomicU64,
+    // Rejects that went without a reply because it couldn't be sent
+    unanswered: AtomicU64,
+    // Dropped because they didn't decode
+    undecodable: AtomicU64,
+    // Writes carrying the verdicts for a batch
+    batches: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl fmt::Display for NfQueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} packets ({} accepted, {} dropped, {} rejected, {} unanswered, {} undecodable) in {} batches, {} errors",
+            load(&self.packets),
+            load(&self.accepted),
+            load(&self.dropped),
+            load(&self.rejected),
+            load(&self.unanswered),
+            load(&self.undecodable),
+            load(&self.batches),
+            load(&self.errors),
+        )
+    }
+}
+
+// One thread per queue, each with its own socket, all feeding the same
+// engine. Stops the workers when dropped.
+pub struct NfQueueWorkers {
+    stop: Arc<AtomicBool>,
+    stats: Arc<NfQueueStats>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl NfQueueWorkers {
+    // Binds every queue before starting any worker, so a queue another
+    // process holds fails the whole start
+    pub fn start(engine: Arc<Firewall>, config: &NfQueueConfig) -> io::Result<Self> {
+        let sockets = config.queues.clone()
+            .map(|queue| NfQueue::open(queue, config)
+                .map_err(|e| io::Error::new(e.kind(), format!("queue {}: {}", queue, e))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Without it rejects still drop, but the sender isn't told
+        let replies = match RejectSender::open() {
+            Ok(sender) => Some(Arc::new(sender)),
+            Err(e) => {
+                log::warn!("nfqueue: can't send reject replies, rejects will drop silently: {}", e);
+                None
+            }
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(NfQueueStats::default());
+        let mut workers = Vec::new();
+        for socket in sockets {
+            let engine = Arc::clone(&engine);
+            let stop = Arc::clone(&stop);
+            let stats = Arc::clone(&stats);
+            let replies = replies.clone();
+            let batch_size = config.batch_size.max(1);
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("nfqueue-{}", socket.queue()))
+                    .spawn(move || run(socket, &engine, replies.as_deref(), &stop, &stats, batch_size))?,
+            );
+        }
+        Ok(Self { stop, stats, workers })
+    }
+
+    pub fn stats(&self) -> &NfQueueStats {
+        &self.stats
+    }
+}
+
+impl Drop for NfQueueWorkers {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run(
+    mut socket: NfQueue,
+    engine: &Firewall,
+    replies: Option<&RejectSender>,
+    stop: &AtomicBool,
+    stats: &NfQueueStats,
+    batch_size: usize,
+) {
+    let mut verdicts = Vec::with_capacity(batch_size);
+    while !stop.load(Ordering::Relaxed) {
+        let packets = match socket.recv_batch(batch_size) {
+            Ok(packets) if packets.is_empty() => continue,
+            Ok(packets) => packets,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                log::error!("nfqueue {}: receive failed: {}", socket.queue(), e);
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+
+        verdicts.clear();
+        for packet in &packets {
+            let verdict = match Packet::from_ip(&packet.payload) {
+                Ok(decoded) => {
+                    let verdict = NfVerdict::of(&engine.process_verdict(&decoded));
+                    if let NfVerdict::Reject(with) = verdict
+                        && !answer(replies, &packet.payload, &decoded, with)
+                    {
+                        stats.unanswered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    verdict
+                }
+                Err(e) => {
+                    stats.undecodable.fetch_add(1, Ordering::Relaxed);
+                    log::debug!("nfqueue {}: dropping packet {}: {}", socket.queue(), packet.id, e);
+                    NfVerdict::Drop
+                }
+            };
+            let counter = match verdict {
+                NfVerdict::Accept { .. } => &stats.accepted,
+                NfVerdict::Drop => &stats.dropped,
+                NfVerdict::Reject(_) => &stats.rejected,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            verdicts.push((packet.id, verdict));
+        }
+        stats.packets.fetch_add(packets.len() as u64, Ordering::Relaxed);
+
+        match socket.send_verdicts(&verdicts) {
+            Ok(()) => {
+                stats.batches.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                log::error!("nfqueue {}: sending {} verdicts failed: {}", socket.queue(), verdicts.len(), e);
+            }
+        }
+    }
+}
+
+// Sends the reply for a rejected packet; false if it had to go without.
+// Packets the kernel wouldn't answer either count as answered.
+fn answer(replies: Option<&RejectSender>, datagram: &[u8], packet: &Packet, with: RejectWith) -> bool {
+    let Some(reply) = reject::build_reply(datagram, packet, with) else {
+        return true;
+    };
+    let Some(replies) = replies else {
+        return false;
+    };
+    match replies.send(&reply) {
+        Ok(()) => true,
+        Err(e) => {
+            log::debug!("nfqueue: sending a reject reply to {} failed: {}", packet.source_ip, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE: u16 = 0x0102;
+    const SEQ: u32 = 7;
+
+    // A netlink message for the queue subsystem built field by field, to
+    // hold the encoders to
+    fn message(msg: u16, flags: u16, seq: u32, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = vec![0, 0, 0x01, 0x02];
+        for (kind, payload) in attrs {
+            body.extend_from_slice(&(4 + payload.len() as u16).to_ne_bytes());
+            body.extend_from_slice(&kind.to_ne_bytes());
+            body.extend_from_slice(payload);
+            while body.len() % 4 != 0 {
+                body.push(0);
+            }
+        }
+        let mut out = Vec::new();
+        out.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&(0x0300 | msg).to_ne_bytes());
+        out.extend_from_slice(&flags.to_ne_bytes());
+        out.extend_from_slice(&seq.to_ne_bytes());
+        out.extend_from_slice(&0u32.to_ne_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn verdict_header(code: u32, id: u32) -> Vec<u8> {
+        [code.to_be_bytes(), id.to_be_bytes()].concat()
+    }
+
+    fn verdict(msg: u16, code: u32, id: u32, mark: Option<u32>) -> Vec<u8> {
+        let header = verdict_header(code, id);
+        let mark = mark.map(u32::to_be_bytes);
+        let mut attrs: Vec<(u16, &[u8])> = vec![(NFQA_VERDICT_HDR, &header)];
+        if let Some(mark) = &mark {
+            attrs.push((NFQA_MARK, mark));
+        }
+        message(msg, NLM_F_REQUEST, SEQ, &attrs)
+    }
+
+    const ACCEPT: NfVerdict = NfVerdict::Accept { mark: None };
+
+    fn marked(mark: u32) -> NfVerdict {
+        NfVerdict::Accept { mark: Some(mark) }
+    }
+
+    #[test]
+    fn maps_engine_verdicts() {
+        let mut marked_verdict = Verdict::new(Action::Allow);
+        marked_verdict.mark = Some(9);
+        assert_eq!(NfVerdict::of(&marked_verdict), marked(9));
+        assert_eq!(NfVerdict::of(&Verdict::new(Action::Block)), NfVerdict::Drop);
+        let reject = Verdict::new(Action::Reject(RejectWith::TcpReset));
+        assert_eq!(NfVerdict::of(&reject), NfVerdict::Reject(RejectWith::TcpReset));
+        let mut monitored = Verdict::new(Action::Block);
+        monitored.monitored = true;
+        assert_eq!(NfVerdict::of(&monitored), ACCEPT);
+    }
+
+    #[test]
+    fn encodes_runs_as_batches() {
+        let verdicts = [
+            (1, ACCEPT),
+            (2, ACCEPT),
+            (3, NfVerdict::Drop),
+            (4, marked(7)),
+            (5, marked(7)),
+            (6, marked(8)),
+            (7, NfVerdict::Reject(RejectWith::IcmpPortUnreachable)),
+            (8, NfVerdict::Drop),
+            (9, ACCEPT),
+        ];
+        let expected = [
+            verdict(NFQNL_MSG_VERDICT_BATCH, NF_ACCEPT, 2, None),
+            verdict(NFQNL_MSG_VERDICT, NF_DROP, 3, None),
+            verdict(NFQNL_MSG_VERDICT_BATCH, NF_ACCEPT, 5, Some(7)),
+            verdict(NFQNL_MSG_VERDICT, NF_ACCEPT, 6, Some(8)),
+            // A reject is a drop to the kernel, so it shares the batch
+            verdict(NFQNL_MSG_VERDICT_BATCH, NF_DROP, 8, None),
+            verdict(NFQNL_MSG_VERDICT, NF_ACCEPT, 9, None),
+        ]
+        .concat();
+        assert_eq!(encode_verdicts(QUEUE, SEQ, &verdicts), expected);
+        assert!(encode_verdicts(QUEUE, SEQ, &[]).is_empty());
+    }
+
+    // What the kernel makes of the encoded verdicts: a verdict message
+    // settles its id, a batch settles every unsettled id up to its own
+    fn settle(queued: &[u32], encoded: &[u8]) -> Vec<Option<(u32, Option<u32>)>> {
+        let mut settled = vec![None; queued.len()];
+        let mut buf = encoded;
+        while !buf.is_empty() {
+            let len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+            let msg = u16::from_ne_bytes(buf[4..6].try_into().unwrap()) & 0xff;
+            let mut attrs = &buf[20..len];
+            let (mut header, mut mark) = (None, None);
+            while !attrs.is_empty() {
+                let attr_len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+                let value = &attrs[4..attr_len];
+                match u16::from_ne_bytes(attrs[2..4].try_into().unwrap()) {
+                    NFQA_VERDICT_HDR => header = Some(value.to_vec()),
+                    NFQA_MARK => mark = Some(u32::from_be_bytes(value.try_into().unwrap())),
+                    kind => panic!("unexpected attribute {}", kind),
+                }
+                attrs = &attrs[align(attr_len)..];
+            }
+            let header = header.expect("verdict header");
+            let code = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let id = u32::from_be_bytes(header[4..].try_into().unwrap());
+            for (slot, queued_id) in settled.iter_mut().zip(queued) {
+                let covered = match msg {
+                    NFQNL_MSG_VERDICT => *queued_id == id,
+                    NFQNL_MSG_VERDICT_BATCH => *queued_id <= id,
+                    _ => panic!("unexpected message {}", msg),
+                };
+                if covered && slot.is_none() {
+                    *slot = Some((code, mark));
+                }
+            }
+            buf = &buf[len..];
+        }
+        settled
+    }
+
+    #[test]
+    fn batches_never_cover_another_verdict() {
+        let choices = [
+            ACCEPT,
+            marked(1),
+            marked(2),
+            NfVerdict::Drop,
+            NfVerdict::Reject(RejectWith::TcpReset),
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        for _ in 0..500 {
+            // Ids ascend with gaps, where other packets were settled already
+            let mut id = next(1000) as u32;
+            let verdicts: Vec<(u32, NfVerdict)> = (0..1 + next(40))
+                .map(|_| {
+                    id += 1 + next(3) as u32;
+                    // Long runs are likely
+                    (id, choices[next(choices.len()).min(next(choices.len()))])
+                })
+                .collect();
+            let ids: Vec<u32> = verdicts.iter().map(|(id, _)| *id).collect();
+            let settled = settle(&ids, &encode_verdicts(QUEUE, SEQ, &verdicts));
+            let expected: Vec<_> = verdicts.iter().map(|(_, verdict)| Some(verdict.wire())).collect();
+            assert_eq!(settled, expected, "{:?}", verdicts);
+        }
+    }
+
+    #[test]
+    fn encodes_bind() {
+        let params = [0, 0, 0xff, 0xff, NFQNL_COPY_PACKET];
+        for (fail_open, flags) in [(false, 0u32), (true, 1)] {
+            let config = NfQueueConfig { max_len: 4096, fail_open, ..NfQueueConfig::default() };
+            let expected = message(
+                NFQNL_MSG_CONFIG,
+                NLM_F_REQUEST | NLM_F_ACK,
+                SEQ,
+                &[
+                    (NFQA_CFG_CMD, &[NFQNL_CFG_CMD_BIND, 0, 0, 0]),
+                    (NFQA_CFG_PARAMS, &params),
+                    (NFQA_CFG_QUEUE_MAXLEN, &4096u32.to_be_bytes()),
+                    (NFQA_CFG_MASK, &1u32.to_be_bytes()),
+                    (NFQA_CFG_FLAGS, &flags.to_be_bytes()),
+                ],
+            );
+            let encoded = encode_bind(QUEUE, SEQ, &config);
+            assert_eq!(encoded, expected);
+            // The five-byte params are padded out
+            assert_eq!(encoded.len(), 16 + 4 + 8 + 12 + 8 + 8 + 8);
+        }
+        assert_eq!(
+            encode_unbind(QUEUE, SEQ),
+            message(NFQNL_MSG_CONFIG, NLM_F_REQUEST, SEQ, &[(NFQA_CFG_CMD, &[NFQNL_CFG_CMD_UNBIND, 0, 0, 0])])
+        );
+    }
+
+    fn ack(seq: u32, errno: i32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&36u32.to_ne_bytes());
+        out.extend_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        out.extend_from_slice(&0u16.to_ne_bytes());
+        out.extend_from_slice(&seq.to_ne_bytes());
+        out.extend_from_slice(&0u32.to_ne_bytes());
+        // struct nlmsgerr: negative errno, then the request's header
+        out.extend_from_slice(&(-errno).to_ne_bytes());
+        out.extend_from_slice(&[0; 16]);
+        out
+    }
+
+    fn queued(id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut header = id.to_be_bytes().to_vec();
+        header.extend_from_slice(&[0x08, 0x00, 1]);
+        message(
+            NFQNL_MSG_PACKET,
+            0,
+            0,
+            &[
+                (NFQA_PACKET_HDR, &header),
+                (NFQA_MARK, &5u32.to_be_bytes()),
+                (NFQA_PAYLOAD, payload),
+            ],
+        )
+    }
+
+    #[test]
+    fn parses_acks_and_packets() {
+        let mut buf = ack(3, 0);
+        buf.extend(ack(4, libc::EPERM));
+        buf.extend(queued(41, &[0x45, 1, 2, 3, 4]));
+        // Something else from the subsystem, skipped
+        buf.extend(message(NFQNL_MSG_CONFIG, 0, 0, &[]));
+        buf.extend(queued(42, &[]));
+        assert_eq!(
+            parse_messages(&buf).unwrap(),
+            vec![
+                Message::Ack { seq: 3, errno: 0 },
+                Message::Ack { seq: 4, errno: libc::EPERM },
+                Message::Packet(QueuedPacket { id: 41, payload: vec![0x45, 1, 2, 3, 4] }),
+                Message::Packet(QueuedPacket { id: 42, payload: Vec::new() }),
+            ]
+        );
+        assert!(parse_messages(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn masks_attribute_flags() {
+        let mut packet = queued(9, &[0x60]);
+        // NLA_F_NET_BYTEORDER on the packet header attribute
+        packet[22..24].copy_from_slice(&(NFQA_PACKET_HDR | 0x4000).to_ne_bytes());
+        assert_eq!(
+            parse_messages(&packet).unwrap(),
+            vec![Message::Packet(QueuedPacket { id: 9, payload: vec![0x60] })]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lengths() {
+        let malformed = |buf: &[u8]| parse_messages(buf).unwrap_err().kind() == io::ErrorKind::InvalidData;
+        let packet = queued(1, &[0x45; 6]);
+        let set_len = |buf: &[u8], len: u32| {
+            let mut buf = buf.to_vec();
+            buf[0..4].copy_from_slice(&len.to_ne_bytes());
+            buf
+        };
+
+        // Message lengths shorter than a header, or past the datagram
+        assert!(malformed(&set_len(&packet, 8)));
+        assert!(malformed(&set_len(&packet, packet.len() as u32 + 4)));
+        assert!(malformed(&packet[..packet.len() - 4]));
+
+        // A trailing fragment too short to be a message is ignored
+        let mut trailing = packet.clone();
+        trailing.extend_from_slice(&[0; 8]);
+        assert_eq!(parse_messages(&trailing).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn skips_malformed_messages_and_keeps_the_rest() {
+        let packet = queued(1, &[0x45; 6]);
+        let set_len = |buf: &[u8], len: u32| {
+            let mut buf = buf.to_vec();
+            buf[0..4].copy_from_slice(&len.to_ne_bytes());
+            buf
+        };
+        let set_attr_len = |at: usize, len: u16| {
+            let mut buf = packet.clone();
+            buf[at..at + 2].copy_from_slice(&len.to_ne_bytes());
+            buf
+        };
+        // Each bad message followed by a good one
+        let parse = |bad: &[u8]| {
+            let mut buf = bad.to_vec();
+            buf.resize(align(buf.len()), 0);
+            buf.extend(queued(2, &[0x60]));
+            parse_messages(&buf).unwrap()
+        };
+        let good = || Message::Packet(QueuedPacket { id: 2, payload: vec![0x60] });
+
+        // An ack too short for its errno, a packet message too short for its
+        // nfgenmsg
+        assert_eq!(parse(&set_len(&ack(1, 0)[..18], 18)), [good()]);
+        assert_eq!(parse(&set_len(&packet[..18], 18)), [good()]);
+        // Attribute lengths shorter than a header, or past the message,
+        // before the id is known
+        assert_eq!(parse(&set_attr_len(20, 2)), [good()]);
+        assert_eq!(parse(&set_attr_len(20, 200)), [good()]);
+        // A packet header too short for the id, or none at all
+        assert_eq!(parse(&set_attr_len(20, 6)), [good()]);
+        let headerless = message(NFQNL_MSG_PACKET, 0, 0, &[(NFQA_PAYLOAD, &[0x45])]);
+        assert_eq!(parse(&headerless), [good()]);
+
+        // Past the id, the packet is kept without its payload so it's
+        // dropped rather than left queued
+        let dropped = Message::Packet(QueuedPacket { id: 1, payload: Vec::new() });
+        assert_eq!(parse(&set_attr_len(40, 200)), [dropped, good()]);
+    }
+}
@@ -0,0 +1,126 @@
+use firewall_core::{ConfigError, Firewall};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// The rule config on disk and the mtime of the copy last loaded from it
+pub struct PolicyFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl PolicyFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), modified: None }
+    }
+
+    // Loads the file into the engine. A file that fails to parse or
+    // validate leaves the engine's current rule set in place.
+    pub fn load(&mut self, engine: &Firewall) -> Result<(), ConfigError> {
+        let modified = modified(&self.path);
+        load_policy(engine, &self.path)?;
+        self.modified = modified;
+        Ok(())
+    }
+
+    // Reloads the file if its mtime moved since the last successful load;
+    // returns whether it did. A bad edit is only reported once.
+    pub fn reload_if_changed(&mut self, engine: &Firewall) -> Result<bool, ConfigError> {
+        let on_disk = modified(&self.path);
+        if on_disk.is_none() || on_disk == self.modified {
+            return Ok(false);
+        }
+        let result = load_policy(engine, &self.path);
+        self.modified = on_disk;
+        result.map(|()| true)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Loads a rule config into the engine and reports rules that can't work as
+// written. Every config load goes through here.
+fn load_policy(engine: &Firewall, path: &Path) -> Result<(), ConfigError> {
+    let ids = engine.load_config(path)?;
+    log::info!("Loaded {} rules from {}", ids.len(), path.display());
+
+    let report = engine.analyze_rules();
+    for finding in &report.findings {
+        log::warn!("{}", finding);
+    }
+    for rule in &report.unmodeled {
+        log::debug!("Rule {} in chain '{}' was not analyzed", rule, rule.chain);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firewall_core::{Action, FirewallBuilder};
+    use std::fs::{self, File};
+    use std::time::Duration;
+
+    const GOOD: &str = r#"
+default_action = "allow"
+
+[[rule]]
+name = "no-telnet"
+type = "port_blocklist"
+ports = [23]
+"#;
+
+    // Jumps to a chain that isn't defined
+    const BAD: &str = r#"
+default_action = "allow"
+
+[[rule]]
+name = "no-telnet"
+chain = "missing"
+type = "port_blocklist"
+ports = [23]
+"#;
+
+    // Writes `contents` with an mtime `secs` on from the epoch, so quick
+    // rewrites are always seen as changes
+    fn write(path: &Path, contents: &str, secs: u64) {
+        fs::write(path, contents).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs);
+        File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn bad_reload_keeps_the_loaded_rules() {
+        let dir = std::env::temp_dir().join(format!("firewall-policy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("firewall.toml");
+        write(&path, GOOD, 0);
+
+        let engine = FirewallBuilder::new(Action::Allow).build();
+        let mut policy = PolicyFile::new(&path);
+        policy.load(&engine).unwrap();
+        let generation = engine.rule_generation();
+        assert!(!policy.reload_if_changed(&engine).unwrap());
+
+        write(&path, BAD, 10);
+        assert!(policy.reload_if_changed(&engine).is_err());
+        assert_eq!(engine.rule_generation(), generation);
+        assert_eq!(engine.list_rules().len(), 1);
+        // Reported once, not on every tick until it's fixed
+        assert!(!policy.reload_if_changed(&engine).unwrap());
+
+        write(&path, GOOD, 20);
+        assert!(policy.reload_if_changed(&engine).unwrap());
+        assert!(engine.rule_generation() > generation);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_fails_to_load() {
+        let engine = FirewallBuilder::new(Action::Allow).build();
+        let mut policy = PolicyFile::new("/nonexistent/firewall.toml");
+        assert!(policy.load(&engine).is_err());
+        assert!(!policy.reload_if_changed(&engine).unwrap());
+    }
+}
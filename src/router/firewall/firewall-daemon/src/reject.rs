@@ -0,0 +1,434 @@
+use firewall_core::{Packet, Protocol, RejectWith, TcpFlags};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// A queued packet can only be accepted or dropped, so the reply the kernel's
+// REJECT target would send is built and sent from here instead
+
+// Hop limit of the replies, as the kernel's default
+const REPLY_TTL: u8 = 64;
+// Errors quote as much of the offending packet as fits in these totals
+// (RFC 1812 4.3.2.3, RFC 4443 2.4)
+const ICMP_MAX_LEN: usize = 576;
+const ICMPV6_MAX_LEN: usize = 1280;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const ICMP_HEADER_LEN: usize = 8;
+
+// The reply to `packet`, which was queued as `datagram`, or None where the
+// kernel wouldn't answer either: non-first fragments, RSTs, ICMP errors and
+// packets from or to no single host. A TCP reset for anything but TCP is a
+// port unreachable instead, as in the backends.
+pub fn build_reply(datagram: &[u8], packet: &Packet, with: RejectWith) -> Option<Vec<u8>> {
+    if packet.fragment_offset != 0 || !unicast(packet.source_ip) || !unicast(packet.destination_ip) {
+        return None;
+    }
+    if let Some(icmp) = packet.icmp
+        && icmp.is_error(packet.protocol)
+    {
+        return None;
+    }
+    match (with, packet.tcp) {
+        (RejectWith::TcpReset, Some(tcp)) if packet.protocol == Protocol::Tcp => {
+            if tcp.flags.contains(TcpFlags::RST) {
+                return None;
+            }
+            Some(tcp_reset(packet))
+        }
+        _ => Some(icmp_unreachable(datagram, packet, with)),
+    }
+}
+
+fn unicast(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()),
+        IpAddr::V6(ip) => !(ip.is_unspecified() || ip.is_multicast()),
+    }
+}
+
+fn tcp_reset(packet: &Packet) -> Vec<u8> {
+    let tcp = packet.tcp.expect("checked by the caller");
+    let mut segment = vec![0u8; TCP_HEADER_LEN];
+    segment[0..2].copy_from_slice(&packet.destination_port.unwrap_or(0).to_be_bytes());
+    segment[2..4].copy_from_slice(&packet.source_port.unwrap_or(0).to_be_bytes());
+    // A reset must carry the sequence number the peer expects: the one it
+    // acknowledged, or else an ack of everything it sent
+    if tcp.flags.contains(TcpFlags::ACK) {
+        segment[4..8].copy_from_slice(&tcp.acknowledgment.to_be_bytes());
+        segment[13] = TcpFlags::RST.bits() as u8;
+    } else {
+        let consumed = [TcpFlags::SYN, TcpFlags::FIN].iter().filter(|f| tcp.flags.contains(**f)).count();
+        let ack = tcp.sequence.wrapping_add(packet.payload.len() as u32).wrapping_add(consumed as u32);
+        segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        segment[13] = (TcpFlags::RST | TcpFlags::ACK).bits() as u8;
+    }
+    segment[12] = (TCP_HEADER_LEN as u8 / 4) << 4;
+    wrap(packet.destination_ip, packet.source_ip, 6, segment, 16)
+}
+
+fn icmp_unreachable(datagram: &[u8], packet: &Packet, with: RejectWith) -> Vec<u8> {
+    let v4 = packet.source_ip.is_ipv4();
+    let (kind, code, max_len, next_header) = match (with, v4) {
+        (RejectWith::IcmpHostUnreachable, true) => (3, 1, ICMP_MAX_LEN, 1),
+        (RejectWith::IcmpAdminProhibited, true) => (3, 13, ICMP_MAX_LEN, 1),
+        (_, true) => (3, 3, ICMP_MAX_LEN, 1),
+        (RejectWith::IcmpHostUnreachable, false) => (1, 3, ICMPV6_MAX_LEN, 58),
+        (RejectWith::IcmpAdminProhibited, false) => (1, 1, ICMPV6_MAX_LEN, 58),
+        (_, false) => (1, 4, ICMPV6_MAX_LEN, 58),
+    };
+    let header_len = if v4 { IPV4_HEADER_LEN } else { IPV6_HEADER_LEN };
+    let quoted = datagram.len().min(max_len - header_len - ICMP_HEADER_LEN);
+    let mut message = vec![0u8; ICMP_HEADER_LEN];
+    message[0] = kind;
+    message[1] = code;
+    message.extend_from_slice(&datagram[..quoted]);
+    wrap(packet.destination_ip, packet.source_ip, next_header, message, 2)
+}
+
+// Puts an IP header from `source` to `destination` in front of `body` and
+// fills in the checksum at `checksum_at` within the body. ICMPv4 is the
+// only one without a pseudo-header.
+fn wrap(source: IpAddr, destination: IpAddr, next_header: u8, mut body: Vec<u8>, checksum_at: usize) -> Vec<u8> {
+    let mut out = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let sum = if next_header == 1 { 0 } else { pseudo_header_v4(source, destination, next_header, body.len()) };
+            let checksum = checksum(sum, &body);
+            body[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+            ipv4_header(source, destination, next_header, body.len())
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let checksum = checksum(pseudo_header_v6(source, destination, next_header, body.len()), &body);
+            body[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+            ipv6_header(source, destination, next_header, body.len())
+        }
+        _ => unreachable!("a packet's addresses are of one family"),
+    };
+    out.extend_from_slice(&body);
+    out
+}
+
+fn ipv4_header(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, body_len: usize) -> Vec<u8> {
+    let mut header = vec![0u8; IPV4_HEADER_LEN];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&((IPV4_HEADER_LEN + body_len) as u16).to_be_bytes());
+    // Don't fragment
+    header[6] = 0x40;
+    header[8] = REPLY_TTL;
+    header[9] = protocol;
+    header[12..16].copy_from_slice(&source.octets());
+    header[16..20].copy_from_slice(&destination.octets());
+    let checksum = checksum(0, &header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn ipv6_header(source: Ipv6Addr, destination: Ipv6Addr, next_header: u8, body_len: usize) -> Vec<u8> {
+    let mut header = vec![0u8; IPV6_HEADER_LEN];
+    header[0] = 0x60;
+    header[4..6].copy_from_slice(&(body_len as u16).to_be_bytes());
+    header[6] = next_header;
+    header[7] = REPLY_TTL;
+    header[8..24].copy_from_slice(&source.octets());
+    header[24..40].copy_from_slice(&destination.octets());
+    header
+}
+
+fn pseudo_header_v4(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&destination.octets());
+    header.extend_from_slice(&[0, protocol]);
+    header.extend_from_slice(&(len as u16).to_be_bytes());
+    sum_words(0, &header)
+}
+
+fn pseudo_header_v6(source: Ipv6Addr, destination: Ipv6Addr, next_header: u8, len: usize) -> u32 {
+    let mut header = Vec::with_capacity(40);
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&destination.octets());
+    header.extend_from_slice(&(len as u32).to_be_bytes());
+    header.extend_from_slice(&[0, 0, 0, next_header]);
+    sum_words(0, &header)
+}
+
+fn sum_words(mut sum: u32, bytes: &[u8]) -> u32 {
+    for word in bytes.chunks(2) {
+        let high = word[0] as u32;
+        let low = word.get(1).copied().unwrap_or(0) as u32;
+        sum += high << 8 | low;
+    }
+    sum
+}
+
+// RFC 1071 internet checksum of `bytes`, carrying on from `sum`
+fn checksum(sum: u32, bytes: &[u8]) -> u16 {
+    let mut sum = sum_words(sum, bytes);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Raw sockets the replies go out on. The IP header is ours, so the kernel
+// only routes them.
+pub struct RejectSender {
+    v4: OwnedFd,
+    v6: OwnedFd,
+}
+
+impl RejectSender {
+    // Needs CAP_NET_RAW, which the queue itself doesn't
+    pub fn open() -> io::Result<Self> {
+        Ok(Self { v4: raw_socket(libc::AF_INET)?, v6: raw_socket(libc::AF_INET6)? })
+    }
+
+    pub fn send(&self, reply: &[u8]) -> io::Result<()> {
+        let sent = match reply.first().map(|b| b >> 4) {
+            Some(4) if reply.len() >= IPV4_HEADER_LEN => {
+                let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from_ne_bytes(reply[16..20].try_into().unwrap());
+                send_to(&self.v4, reply, &addr)
+            }
+            Some(6) if reply.len() >= IPV6_HEADER_LEN => {
+                let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr.copy_from_slice(&reply[24..40]);
+                send_to(&self.v6, reply, &addr)
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an IP datagram")),
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// IPPROTO_RAW implies the header is included, for IPv6 as well
+fn raw_socket(family: i32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::IPPROTO_RAW) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn send_to<T>(fd: &OwnedFd, reply: &[u8], addr: &T) -> isize {
+    unsafe {
+        libc::sendto(
+            fd.as_raw_fd(),
+            reply.as_ptr().cast(),
+            reply.len(),
+            0,
+            addr as *const T as *const libc::sockaddr,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "192.0.2.1";
+    const SERVER: &str = "10.0.0.1";
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn segment(source_port: u16, destination_port: u16, sequence: u32, ack: u32, flags: TcpFlags, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0u8; TCP_HEADER_LEN];
+        segment[0..2].copy_from_slice(&source_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&destination_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&sequence.to_be_bytes());
+        segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        segment[12] = 0x50;
+        segment[13] = flags.bits() as u8;
+        segment[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&source_port.to_be_bytes());
+        datagram.extend_from_slice(&destination_port.to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    // An IP datagram from `source` to `destination`
+    fn datagram(source: &str, destination: &str, protocol: u8, body: Vec<u8>) -> Vec<u8> {
+        let checksum_at = match protocol {
+            6 => 16,
+            17 => 6,
+            _ => 2,
+        };
+        wrap(ip(source), ip(destination), protocol, body, checksum_at)
+    }
+
+    fn answer_to(datagram: &[u8], with: RejectWith) -> Option<(Vec<u8>, Packet)> {
+        let packet = Packet::from_ip(datagram).unwrap();
+        let reply = build_reply(datagram, &packet, with)?;
+        let decoded = Packet::from_ip(&reply).unwrap();
+        Some((reply, decoded))
+    }
+
+    // Checksums that verify sum to zero
+    fn assert_checksums(reply: &[u8]) {
+        match reply[0] >> 4 {
+            4 => {
+                assert_eq!(checksum(0, &reply[..IPV4_HEADER_LEN]), 0, "IP header checksum");
+                let source: [u8; 4] = reply[12..16].try_into().unwrap();
+                let destination: [u8; 4] = reply[16..20].try_into().unwrap();
+                let body = &reply[IPV4_HEADER_LEN..];
+                let sum = match reply[9] {
+                    1 => 0,
+                    protocol => pseudo_header_v4(source.into(), destination.into(), protocol, body.len()),
+                };
+                assert_eq!(checksum(sum, body), 0, "transport checksum");
+            }
+            _ => {
+                let source: [u8; 16] = reply[8..24].try_into().unwrap();
+                let destination: [u8; 16] = reply[24..40].try_into().unwrap();
+                let body = &reply[IPV6_HEADER_LEN..];
+                let sum = pseudo_header_v6(source.into(), destination.into(), reply[6], body.len());
+                assert_eq!(checksum(sum, body), 0, "transport checksum");
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        assert_eq!(checksum(0, &[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        // An odd byte is padded with zero
+        assert_eq!(checksum(0, &[0x01]), !0x0100);
+    }
+
+    #[test]
+    fn resets_a_syn_by_acknowledging_it() {
+        let syn = datagram(CLIENT, SERVER, 6, segment(40000, 22, 1000, 0, TcpFlags::SYN, b"hello"));
+        let (reply, packet) = answer_to(&syn, RejectWith::TcpReset).unwrap();
+        assert_checksums(&reply);
+        assert_eq!((packet.source_ip, packet.destination_ip), (ip(SERVER), ip(CLIENT)));
+        assert_eq!((packet.source_port, packet.destination_port), (Some(22), Some(40000)));
+        assert_eq!(packet.ttl, REPLY_TTL);
+        let tcp = packet.tcp.unwrap();
+        assert_eq!(tcp.flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!((tcp.sequence, tcp.acknowledgment), (0, 1006));
+        assert!(packet.payload.is_empty());
+    }
+
+    #[test]
+    fn resets_an_established_segment_at_its_ack() {
+        let data = datagram(CLIENT, SERVER, 6, segment(40000, 22, 5000, 0xffff_fff0, TcpFlags::ACK, b"data"));
+        let (reply, packet) = answer_to(&data, RejectWith::TcpReset).unwrap();
+        assert_checksums(&reply);
+        let tcp = packet.tcp.unwrap();
+        assert_eq!(tcp.flags, TcpFlags::RST);
+        assert_eq!((tcp.sequence, tcp.acknowledgment), (0xffff_fff0, 0));
+
+        // Sequence numbers wrap
+        let fin = datagram(CLIENT, SERVER, 6, segment(40000, 22, u32::MAX, 0, TcpFlags::FIN, b""));
+        let (_, packet) = answer_to(&fin, RejectWith::TcpReset).unwrap();
+        assert_eq!(packet.tcp.unwrap().acknowledgment, 0);
+    }
+
+    #[test]
+    fn resets_over_ipv6() {
+        let syn = datagram("2001:db8::1", "2001:db8::2", 6, segment(40000, 443, 7, 0, TcpFlags::SYN, b""));
+        let (reply, packet) = answer_to(&syn, RejectWith::TcpReset).unwrap();
+        assert_eq!(reply.len(), IPV6_HEADER_LEN + TCP_HEADER_LEN);
+        assert_checksums(&reply);
+        assert_eq!(packet.destination_ip, ip("2001:db8::1"));
+        assert_eq!(packet.destination_port, Some(40000));
+        assert_eq!(packet.tcp.unwrap().acknowledgment, 8);
+    }
+
+    #[test]
+    fn never_resets_a_reset() {
+        let rst = datagram(CLIENT, SERVER, 6, segment(40000, 22, 1, 0, TcpFlags::RST, b""));
+        assert!(answer_to(&rst, RejectWith::TcpReset).is_none());
+    }
+
+    #[test]
+    fn answers_udp_with_the_chosen_unreachable() {
+        let query = datagram(CLIENT, SERVER, 17, udp(5353, 53, b"query"));
+        for (with, code) in [
+            (RejectWith::TcpReset, 3),
+            (RejectWith::IcmpPortUnreachable, 3),
+            (RejectWith::IcmpHostUnreachable, 1),
+            (RejectWith::IcmpAdminProhibited, 13),
+        ] {
+            let (reply, packet) = answer_to(&query, with).unwrap();
+            assert_checksums(&reply);
+            assert_eq!(packet.protocol, Protocol::Icmp);
+            let icmp = packet.icmp.unwrap();
+            assert_eq!((icmp.icmp_type, icmp.code), (3, code), "{:?}", with);
+            // The whole query is quoted after the unused word
+            assert_eq!(&reply[IPV4_HEADER_LEN + ICMP_HEADER_LEN..], &query[..]);
+        }
+
+        let query = datagram("2001:db8::1", "2001:db8::2", 17, udp(5353, 53, b"query"));
+        for (with, code) in [
+            (RejectWith::IcmpPortUnreachable, 4),
+            (RejectWith::IcmpHostUnreachable, 3),
+            (RejectWith::IcmpAdminProhibited, 1),
+        ] {
+            let (reply, packet) = answer_to(&query, with).unwrap();
+            assert_checksums(&reply);
+            assert_eq!(packet.protocol, Protocol::Icmpv6);
+            let icmp = packet.icmp.unwrap();
+            assert_eq!((icmp.icmp_type, icmp.code), (1, code), "{:?}", with);
+        }
+    }
+
+    #[test]
+    fn quotes_no_more_than_the_minimum_mtu() {
+        let big = datagram(CLIENT, SERVER, 17, udp(5353, 53, &[0xab; 1400]));
+        let (reply, _) = answer_to(&big, RejectWith::IcmpPortUnreachable).unwrap();
+        assert_eq!(reply.len(), ICMP_MAX_LEN);
+        assert_eq!(&reply[IPV4_HEADER_LEN + ICMP_HEADER_LEN..], &big[..ICMP_MAX_LEN - 28]);
+        assert_checksums(&reply);
+
+        let big = datagram("2001:db8::1", "2001:db8::2", 17, udp(5353, 53, &[0xab; 1400]));
+        let (reply, _) = answer_to(&big, RejectWith::IcmpPortUnreachable).unwrap();
+        assert_eq!(reply.len(), ICMPV6_MAX_LEN);
+        assert_checksums(&reply);
+    }
+
+    #[test]
+    fn stays_quiet_where_the_kernel_would() {
+        // An ICMP error about an ICMP error
+        let mut unreachable = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        unreachable.extend_from_slice(&datagram(SERVER, CLIENT, 17, udp(53, 5353, b"")));
+        let error = datagram(CLIENT, SERVER, 1, unreachable);
+        assert!(answer_to(&error, RejectWith::IcmpAdminProhibited).is_none());
+        // Echo requests are answered
+        let ping = datagram(CLIENT, SERVER, 1, vec![8, 0, 0, 0, 0, 1, 0, 1]);
+        assert!(answer_to(&ping, RejectWith::IcmpAdminProhibited).is_some());
+
+        // Broadcast and multicast
+        let query = datagram(CLIENT, "255.255.255.255", 17, udp(68, 67, b""));
+        assert!(answer_to(&query, RejectWith::IcmpPortUnreachable).is_none());
+        let query = datagram("fe80::1", "ff02::1:2", 17, udp(546, 547, b""));
+        assert!(answer_to(&query, RejectWith::IcmpPortUnreachable).is_none());
+        let query = datagram("0.0.0.0", SERVER, 17, udp(68, 67, b""));
+        assert!(answer_to(&query, RejectWith::IcmpPortUnreachable).is_none());
+
+        // Only the first fragment is answered
+        let mut fragment = datagram(CLIENT, SERVER, 17, udp(5353, 53, &[0; 16]));
+        fragment[6..8].copy_from_slice(&0x2003u16.to_be_bytes());
+        assert!(answer_to(&fragment, RejectWith::IcmpPortUnreachable).is_none());
+        fragment[6..8].copy_from_slice(&0x2000u16.to_be_bytes());
+        assert!(answer_to(&fragment, RejectWith::IcmpPortUnreachable).is_some());
+    }
+}
@@ -0,0 +1,87 @@
+#!/bin/sh
+# End-to-end check of passive capture that needs no root. The daemon
+# watches one end of a veth pair in monitor mode while a peer namespace
+# connects through it with trafficgen: blocked ports must stay reachable
+# and raise alerts instead.
+#
+#   cargo build --bin firewall-daemon --example trafficgen
+#   tests/netns/capture.sh [TARGET_DIR]
+#
+# TARGET_DIR defaults to the workspace's ../target/debug. Needs unshare,
+# nsenter and ip, and unprivileged user namespaces. Exits 77 (skipped) when
+# any of that is missing.
+set -eu
+
+if [ -z "${FW_NETNS_INNER:-}" ]; then
+    for tool in unshare nsenter ip; do
+        command -v "$tool" >/dev/null || { echo "skip: $tool not found"; exit 77; }
+    done
+    unshare --user --map-root-user --net true 2>/dev/null \
+        || { echo "skip: unprivileged user namespaces are disabled"; exit 77; }
+    bin=$(cd "${1:-../target/debug}" && pwd)
+    FW_NETNS_INNER=1 exec unshare --user --map-root-user --net sh "$0" "$bin"
+fi
+
+bin=$1
+work=$(mktemp -d)
+pids=""
+cleanup() {
+    # shellcheck disable=SC2086
+    [ -n "$pids" ] && kill $pids 2>/dev/null
+    rm -rf "$work"
+}
+trap cleanup EXIT
+fail() {
+    echo "FAIL: $*"
+    [ -f "$work/firewall.log" ] && cat "$work/firewall.log"
+    exit 1
+}
+
+# Peer namespace, held open by a sleeping process
+unshare --net sleep 600 &
+peer=$!
+pids="$pids $peer"
+sleep 0.2
+in_peer() { nsenter --target "$peer" --net "$@"; }
+
+ip link set lo up
+ip link add fw0 type veth peer name peer0 netns "$peer"
+ip addr add 10.99.0.1/24 dev fw0
+ip link set fw0 up
+in_peer ip link set lo up
+in_peer ip addr add 10.99.0.2/24 dev peer0
+in_peer ip link set peer0 up
+
+cat > "$work/firewall.toml" <<'TOML'
+default_action = "accept"
+
+[[rule]]
+name = "no-telnet"
+type = "port_blocklist"
+ports = [23]
+protocols = ["tcp"]
+action = "block"
+TOML
+
+# Only IPv4 TCP reaches the ring (tcpdump -ddd 'ip and tcp')
+bpf="6,40 0 0 12,21 0 3 2048,48 0 0 23,21 0 1 6,6 0 0 262144,6 0 0 0"
+(cd "$work" && exec "$bin/firewall-daemon" firewall.toml --capture fw0 --bpf "$bpf" >/dev/null 2>&1) &
+pids="$pids $!"
+"$bin/examples/trafficgen" listen 10.99.0.1 22 23 &
+pids="$pids $!"
+sleep 1
+
+out=$(in_peer "$bin/examples/trafficgen" probe 10.99.0.1 22 23 --count 5 --timeout-ms 300)
+echo "$out"
+sleep 0.5
+expect() {
+    echo "$out" | grep -qx "$1" || fail "expected '$1'"
+}
+# Monitor mode never drops
+expect "22 open=5 refused=0 filtered=0"
+expect "23 open=5 refused=0 filtered=0"
+
+alerts=$(grep -c "would block tcp 10.99.0.2:[0-9]* -> 10.99.0.1:23" "$work/firewall.log" || true)
+[ "$alerts" -gt 0 ] || fail "no alerts for port 23"
+grep -q -- "-> 10.99.0.1:22" "$work/firewall.log" && fail "alert raised for port 22"
+echo "PASS: $alerts alerts"